@@ -0,0 +1,253 @@
+use crate::cmd::table::{self, CommandInfo};
+use crate::util::{read, write};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// User every connection starts out as
+pub const DEFAULT_USER: &str = "default";
+
+/// Reply to commands sent before a required AUTH
+pub const NOAUTH_ERR: &str = "NOAUTH Authentication required.";
+
+/// Reply to AUTH with an unknown user, a wrong password or a disabled user
+pub const WRONGPASS_ERR: &str = "WRONGPASS invalid username-password pair or user is disabled.";
+
+/// Users and the commands they may run
+///
+/// Only command permissions are modelled: every user may access every key
+/// and channel. Passwords are kept as given rather than hashed.
+#[derive(Clone)]
+pub struct Acl {
+    users: Arc<RwLock<HashMap<String, User>>>,
+}
+
+/// One ACL user, changed with `ACL SETUSER`
+#[derive(Clone, Debug)]
+pub struct User {
+    enabled: bool,
+    nopass: bool,
+    passwords: Vec<String>,
+
+    /// `+`/`-` rules in the order given; the last one matching a command
+    /// decides, and a command no rule matches is denied
+    rules: Vec<(bool, Selector)>,
+}
+
+#[derive(Clone, Debug)]
+enum Selector {
+    All,
+    Category(u32),
+    Command(&'static str),
+}
+
+impl Selector {
+    fn matches(&self, info: &CommandInfo) -> bool {
+        match self {
+            Selector::All => true,
+            Selector::Category(bit) => info.categories() & bit != 0,
+            Selector::Command(name) => info.name == *name,
+        }
+    }
+}
+
+impl User {
+    /// A new user: disabled, without passwords and allowed nothing
+    fn new() -> User {
+        User {
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            rules: Vec::new(),
+        }
+    }
+
+    /// Apply one `ACL SETUSER` rule
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        let invalid =
+            |reason: &str| format!("ERR Error in ACL SETUSER modifier '{}': {}", rule, reason);
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allcommands" | "+@all" => self.rules = vec![(true, Selector::All)],
+            "nocommands" | "-@all" => self.rules = vec![(false, Selector::All)],
+            // Keys and channels are not restricted
+            "allkeys" | "~*" | "allchannels" | "&*" | "resetkeys" | "resetchannels" => {}
+            "reset" => *self = User::new(),
+            _ => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.nopass = false;
+                    if !self.passwords.iter().any(|p| p == password) {
+                        self.passwords.push(password.to_string());
+                    }
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    self.passwords.retain(|p| p != password);
+                } else if let Some(name) = rule.strip_prefix('+') {
+                    self.rules.push((
+                        true,
+                        selector(name)
+                            .ok_or_else(|| invalid("Unknown command or category name in ACL"))?,
+                    ));
+                } else if let Some(name) = rule.strip_prefix('-') {
+                    self.rules.push((
+                        false,
+                        selector(name)
+                            .ok_or_else(|| invalid("Unknown command or category name in ACL"))?,
+                    ));
+                } else if rule.starts_with('~') || rule.starts_with('&') {
+                    return Err(invalid("Key and channel patterns are not supported"));
+                } else {
+                    return Err(invalid("Syntax error"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the user may run the command described by `info`
+    pub fn can_run(&self, info: &CommandInfo) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(_, selector)| selector.matches(info))
+            .is_some_and(|&(allow, _)| allow)
+    }
+}
+
+/// `@category` or a command name, as used in `+`/`-` rules
+fn selector(name: &str) -> Option<Selector> {
+    match name.strip_prefix('@') {
+        Some(category) if category.eq_ignore_ascii_case("all") => Some(Selector::All),
+        Some(category) => table::category(category).map(Selector::Category),
+        None => table::lookup(name).map(|info| Selector::Command(info.name)),
+    }
+}
+
+impl Acl {
+    /// Just the default user, enabled without a password and allowed every
+    /// command
+    pub fn new() -> Acl {
+        let mut default = User::new();
+        for rule in ["on", "nopass", "+@all"] {
+            default.apply(rule).unwrap();
+        }
+        Acl {
+            users: Arc::new(RwLock::new(HashMap::from([(
+                DEFAULT_USER.to_string(),
+                default,
+            )]))),
+        }
+    }
+
+    /// Create `name` if needed and apply `rules` to it in order
+    ///
+    /// Nothing changes if any rule is invalid.
+    pub fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut users = write(&self.users);
+        let mut user = users.get(name).cloned().unwrap_or_else(User::new);
+        for rule in rules {
+            user.apply(rule)?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Names of every user, sorted
+    pub fn users(&self) -> Vec<String> {
+        let mut names: Vec<_> = read(&self.users).keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Whether `password` logs in as `name`
+    pub fn authenticate(&self, name: &str, password: &str) -> bool {
+        read(&self.users).get(name).is_some_and(|user| {
+            user.enabled && (user.nopass || user.passwords.iter().any(|p| p == password))
+        })
+    }
+
+    /// Whether a new connection is logged in as the default user without
+    /// sending AUTH
+    pub fn default_user_needs_auth(&self) -> bool {
+        !read(&self.users)
+            .get(DEFAULT_USER)
+            .is_some_and(|user| user.enabled && user.nopass)
+    }
+
+    /// The NOPERM error to refuse `command` with when sent by `user`,
+    /// `None` if the user may run it
+    pub fn refusal(&self, user: &str, command: &str) -> Option<String> {
+        let info = table::lookup(command)?;
+        let allowed = read(&self.users)
+            .get(user)
+            .is_some_and(|user| user.enabled && user.can_run(info));
+        (!allowed).then(|| {
+            format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                user, info.name
+            )
+        })
+    }
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<String> {
+        rules.iter().map(|rule| rule.to_string()).collect()
+    }
+
+    #[test]
+    fn test_rules_by_category() {
+        let acl = Acl::new();
+        acl.set_user("reader", &rules(&["on", "nopass", "+@read"]))
+            .unwrap();
+        assert_eq!(acl.refusal("reader", "get"), None);
+        assert_eq!(
+            acl.refusal("reader", "set").as_deref(),
+            Some("NOPERM User reader has no permissions to run the 'set' command")
+        );
+
+        // The last matching rule wins
+        acl.set_user("ops", &rules(&["on", "+@all", "-@dangerous", "+info"]))
+            .unwrap();
+        assert_eq!(acl.refusal("ops", "set"), None);
+        assert!(acl.refusal("ops", "flushdb").is_some());
+        assert_eq!(acl.refusal("ops", "info"), None);
+
+        assert!(acl
+            .set_user("reader", &rules(&["-get", "+@nosuch"]))
+            .is_err());
+        assert_eq!(acl.refusal("reader", "get"), None);
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let acl = Acl::new();
+        assert!(!acl.default_user_needs_auth());
+        acl.set_user("alice", &rules(&[">secret", "+@all"]))
+            .unwrap();
+        assert!(!acl.authenticate("alice", "secret"));
+        acl.set_user("alice", &rules(&["on"])).unwrap();
+        assert!(acl.authenticate("alice", "secret"));
+        assert!(!acl.authenticate("alice", "wrong"));
+        assert!(!acl.authenticate("bob", "secret"));
+
+        acl.set_user(DEFAULT_USER, &rules(&[">pw"])).unwrap();
+        assert!(acl.default_user_needs_auth());
+    }
+}
@@ -1,216 +1,38 @@
-use anyhow::Result;
-use rust_redis::{
-    cmd::Command,
-    command_metrics::{self, CommandMetricsCollector, MetricsStrategy, SharedCommandMetrics},
-    connection::Connection,
-    db::Db,
-    metrics::{Metrics, SharedMetrics},
-    persistence::{Aof, AofSyncPolicy},
-    pubsub::PubSub,
-};
-use std::sync::Arc;
-use std::time::Instant;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::signal;
-use tracing::{debug, error, info, warn};
+use anyhow::{anyhow, Result};
+use rust_redis::config::ServerConfig;
+use rust_redis::server::{self, Server};
+use tracing::info;
 
-#[tokio::main(flavor = "multi_thread")]
-async fn main() -> Result<()> {
-    // Initialize tracing subscriber for structured logging
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .with_thread_ids(true)
-        .with_level(true)
-        .init();
-
-    // Create the shared database
-    let db = Db::new();
-
-    // Create Pub/Sub manager
-    let pubsub = PubSub::new();
-    info!("Pub/Sub system initialized");
-
-    // Create metrics
-    let metrics = Metrics::new();
-    info!("Metrics system initialized");
-
-    let disable_aof = std::env::var("RUSTREDIS_DISABLE_AOF")
-        .map(|v| {
-            let normalized = v.to_ascii_lowercase();
-            normalized == "1" || normalized == "true" || normalized == "yes"
-        })
-        .unwrap_or(false);
-    let aof_path = std::env::var("RUSTREDIS_AOF_PATH").unwrap_or_else(|_| "appendonly.aof".to_string());
-
-    // Create per-command metrics collector
-    let strategy = std::env::var("RUSTREDIS_METRICS_STRATEGY")
-        .map(|s| MetricsStrategy::from_str_loose(&s))
-        .unwrap_or(MetricsStrategy::Sharded2Key);
-    let command_metrics = CommandMetricsCollector::new(strategy);
-    info!("Command metrics initialized (strategy: {})", strategy.name());
-
-    // Start background flush task for ThreadLocalBatched strategy
-    if let Some(tl_collector) = command_metrics.thread_local_collector() {
-        command_metrics::start_thread_local_flush_task(tl_collector);
-        info!("Thread-local metrics flush task started (100ms interval)");
-    }
-
-    // Start background flush task for HdrHistogram strategy
-    if let Some(hdr_collector) = command_metrics.hdr_histogram_collector() {
-        command_metrics::start_hdr_flush_task(hdr_collector);
-        info!("HdrHistogram metrics flush task started (100ms interval)");
-    }
-
-    // Initialize AOF persistence unless explicitly disabled for experiment runs.
-    let aof = if disable_aof {
-        warn!("AOF persistence disabled via RUSTREDIS_DISABLE_AOF");
-        None
-    } else {
-        match Aof::new(&aof_path, AofSyncPolicy::EverySecond) {
-            Ok(aof) => {
-                info!(
-                    "AOF persistence enabled with EverySecond sync policy (path: {})",
-                    aof_path
-                );
-                let aof = Arc::new(aof);
-
-                // Start background sync task
-                Arc::clone(&aof).start_background_sync();
-
-                // Try to load existing AOF file
-                match Aof::load(&aof_path) {
-                    Ok(frames) => {
-                        info!("Loaded {} commands from AOF", frames.len());
-                        // Replay commands to restore state
-                        for frame in frames {
-                            if let Ok(cmd) = Command::from_frame(frame) {
-                                let _ = cmd.replay(&db);
-                            }
-                        }
-                        info!("AOF replay completed");
-                    }
-                    Err(e) => {
-                        warn!("Could not load AOF (this is normal on first run): {}", e);
-                    }
-                }
-
-                Some(aof)
-            }
-            Err(e) => {
-                warn!("AOF persistence disabled: {}", e);
-                None
-            }
-        }
-    };
+fn main() -> Result<()> {
+    let mut config = ServerConfig::from_env();
+    config
+        .apply_args(std::env::args().skip(1))
+        .map_err(|e| anyhow!(e))?;
 
-    // Bind the TCP listener to port 6379 (Redis default port)
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
-
-    info!("RustRedis server listening on 127.0.0.1:6379");
-    info!("Press CTRL+C to shutdown gracefully");
-
-    loop {
-        tokio::select! {
-            // Accept incoming connections
-            result = listener.accept() => {
-                let (socket, addr) = result?;
-
-                info!("Accepted connection from: {}", addr);
-
-                // Clone handles for this connection
-                let db = db.clone();
-                let aof = aof.clone();
-                let pubsub = pubsub.clone();
-                let metrics = Arc::clone(&metrics);
-                let command_metrics = Arc::clone(&command_metrics);
-
-                metrics.increment_connections();
-
-                // Spawn a new task to handle the connection
-                tokio::spawn(async move {
-                    if let Err(e) = handle_connection(
-                        socket, db, aof, pubsub,
-                        Arc::clone(&metrics),
-                        Arc::clone(&command_metrics),
-                    ).await {
-                        error!("Error handling connection: {}", e);
-                    }
-                    metrics.decrement_connections();
-                });
-            }
-
-            // Listen for shutdown signal (CTRL+C)
-            _ = signal::ctrl_c() => {
-                info!("Received shutdown signal. Gracefully shutting down...");
-                break;
-            }
-        }
+    // Initialize tracing subscriber for structured logging
+    tracing::subscriber::set_global_default(server::log_subscriber(&config)?)?;
+
+    // Build the runtime by hand so the worker count is configurable
+    let runtime = config.build_runtime()?;
+    if config.single_thread {
+        info!("Running on a single-threaded runtime");
+    } else if let Some(threads) = config.io_threads {
+        info!("Running with {} worker threads", threads);
     }
 
-    info!("Server shut down successfully");
-    Ok(())
-}
-
-/// Handle a single client connection
-async fn handle_connection(
-    socket: TcpStream,
-    db: Db,
-    aof: Option<Arc<Aof>>,
-    pubsub: PubSub,
-    metrics: SharedMetrics,
-    command_metrics: SharedCommandMetrics,
-) -> Result<()> {
-    // Wrap the socket in our Connection struct
-    let mut connection = Connection::new(socket);
-
-    debug!("Connection handler started");
+    runtime.block_on(async move {
+        let server = Server::new(config);
 
-    // Process commands in a loop
-    loop {
-        // Read a frame from the connection
-        let frame = match connection.read_frame().await? {
-            Some(frame) => frame,
-            None => {
-                // Connection closed
-                debug!("Client disconnected");
-                return Ok(());
-            }
-        };
+        // Bind the TCP listener to port 6379 (Redis default port)
+        let listener = server.bind(([127, 0, 0, 1], 6379).into())?;
 
-        debug!("Received frame: {}", frame);
+        info!("RustRedis server listening on {}", listener.local_addr()?);
+        info!("Press CTRL+C to shutdown gracefully");
 
-        // Parse the frame into a command
-        let command = match Command::from_frame(frame.clone()) {
-            Ok(cmd) => cmd,
-            Err(e) => {
-                error!("Failed to parse command: {}", e);
-                continue;
-            }
-        };
+        // Shut down gracefully on CTRL+C or SIGTERM
+        server.run(listener, server::shutdown_signal()?).await?;
 
-        // Log write commands to AOF (with timing)
-        if let Some(ref aof_writer) = aof {
-            if command.is_write_command() {
-                let aof_start = Instant::now();
-                if let Err(e) = aof_writer.append(&frame) {
-                    error!("Failed to append to AOF: {}", e);
-                }
-                metrics.add_aof_write_time_us(aof_start.elapsed().as_micros() as u64);
-            }
-        }
-
-        // Execute the command (with timing)
-        let cmd_name = command.name();
-        let metrics_key_hint = command.metrics_key_hint();
-        let cmd_start = Instant::now();
-        command
-            .execute(&db, &mut connection, &pubsub, &metrics, &command_metrics)
-            .await?;
-        let duration_us = cmd_start.elapsed().as_micros() as u64;
-        metrics.add_command_duration_us(duration_us);
-        metrics.increment_commands();
-
-        // Record per-command metrics
-        command_metrics.record(cmd_name, metrics_key_hint, duration_us);
-    }
+        info!("Server shut down successfully");
+        Ok(())
+    })
 }
@@ -0,0 +1,136 @@
+use crate::acl::DEFAULT_USER;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Source of connection ids; ids are never reused
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Which replies a connection is sent (CLIENT REPLY)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplyMode {
+    On,
+    Off,
+    /// Skip the reply to the next command only
+    Skip,
+}
+
+/// Per-connection state reported by the CLIENT commands
+#[derive(Debug)]
+pub struct ClientInfo {
+    /// Unique id (CLIENT ID)
+    pub id: u64,
+
+    /// Remote address, if the socket still had one when accepted
+    pub addr: Option<SocketAddr>,
+
+    /// Local address the client connected to
+    pub laddr: Option<SocketAddr>,
+
+    /// Name set with CLIENT SETNAME
+    pub name: Option<String>,
+
+    /// Index of the selected database (SELECT)
+    pub db: usize,
+
+    /// ACL user the connection is logged in as (AUTH)
+    pub user: String,
+
+    /// Whether the connection may run commands other than AUTH
+    pub authenticated: bool,
+
+    /// Set with CLIENT REPLY
+    pub reply_mode: ReplyMode,
+
+    /// When the connection was accepted
+    created: Instant,
+
+    /// When the last command was received
+    last_interaction: Instant,
+
+    /// Lowercase name of the last command
+    last_command: Option<&'static str>,
+
+    /// Number of commands received
+    commands: u64,
+}
+
+impl ClientInfo {
+    pub fn new(addr: Option<SocketAddr>, laddr: Option<SocketAddr>) -> Self {
+        let now = Instant::now();
+        ClientInfo {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            addr,
+            laddr,
+            name: None,
+            db: 0,
+            user: DEFAULT_USER.to_string(),
+            authenticated: true,
+            reply_mode: ReplyMode::On,
+            created: now,
+            last_interaction: now,
+            last_command: None,
+            commands: 0,
+        }
+    }
+
+    /// Note that a command was received
+    pub fn record_command(&mut self, name: &'static str) {
+        self.last_interaction = Instant::now();
+        self.last_command = Some(name);
+        self.commands += 1;
+    }
+
+    /// Forget the name and selected database, turn replies back on and log
+    /// back in as the default user (RESET)
+    pub fn reset(&mut self) {
+        self.name = None;
+        self.db = 0;
+        self.reply_mode = ReplyMode::On;
+        self.user = DEFAULT_USER.to_string();
+    }
+
+    /// Whether the command just received is replied to
+    ///
+    /// A CLIENT REPLY SKIP is used up by this command.
+    pub fn take_reply(&mut self) -> bool {
+        match self.reply_mode {
+            ReplyMode::On => true,
+            ReplyMode::Off => false,
+            ReplyMode::Skip => {
+                self.reply_mode = ReplyMode::On;
+                false
+            }
+        }
+    }
+
+    /// Number of commands received
+    pub fn commands(&self) -> u64 {
+        self.commands
+    }
+
+    /// The CLIENT INFO line, without the trailing newline
+    ///
+    /// `multi` is the number of queued commands inside MULTI, or `None`
+    /// outside a transaction. CLIENT is not accepted in subscribed mode, so
+    /// a client that can ask is never subscribed.
+    pub fn describe(&self, multi: Option<usize>) -> String {
+        let addr = |addr: Option<SocketAddr>| addr.map(|a| a.to_string()).unwrap_or_default();
+        let cmd = self.last_command.unwrap_or("NULL").to_lowercase();
+        format!(
+            "id={} addr={} laddr={} name={} age={} idle={} flags={} db={} sub=0 psub=0 ssub=0 multi={} tot-cmds={} cmd={} user={} resp=2",
+            self.id,
+            addr(self.addr),
+            addr(self.laddr),
+            self.name.as_deref().unwrap_or(""),
+            self.created.elapsed().as_secs(),
+            self.last_interaction.elapsed().as_secs(),
+            if multi.is_some() { "x" } else { "N" },
+            self.db,
+            multi.map(|n| n as i64).unwrap_or(-1),
+            self.commands,
+            cmd,
+            self.user,
+        )
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A monotonic instant paired with the wall-clock time it was taken at
+///
+/// Expirations are stored as `Instant`s so they are immune to wall-clock
+/// jumps. Commands that speak absolute Unix time (EXPIREAT, EXPIRETIME, ...)
+/// convert through this single offset captured on first use.
+static BASE: OnceLock<(Instant, u64)> = OnceLock::new();
+
+fn base() -> (Instant, u64) {
+    *BASE.get_or_init(|| (Instant::now(), unix_time_ms()))
+}
+
+/// Current wall-clock time in milliseconds since the Unix epoch
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Convert a Unix timestamp in milliseconds to an `Instant`
+///
+/// Timestamps before the process started are clamped to the start, which
+/// is already in the past and therefore still expired.
+pub fn unix_ms_to_instant(unix_ms: u64) -> Instant {
+    let (instant, base_ms) = base();
+    if unix_ms >= base_ms {
+        instant + Duration::from_millis(unix_ms - base_ms)
+    } else {
+        instant
+            .checked_sub(Duration::from_millis(base_ms - unix_ms))
+            .unwrap_or(instant)
+    }
+}
+
+/// Convert an `Instant` to a Unix timestamp in milliseconds
+pub fn instant_to_unix_ms(at: Instant) -> u64 {
+    let (instant, base_ms) = base();
+    if at >= instant {
+        base_ms + (at - instant).as_millis() as u64
+    } else {
+        base_ms.saturating_sub((instant - at).as_millis() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let future = unix_time_ms() + 60_000;
+        assert_eq!(instant_to_unix_ms(unix_ms_to_instant(future)), future);
+
+        let now = Instant::now();
+        let converted = unix_ms_to_instant(instant_to_unix_ms(now));
+        let drift = if converted > now {
+            converted - now
+        } else {
+            now - converted
+        };
+        assert!(drift < Duration::from_millis(1));
+    }
+}
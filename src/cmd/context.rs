@@ -0,0 +1,116 @@
+use crate::client::ClientInfo;
+use crate::command_metrics::SharedCommandMetrics;
+use crate::db::Db;
+use crate::frame::Frame;
+use crate::metrics::SharedMetrics;
+use crate::pubsub::PubSub;
+use std::net::SocketAddr;
+
+/// Who issued a command
+///
+/// MONITOR shows client commands; events the server generates itself
+/// (keyspace and expiry notifications) are internal and hidden unless
+/// `monitor-internal-events` is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// Sent by a client over its connection
+    Client,
+
+    /// Generated by the server, never fed back to the notification system
+    Internal,
+}
+
+/// Everything a command can see while it runs besides its own arguments
+///
+/// Built by the connection for each command, see `Command::apply`.
+#[derive(Clone, Copy)]
+pub struct ExecContext<'a> {
+    /// Id of the connection running the command (CLIENT ID)
+    pub client_id: u64,
+
+    /// Remote address of the connection
+    pub peer_addr: Option<SocketAddr>,
+
+    /// Who issued the command
+    pub origin: Origin,
+
+    /// Index of the selected database (SELECT)
+    pub db_index: usize,
+
+    /// RESP version spoken on the connection; always 2, there is no HELLO
+    pub protocol: u8,
+
+    /// Whether the connection may run commands other than AUTH
+    pub authenticated: bool,
+
+    /// ACL user the connection is logged in as (ACL WHOAMI)
+    pub user: &'a str,
+
+    /// The selected database
+    pub db: &'a Db,
+
+    pub pubsub: &'a PubSub,
+
+    pub metrics: &'a SharedMetrics,
+
+    pub command_metrics: &'a SharedCommandMetrics,
+}
+
+impl<'a> ExecContext<'a> {
+    /// Context for a command sent by `client` against `db`
+    pub fn new(
+        client: &'a ClientInfo,
+        db: &'a Db,
+        pubsub: &'a PubSub,
+        metrics: &'a SharedMetrics,
+        command_metrics: &'a SharedCommandMetrics,
+    ) -> ExecContext<'a> {
+        ExecContext {
+            client_id: client.id,
+            peer_addr: client.addr,
+            origin: Origin::Client,
+            db_index: client.db,
+            protocol: 2,
+            authenticated: client.authenticated,
+            user: &client.user,
+            db,
+            pubsub,
+            metrics,
+            command_metrics,
+        }
+    }
+
+    /// Show `frame`, the command run in this context, to MONITOR clients
+    pub fn feed_monitor(&self, frame: &Frame) {
+        self.pubsub
+            .monitor()
+            .feed(self.origin, self.db_index, self.peer_addr, frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::Command;
+    use crate::command_metrics::{CommandMetricsCollector, MetricsStrategy};
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn test_command_reads_peer_addr_from_context() {
+        let addr: SocketAddr = "10.1.2.3:4567".parse().unwrap();
+        let client = ClientInfo::new(Some(addr), None);
+        let db = Db::new();
+        let pubsub = PubSub::new();
+        let metrics = Metrics::new();
+        let command_metrics = CommandMetricsCollector::new(MetricsStrategy::Disabled);
+        let ctx = ExecContext::new(&client, &db, &pubsub, &metrics, &command_metrics);
+        assert_eq!(ctx.peer_addr, Some(addr));
+
+        let frame = Frame::Array(vec![Frame::Bulk("CLIENT".into()), Frame::Bulk("ID".into())]);
+        let command = Command::from_frame(frame).unwrap();
+        assert_eq!(
+            command.apply_blocking(&ctx),
+            Frame::Integer(client.id as i64)
+        );
+    }
+}
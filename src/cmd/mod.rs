@@ -1,39 +1,157 @@
-use crate::command_metrics::SharedCommandMetrics;
+use crate::client::ReplyMode;
+use crate::clock;
 use crate::connection::Connection;
-use crate::db::Db;
+#[cfg(feature = "json")]
+use crate::db::json::JsonPath;
+use crate::db::{
+    Db, LexBound, Score, StreamEntry, StreamId, INVALID_LEX_RANGE_ERR, INVALID_STREAM_ID_ERR,
+};
 use crate::frame::Frame;
-use crate::metrics::SharedMetrics;
-use crate::pubsub::PubSub;
+use crate::util::{parse_redis_float, parse_redis_int};
 use bytes::Bytes;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
+use std::sync::OnceLock;
 use std::time::{Duration, Instant};
 
+pub mod context;
+pub mod table;
+mod validate;
+
+pub use context::ExecContext;
+pub use validate::CommandError;
+
 /// Represents a Redis command
 pub enum Command {
     /// PING [message] - Test connection
     Ping(Option<Bytes>),
 
-    /// SET key value [EX seconds] - Set a key-value pair with optional expiration
+    /// SET key value [EX seconds|PX ms|EXAT unix-s|PXAT unix-ms] [NX|XX] - Set a
+    /// key-value pair with optional expiration
     Set {
-        key: String,
+        key: Bytes,
+        value: Bytes,
+        expires_at: Option<Instant>,
+        /// Only set the key if it does not exist
+        nx: bool,
+        /// Only set the key if it already exists
+        xx: bool,
+    },
+
+    /// SETEX key seconds value | PSETEX key milliseconds value - SET with a TTL
+    SetEx {
+        key: Bytes,
         value: Bytes,
+        expires_at: Instant,
+        millis: bool,
+    },
+
+    /// GETEX key [EX seconds|PX ms|EXAT unix-s|PXAT unix-ms|PERSIST] - Get a String
+    /// and change its expiration
+    GetEx {
+        key: Bytes,
         expires_at: Option<Instant>,
+        /// Remove the expiration instead
+        persist: bool,
+    },
+
+    /// EXPIRE key seconds | PEXPIRE key milliseconds - Set a relative expiration
+    Expire {
+        key: Bytes,
+        expires_at: Instant,
+        millis: bool,
+    },
+
+    /// EXPIREAT key timestamp | PEXPIREAT key timestamp - Set an absolute expiration
+    ExpireAt {
+        key: Bytes,
+        unix_ms: i64,
+        millis: bool,
+    },
+
+    /// TTL key | PTTL key - Get the remaining time to live
+    Ttl { key: Bytes, millis: bool },
+
+    /// EXPIRETIME key | PEXPIRETIME key - Get the absolute expiration as a Unix timestamp
+    ExpireTime { key: Bytes, millis: bool },
+
+    /// APPEND key value - Append to a string, returning the new length
+    Append { key: Bytes, value: Bytes },
+
+    /// SETRANGE key offset value - Overwrite part of a string
+    SetRange {
+        key: Bytes,
+        offset: usize,
+        value: Bytes,
+    },
+
+    /// GETRANGE key start end - Get a substring
+    GetRange {
+        key: Bytes,
+        start: isize,
+        end: isize,
+    },
+
+    /// LCS key1 key2 [LEN] [IDX] [MINMATCHLEN len] [WITHMATCHLEN] - Longest
+    /// common subsequence of two strings
+    Lcs {
+        key1: Bytes,
+        key2: Bytes,
+        len: bool,
+        idx: bool,
+        min_match_len: usize,
+        with_match_len: bool,
     },
 
+    /// SETBIT key offset value - Set or clear one bit of a string
+    SetBit { key: Bytes, offset: u64, on: bool },
+
+    /// GETBIT key offset - Read one bit of a string
+    GetBit { key: Bytes, offset: u64 },
+
+    /// INCR key - Increment an integer by one
+    Incr { key: Bytes },
+
+    /// DECR key - Decrement an integer by one
+    Decr { key: Bytes },
+
+    /// INCRBY key increment - Increment an integer
+    IncrBy { key: Bytes, delta: i64 },
+
+    /// DECRBY key decrement - Decrement an integer
+    DecrBy { key: Bytes, delta: i64 },
+
+    /// INCRBYFLOAT key increment - Add a float to a string
+    IncrByFloat { key: Bytes, delta: f64 },
+
+    /// MSET key value [key value ...] - Set several key-value pairs
+    MSet { pairs: Vec<(Bytes, Bytes)> },
+
     /// GET key - Get a value by key
-    Get { key: String },
+    Get { key: Bytes },
 
     /// ECHO message - Echo back a message
     Echo { message: Bytes },
 
     /// DEL key [key ...] - Delete one or more keys
-    Del { keys: Vec<String> },
+    Del { keys: Vec<Bytes> },
+
+    /// UNLINK key [key ...] - Delete keys, freeing large values in the background
+    Unlink { keys: Vec<Bytes> },
 
     /// EXISTS key - Check if key exists
-    Exists { key: String },
+    Exists { key: Bytes },
+
+    /// COPY source destination [REPLACE] - Copy a key's value and TTL
+    Copy {
+        source: Bytes,
+        destination: Bytes,
+        replace: bool,
+    },
 
     /// TYPE key - Get the type of a value
-    Type { key: String },
+    Type { key: Bytes },
 
     /// DBSIZE - Get the number of keys in the database
     DbSize,
@@ -41,88 +159,581 @@ pub enum Command {
     /// FLUSHDB - Clear all keys from the database
     FlushDb,
 
+    /// SELECT index - Switch the connection to another database (handled by
+    /// the connection)
+    Select { index: usize },
+
     /// KEYS pattern - Get all keys matching a pattern
-    Keys { pattern: String },
+    Keys { pattern: Bytes },
+
+    /// COUNTKEYS pattern - Count the keys matching a pattern
+    CountKeys { pattern: Bytes },
+
+    /// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type] - Incrementally iterate keys
+    Scan {
+        cursor: u64,
+        pattern: Option<Bytes>,
+        count: usize,
+        type_name: Option<String>,
+    },
 
     // List commands
     /// LPUSH key value [value ...] - Push values to the left of a list
-    LPush { key: String, values: Vec<Bytes> },
+    LPush { key: Bytes, values: Vec<Bytes> },
 
     /// RPUSH key value [value ...] - Push values to the right of a list
-    RPush { key: String, values: Vec<Bytes> },
+    RPush { key: Bytes, values: Vec<Bytes> },
 
     /// LPOP key - Pop a value from the left of a list
-    LPop { key: String },
+    LPop { key: Bytes },
 
     /// RPOP key - Pop a value from the right of a list
-    RPop { key: String },
+    RPop { key: Bytes },
+
+    /// BLPOP/BRPOP key [key ...] timeout - Pop from the first non-empty
+    /// list, blocking until one is pushed to (handled by the connection
+    /// outside of MULTI); `None` blocks forever
+    BPop {
+        keys: Vec<Bytes>,
+        timeout: Option<Duration>,
+        left: bool,
+    },
 
     /// LRANGE key start stop - Get a range of elements from a list
     LRange {
-        key: String,
+        key: Bytes,
         start: isize,
         stop: isize,
     },
 
     /// LLEN key - Get the length of a list
-    LLen { key: String },
+    LLen { key: Bytes },
+
+    /// LINSERT key BEFORE|AFTER pivot value - Insert next to a pivot element
+    LInsert {
+        key: Bytes,
+        before: bool,
+        pivot: Bytes,
+        value: Bytes,
+    },
 
     // Set commands
     /// SADD key member [member ...] - Add members to a set
-    SAdd { key: String, members: Vec<String> },
+    SAdd { key: Bytes, members: Vec<Bytes> },
 
     /// SREM key member [member ...] - Remove members from a set
-    SRem { key: String, members: Vec<String> },
+    SRem { key: Bytes, members: Vec<Bytes> },
+
+    /// SPOP key [count] - Remove and return random members of a set
+    SPop { key: Bytes, count: Option<usize> },
+
+    /// SRANDMEMBER key [count] - Return random members of a set, a
+    /// negative count allowing repeats
+    SRandMember { key: Bytes, count: Option<i64> },
 
     /// SMEMBERS key - Get all members of a set
-    SMembers { key: String },
+    SMembers { key: Bytes },
 
     /// SISMEMBER key member - Check if a member exists in a set
-    SIsMember { key: String, member: String },
+    SIsMember { key: Bytes, member: Bytes },
 
     /// SCARD key - Get the cardinality (size) of a set
-    SCard { key: String },
+    SCard { key: Bytes },
 
     // Hash commands
     /// HSET key field value - Set a field in a hash
     HSet {
-        key: String,
-        field: String,
+        key: Bytes,
+        field: Bytes,
         value: Bytes,
     },
 
     /// HGET key field - Get a field from a hash
-    HGet { key: String, field: String },
+    HGet { key: Bytes, field: Bytes },
 
     /// HGETALL key - Get all fields and values from a hash
-    HGetAll { key: String },
+    HGetAll { key: Bytes },
 
     /// HDEL key field [field ...] - Delete fields from a hash
-    HDel { key: String, fields: Vec<String> },
+    HDel { key: Bytes, fields: Vec<Bytes> },
 
     /// HEXISTS key field - Check if a field exists in a hash
-    HExists { key: String, field: String },
+    HExists { key: Bytes, field: Bytes },
 
     /// HLEN key - Get the number of fields in a hash
-    HLen { key: String },
+    HLen { key: Bytes },
+
+    // Sorted set commands
+    /// ZADD key score member [score member ...] - Add members to a sorted set
+    ZAdd {
+        key: Bytes,
+        members: Vec<(Score, Bytes)>,
+    },
+
+    /// ZREM key member [member ...] - Remove members from a sorted set
+    ZRem { key: Bytes, members: Vec<Bytes> },
+
+    /// ZSCORE key member - Get the score of a member of a sorted set
+    ZScore { key: Bytes, member: Bytes },
+
+    /// ZCARD key - Get the number of members of a sorted set
+    ZCard { key: Bytes },
+
+    /// ZRANGE key start stop [WITHSCORES] - Get members by rank
+    ZRange {
+        key: Bytes,
+        start: isize,
+        stop: isize,
+        with_scores: bool,
+    },
+
+    /// ZRANGEBYLEX key min max [LIMIT offset count] - Get members in a
+    /// lexicographic range
+    ZRangeByLex {
+        key: Bytes,
+        min: LexBound,
+        max: LexBound,
+        limit: Option<(i64, i64)>,
+    },
+
+    /// ZLEXCOUNT key min max - Count members in a lexicographic range
+    ZLexCount {
+        key: Bytes,
+        min: LexBound,
+        max: LexBound,
+    },
+
+    // Stream commands
+    /// XADD key id|* field value [field value ...] - Append an entry to a
+    /// stream; `id` of `None` stands for `*`
+    XAdd {
+        key: Bytes,
+        id: Option<StreamId>,
+        fields: Vec<(Bytes, Bytes)>,
+    },
+
+    /// XLEN key - Get the number of entries in a stream
+    XLen { key: Bytes },
+
+    /// XRANGE key start end [COUNT count] - Get entries in an ID range
+    XRange {
+        key: Bytes,
+        start: StreamId,
+        end: StreamId,
+        count: Option<i64>,
+    },
+
+    /// XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id
+    /// [id ...] - Get entries added after `ids`; an ID of `None` stands
+    /// for `$`, the last one at the time of the call. With `block`, waits
+    /// for new entries (handled by the connection outside of MULTI);
+    /// `Some(None)` blocks forever
+    XRead {
+        keys: Vec<Bytes>,
+        ids: Vec<Option<StreamId>>,
+        count: Option<usize>,
+        block: Option<Option<Duration>>,
+    },
+
+    // JSON commands
+    /// JSON.SET key path value - Set a JSON document or a value inside it
+    #[cfg(feature = "json")]
+    JsonSet {
+        key: Bytes,
+        path: JsonPath,
+        value: serde_json::Value,
+    },
+
+    /// JSON.GET key [path] - Get a JSON document or a value inside it
+    #[cfg(feature = "json")]
+    JsonGet { key: Bytes, path: JsonPath },
 
     // Pub/Sub commands
     /// PUBLISH channel message - Publish a message to a channel
     Publish { channel: String, message: Bytes },
 
-    /// STATS - Get server statistics and metrics
-    Stats,
+    /// SUBSCRIBE channel [channel ...] - Enter subscribed mode (handled by the connection)
+    Subscribe { channels: Vec<String> },
+
+    /// UNSUBSCRIBE [channel ...] - Leave some or all channels (handled by the connection)
+    Unsubscribe { channels: Vec<String> },
+
+    /// SPUBLISH shardchannel message - Publish a message to a shard channel
+    SPublish { channel: String, message: Bytes },
+
+    /// SSUBSCRIBE shardchannel [shardchannel ...] - Enter subscribed mode on shard channels
+    SSubscribe { channels: Vec<String> },
+
+    /// SUNSUBSCRIBE [shardchannel ...] - Leave some or all shard channels
+    SUnsubscribe { channels: Vec<String> },
+
+    /// PUBSUB NUMSUB [channel ...] - Subscriber count of each channel
+    PubSubNumSub { channels: Vec<String> },
+
+    /// PUBSUB NUMPAT - Number of pattern subscriptions
+    PubSubNumPat,
+
+    /// PUBSUB RETAIN channel count - Replay the last `count` messages to new subscribers
+    PubSubRetain { channel: String, count: usize },
+
+    /// STATS / INFO [section ...] - Get server statistics and metrics,
+    /// only the named sections if any are given
+    Stats { sections: Vec<String> },
 
     /// CMDSTAT - Get per-command telemetry statistics
     CmdStat,
 
+    /// ROLE - Replication role: always a master, with its stream offset
+    Role,
+
+    /// FAILOVER [ABORT | options] - Hand the master role to a replica; never
+    /// possible since no replica can take over
+    Failover { abort: bool },
+
+    /// MEMORY STATS - Get a whole-database memory report
+    MemoryStats,
+
+    /// COMMAND GETKEYS command [arg ...] - Extract the key arguments of a command
+    CommandGetKeys { args: Vec<String> },
+
+    /// COMMAND COUNT - Number of commands in the command table
+    CommandCount,
+
+    /// COMMAND INFO [command ...] - Arity and key positions of commands
+    CommandInfo { names: Vec<String> },
+
+    /// OBJECT ENCODING key - Get the internal representation of a value
+    ObjectEncoding { key: Bytes },
+
+    /// DEBUG SLEEP seconds - Stall the connection (for latency testing)
+    DebugSleep { duration: Duration },
+
+    /// DEBUG ERROR message - Reply with `message` as an error (for testing
+    /// client error handling)
+    DebugError { message: String },
+
+    /// DEBUG PANIC - Panic the connection's task; debug builds only
+    #[cfg(debug_assertions)]
+    DebugPanic,
+
+    /// CLUSTER INFO|MYID|SLOTS|SHARDS|NODES - Standalone replies for
+    /// cluster-aware clients
+    Cluster { subcommand: String },
+
+    // Connection commands (handled by the connection, see `ClientInfo`)
+    /// CLIENT ID - Unique id of the connection
+    ClientId,
+
+    /// CLIENT SETNAME name - Name the connection
+    ClientSetName { name: String },
+
+    /// CLIENT GETNAME - Name of the connection, if set
+    ClientGetName,
+
+    /// CLIENT INFO - One line describing the connection
+    ClientInfo,
+
+    /// CLIENT REPLY ON|OFF|SKIP - Turn replies off, or skip the next one
+    ClientReply { mode: ReplyMode },
+
+    /// QUIT - Close the connection once the reply is sent
+    Quit,
+
+    /// RESET - Leave MULTI and subscribed mode and forget the connection's
+    /// name and selected database
+    Reset,
+
+    /// MONITOR - Stream every command the server runs until the client
+    /// disconnects
+    Monitor,
+
+    /// AUTH [username] password - Log the connection in as a user
+    Auth {
+        username: Option<String>,
+        password: String,
+    },
+
+    /// ACL SETUSER username [rule ...] - Create or change a user
+    AclSetUser {
+        username: String,
+        rules: Vec<String>,
+    },
+
+    /// ACL USERS - Names of every user
+    AclUsers,
+
+    /// ACL WHOAMI - User the connection is logged in as
+    AclWhoAmI,
+
+    /// ACL CAT [category] - ACL categories, or the commands in one
+    AclCat { category: Option<String> },
+
+    /// CONFIG GET pattern [pattern ...] - Settings matching the patterns
+    ConfigGet { patterns: Vec<String> },
+
+    /// CONFIG SET name value [name value ...] - Change settings at runtime
+    ConfigSet { pairs: Vec<(String, String)> },
+
+    /// CONFIG REWRITE - Write the current settings to the config file
+    ConfigRewrite,
+
+    /// BGREWRITEAOF - Rewrite the AOF from the current data in the background
+    BgRewriteAof,
+
+    /// SAVE - Write a snapshot of every database now
+    Save,
+
+    /// LASTSAVE - Unix time of the last successful snapshot
+    LastSave,
+
+    /// WAITAOF numlocal numreplicas timeout - Wait until the writes made so
+    /// far are fsynced to the AOF (`None` timeout blocks forever)
+    WaitAof {
+        numlocal: u64,
+        numreplicas: u64,
+        timeout: Option<Duration>,
+    },
+
+    /// LATENCY LATEST - Latest latency spike of every event
+    LatencyLatest,
+
+    /// LATENCY HISTORY event - Recent latency spikes of one event
+    LatencyHistory { event: String },
+
+    /// LATENCY RESET [event ...] - Forget recorded latency spikes
+    LatencyReset { events: Vec<String> },
+
+    // Transaction commands (handled by the connection, see `Transaction`)
+    /// MULTI - Start queueing commands
+    Multi,
+
+    /// EXEC - Run all queued commands
+    Exec,
+
+    /// DISCARD - Drop all queued commands
+    Discard,
+
+    /// WATCH key [key ...] - Abort the next EXEC if any of the keys change
+    Watch { keys: Vec<Bytes> },
+
+    /// UNWATCH - Forget all watched keys
+    Unwatch,
+
+    /// IDEMPOTENT token command [arg ...] - Run `command` at most once per
+    /// token (handled by the connection, see `IdempotencyCache`)
+    Idempotent {
+        token: String,
+        /// The wrapped command as sent, for the AOF
+        frame: Frame,
+        command: Box<Command>,
+    },
+
     /// Unknown command
     Unknown(String),
 }
 
+/// Parse an integer argument with Redis's strict rules (see `parse_redis_int`)
+fn parse_int_frame(frame: &Frame) -> Result<i64, String> {
+    match frame {
+        Frame::Bulk(data) => parse_redis_int(data),
+        Frame::Simple(s) => parse_redis_int(s.as_bytes()),
+        _ => Err(()),
+    }
+    .map_err(|_| "ERR value is not an integer or out of range".to_string())
+}
+
+/// Parse the argument of an EX, PX, EXAT or PXAT option of `command` into
+/// a deadline
+///
+/// Like SETEX, the amount must be positive. EXAT and PXAT are Unix
+/// timestamps; one in the past gives a deadline that has already passed.
+fn parse_expiry_option(option: &str, frame: &Frame, command: &str) -> Result<Instant, String> {
+    let amount = parse_int_frame(frame)?;
+    let invalid = || format!("ERR invalid expire time in '{}' command", command);
+    if amount <= 0 {
+        return Err(invalid());
+    }
+    let ms = match option {
+        "EX" | "EXAT" => amount.checked_mul(1000).ok_or_else(invalid)?,
+        _ => amount,
+    } as u64;
+    Ok(match option {
+        "EX" | "PX" => Instant::now()
+            .checked_add(Duration::from_millis(ms))
+            .ok_or_else(invalid)?,
+        _ => clock::unix_ms_to_instant(ms),
+    })
+}
+
+/// PEXPIREAT of `key` at `expires_at`, as logged in place of a relative TTL
+fn pexpireat_frame(key: &Bytes, expires_at: Instant) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(b"PEXPIREAT")),
+        Frame::Bulk(key.clone()),
+        Frame::Bulk(Bytes::from(
+            clock::instant_to_unix_ms(expires_at).to_string(),
+        )),
+    ])
+}
+
+/// Parse a sorted set score, accepting `inf`, `+inf` and `-inf`
+fn parse_score_frame(frame: &Frame) -> Result<Score, String> {
+    match frame {
+        Frame::Bulk(data) => std::str::from_utf8(data).ok(),
+        Frame::Simple(s) => Some(s.as_str()),
+        _ => None,
+    }
+    .and_then(|s| s.parse::<f64>().ok())
+    .and_then(Score::new)
+    .ok_or_else(|| "ERR value is not a valid float".to_string())
+}
+
+/// Parse a ZRANGEBYLEX/ZLEXCOUNT bound: `-`, `+`, `[member` or `(member`
+fn parse_lex_bound_frame(frame: &Frame) -> Result<LexBound, String> {
+    match frame {
+        Frame::Bulk(data) => LexBound::parse(data),
+        Frame::Simple(s) => LexBound::parse(s.as_bytes()),
+        _ => Err(INVALID_LEX_RANGE_ERR),
+    }
+    .map_err(str::to_string)
+}
+
+/// Parse an ID argument of a stream command with `parse`, see `StreamId`
+fn parse_stream_id_frame(
+    frame: &Frame,
+    parse: impl FnOnce(&[u8]) -> Result<StreamId, &'static str>,
+) -> Result<StreamId, String> {
+    match frame {
+        Frame::Bulk(data) => parse(data),
+        Frame::Simple(s) => parse(s.as_bytes()),
+        _ => Err(INVALID_STREAM_ID_ERR),
+    }
+    .map_err(str::to_string)
+}
+
+/// Reply form of a stream entry: its ID, then its fields and values
+fn stream_entry_frame(id: StreamId, fields: Vec<(Bytes, Bytes)>) -> Frame {
+    let fields = fields
+        .into_iter()
+        .flat_map(|(field, value)| [Frame::Bulk(field), Frame::Bulk(value)])
+        .collect();
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from(id.to_string())),
+        Frame::Array(fields),
+    ])
+}
+
+/// Resolve the `$` IDs of XREAD to the last ID of their stream
+pub fn xread_ids(
+    db: &Db,
+    keys: &[Bytes],
+    ids: &[Option<StreamId>],
+) -> Result<Vec<StreamId>, &'static str> {
+    keys.iter()
+        .zip(ids)
+        .map(|(key, id)| id.map_or_else(|| db.xlast_id(key), Ok))
+        .collect()
+}
+
+/// Reply form of XREAD: `[key, [entry ...]]` per stream, Null if there
+/// are no entries
+pub fn xread_frame(streams: Vec<(Bytes, Vec<StreamEntry>)>) -> Frame {
+    if streams.is_empty() {
+        return Frame::Null;
+    }
+    Frame::Array(
+        streams
+            .into_iter()
+            .map(|(key, entries)| {
+                let entries = entries
+                    .into_iter()
+                    .map(|(id, fields)| stream_entry_frame(id, fields))
+                    .collect();
+                Frame::Array(vec![Frame::Bulk(key), Frame::Array(entries)])
+            })
+            .collect(),
+    )
+}
+
+/// The sections of an INFO reply asked for by `INFO [section ...]`
+///
+/// No section or `default` gives every section but `commandstats`, like
+/// Redis; `all` and `everything` give every section.
+fn info_sections(info: &str, sections: &[String]) -> String {
+    let asked = |name: &str| sections.iter().any(|section| section == name);
+    let keep = |name: &str| match name {
+        _ if asked("all") || asked("everything") => true,
+        "commandstats" => asked("commandstats"),
+        _ => sections.is_empty() || asked("default") || asked(name),
+    };
+
+    let kept: Vec<&str> = info
+        .split("\r\n\r\n")
+        .map(|section| section.trim_end_matches("\r\n"))
+        .filter(|section| {
+            section
+                .lines()
+                .next()
+                .and_then(|header| header.strip_prefix("# "))
+                .is_some_and(|name| keep(&name.to_lowercase()))
+        })
+        .collect();
+    if kept.is_empty() {
+        return String::new();
+    }
+    kept.join("\r\n\r\n") + "\r\n"
+}
+
+/// Reply form of a sorted set score
+fn score_frame(score: Score) -> Frame {
+    Frame::Bulk(Bytes::from(score.value().to_string()))
+}
+
+/// One `COMMAND INFO` entry: name, arity, flags, first key, last key, step
+fn command_info_frame(info: &table::CommandInfo) -> Frame {
+    let flags = table::FLAGS
+        .iter()
+        .filter(|&&(_, bit)| info.flags & bit != 0)
+        .map(|&(name, _)| Frame::Simple(name.to_string()))
+        .collect();
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(info.name.as_bytes())),
+        Frame::Integer(info.arity.to_redis() as i64),
+        Frame::Array(flags),
+        Frame::Integer(info.first_key as i64),
+        Frame::Integer(info.last_key as i64),
+        Frame::Integer(info.step as i64),
+    ])
+}
+
+/// 40 hex character node id reported by `CLUSTER MYID`
+///
+/// Generated once per process from randomly seeded hashers, so it stays
+/// the same for the lifetime of the server.
+fn cluster_node_id() -> &'static str {
+    static NODE_ID: OnceLock<String> = OnceLock::new();
+    NODE_ID.get_or_init(|| {
+        let mut id = String::with_capacity(48);
+        while id.len() < 40 {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u64(clock::unix_time_ms());
+            id.push_str(&format!("{:016x}", hasher.finish()));
+        }
+        id.truncate(40);
+        id
+    })
+}
+
 impl Command {
     /// Parse a command from a frame
     pub fn from_frame(frame: Frame) -> Result<Command, String> {
+        let command = Self::parse(frame)?;
+        command.validate()?;
+        Ok(command)
+    }
+
+    /// Parse a command without `validate`
+    fn parse(frame: Frame) -> Result<Command, String> {
         // Commands are sent as arrays: [command_name, arg1, arg2, ...]
         let mut array = match frame {
             Frame::Array(arr) => arr,
@@ -134,13 +745,20 @@ impl Command {
         }
 
         // Extract command name
-        let cmd_name = match &array[0] {
+        let mut cmd_name = match &array[0] {
             Frame::Bulk(data) => std::str::from_utf8(data)
                 .map_err(|_| "invalid UTF-8 in command name")?
                 .to_uppercase(),
             Frame::Simple(s) => s.to_uppercase(),
             _ => return Err("command name must be a string".to_string()),
         };
+        if let Some(command) = table::resolve_alias(&cmd_name) {
+            cmd_name = command.to_uppercase();
+        }
+        // Refused before any argument is copied out of the frame
+        if let Some(info) = table::lookup(&cmd_name) {
+            info.check_args(array.len())?;
+        }
 
         // Match specific commands
         match cmd_name.as_str() {
@@ -148,28 +766,20 @@ impl Command {
                 // PING can optionally take a message argument
                 if array.len() == 1 {
                     Ok(Command::Ping(None))
-                } else if array.len() == 2 {
+                } else {
                     let message = match array.remove(1) {
                         Frame::Bulk(data) => data,
                         Frame::Simple(s) => Bytes::from(s),
                         _ => return Err("PING message must be a string".to_string()),
                     };
                     Ok(Command::Ping(Some(message)))
-                } else {
-                    Err("ERR wrong number of arguments for 'ping' command".to_string())
                 }
             }
             "SET" => {
-                // SET key value [EX seconds]
-                if array.len() < 3 {
-                    return Err("ERR wrong number of arguments for 'set' command".to_string());
-                }
-
+                // SET key value [EX seconds|PX ms|EXAT unix-s|PXAT unix-ms] [NX|XX]
                 let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
                     _ => return Err("SET key must be a string".to_string()),
                 };
 
@@ -179,8 +789,10 @@ impl Command {
                     _ => return Err("SET value must be a string".to_string()),
                 };
 
-                // Parse optional EX (expiration in seconds)
+                // Parse optional expiration and condition
                 let mut expires_at = None;
+                let mut nx = false;
+                let mut xx = false;
                 let mut i = 3;
                 while i < array.len() {
                     let option = match &array[i] {
@@ -192,30 +804,21 @@ impl Command {
                     };
 
                     match option.as_str() {
-                        "EX" => {
-                            if i + 1 >= array.len() {
+                        "EX" | "PX" | "EXAT" | "PXAT" => {
+                            if i + 1 >= array.len() || expires_at.is_some() {
                                 return Err("ERR syntax error".to_string());
                             }
-                            let seconds = match &array[i + 1] {
-                                Frame::Bulk(data) => {
-                                    let s = std::str::from_utf8(data)
-                                        .map_err(|_| "invalid UTF-8 in seconds")?;
-                                    s.parse::<u64>().map_err(|_| {
-                                        "ERR value is not an integer or out of range"
-                                    })?
-                                }
-                                Frame::Simple(s) => s
-                                    .parse::<u64>()
-                                    .map_err(|_| "ERR value is not an integer or out of range")?,
-                                _ => {
-                                    return Err(
-                                        "ERR value is not an integer or out of range".to_string()
-                                    )
-                                }
-                            };
-                            expires_at = Some(Instant::now() + Duration::from_secs(seconds));
+                            expires_at = Some(parse_expiry_option(&option, &array[i + 1], "set")?);
                             i += 2;
                         }
+                        "NX" => {
+                            nx = true;
+                            i += 1;
+                        }
+                        "XX" => {
+                            xx = true;
+                            i += 1;
+                        }
                         _ => return Err(format!("ERR syntax error near '{}'", option)),
                     }
                 }
@@ -224,133 +827,591 @@ impl Command {
                     key,
                     value,
                     expires_at,
+                    nx,
+                    xx,
                 })
             }
-            "GET" => {
-                // GET key
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'get' command".to_string());
-                }
-
+            "GETEX" => {
+                // GETEX key [EX seconds|PX ms|EXAT unix-s|PXAT unix-ms|PERSIST]
                 let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("GET key must be a string".to_string()),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("GETEX key must be a string".to_string()),
                 };
 
-                Ok(Command::Get { key })
-            }
-            "ECHO" => {
-                // ECHO message
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'echo' command".to_string());
+                let mut expires_at = None;
+                let mut persist = false;
+                if array.len() > 2 {
+                    let option = match &array[2] {
+                        Frame::Bulk(data) => String::from_utf8_lossy(data).to_uppercase(),
+                        Frame::Simple(s) => s.to_uppercase(),
+                        _ => return Err("GETEX option must be a string".to_string()),
+                    };
+                    match (option.as_str(), array.len()) {
+                        ("EX" | "PX" | "EXAT" | "PXAT", 4) => {
+                            expires_at = Some(parse_expiry_option(&option, &array[3], "getex")?);
+                        }
+                        ("PERSIST", 3) => persist = true,
+                        _ => return Err("ERR syntax error".to_string()),
+                    }
                 }
 
-                let message = match array.remove(1) {
-                    Frame::Bulk(data) => data,
-                    Frame::Simple(s) => Bytes::from(s),
-                    _ => return Err("ECHO message must be a string".to_string()),
+                Ok(Command::GetEx {
+                    key,
+                    expires_at,
+                    persist,
+                })
+            }
+            "SETEX" | "PSETEX" => {
+                // SETEX key seconds value | PSETEX key milliseconds value
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err(format!("{} key must be a string", cmd_name)),
                 };
 
-                Ok(Command::Echo { message })
-            }
-            "DEL" => {
-                // DEL key [key ...]
-                if array.len() < 2 {
-                    return Err("ERR wrong number of arguments for 'del' command".to_string());
+                let millis = cmd_name == "PSETEX";
+                let amount = parse_int_frame(&array[2])?;
+                let ttl_ms = if millis {
+                    Some(amount)
+                } else {
+                    amount.checked_mul(1000)
                 }
+                .filter(|&ms| ms > 0)
+                .ok_or_else(|| {
+                    format!(
+                        "ERR invalid expire time in '{}' command",
+                        cmd_name.to_lowercase()
+                    )
+                })?;
 
-                let mut keys = Vec::new();
-                for item in array.iter().skip(1) {
-                    let key = match item {
-                        Frame::Bulk(data) => std::str::from_utf8(data)
-                            .map_err(|_| "invalid UTF-8 in key")?
-                            .to_string(),
-                        Frame::Simple(s) => s.clone(),
-                        _ => return Err("DEL key must be a string".to_string()),
-                    };
-                    keys.push(key);
-                }
+                let value = match &array[3] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err(format!("{} value must be a string", cmd_name)),
+                };
 
-                Ok(Command::Del { keys })
+                Ok(Command::SetEx {
+                    key,
+                    value,
+                    expires_at: Instant::now() + Duration::from_millis(ttl_ms as u64),
+                    millis,
+                })
             }
-            "EXISTS" => {
-                // EXISTS key
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'exists' command".to_string());
-                }
-
+            "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+                // EXPIRE key seconds | PEXPIRE key ms | EXPIREAT key unix-s | PEXPIREAT key unix-ms
                 let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("EXISTS key must be a string".to_string()),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err(format!("{} key must be a string", cmd_name)),
                 };
 
-                Ok(Command::Exists { key })
-            }
-            "TYPE" => {
-                // TYPE key
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'type' command".to_string());
-                }
+                let amount = parse_int_frame(&array[2])?;
 
+                let millis = cmd_name.starts_with('P');
+                let amount_ms = if millis {
+                    Some(amount)
+                } else {
+                    amount.checked_mul(1000)
+                }
+                .ok_or("ERR invalid expire time")?;
+
+                if cmd_name.ends_with("AT") {
+                    Ok(Command::ExpireAt {
+                        key,
+                        unix_ms: amount_ms,
+                        millis,
+                    })
+                } else {
+                    Ok(Command::Expire {
+                        key,
+                        expires_at: Instant::now() + Duration::from_millis(amount_ms.max(0) as u64),
+                        millis,
+                    })
+                }
+            }
+            "TTL" | "PTTL" | "EXPIRETIME" | "PEXPIRETIME" => {
+                // TTL key | PTTL key | EXPIRETIME key | PEXPIRETIME key
                 let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("TYPE key must be a string".to_string()),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err(format!("{} key must be a string", cmd_name)),
                 };
 
-                Ok(Command::Type { key })
-            }
-            "DBSIZE" => {
-                // DBSIZE
-                if array.len() != 1 {
-                    return Err("ERR wrong number of arguments for 'dbsize' command".to_string());
+                let millis = cmd_name.starts_with('P');
+                if cmd_name.ends_with("TTL") {
+                    Ok(Command::Ttl { key, millis })
+                } else {
+                    Ok(Command::ExpireTime { key, millis })
                 }
-
-                Ok(Command::DbSize)
             }
-            "FLUSHDB" => {
-                // FLUSHDB
-                if array.len() != 1 {
-                    return Err("ERR wrong number of arguments for 'flushdb' command".to_string());
+            "APPEND" => {
+                // APPEND key value
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("APPEND key must be a string".to_string()),
+                };
+
+                let value = match &array[2] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("APPEND value must be a string".to_string()),
+                };
+
+                Ok(Command::Append { key, value })
+            }
+            "SETRANGE" => {
+                // SETRANGE key offset value
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("SETRANGE key must be a string".to_string()),
+                };
+
+                let offset = usize::try_from(parse_int_frame(&array[2])?)
+                    .map_err(|_| "ERR offset is out of range")?;
+
+                let value = match &array[3] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("SETRANGE value must be a string".to_string()),
+                };
+
+                Ok(Command::SetRange { key, offset, value })
+            }
+            "GETRANGE" => {
+                // GETRANGE key start end
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("GETRANGE key must be a string".to_string()),
+                };
+
+                let start = parse_int_frame(&array[2])? as isize;
+                let end = parse_int_frame(&array[3])? as isize;
+
+                Ok(Command::GetRange { key, start, end })
+            }
+            "LCS" => {
+                // LCS key1 key2 [LEN] [IDX] [MINMATCHLEN len] [WITHMATCHLEN]
+                let mut keys = Vec::with_capacity(2);
+                for item in &array[1..3] {
+                    let key = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("LCS key must be a string".to_string()),
+                    };
+                    keys.push(key);
+                }
+                let key2 = keys.pop().unwrap();
+                let key1 = keys.pop().unwrap();
+
+                let mut len = false;
+                let mut idx = false;
+                let mut min_match_len = 0;
+                let mut with_match_len = false;
+                let mut options = array[3..].iter();
+                while let Some(option) = options.next() {
+                    let option = match option {
+                        Frame::Bulk(data) => String::from_utf8_lossy(data).to_uppercase(),
+                        Frame::Simple(s) => s.to_uppercase(),
+                        _ => return Err("ERR syntax error".to_string()),
+                    };
+                    match option.as_str() {
+                        "LEN" => len = true,
+                        "IDX" => idx = true,
+                        "WITHMATCHLEN" => with_match_len = true,
+                        "MINMATCHLEN" => {
+                            let value = options.next().ok_or("ERR syntax error")?;
+                            // Negative lengths filter nothing, like Redis
+                            min_match_len = parse_int_frame(value)?.max(0) as usize;
+                        }
+                        _ => return Err("ERR syntax error".to_string()),
+                    }
+                }
+                Ok(Command::Lcs {
+                    key1,
+                    key2,
+                    len,
+                    idx,
+                    min_match_len,
+                    with_match_len,
+                })
+            }
+            "SETBIT" | "GETBIT" => {
+                // SETBIT key offset value | GETBIT key offset
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err(format!("{} key must be a string", cmd_name)),
+                };
+
+                // Offsets are limited to 2^32 bits, like Redis
+                let offset = parse_int_frame(&array[2])
+                    .ok()
+                    .and_then(|offset| u64::try_from(offset).ok())
+                    .filter(|offset| *offset < 1 << 32)
+                    .ok_or("ERR bit offset is not an integer or out of range")?;
+
+                if cmd_name == "GETBIT" {
+                    return Ok(Command::GetBit { key, offset });
+                }
+
+                let on = match parse_int_frame(&array[3]) {
+                    Ok(0) => false,
+                    Ok(1) => true,
+                    _ => return Err("ERR bit is not an integer or out of range".to_string()),
+                };
+
+                Ok(Command::SetBit { key, offset, on })
+            }
+            "INCR" | "DECR" => {
+                // INCR key | DECR key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err(format!("{} key must be a string", cmd_name)),
+                };
+
+                if cmd_name == "INCR" {
+                    Ok(Command::Incr { key })
+                } else {
+                    Ok(Command::Decr { key })
+                }
+            }
+            "INCRBY" | "DECRBY" => {
+                // INCRBY key increment | DECRBY key decrement
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err(format!("{} key must be a string", cmd_name)),
+                };
+
+                let delta = parse_int_frame(&array[2])?;
+
+                if cmd_name == "INCRBY" {
+                    Ok(Command::IncrBy { key, delta })
+                } else {
+                    Ok(Command::DecrBy { key, delta })
+                }
+            }
+            "INCRBYFLOAT" => {
+                // INCRBYFLOAT key increment
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("INCRBYFLOAT key must be a string".to_string()),
+                };
+
+                let delta = match &array[2] {
+                    Frame::Bulk(data) => parse_redis_float(data),
+                    Frame::Simple(s) => parse_redis_float(s.as_bytes()),
+                    _ => None,
+                }
+                .ok_or_else(|| "ERR value is not a valid float".to_string())?;
+
+                Ok(Command::IncrByFloat { key, delta })
+            }
+            "MSET" => {
+                // MSET key value [key value ...]
+                let mut pairs = Vec::with_capacity(array.len() / 2);
+                for chunk in array[1..].chunks(2) {
+                    let key = match &chunk[0] {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("MSET key must be a string".to_string()),
+                    };
+
+                    let value = match &chunk[1] {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("MSET value must be a string".to_string()),
+                    };
+
+                    pairs.push((key, value));
+                }
+
+                Ok(Command::MSet { pairs })
+            }
+            "GET" => {
+                // GET key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("GET key must be a string".to_string()),
+                };
+
+                Ok(Command::Get { key })
+            }
+            "ECHO" => {
+                // ECHO message
+                let message = match array.remove(1) {
+                    Frame::Bulk(data) => data,
+                    Frame::Simple(s) => Bytes::from(s),
+                    _ => return Err("ECHO message must be a string".to_string()),
+                };
+
+                Ok(Command::Echo { message })
+            }
+            "DEL" => {
+                // DEL key [key ...]
+                let mut keys = Vec::new();
+                for item in array.iter().skip(1) {
+                    let key = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("DEL key must be a string".to_string()),
+                    };
+                    keys.push(key);
+                }
+
+                Ok(Command::Del { keys })
+            }
+            "UNLINK" => {
+                // UNLINK key [key ...]
+                let mut keys = Vec::new();
+                for item in array.iter().skip(1) {
+                    let key = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("UNLINK key must be a string".to_string()),
+                    };
+                    keys.push(key);
+                }
+
+                Ok(Command::Unlink { keys })
+            }
+            "EXISTS" => {
+                // EXISTS key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("EXISTS key must be a string".to_string()),
+                };
+
+                Ok(Command::Exists { key })
+            }
+            "COPY" => {
+                // COPY source destination [REPLACE]
+                let mut keys = Vec::with_capacity(2);
+                for frame in &array[1..3] {
+                    keys.push(match frame {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("COPY key must be a string".to_string()),
+                    });
+                }
+                let destination = keys.pop().unwrap();
+                let source = keys.pop().unwrap();
+
+                let mut replace = false;
+                for option in &array[3..] {
+                    let option = match option {
+                        Frame::Bulk(data) => String::from_utf8_lossy(data).to_uppercase(),
+                        Frame::Simple(s) => s.to_uppercase(),
+                        _ => return Err("ERR syntax error".to_string()),
+                    };
+                    match option.as_str() {
+                        "REPLACE" => replace = true,
+                        _ => return Err("ERR syntax error".to_string()),
+                    }
+                }
+
+                Ok(Command::Copy {
+                    source,
+                    destination,
+                    replace,
+                })
+            }
+            "TYPE" => {
+                // TYPE key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("TYPE key must be a string".to_string()),
+                };
+
+                Ok(Command::Type { key })
+            }
+            "DBSIZE" => {
+                // DBSIZE
+                Ok(Command::DbSize)
+            }
+            "BGREWRITEAOF" => {
+                // BGREWRITEAOF
+                Ok(Command::BgRewriteAof)
+            }
+            "SAVE" => {
+                // SAVE
+                Ok(Command::Save)
+            }
+            "LASTSAVE" => {
+                // LASTSAVE
+                Ok(Command::LastSave)
+            }
+            "WAITAOF" => {
+                // WAITAOF numlocal numreplicas timeout
+                let mut counts = [0; 2];
+                for (count, frame) in counts.iter_mut().zip(&array[1..3]) {
+                    *count = u64::try_from(parse_int_frame(frame)?)
+                        .map_err(|_| "ERR value is out of range, must be positive".to_string())?;
+                }
+                let [numlocal, numreplicas] = counts;
+                let millis = parse_int_frame(&array[3])?;
+                if millis < 0 {
+                    return Err("ERR timeout is negative".to_string());
                 }
+                // A timeout of 0 blocks forever
+                let timeout = (millis > 0).then(|| Duration::from_millis(millis as u64));
 
+                Ok(Command::WaitAof {
+                    numlocal,
+                    numreplicas,
+                    timeout,
+                })
+            }
+            "FLUSHDB" => {
+                // FLUSHDB
                 Ok(Command::FlushDb)
             }
+            "SELECT" => {
+                // SELECT index
+                let index = usize::try_from(parse_int_frame(&array[1])?)
+                    .map_err(|_| "ERR DB index is out of range".to_string())?;
+
+                Ok(Command::Select { index })
+            }
+            // QUIT [ignored ...]
+            "QUIT" => Ok(Command::Quit),
+            "RESET" => {
+                // RESET
+                Ok(Command::Reset)
+            }
+            "MONITOR" => {
+                // MONITOR
+                Ok(Command::Monitor)
+            }
+            "AUTH" => {
+                // AUTH [username] password
+                let mut args = Vec::with_capacity(array.len() - 1);
+                for item in array.iter().skip(1) {
+                    let arg = match item {
+                        Frame::Bulk(data) => String::from_utf8_lossy(data).into_owned(),
+                        Frame::Simple(s) => s.clone(),
+                        _ => return Err("AUTH arguments must be strings".to_string()),
+                    };
+                    args.push(arg);
+                }
+                let password = args.pop().unwrap();
+                Ok(Command::Auth {
+                    username: args.pop(),
+                    password,
+                })
+            }
+            "ACL" => {
+                // ACL CAT [category] | SETUSER username [rule ...] | USERS | WHOAMI
+                let mut args = Vec::with_capacity(array.len() - 1);
+                for item in array.iter().skip(1) {
+                    let arg = match item {
+                        Frame::Bulk(data) => std::str::from_utf8(data)
+                            .map_err(|_| "invalid UTF-8 in argument")?
+                            .to_string(),
+                        Frame::Simple(s) => s.clone(),
+                        _ => return Err("ACL arguments must be strings".to_string()),
+                    };
+                    args.push(arg);
+                }
+                let subcommand = args.remove(0).to_uppercase();
+
+                match (subcommand.as_str(), args.len()) {
+                    ("CAT", 0 | 1) => Ok(Command::AclCat { category: args.pop() }),
+                    ("SETUSER", 1..) => Ok(Command::AclSetUser {
+                        username: args.remove(0),
+                        rules: args,
+                    }),
+                    ("USERS", 0) => Ok(Command::AclUsers),
+                    ("WHOAMI", 0) => Ok(Command::AclWhoAmI),
+                    _ => Err(format!(
+                        "ERR unknown subcommand or wrong number of arguments for '{}'. Try ACL HELP.",
+                        subcommand
+                    )),
+                }
+            }
             "KEYS" => {
                 // KEYS pattern
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'keys' command".to_string());
-                }
-
                 let pattern = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in pattern")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
                     _ => return Err("KEYS pattern must be a string".to_string()),
                 };
 
                 Ok(Command::Keys { pattern })
             }
-            "LPUSH" => {
-                // LPUSH key value [value ...]
-                if array.len() < 3 {
-                    return Err("ERR wrong number of arguments for 'lpush' command".to_string());
+            "COUNTKEYS" => {
+                // COUNTKEYS pattern
+                let pattern = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("COUNTKEYS pattern must be a string".to_string()),
+                };
+
+                Ok(Command::CountKeys { pattern })
+            }
+            "SCAN" => {
+                // SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+                // Options come in name/value pairs
+                if array.len() % 2 != 0 {
+                    return Err("ERR syntax error".to_string());
+                }
+
+                let mut args = Vec::with_capacity(array.len() - 1);
+                for item in &array[1..] {
+                    let arg = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("SCAN arguments must be strings".to_string()),
+                    };
+                    args.push(arg);
+                }
+
+                let cursor = std::str::from_utf8(&args[0])
+                    .ok()
+                    .and_then(|cursor| cursor.parse::<u64>().ok())
+                    .ok_or("ERR invalid cursor")?;
+
+                let mut pattern = None;
+                let mut count = 10;
+                let mut type_name = None;
+                for option in args[1..].chunks(2) {
+                    match option[0].to_ascii_uppercase().as_slice() {
+                        b"MATCH" => pattern = Some(option[1].clone()),
+                        b"COUNT" => {
+                            count = parse_redis_int(&option[1])
+                                .ok()
+                                .filter(|&n| n >= 1)
+                                .ok_or("ERR syntax error")?
+                                as usize;
+                        }
+                        b"TYPE" => {
+                            type_name = Some(String::from_utf8_lossy(&option[1]).to_lowercase())
+                        }
+                        _ => return Err("ERR syntax error".to_string()),
+                    }
                 }
 
+                Ok(Command::Scan {
+                    cursor,
+                    pattern,
+                    count,
+                    type_name,
+                })
+            }
+            "LPUSH" => {
+                // LPUSH key value [value ...]
                 let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
                     _ => return Err("LPUSH key must be a string".to_string()),
                 };
 
@@ -368,15 +1429,9 @@ impl Command {
             }
             "RPUSH" => {
                 // RPUSH key value [value ...]
-                if array.len() < 3 {
-                    return Err("ERR wrong number of arguments for 'rpush' command".to_string());
-                }
-
                 let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
                     _ => return Err("RPUSH key must be a string".to_string()),
                 };
 
@@ -394,15 +1449,9 @@ impl Command {
             }
             "LPOP" => {
                 // LPOP key
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'lpop' command".to_string());
-                }
-
                 let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
                     _ => return Err("LPOP key must be a string".to_string()),
                 };
 
@@ -410,355 +1459,1069 @@ impl Command {
             }
             "RPOP" => {
                 // RPOP key
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'rpop' command".to_string());
-                }
-
                 let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
                     _ => return Err("RPOP key must be a string".to_string()),
                 };
 
                 Ok(Command::RPop { key })
             }
-            "LRANGE" => {
-                // LRANGE key start stop
-                if array.len() != 4 {
-                    return Err("ERR wrong number of arguments for 'lrange' command".to_string());
-                }
+            "BLPOP" | "BRPOP" => {
+                // BLPOP key [key ...] timeout | BRPOP key [key ...] timeout
+                let mut keys = Vec::new();
+                for item in &array[1..array.len() - 1] {
+                    let key = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err(format!("{} key must be a string", cmd_name)),
+                    };
+                    keys.push(key);
+                }
 
-                let key = match &array[1] {
+                let seconds = match &array[array.len() - 1] {
                     Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
+                        .ok()
+                        .and_then(|s| s.parse::<f64>().ok()),
+                    Frame::Simple(s) => s.parse::<f64>().ok(),
+                    _ => None,
+                }
+                .filter(|seconds| seconds.is_finite())
+                .ok_or("ERR timeout is not a float or out of range")?;
+                if seconds < 0.0 {
+                    return Err("ERR timeout is negative".to_string());
+                }
+                let timeout = match seconds {
+                    0.0 => None,
+                    seconds => Some(
+                        Duration::try_from_secs_f64(seconds)
+                            .map_err(|_| "ERR timeout is out of range")?,
+                    ),
+                };
+
+                Ok(Command::BPop {
+                    keys,
+                    timeout,
+                    left: cmd_name == "BLPOP",
+                })
+            }
+            "LRANGE" => {
+                // LRANGE key start stop
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
                     _ => return Err("LRANGE key must be a string".to_string()),
                 };
 
-                let start = match &array[2] {
-                    Frame::Bulk(data) => {
-                        let s = std::str::from_utf8(data)
-                            .map_err(|_| "invalid UTF-8 in start index")?;
-                        s.parse::<isize>()
-                            .map_err(|_| "ERR value is not an integer or out of range")?
+                let start = parse_int_frame(&array[2])? as isize;
+                let stop = parse_int_frame(&array[3])? as isize;
+
+                Ok(Command::LRange { key, start, stop })
+            }
+            "LLEN" => {
+                // LLEN key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("LLEN key must be a string".to_string()),
+                };
+
+                Ok(Command::LLen { key })
+            }
+            "LINSERT" => {
+                // LINSERT key BEFORE|AFTER pivot value
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("LINSERT key must be a string".to_string()),
+                };
+
+                let before = match &array[2] {
+                    Frame::Bulk(data) if data.eq_ignore_ascii_case(b"BEFORE") => true,
+                    Frame::Bulk(data) if data.eq_ignore_ascii_case(b"AFTER") => false,
+                    Frame::Simple(s) if s.eq_ignore_ascii_case("BEFORE") => true,
+                    Frame::Simple(s) if s.eq_ignore_ascii_case("AFTER") => false,
+                    _ => return Err("ERR syntax error".to_string()),
+                };
+
+                let pivot = match &array[3] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("LINSERT pivot must be a string".to_string()),
+                };
+
+                let value = match &array[4] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("LINSERT value must be a string".to_string()),
+                };
+
+                Ok(Command::LInsert {
+                    key,
+                    before,
+                    pivot,
+                    value,
+                })
+            }
+            "SADD" => {
+                // SADD key member [member ...]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("SADD key must be a string".to_string()),
+                };
+
+                let mut members = Vec::new();
+                for item in array.iter().skip(2) {
+                    let member = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("SADD member must be a string".to_string()),
+                    };
+                    members.push(member);
+                }
+
+                Ok(Command::SAdd { key, members })
+            }
+            "SREM" => {
+                // SREM key member [member ...]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("SREM key must be a string".to_string()),
+                };
+
+                let mut members = Vec::new();
+                for item in array.iter().skip(2) {
+                    let member = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("SREM member must be a string".to_string()),
+                    };
+                    members.push(member);
+                }
+
+                Ok(Command::SRem { key, members })
+            }
+            "SPOP" => {
+                // SPOP key [count]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("SPOP key must be a string".to_string()),
+                };
+
+                let count = match array.get(2) {
+                    Some(frame) => Some(
+                        usize::try_from(parse_int_frame(frame)?)
+                            .map_err(|_| "ERR value is out of range, must be positive")?,
+                    ),
+                    None => None,
+                };
+
+                Ok(Command::SPop { key, count })
+            }
+            "SRANDMEMBER" => {
+                // SRANDMEMBER key [count]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("SRANDMEMBER key must be a string".to_string()),
+                };
+
+                let count = array.get(2).map(parse_int_frame).transpose()?;
+
+                Ok(Command::SRandMember { key, count })
+            }
+            "SMEMBERS" => {
+                // SMEMBERS key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("SMEMBERS key must be a string".to_string()),
+                };
+
+                Ok(Command::SMembers { key })
+            }
+            "SISMEMBER" => {
+                // SISMEMBER key member
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("SISMEMBER key must be a string".to_string()),
+                };
+
+                let member = match &array[2] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("SISMEMBER member must be a string".to_string()),
+                };
+
+                Ok(Command::SIsMember { key, member })
+            }
+            "SCARD" => {
+                // SCARD key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("SCARD key must be a string".to_string()),
+                };
+
+                Ok(Command::SCard { key })
+            }
+            "HSET" => {
+                // HSET key field value
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("HSET key must be a string".to_string()),
+                };
+
+                let field = match &array[2] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("HSET field must be a string".to_string()),
+                };
+
+                let value = match &array[3] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("HSET value must be a string".to_string()),
+                };
+
+                Ok(Command::HSet { key, field, value })
+            }
+            "HGET" => {
+                // HGET key field
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("HGET key must be a string".to_string()),
+                };
+
+                let field = match &array[2] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("HGET field must be a string".to_string()),
+                };
+
+                Ok(Command::HGet { key, field })
+            }
+            "HGETALL" => {
+                // HGETALL key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("HGETALL key must be a string".to_string()),
+                };
+
+                Ok(Command::HGetAll { key })
+            }
+            "HDEL" => {
+                // HDEL key field [field ...]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("HDEL key must be a string".to_string()),
+                };
+
+                let mut fields = Vec::new();
+                for item in array.iter().skip(2) {
+                    let field = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("HDEL field must be a string".to_string()),
+                    };
+                    fields.push(field);
+                }
+
+                Ok(Command::HDel { key, fields })
+            }
+            "HEXISTS" => {
+                // HEXISTS key field
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("HEXISTS key must be a string".to_string()),
+                };
+
+                let field = match &array[2] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("HEXISTS field must be a string".to_string()),
+                };
+
+                Ok(Command::HExists { key, field })
+            }
+            "HLEN" => {
+                // HLEN key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("HLEN key must be a string".to_string()),
+                };
+
+                Ok(Command::HLen { key })
+            }
+            "ZADD" => {
+                // ZADD key score member [score member ...]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("ZADD key must be a string".to_string()),
+                };
+
+                let mut members = Vec::new();
+                for pair in array[2..].chunks(2) {
+                    let score = parse_score_frame(&pair[0])?;
+                    let member = match &pair[1] {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("ZADD member must be a string".to_string()),
+                    };
+                    members.push((score, member));
+                }
+
+                Ok(Command::ZAdd { key, members })
+            }
+            "ZREM" => {
+                // ZREM key member [member ...]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("ZREM key must be a string".to_string()),
+                };
+
+                let mut members = Vec::new();
+                for item in array.iter().skip(2) {
+                    let member = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("ZREM member must be a string".to_string()),
+                    };
+                    members.push(member);
+                }
+
+                Ok(Command::ZRem { key, members })
+            }
+            "ZSCORE" => {
+                // ZSCORE key member
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("ZSCORE key must be a string".to_string()),
+                };
+
+                let member = match &array[2] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("ZSCORE member must be a string".to_string()),
+                };
+
+                Ok(Command::ZScore { key, member })
+            }
+            "ZCARD" => {
+                // ZCARD key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("ZCARD key must be a string".to_string()),
+                };
+
+                Ok(Command::ZCard { key })
+            }
+            "ZRANGE" => {
+                // ZRANGE key start stop [WITHSCORES]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("ZRANGE key must be a string".to_string()),
+                };
+
+                let start = parse_int_frame(&array[2])? as isize;
+                let stop = parse_int_frame(&array[3])? as isize;
+
+                let with_scores = match array.get(4) {
+                    None => false,
+                    Some(Frame::Bulk(data)) if data.eq_ignore_ascii_case(b"WITHSCORES") => true,
+                    Some(Frame::Simple(s)) if s.eq_ignore_ascii_case("WITHSCORES") => true,
+                    Some(_) => return Err("ERR syntax error".to_string()),
+                };
+
+                Ok(Command::ZRange {
+                    key,
+                    start,
+                    stop,
+                    with_scores,
+                })
+            }
+            "ZRANGEBYLEX" => {
+                // ZRANGEBYLEX key min max [LIMIT offset count]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("ZRANGEBYLEX key must be a string".to_string()),
+                };
+
+                let min = parse_lex_bound_frame(&array[2])?;
+                let max = parse_lex_bound_frame(&array[3])?;
+
+                let limit = match (array.get(4), array.len()) {
+                    (None, _) => None,
+                    (Some(Frame::Bulk(data)), 7) if data.eq_ignore_ascii_case(b"LIMIT") => {
+                        Some((parse_int_frame(&array[5])?, parse_int_frame(&array[6])?))
+                    }
+                    (Some(Frame::Simple(s)), 7) if s.eq_ignore_ascii_case("LIMIT") => {
+                        Some((parse_int_frame(&array[5])?, parse_int_frame(&array[6])?))
+                    }
+                    // Anything else, including LIMIT without both numbers
+                    (Some(_), _) => return Err("ERR syntax error".to_string()),
+                };
+
+                Ok(Command::ZRangeByLex {
+                    key,
+                    min,
+                    max,
+                    limit,
+                })
+            }
+            "ZLEXCOUNT" => {
+                // ZLEXCOUNT key min max
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("ZLEXCOUNT key must be a string".to_string()),
+                };
+
+                let min = parse_lex_bound_frame(&array[2])?;
+                let max = parse_lex_bound_frame(&array[3])?;
+
+                Ok(Command::ZLexCount { key, min, max })
+            }
+            "XADD" => {
+                // XADD key id|* field value [field value ...]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("XADD key must be a string".to_string()),
+                };
+
+                let id = match &array[2] {
+                    Frame::Bulk(data) if data[..] == *b"*" => None,
+                    Frame::Simple(s) if s == "*" => None,
+                    frame => Some(parse_stream_id_frame(frame, |id| StreamId::parse(id, 0))?),
+                };
+
+                let mut fields = Vec::new();
+                for pair in array[3..].chunks(2) {
+                    let mut strings = pair.iter().map(|frame| match frame {
+                        Frame::Bulk(data) => Ok(data.clone()),
+                        Frame::Simple(s) => Ok(Bytes::from(s.clone())),
+                        _ => Err("XADD field and value must be strings".to_string()),
+                    });
+                    fields.push((strings.next().unwrap()?, strings.next().unwrap()?));
+                }
+
+                Ok(Command::XAdd { key, id, fields })
+            }
+            "XLEN" => {
+                // XLEN key
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("XLEN key must be a string".to_string()),
+                };
+
+                Ok(Command::XLen { key })
+            }
+            "XRANGE" => {
+                // XRANGE key start end [COUNT count]
+                let key = match &array[1] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("XRANGE key must be a string".to_string()),
+                };
+
+                let start = parse_stream_id_frame(&array[2], |id| StreamId::parse_bound(id, true))?;
+                let end = parse_stream_id_frame(&array[3], |id| StreamId::parse_bound(id, false))?;
+
+                let count = match array.get(4) {
+                    None => None,
+                    Some(Frame::Bulk(data))
+                        if data.eq_ignore_ascii_case(b"COUNT") && array.len() == 6 =>
+                    {
+                        Some(parse_int_frame(&array[5])?)
                     }
-                    Frame::Simple(s) => s
-                        .parse::<isize>()
-                        .map_err(|_| "ERR value is not an integer or out of range")?,
-                    _ => return Err("ERR value is not an integer or out of range".to_string()),
-                };
-
-                let stop = match &array[3] {
-                    Frame::Bulk(data) => {
-                        let s =
-                            std::str::from_utf8(data).map_err(|_| "invalid UTF-8 in stop index")?;
-                        s.parse::<isize>()
-                            .map_err(|_| "ERR value is not an integer or out of range")?
+                    Some(Frame::Simple(s))
+                        if s.eq_ignore_ascii_case("COUNT") && array.len() == 6 =>
+                    {
+                        Some(parse_int_frame(&array[5])?)
                     }
-                    Frame::Simple(s) => s
-                        .parse::<isize>()
-                        .map_err(|_| "ERR value is not an integer or out of range")?,
-                    _ => return Err("ERR value is not an integer or out of range".to_string()),
+                    Some(_) => return Err("ERR syntax error".to_string()),
                 };
 
-                Ok(Command::LRange { key, start, stop })
+                Ok(Command::XRange {
+                    key,
+                    start,
+                    end,
+                    count,
+                })
             }
-            "LLEN" => {
-                // LLEN key
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'llen' command".to_string());
+            "XREAD" => {
+                // XREAD [COUNT count] [BLOCK milliseconds] STREAMS key [key ...] id [id ...]
+                let mut count = None;
+                let mut block = None;
+                let mut i = 1;
+                let streams = loop {
+                    let option = match array.get(i) {
+                        Some(Frame::Bulk(data)) => String::from_utf8_lossy(data).to_uppercase(),
+                        Some(Frame::Simple(s)) => s.to_uppercase(),
+                        _ => return Err("ERR syntax error".to_string()),
+                    };
+                    match option.as_str() {
+                        "STREAMS" => break i + 1,
+                        "COUNT" if i + 1 < array.len() => {
+                            // COUNT 0 or less means no limit
+                            let n = parse_int_frame(&array[i + 1])?;
+                            count = (n > 0).then_some(n as usize);
+                        }
+                        "BLOCK" if i + 1 < array.len() => {
+                            let ms = parse_int_frame(&array[i + 1]).map_err(|_| {
+                                "ERR timeout is not an integer or out of range".to_string()
+                            })?;
+                            if ms < 0 {
+                                return Err("ERR timeout is negative".to_string());
+                            }
+                            block = Some((ms > 0).then(|| Duration::from_millis(ms as u64)));
+                        }
+                        _ => return Err("ERR syntax error".to_string()),
+                    }
+                    i += 2;
+                };
+
+                let args = &array[streams..];
+                if args.is_empty() || args.len() % 2 != 0 {
+                    return Err(
+                        "ERR Unbalanced 'xread' list of streams: for each stream key \
+                         an ID or '$' must be specified."
+                            .to_string(),
+                    );
                 }
+                let (key_args, id_args) = args.split_at(args.len() / 2);
 
-                let key = match &array[1] {
+                let mut keys = Vec::new();
+                for item in key_args {
+                    let key = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("XREAD key must be a string".to_string()),
+                    };
+                    keys.push(key);
+                }
+                let mut ids = Vec::new();
+                for item in id_args {
+                    let id = match item {
+                        Frame::Bulk(data) if data[..] == *b"$" => None,
+                        Frame::Simple(s) if s == "$" => None,
+                        frame => Some(parse_stream_id_frame(frame, |id| StreamId::parse(id, 0))?),
+                    };
+                    ids.push(id);
+                }
+
+                Ok(Command::XRead {
+                    keys,
+                    ids,
+                    count,
+                    block,
+                })
+            }
+            "PUBLISH" | "SPUBLISH" => {
+                // PUBLISH channel message | SPUBLISH shardchannel message
+                let channel = match &array[1] {
                     Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
+                        .map_err(|_| "invalid UTF-8 in channel")?
                         .to_string(),
                     Frame::Simple(s) => s.clone(),
-                    _ => return Err("LLEN key must be a string".to_string()),
+                    _ => return Err(format!("{} channel must be a string", cmd_name)),
                 };
 
-                Ok(Command::LLen { key })
-            }
-            "SADD" => {
-                // SADD key member [member ...]
-                if array.len() < 3 {
-                    return Err("ERR wrong number of arguments for 'sadd' command".to_string());
-                }
+                let message = match &array[2] {
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err(format!("{} message must be a string", cmd_name)),
+                };
 
+                if cmd_name == "SPUBLISH" {
+                    Ok(Command::SPublish { channel, message })
+                } else {
+                    Ok(Command::Publish { channel, message })
+                }
+            }
+            #[cfg(feature = "json")]
+            "JSON.SET" => {
+                // JSON.SET key path value
                 let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("SADD key must be a string".to_string()),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("JSON.SET arguments must be strings".to_string()),
                 };
 
-                let mut members = Vec::new();
-                for item in array.iter().skip(2) {
-                    let member = match item {
+                let mut args = Vec::with_capacity(2);
+                for item in &array[2..] {
+                    let arg = match item {
                         Frame::Bulk(data) => std::str::from_utf8(data)
-                            .map_err(|_| "invalid UTF-8 in member")?
+                            .map_err(|_| "invalid UTF-8 in argument")?
                             .to_string(),
                         Frame::Simple(s) => s.clone(),
-                        _ => return Err("SADD member must be a string".to_string()),
+                        _ => return Err("JSON.SET arguments must be strings".to_string()),
                     };
-                    members.push(member);
+                    args.push(arg);
                 }
 
-                Ok(Command::SAdd { key, members })
-            }
-            "SREM" => {
-                // SREM key member [member ...]
-                if array.len() < 3 {
-                    return Err("ERR wrong number of arguments for 'srem' command".to_string());
-                }
+                let path = JsonPath::parse(&args[0])?;
+                let value = serde_json::from_str(&args[1])
+                    .map_err(|e| format!("ERR invalid JSON: {}", e))?;
 
+                Ok(Command::JsonSet { key, path, value })
+            }
+            #[cfg(feature = "json")]
+            "JSON.GET" => {
+                // JSON.GET key [path]
                 let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("SREM key must be a string".to_string()),
+                    Frame::Bulk(data) => data.clone(),
+                    Frame::Simple(s) => Bytes::from(s.clone()),
+                    _ => return Err("JSON.GET key must be a string".to_string()),
                 };
 
-                let mut members = Vec::new();
-                for item in array.iter().skip(2) {
-                    let member = match item {
+                let path = match array.get(2) {
+                    None => JsonPath::root(),
+                    Some(Frame::Bulk(data)) => JsonPath::parse(
+                        std::str::from_utf8(data).map_err(|_| "invalid UTF-8 in path")?,
+                    )?,
+                    Some(Frame::Simple(s)) => JsonPath::parse(s)?,
+                    Some(_) => return Err("JSON.GET path must be a string".to_string()),
+                };
+
+                Ok(Command::JsonGet { key, path })
+            }
+            "SUBSCRIBE" | "UNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE" => {
+                // SUBSCRIBE channel [channel ...] | UNSUBSCRIBE [channel ...]
+                // and their shard channel counterparts
+                let mut channels = Vec::new();
+                for item in array.iter().skip(1) {
+                    let channel = match item {
                         Frame::Bulk(data) => std::str::from_utf8(data)
-                            .map_err(|_| "invalid UTF-8 in member")?
+                            .map_err(|_| "invalid UTF-8 in channel")?
                             .to_string(),
                         Frame::Simple(s) => s.clone(),
-                        _ => return Err("SREM member must be a string".to_string()),
+                        _ => return Err(format!("{} channel must be a string", cmd_name)),
                     };
-                    members.push(member);
+                    channels.push(channel);
                 }
 
-                Ok(Command::SRem { key, members })
+                match cmd_name.as_str() {
+                    "SUBSCRIBE" => Ok(Command::Subscribe { channels }),
+                    "UNSUBSCRIBE" => Ok(Command::Unsubscribe { channels }),
+                    "SSUBSCRIBE" => Ok(Command::SSubscribe { channels }),
+                    _ => Ok(Command::SUnsubscribe { channels }),
+                }
             }
-            "SMEMBERS" => {
-                // SMEMBERS key
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'smembers' command".to_string());
+            "PUBSUB" => {
+                // PUBSUB NUMSUB [channel ...] | PUBSUB NUMPAT | PUBSUB RETAIN channel count
+                let mut args = Vec::with_capacity(array.len() - 1);
+                for item in &array[1..] {
+                    let arg = match item {
+                        Frame::Bulk(data) => std::str::from_utf8(data)
+                            .map_err(|_| "invalid UTF-8 in PUBSUB argument")?
+                            .to_string(),
+                        Frame::Simple(s) => s.clone(),
+                        _ => return Err("PUBSUB arguments must be strings".to_string()),
+                    };
+                    args.push(arg);
                 }
 
-                let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("SMEMBERS key must be a string".to_string()),
-                };
-
-                Ok(Command::SMembers { key })
+                let subcommand = args.remove(0).to_uppercase();
+                match subcommand.as_str() {
+                    "NUMSUB" => Ok(Command::PubSubNumSub { channels: args }),
+                    "NUMPAT" if args.is_empty() => Ok(Command::PubSubNumPat),
+                    "RETAIN" if args.len() == 2 => {
+                        let count = args[1]
+                            .parse::<usize>()
+                            .map_err(|_| "ERR value is not an integer or out of range")?;
+                        Ok(Command::PubSubRetain {
+                            channel: args.swap_remove(0),
+                            count,
+                        })
+                    }
+                    _ => Err(format!(
+                        "ERR unknown subcommand or wrong number of arguments for '{}'. Try PUBSUB HELP.",
+                        subcommand
+                    )),
+                }
             }
-            "SISMEMBER" => {
-                // SISMEMBER key member
-                if array.len() != 3 {
-                    return Err("ERR wrong number of arguments for 'sismember' command".to_string());
+            "STATS" | "INFO" => {
+                // INFO [section ...]
+                let mut sections = Vec::new();
+                for item in &array[1..] {
+                    let section = match item {
+                        Frame::Bulk(data) => String::from_utf8_lossy(data).to_lowercase(),
+                        Frame::Simple(s) => s.to_lowercase(),
+                        _ => return Err("INFO section must be a string".to_string()),
+                    };
+                    sections.push(section);
                 }
+                Ok(Command::Stats { sections })
+            }
+            "CMDSTAT" | "CMDSTATS" => {
+                Ok(Command::CmdStat)
+            }
+            "ROLE" => {
+                // ROLE
+                Ok(Command::Role)
+            }
+            "FAILOVER" => {
+                // FAILOVER [TO host port [FORCE]] [ABORT] [TIMEOUT milliseconds]
+                let abort = array.iter().skip(1).any(|arg| match arg {
+                    Frame::Bulk(data) => data.eq_ignore_ascii_case(b"ABORT"),
+                    Frame::Simple(s) => s.eq_ignore_ascii_case("ABORT"),
+                    _ => false,
+                });
 
-                let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("SISMEMBER key must be a string".to_string()),
-                };
-
-                let member = match &array[2] {
+                Ok(Command::Failover { abort })
+            }
+            "MEMORY" => {
+                // MEMORY STATS
+                let subcommand = match &array[1] {
                     Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in member")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("SISMEMBER member must be a string".to_string()),
+                        .map_err(|_| "invalid UTF-8 in subcommand")?
+                        .to_uppercase(),
+                    Frame::Simple(s) => s.to_uppercase(),
+                    _ => return Err("MEMORY subcommand must be a string".to_string()),
                 };
 
-                Ok(Command::SIsMember { key, member })
+                match subcommand.as_str() {
+                    "STATS" => Ok(Command::MemoryStats),
+                    _ => Err(format!(
+                        "ERR unknown subcommand '{}'. Try MEMORY HELP.",
+                        subcommand
+                    )),
+                }
             }
-            "SCARD" => {
-                // SCARD key
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'scard' command".to_string());
+            "COMMAND" => {
+                // COMMAND [GETKEYS command [arg ...] | COUNT | INFO [command ...]]
+                let mut args = Vec::with_capacity(array.len() - 1);
+                for item in array.iter().skip(1) {
+                    let arg = match item {
+                        Frame::Bulk(data) => std::str::from_utf8(data)
+                            .map_err(|_| "invalid UTF-8 in argument")?
+                            .to_string(),
+                        Frame::Simple(s) => s.clone(),
+                        _ => return Err("COMMAND arguments must be strings".to_string()),
+                    };
+                    args.push(arg);
                 }
-
-                let key = match &array[1] {
+                // Bare COMMAND lists every command, like COMMAND INFO
+                if args.is_empty() {
+                    return Ok(Command::CommandInfo { names: args });
+                }
+                let subcommand = args.remove(0).to_uppercase();
+
+                match subcommand.as_str() {
+                    "GETKEYS" => {
+                        if args.is_empty() {
+                            return Err(
+                                "ERR wrong number of arguments for 'command|getkeys' command"
+                                    .to_string(),
+                            );
+                        }
+                        Ok(Command::CommandGetKeys { args })
+                    }
+                    "COUNT" => Ok(Command::CommandCount),
+                    "INFO" => Ok(Command::CommandInfo { names: args }),
+                    _ => Err(format!(
+                        "ERR unknown subcommand '{}'. Try COMMAND HELP.",
+                        subcommand
+                    )),
+                }
+            }
+            "OBJECT" => {
+                // OBJECT ENCODING key
+                let subcommand = match &array[1] {
                     Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("SCARD key must be a string".to_string()),
+                        .map_err(|_| "invalid UTF-8 in subcommand")?
+                        .to_uppercase(),
+                    Frame::Simple(s) => s.to_uppercase(),
+                    _ => return Err("OBJECT subcommand must be a string".to_string()),
                 };
 
-                Ok(Command::SCard { key })
-            }
-            "HSET" => {
-                // HSET key field value
-                if array.len() != 4 {
-                    return Err("ERR wrong number of arguments for 'hset' command".to_string());
-                }
+                match subcommand.as_str() {
+                    "ENCODING" => {
+                        if array.len() != 3 {
+                            return Err(
+                                "ERR wrong number of arguments for 'object|encoding' command"
+                                    .to_string(),
+                            );
+                        }
 
-                let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("HSET key must be a string".to_string()),
-                };
+                        let key = match &array[2] {
+                            Frame::Bulk(data) => data.clone(),
+                            Frame::Simple(s) => Bytes::from(s.clone()),
+                            _ => return Err("OBJECT key must be a string".to_string()),
+                        };
 
-                let field = match &array[2] {
+                        Ok(Command::ObjectEncoding { key })
+                    }
+                    _ => Err(format!(
+                        "ERR unknown subcommand '{}'. Try OBJECT HELP.",
+                        subcommand
+                    )),
+                }
+            }
+            "DEBUG" => {
+                // DEBUG SLEEP seconds | DEBUG ERROR message | DEBUG PANIC
+                let subcommand = match &array[1] {
                     Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in field")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("HSET field must be a string".to_string()),
+                        .map_err(|_| "invalid UTF-8 in subcommand")?
+                        .to_uppercase(),
+                    Frame::Simple(s) => s.to_uppercase(),
+                    _ => return Err("DEBUG subcommand must be a string".to_string()),
                 };
 
-                let value = match &array[3] {
-                    Frame::Bulk(data) => data.clone(),
-                    Frame::Simple(s) => Bytes::from(s.clone()),
-                    _ => return Err("HSET value must be a string".to_string()),
-                };
+                match subcommand.as_str() {
+                    "SLEEP" => {
+                        if array.len() != 3 {
+                            return Err("ERR wrong number of arguments for 'debug|sleep' command"
+                                .to_string());
+                        }
 
-                Ok(Command::HSet { key, field, value })
-            }
-            "HGET" => {
-                // HGET key field
-                if array.len() != 3 {
-                    return Err("ERR wrong number of arguments for 'hget' command".to_string());
-                }
+                        let seconds = match &array[2] {
+                            Frame::Bulk(data) => std::str::from_utf8(data)
+                                .ok()
+                                .and_then(|s| s.parse::<f64>().ok()),
+                            Frame::Simple(s) => s.parse::<f64>().ok(),
+                            _ => None,
+                        }
+                        .filter(|s| s.is_finite() && *s >= 0.0)
+                        .ok_or("ERR value is not a valid float")?;
 
-                let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("HGET key must be a string".to_string()),
-                };
+                        let duration = Duration::try_from_secs_f64(seconds)
+                            .map_err(|_| "ERR value is out of range")?;
 
-                let field = match &array[2] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in field")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("HGET field must be a string".to_string()),
-                };
+                        Ok(Command::DebugSleep { duration })
+                    }
+                    "ERROR" => {
+                        if array.len() != 3 {
+                            return Err("ERR wrong number of arguments for 'debug|error' command"
+                                .to_string());
+                        }
 
-                Ok(Command::HGet { key, field })
-            }
-            "HGETALL" => {
-                // HGETALL key
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'hgetall' command".to_string());
-                }
+                        let message = match &array[2] {
+                            Frame::Bulk(data) => String::from_utf8_lossy(data).into_owned(),
+                            Frame::Simple(s) => s.clone(),
+                            _ => return Err("DEBUG ERROR message must be a string".to_string()),
+                        };
 
-                let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("HGETALL key must be a string".to_string()),
-                };
+                        Ok(Command::DebugError { message })
+                    }
+                    #[cfg(debug_assertions)]
+                    "PANIC" => {
+                        if array.len() != 2 {
+                            return Err("ERR wrong number of arguments for 'debug|panic' command"
+                                .to_string());
+                        }
 
-                Ok(Command::HGetAll { key })
-            }
-            "HDEL" => {
-                // HDEL key field [field ...]
-                if array.len() < 3 {
-                    return Err("ERR wrong number of arguments for 'hdel' command".to_string());
+                        Ok(Command::DebugPanic)
+                    }
+                    _ => Err(format!("ERR unknown subcommand '{}'", subcommand)),
                 }
-
-                let key = match &array[1] {
+            }
+            "CLUSTER" => {
+                // CLUSTER INFO | MYID | SLOTS | SHARDS | NODES
+                let subcommand = match &array[1] {
                     Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("HDEL key must be a string".to_string()),
+                        .map_err(|_| "invalid UTF-8 in subcommand")?
+                        .to_uppercase(),
+                    Frame::Simple(s) => s.to_uppercase(),
+                    _ => return Err("CLUSTER subcommand must be a string".to_string()),
                 };
 
-                let mut fields = Vec::new();
-                for item in array.iter().skip(2) {
-                    let field = match item {
+                match subcommand.as_str() {
+                    "INFO" | "MYID" | "SLOTS" | "SHARDS" | "NODES" => {
+                        Ok(Command::Cluster { subcommand })
+                    }
+                    _ => Err("ERR This instance has cluster support disabled".to_string()),
+                }
+            }
+            "CLIENT" => {
+                // CLIENT ID | SETNAME name | GETNAME | INFO | REPLY ON|OFF|SKIP
+                let mut args = Vec::with_capacity(array.len() - 1);
+                for item in array.iter().skip(1) {
+                    let arg = match item {
                         Frame::Bulk(data) => std::str::from_utf8(data)
-                            .map_err(|_| "invalid UTF-8 in field")?
+                            .map_err(|_| "invalid UTF-8 in argument")?
                             .to_string(),
                         Frame::Simple(s) => s.clone(),
-                        _ => return Err("HDEL field must be a string".to_string()),
+                        _ => return Err("CLIENT arguments must be strings".to_string()),
                     };
-                    fields.push(field);
+                    args.push(arg);
                 }
-
-                Ok(Command::HDel { key, fields })
-            }
-            "HEXISTS" => {
-                // HEXISTS key field
-                if array.len() != 3 {
-                    return Err("ERR wrong number of arguments for 'hexists' command".to_string());
+                let subcommand = args.remove(0).to_uppercase();
+
+                match (subcommand.as_str(), args.len()) {
+                    ("ID", 0) => Ok(Command::ClientId),
+                    ("GETNAME", 0) => Ok(Command::ClientGetName),
+                    ("INFO", 0) => Ok(Command::ClientInfo),
+                    ("REPLY", 1) => {
+                        let mode = match args[0].to_uppercase().as_str() {
+                            "ON" => ReplyMode::On,
+                            "OFF" => ReplyMode::Off,
+                            "SKIP" => ReplyMode::Skip,
+                            _ => return Err("ERR syntax error".to_string()),
+                        };
+                        Ok(Command::ClientReply { mode })
+                    }
+                    ("SETNAME", 1) => {
+                        let name = args.remove(0);
+                        // Names end up in the space separated CLIENT INFO line
+                        if name.chars().any(|c| c <= ' ' || c > '~') {
+                            return Err(
+                                "ERR Client names cannot contain spaces, newlines or special characters."
+                                    .to_string(),
+                            );
+                        }
+                        Ok(Command::ClientSetName { name })
+                    }
+                    _ => Err(format!(
+                        "ERR unknown subcommand or wrong number of arguments for '{}'. Try CLIENT HELP.",
+                        subcommand
+                    )),
                 }
-
-                let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("HEXISTS key must be a string".to_string()),
-                };
-
-                let field = match &array[2] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in field")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("HEXISTS field must be a string".to_string()),
-                };
-
-                Ok(Command::HExists { key, field })
             }
-            "HLEN" => {
-                // HLEN key
-                if array.len() != 2 {
-                    return Err("ERR wrong number of arguments for 'hlen' command".to_string());
+            "CONFIG" => {
+                // CONFIG GET pattern [pattern ...] | SET name value [name value ...] | REWRITE
+                let mut args = Vec::with_capacity(array.len() - 1);
+                for item in array.iter().skip(1) {
+                    let arg = match item {
+                        Frame::Bulk(data) => std::str::from_utf8(data)
+                            .map_err(|_| "invalid UTF-8 in argument")?
+                            .to_string(),
+                        Frame::Simple(s) => s.clone(),
+                        _ => return Err("CONFIG arguments must be strings".to_string()),
+                    };
+                    args.push(arg);
                 }
-
-                let key = match &array[1] {
-                    Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in key")?
-                        .to_string(),
-                    Frame::Simple(s) => s.clone(),
-                    _ => return Err("HLEN key must be a string".to_string()),
-                };
-
-                Ok(Command::HLen { key })
+                let subcommand = args.remove(0).to_uppercase();
+
+                match subcommand.as_str() {
+                    "GET" if !args.is_empty() => Ok(Command::ConfigGet { patterns: args }),
+                    "SET" if !args.is_empty() && args.len() % 2 == 0 => {
+                        let mut pairs = Vec::with_capacity(args.len() / 2);
+                        let mut args = args.into_iter();
+                        while let (Some(name), Some(value)) = (args.next(), args.next()) {
+                            pairs.push((name, value));
+                        }
+                        Ok(Command::ConfigSet { pairs })
+                    }
+                    "REWRITE" if args.is_empty() => Ok(Command::ConfigRewrite),
+                    _ => Err(format!(
+                        "ERR unknown subcommand or wrong number of arguments for '{}'. Try CONFIG HELP.",
+                        subcommand
+                    )),
+                }
+            }
+            "MULTI" | "EXEC" | "DISCARD" | "UNWATCH" => {
+                // MULTI | EXEC | DISCARD | UNWATCH
+                Ok(match cmd_name.as_str() {
+                    "MULTI" => Command::Multi,
+                    "EXEC" => Command::Exec,
+                    "DISCARD" => Command::Discard,
+                    _ => Command::Unwatch,
+                })
             }
-            "PUBLISH" => {
-                // PUBLISH channel message
-                if array.len() != 3 {
-                    return Err("ERR wrong number of arguments for 'publish' command".to_string());
+            "WATCH" => {
+                // WATCH key [key ...]
+                let mut keys = Vec::new();
+                for item in array.iter().skip(1) {
+                    let key = match item {
+                        Frame::Bulk(data) => data.clone(),
+                        Frame::Simple(s) => Bytes::from(s.clone()),
+                        _ => return Err("WATCH key must be a string".to_string()),
+                    };
+                    keys.push(key);
                 }
 
-                let channel = match &array[1] {
+                Ok(Command::Watch { keys })
+            }
+            "IDEMPOTENT" => {
+                // IDEMPOTENT token command [arg ...]
+                let token = match &array[1] {
                     Frame::Bulk(data) => std::str::from_utf8(data)
-                        .map_err(|_| "invalid UTF-8 in channel")?
+                        .map_err(|_| "invalid UTF-8 in token")?
                         .to_string(),
                     Frame::Simple(s) => s.clone(),
-                    _ => return Err("PUBLISH channel must be a string".to_string()),
+                    _ => return Err("IDEMPOTENT token must be a string".to_string()),
                 };
 
-                let message = match &array[2] {
-                    Frame::Bulk(data) => data.clone(),
-                    Frame::Simple(s) => Bytes::from(s.clone()),
-                    _ => return Err("PUBLISH message must be a string".to_string()),
-                };
+                let frame = Frame::Array(array.split_off(2));
+                let command = Command::from_frame(frame.clone())?;
+                // Commands that change connection state (tagged NO_MULTI or
+                // PUBSUB in the command table) or block cannot be replayed
+                let connection_state = table::lookup(command.name())
+                    .is_some_and(|info| info.flags & (table::NO_MULTI | table::PUBSUB) != 0);
+                if connection_state
+                    || matches!(
+                        command,
+                        Command::Multi
+                            | Command::Exec
+                            | Command::Discard
+                            | Command::Unwatch
+                            | Command::BPop { .. }
+                            | Command::XRead { block: Some(_), .. }
+                            | Command::DebugSleep { .. }
+                    )
+                {
+                    return Err(format!(
+                        "ERR '{}' cannot be used with IDEMPOTENT",
+                        command.name().to_lowercase()
+                    ));
+                }
 
-                Ok(Command::Publish { channel, message })
-            }
-            "STATS" | "INFO" => {
-                Ok(Command::Stats)
+                Ok(Command::Idempotent {
+                    token,
+                    frame,
+                    command: Box::new(command),
+                })
             }
-            "CMDSTAT" | "CMDSTATS" => {
-                Ok(Command::CmdStat)
+            "LATENCY" => {
+                // LATENCY LATEST | HISTORY event | RESET [event ...]
+                let mut args = Vec::new();
+                for item in array.iter().skip(1) {
+                    let arg = match item {
+                        Frame::Bulk(data) => std::str::from_utf8(data)
+                            .map_err(|_| "invalid UTF-8 in argument")?
+                            .to_string(),
+                        Frame::Simple(s) => s.clone(),
+                        _ => return Err("LATENCY argument must be a string".to_string()),
+                    };
+                    args.push(arg);
+                }
+
+                let subcommand = args.remove(0).to_uppercase();
+                match subcommand.as_str() {
+                    "LATEST" if args.is_empty() => Ok(Command::LatencyLatest),
+                    "HISTORY" if args.len() == 1 => Ok(Command::LatencyHistory {
+                        event: args.remove(0),
+                    }),
+                    "RESET" => Ok(Command::LatencyReset { events: args }),
+                    "LATEST" | "HISTORY" => Err(format!(
+                        "ERR wrong number of arguments for 'latency|{}' command",
+                        subcommand.to_lowercase()
+                    )),
+                    _ => Err(format!(
+                        "ERR unknown subcommand '{}'. Try LATENCY HELP.",
+                        subcommand
+                    )),
+                }
             }
             _ => Ok(Command::Unknown(cmd_name)),
         }
@@ -770,22 +2533,80 @@ impl Command {
         match self {
             Command::Ping(_) => "PING",
             Command::Set { .. } => "SET",
+            Command::GetEx { .. } => "GETEX",
+            Command::SetEx { millis, .. } => {
+                if *millis {
+                    "PSETEX"
+                } else {
+                    "SETEX"
+                }
+            }
+            Command::MSet { .. } => "MSET",
+            Command::Append { .. } => "APPEND",
+            Command::SetRange { .. } => "SETRANGE",
+            Command::GetRange { .. } => "GETRANGE",
+            Command::Lcs { .. } => "LCS",
+            Command::SetBit { .. } => "SETBIT",
+            Command::GetBit { .. } => "GETBIT",
+            Command::Incr { .. } => "INCR",
+            Command::Decr { .. } => "DECR",
+            Command::IncrBy { .. } => "INCRBY",
+            Command::IncrByFloat { .. } => "INCRBYFLOAT",
+            Command::DecrBy { .. } => "DECRBY",
+            Command::Expire { millis, .. } => {
+                if *millis {
+                    "PEXPIRE"
+                } else {
+                    "EXPIRE"
+                }
+            }
+            Command::ExpireAt { millis, .. } => {
+                if *millis {
+                    "PEXPIREAT"
+                } else {
+                    "EXPIREAT"
+                }
+            }
+            Command::Ttl { millis, .. } => {
+                if *millis {
+                    "PTTL"
+                } else {
+                    "TTL"
+                }
+            }
+            Command::ExpireTime { millis, .. } => {
+                if *millis {
+                    "PEXPIRETIME"
+                } else {
+                    "EXPIRETIME"
+                }
+            }
             Command::Get { .. } => "GET",
             Command::Echo { .. } => "ECHO",
             Command::Del { .. } => "DEL",
+            Command::Unlink { .. } => "UNLINK",
             Command::Exists { .. } => "EXISTS",
+            Command::Copy { .. } => "COPY",
             Command::Type { .. } => "TYPE",
             Command::DbSize => "DBSIZE",
+            Command::Select { .. } => "SELECT",
             Command::FlushDb => "FLUSHDB",
             Command::Keys { .. } => "KEYS",
+            Command::CountKeys { .. } => "COUNTKEYS",
+            Command::Scan { .. } => "SCAN",
             Command::LPush { .. } => "LPUSH",
             Command::RPush { .. } => "RPUSH",
             Command::LPop { .. } => "LPOP",
+            Command::BPop { left: true, .. } => "BLPOP",
+            Command::BPop { left: false, .. } => "BRPOP",
             Command::RPop { .. } => "RPOP",
             Command::LRange { .. } => "LRANGE",
             Command::LLen { .. } => "LLEN",
+            Command::LInsert { .. } => "LINSERT",
             Command::SAdd { .. } => "SADD",
             Command::SRem { .. } => "SREM",
+            Command::SPop { .. } => "SPOP",
+            Command::SRandMember { .. } => "SRANDMEMBER",
             Command::SMembers { .. } => "SMEMBERS",
             Command::SIsMember { .. } => "SISMEMBER",
             Command::SCard { .. } => "SCARD",
@@ -795,22 +2616,84 @@ impl Command {
             Command::HDel { .. } => "HDEL",
             Command::HExists { .. } => "HEXISTS",
             Command::HLen { .. } => "HLEN",
+            Command::ZAdd { .. } => "ZADD",
+            Command::ZRem { .. } => "ZREM",
+            Command::ZScore { .. } => "ZSCORE",
+            Command::ZCard { .. } => "ZCARD",
+            Command::ZRange { .. } => "ZRANGE",
+            Command::ZRangeByLex { .. } => "ZRANGEBYLEX",
+            Command::ZLexCount { .. } => "ZLEXCOUNT",
+            Command::XAdd { .. } => "XADD",
+            Command::XLen { .. } => "XLEN",
+            Command::XRange { .. } => "XRANGE",
+            Command::XRead { .. } => "XREAD",
+            #[cfg(feature = "json")]
+            Command::JsonSet { .. } => "JSON.SET",
+            #[cfg(feature = "json")]
+            Command::JsonGet { .. } => "JSON.GET",
             Command::Publish { .. } => "PUBLISH",
-            Command::Stats => "STATS",
+            Command::Subscribe { .. } => "SUBSCRIBE",
+            Command::Unsubscribe { .. } => "UNSUBSCRIBE",
+            Command::SPublish { .. } => "SPUBLISH",
+            Command::SSubscribe { .. } => "SSUBSCRIBE",
+            Command::SUnsubscribe { .. } => "SUNSUBSCRIBE",
+            Command::PubSubNumSub { .. } | Command::PubSubNumPat | Command::PubSubRetain { .. } => {
+                "PUBSUB"
+            }
+            Command::Stats { .. } => "STATS",
+            Command::Role => "ROLE",
+            Command::Failover { .. } => "FAILOVER",
             Command::CmdStat => "CMDSTAT",
-            Command::Unknown(name) => {
-                // Return a static str for common unknowns; otherwise "UNKNOWN"
-                match name.as_str() {
-                    _ => "UNKNOWN",
-                }
-            }
+            Command::MemoryStats => "MEMORY",
+            Command::CommandGetKeys { .. }
+            | Command::CommandCount
+            | Command::CommandInfo { .. } => "COMMAND",
+            Command::ObjectEncoding { .. } => "OBJECT",
+            Command::DebugSleep { .. } | Command::DebugError { .. } => "DEBUG",
+            #[cfg(debug_assertions)]
+            Command::DebugPanic => "DEBUG",
+            Command::Cluster { .. } => "CLUSTER",
+            Command::ClientId
+            | Command::ClientSetName { .. }
+            | Command::ClientGetName
+            | Command::ClientInfo
+            | Command::ClientReply { .. } => "CLIENT",
+            Command::Quit => "QUIT",
+            Command::Reset => "RESET",
+            Command::Monitor => "MONITOR",
+            Command::Auth { .. } => "AUTH",
+            Command::AclSetUser { .. }
+            | Command::AclUsers
+            | Command::AclWhoAmI
+            | Command::AclCat { .. } => "ACL",
+            Command::ConfigGet { .. } | Command::ConfigSet { .. } | Command::ConfigRewrite => {
+                "CONFIG"
+            }
+            Command::BgRewriteAof => "BGREWRITEAOF",
+            Command::Save => "SAVE",
+            Command::LastSave => "LASTSAVE",
+            Command::WaitAof { .. } => "WAITAOF",
+            Command::LatencyLatest
+            | Command::LatencyHistory { .. }
+            | Command::LatencyReset { .. } => "LATENCY",
+            Command::Multi => "MULTI",
+            Command::Exec => "EXEC",
+            Command::Discard => "DISCARD",
+            Command::Watch { .. } => "WATCH",
+            Command::Unwatch => "UNWATCH",
+            Command::Idempotent { .. } => "IDEMPOTENT",
+            Command::Unknown(_) => "UNKNOWN",
         }
     }
 
     /// Return a best-effort logical key for key-space metrics strategies.
+    ///
+    /// Keys that are not valid UTF-8 give no hint.
     pub fn metrics_key_hint(&self) -> Option<&str> {
         match self {
             Command::Set { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::GetEx { key, .. }
             | Command::Get { key }
             | Command::Exists { key }
             | Command::Type { key }
@@ -820,8 +2703,11 @@ impl Command {
             | Command::RPop { key }
             | Command::LRange { key, .. }
             | Command::LLen { key }
+            | Command::LInsert { key, .. }
             | Command::SAdd { key, .. }
             | Command::SRem { key, .. }
+            | Command::SPop { key, .. }
+            | Command::SRandMember { key, .. }
             | Command::SMembers { key }
             | Command::SIsMember { key, .. }
             | Command::SCard { key }
@@ -830,10 +2716,53 @@ impl Command {
             | Command::HGetAll { key }
             | Command::HDel { key, .. }
             | Command::HExists { key, .. }
-            | Command::HLen { key } => Some(key.as_str()),
-            Command::Del { keys } => keys.first().map(|key| key.as_str()),
-            Command::Keys { pattern } => Some(pattern.as_str()),
-            Command::Publish { channel, .. } => Some(channel.as_str()),
+            | Command::HLen { key }
+            | Command::ZAdd { key, .. }
+            | Command::ZRem { key, .. }
+            | Command::ZScore { key, .. }
+            | Command::ZCard { key }
+            | Command::ZRange { key, .. }
+            | Command::ZRangeByLex { key, .. }
+            | Command::ZLexCount { key, .. }
+            | Command::XAdd { key, .. }
+            | Command::XLen { key }
+            | Command::XRange { key, .. }
+            | Command::ObjectEncoding { key }
+            | Command::Expire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::Ttl { key, .. }
+            | Command::ExpireTime { key, .. }
+            | Command::Append { key, .. }
+            | Command::SetRange { key, .. }
+            | Command::GetRange { key, .. }
+            | Command::Lcs { key1: key, .. }
+            | Command::SetBit { key, .. }
+            | Command::GetBit { key, .. }
+            | Command::Incr { key }
+            | Command::Decr { key }
+            | Command::IncrBy { key, .. }
+            | Command::DecrBy { key, .. }
+            | Command::IncrByFloat { key, .. } => std::str::from_utf8(key).ok(),
+            Command::Copy { source, .. } => std::str::from_utf8(source).ok(),
+            Command::Del { keys }
+            | Command::Unlink { keys }
+            | Command::BPop { keys, .. }
+            | Command::XRead { keys, .. } => {
+                keys.first().and_then(|key| std::str::from_utf8(key).ok())
+            }
+            #[cfg(feature = "json")]
+            Command::JsonSet { key, .. } | Command::JsonGet { key, .. } => {
+                std::str::from_utf8(key).ok()
+            }
+            Command::MSet { pairs } => pairs
+                .first()
+                .and_then(|(key, _)| std::str::from_utf8(key).ok()),
+            Command::Keys { pattern } | Command::CountKeys { pattern } => {
+                std::str::from_utf8(pattern).ok()
+            }
+            Command::Publish { channel, .. } | Command::SPublish { channel, .. } => {
+                Some(channel.as_str())
+            }
             _ => None,
         }
     }
@@ -841,46 +2770,199 @@ impl Command {
     /// Execute the command and write the response to the connection
     pub async fn execute(
         &self,
-        db: &Db,
+        ctx: &mut ExecContext<'_>,
         dst: &mut Connection,
-        pubsub: &PubSub,
-        metrics: &SharedMetrics,
-        command_metrics: &SharedCommandMetrics,
     ) -> Result<(), io::Error> {
+        let response = self.apply(ctx).await;
+        dst.write_frame(&response).await
+    }
+
+    /// Execute the command and return the response frame
+    ///
+    /// Only commands that changed data count towards the snapshot save
+    /// points, see `changed_data`.
+    pub async fn apply(&self, ctx: &ExecContext<'_>) -> Frame {
+        if let Command::DebugSleep { duration } = self {
+            tokio::time::sleep(*duration).await;
+            return Frame::Simple("OK".to_string());
+        }
+        self.apply_blocking(ctx)
+    }
+
+    /// Execute the command without yielding to the runtime
+    ///
+    /// Used by EXEC, which runs all queued commands inside `Db::transaction`
+    /// and collects their replies into a single array. DEBUG SLEEP blocks
+    /// the thread here, holding up the whole database as in Redis.
+    pub fn apply_blocking(&self, ctx: &ExecContext<'_>) -> Frame {
+        let reply = self.run(ctx);
+        if self.changed_data(&reply) {
+            ctx.metrics.add_dirty(1);
+            if ctx.db.has_change_observer() {
+                for key in self.changed_keys(&reply) {
+                    ctx.db.record_change(self.name(), key);
+                }
+            }
+        }
+        reply
+    }
+
+    fn run(&self, ctx: &ExecContext<'_>) -> Frame {
+        let &ExecContext {
+            db,
+            pubsub,
+            metrics,
+            command_metrics,
+            ..
+        } = ctx;
         match self {
             Command::Ping(msg) => {
-                let response = if let Some(msg) = msg {
+                if let Some(msg) = msg {
                     Frame::Bulk(msg.clone())
                 } else {
                     Frame::Simple("PONG".to_string())
-                };
-                dst.write_frame(&response).await?;
+                }
             }
             Command::Set {
                 key,
                 value,
                 expires_at,
+                nx,
+                xx,
             } => {
                 // Write to database with optional expiration
-                db.write_string(key.clone(), value.clone(), *expires_at);
+                let exists = (*nx || *xx).then_some(*xx);
+                if !db.set_string(key.clone(), value.clone(), *expires_at, exists) {
+                    return Frame::Null;
+                }
 
                 // Return OK
-                let response = Frame::Simple("OK".to_string());
-                dst.write_frame(&response).await?;
+                Frame::Simple("OK".to_string())
+            }
+            Command::SetEx {
+                key,
+                value,
+                expires_at,
+                ..
+            } => {
+                db.write_string(key.clone(), value.clone(), Some(*expires_at));
+                Frame::Simple("OK".to_string())
+            }
+            Command::MSet { pairs } => {
+                db.mset(pairs.clone());
+                Frame::Simple("OK".to_string())
+            }
+            Command::Append { key, value } => match db.append(key, value.clone()) {
+                Ok(len) => Frame::Integer(len as i64),
+                Err(e) => Frame::error(e),
+            },
+            Command::SetRange { key, offset, value } => {
+                match db.setrange(key, *offset, value.clone()) {
+                    Ok(len) => Frame::Integer(len as i64),
+                    Err(e) => Frame::error(e),
+                }
             }
+            Command::GetRange { key, start, end } => match db.getrange(key, *start, *end) {
+                Ok(value) => Frame::Bulk(value),
+                Err(e) => Frame::error(e),
+            },
+            Command::Lcs {
+                key1,
+                key2,
+                len,
+                idx,
+                min_match_len,
+                with_match_len,
+            } => {
+                let lcs = match db.lcs(key1, key2) {
+                    Ok(lcs) => lcs,
+                    Err(e) => return Frame::error(e),
+                };
+                if *len {
+                    return Frame::Integer(lcs.sequence.len() as i64);
+                }
+                if !*idx {
+                    return Frame::Bulk(Bytes::from(lcs.sequence));
+                }
+
+                let range = |(start, end): (usize, usize)| {
+                    Frame::Array(vec![
+                        Frame::Integer(start as i64),
+                        Frame::Integer(end as i64),
+                    ])
+                };
+                let matches = lcs
+                    .matches
+                    .iter()
+                    .filter(|m| m.match_len() >= *min_match_len)
+                    .map(|m| {
+                        let mut fields = vec![range(m.a), range(m.b)];
+                        if *with_match_len {
+                            fields.push(Frame::Integer(m.match_len() as i64));
+                        }
+                        Frame::Array(fields)
+                    })
+                    .collect();
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from_static(b"matches")),
+                    Frame::Array(matches),
+                    Frame::Bulk(Bytes::from_static(b"len")),
+                    Frame::Integer(lcs.sequence.len() as i64),
+                ])
+            }
+            Command::SetBit { key, offset, on } => match db.setbit(key, *offset, *on) {
+                Ok(previous) => Frame::Integer(previous as i64),
+                Err(e) => Frame::error(e),
+            },
+            Command::GetBit { key, offset } => match db.getbit(key, *offset) {
+                Ok(bit) => Frame::Integer(bit as i64),
+                Err(e) => Frame::error(e),
+            },
+            Command::Incr { .. }
+            | Command::Decr { .. }
+            | Command::IncrBy { .. }
+            | Command::DecrBy { .. } => match self.apply_incr(db) {
+                Ok(value) => Frame::Integer(value),
+                Err(e) => Frame::error(e),
+            },
+            Command::IncrByFloat { key, delta } => match db.incr_by_float(key, *delta) {
+                Ok(value) => Frame::Bulk(value),
+                Err(e) => Frame::error(e),
+            },
+            Command::Expire {
+                key, expires_at, ..
+            } => Frame::Integer(db.expire_at(key, *expires_at) as i64),
+            Command::ExpireAt { key, unix_ms, .. } => {
+                let expires_at = clock::unix_ms_to_instant((*unix_ms).max(0) as u64);
+                Frame::Integer(db.expire_at(key, expires_at) as i64)
+            }
+            Command::Ttl { key, millis } => {
+                Frame::Integer(if *millis { db.pttl(key) } else { db.ttl(key) })
+            }
+            Command::ExpireTime { key, millis } => Frame::Integer(if *millis {
+                db.pexpiretime(key)
+            } else {
+                db.expiretime(key)
+            }),
+            Command::GetEx {
+                key,
+                expires_at,
+                persist,
+            } => match db.getex(key, *expires_at, *persist) {
+                Some(value) => Frame::Bulk(value),
+                None => Frame::Null,
+            },
             Command::Get { key } => {
                 // Read from database
-                let response = if let Some(value) = db.read_string(key) {
+                if let Some(value) = db.read_string(key) {
                     Frame::Bulk(value)
                 } else {
                     Frame::Null
-                };
-                dst.write_frame(&response).await?;
+                }
             }
             Command::Echo { message } => {
                 // Echo back the message
-                let response = Frame::Bulk(message.clone());
-                dst.write_frame(&response).await?;
+                Frame::Bulk(message.clone())
             }
             Command::Del { keys } => {
                 // Delete keys and return count of deleted keys
@@ -890,215 +2972,842 @@ impl Command {
                         count += 1;
                     }
                 }
-                let response = Frame::Integer(count);
-                dst.write_frame(&response).await?;
+                Frame::Integer(count)
+            }
+            Command::Unlink { keys } => {
+                // Same as DEL, but large values are always freed in the background
+                let mut count = 0;
+                for key in keys {
+                    if db.unlink(key) {
+                        count += 1;
+                    }
+                }
+                Frame::Integer(count)
             }
             Command::Exists { key } => {
                 // Check if key exists
                 let exists = db.exists(key);
-                let response = Frame::Integer(if exists { 1 } else { 0 });
-                dst.write_frame(&response).await?;
+                Frame::Integer(if exists { 1 } else { 0 })
             }
+            Command::Copy {
+                source,
+                destination,
+                replace,
+            } => Frame::Integer(db.copy(source, destination, *replace) as i64),
             Command::Type { key } => {
                 // Get the type of a value
                 let type_name = db.get_type(key).unwrap_or("none");
-                let response = Frame::Simple(type_name.to_string());
-                dst.write_frame(&response).await?;
+                Frame::Simple(type_name.to_string())
             }
             Command::DbSize => {
                 // Get the number of keys in the database
                 let size = db.dbsize();
-                let response = Frame::Integer(size as i64);
-                dst.write_frame(&response).await?;
+                Frame::Integer(size as i64)
             }
             Command::FlushDb => {
                 // Clear all keys from the database
                 db.flushdb();
-                let response = Frame::Simple("OK".to_string());
-                dst.write_frame(&response).await?;
+                Frame::Simple("OK".to_string())
+            }
+            Command::Scan {
+                cursor,
+                pattern,
+                count,
+                type_name,
+            } => {
+                let (next, keys) =
+                    db.scan(*cursor, *count, pattern.as_deref(), type_name.as_deref());
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from(next.to_string())),
+                    Frame::Array(keys.into_iter().map(Frame::Bulk).collect()),
+                ])
             }
             Command::Keys { pattern } => {
                 // Get all keys matching a pattern
-                let keys = db.keys(pattern);
-                let response = Frame::Array(
-                    keys.into_iter()
-                        .map(|k| Frame::Bulk(Bytes::from(k)))
-                        .collect(),
-                );
-                dst.write_frame(&response).await?;
+                match db.keys(pattern) {
+                    Ok(keys) => Frame::Array(keys.into_iter().map(Frame::Bulk).collect()),
+                    Err(e) => Frame::error(e),
+                }
             }
+            Command::CountKeys { pattern } => Frame::Integer(db.count_keys(pattern) as i64),
             Command::LPush { key, values } => {
                 // Push values to the left of a list
                 let len = db.lpush(key.clone(), values.clone());
-                let response = Frame::Integer(len as i64);
-                dst.write_frame(&response).await?;
+                Frame::Integer(len as i64)
             }
             Command::RPush { key, values } => {
                 // Push values to the right of a list
                 let len = db.rpush(key.clone(), values.clone());
-                let response = Frame::Integer(len as i64);
-                dst.write_frame(&response).await?;
+                Frame::Integer(len as i64)
             }
             Command::LPop { key } => {
                 // Pop a value from the left of a list
-                let response = if let Some(value) = db.lpop(key) {
+                if let Some(value) = db.lpop(key) {
                     Frame::Bulk(value)
                 } else {
                     Frame::Null
-                };
-                dst.write_frame(&response).await?;
+                }
             }
             Command::RPop { key } => {
                 // Pop a value from the right of a list
-                let response = if let Some(value) = db.rpop(key) {
+                if let Some(value) = db.rpop(key) {
                     Frame::Bulk(value)
                 } else {
                     Frame::Null
-                };
-                dst.write_frame(&response).await?;
+                }
+            }
+            Command::BPop { keys, left, .. } => {
+                // Inside MULTI: never blocks, like Redis
+                match db.pop_first(keys, *left) {
+                    Some((key, value)) => Frame::Array(vec![Frame::Bulk(key), Frame::Bulk(value)]),
+                    None => Frame::Null,
+                }
             }
             Command::LRange { key, start, stop } => {
                 // Get a range of elements from a list
-                let response = if let Some(values) = db.lrange(key, *start, *stop) {
+                if let Some(values) = db.lrange(key, *start, *stop) {
                     Frame::Array(values.into_iter().map(Frame::Bulk).collect())
                 } else {
                     Frame::Array(Vec::new())
-                };
-                dst.write_frame(&response).await?;
+                }
             }
             Command::LLen { key } => {
                 // Get the length of a list
                 let len = db.llen(key).unwrap_or(0);
-                let response = Frame::Integer(len as i64);
-                dst.write_frame(&response).await?;
+                Frame::Integer(len as i64)
             }
+            Command::LInsert {
+                key,
+                before,
+                pivot,
+                value,
+            } => match db.linsert(key, *before, pivot, value.clone()) {
+                Ok(len) => Frame::Integer(len),
+                Err(e) => Frame::error(e),
+            },
             Command::SAdd { key, members } => {
                 // Add members to a set
                 let added = db.sadd(key.clone(), members.clone());
-                let response = Frame::Integer(added as i64);
-                dst.write_frame(&response).await?;
+                Frame::Integer(added as i64)
             }
             Command::SRem { key, members } => {
                 // Remove members from a set
-                let removed = db.srem(key, members.clone());
-                let response = Frame::Integer(removed as i64);
-                dst.write_frame(&response).await?;
+                match db.srem(key, members.clone()) {
+                    Ok(removed) => Frame::Integer(removed as i64),
+                    Err(e) => Frame::error(e),
+                }
             }
+            Command::SPop { key, count } => match db.spop(key, count.unwrap_or(1)) {
+                // Without a count: the member, or nil for an empty set
+                Ok(mut members) if count.is_none() => match members.pop() {
+                    Some(member) => Frame::Bulk(member),
+                    None => Frame::Null,
+                },
+                Ok(members) => Frame::Array(members.into_iter().map(Frame::Bulk).collect()),
+                Err(e) => Frame::error(e),
+            },
+            Command::SRandMember { key, count } => match db.srandmember(key, count.unwrap_or(1)) {
+                // Without a count: the member, or nil for an empty set
+                Ok(mut members) if count.is_none() => match members.pop() {
+                    Some(member) => Frame::Bulk(member),
+                    None => Frame::Null,
+                },
+                Ok(members) => Frame::Array(members.into_iter().map(Frame::Bulk).collect()),
+                Err(e) => Frame::error(e),
+            },
             Command::SMembers { key } => {
                 // Get all members of a set
-                let response = if let Some(members) = db.smembers(key) {
-                    Frame::Array(
-                        members
-                            .into_iter()
-                            .map(|m| Frame::Bulk(Bytes::from(m)))
-                            .collect(),
-                    )
-                } else {
-                    Frame::Array(Vec::new())
-                };
-                dst.write_frame(&response).await?;
+                match db.smembers(key) {
+                    Ok(members) => Frame::Array(members.into_iter().map(Frame::Bulk).collect()),
+                    Err(e) => Frame::error(e),
+                }
             }
             Command::SIsMember { key, member } => {
                 // Check if a member exists in a set
-                let exists = db.sismember(key, member);
-                let response = Frame::Integer(if exists { 1 } else { 0 });
-                dst.write_frame(&response).await?;
+                match db.sismember(key, member) {
+                    Ok(exists) => Frame::Integer(if exists { 1 } else { 0 }),
+                    Err(e) => Frame::error(e),
+                }
             }
             Command::SCard { key } => {
                 // Get the cardinality of a set
-                let card = db.scard(key);
-                let response = Frame::Integer(card as i64);
-                dst.write_frame(&response).await?;
+                match db.scard(key) {
+                    Ok(card) => Frame::Integer(card as i64),
+                    Err(e) => Frame::error(e),
+                }
             }
             Command::HSet { key, field, value } => {
                 // Set a field in a hash
                 let is_new = db.hset(key.clone(), field.clone(), value.clone());
-                let response = Frame::Integer(if is_new { 1 } else { 0 });
-                dst.write_frame(&response).await?;
+                Frame::Integer(if is_new { 1 } else { 0 })
+            }
+            #[cfg(feature = "json")]
+            Command::JsonSet { key, path, value } => {
+                match db.json_set(key.clone(), path, value.clone()) {
+                    Ok(true) => Frame::Simple("OK".to_string()),
+                    Ok(false) => Frame::Null,
+                    Err(e) => Frame::error(e),
+                }
             }
+            #[cfg(feature = "json")]
+            Command::JsonGet { key, path } => match db.json_get(key, path) {
+                Ok(Some(json)) => Frame::Bulk(Bytes::from(json)),
+                Ok(None) => Frame::Null,
+                Err(e) => Frame::error(e),
+            },
             Command::HGet { key, field } => {
                 // Get a field from a hash
-                let response = if let Some(value) = db.hget(key, field) {
-                    Frame::Bulk(value)
-                } else {
-                    Frame::Null
-                };
-                dst.write_frame(&response).await?;
+                match db.hget(key, field) {
+                    Ok(Some(value)) => Frame::Bulk(value),
+                    Ok(None) => Frame::Null,
+                    Err(e) => Frame::error(e),
+                }
             }
             Command::HGetAll { key } => {
                 // Get all fields and values from a hash
-                let response = if let Some(pairs) = db.hgetall(key) {
-                    let mut result = Vec::new();
-                    for (field, value) in pairs {
-                        result.push(Frame::Bulk(Bytes::from(field)));
-                        result.push(Frame::Bulk(value));
+                match db.hgetall(key) {
+                    Ok(pairs) => {
+                        let mut result = Vec::new();
+                        for (field, value) in pairs {
+                            result.push(Frame::Bulk(field));
+                            result.push(Frame::Bulk(value));
+                        }
+                        Frame::Array(result)
                     }
-                    Frame::Array(result)
-                } else {
-                    Frame::Array(Vec::new())
-                };
-                dst.write_frame(&response).await?;
+                    Err(e) => Frame::error(e),
+                }
             }
             Command::HDel { key, fields } => {
                 // Delete fields from a hash
-                let deleted = db.hdel(key, fields.clone());
-                let response = Frame::Integer(deleted as i64);
-                dst.write_frame(&response).await?;
+                match db.hdel(key, fields.clone()) {
+                    Ok(deleted) => Frame::Integer(deleted as i64),
+                    Err(e) => Frame::error(e),
+                }
             }
             Command::HExists { key, field } => {
                 // Check if a field exists in a hash
-                let exists = db.hexists(key, field);
-                let response = Frame::Integer(if exists { 1 } else { 0 });
-                dst.write_frame(&response).await?;
+                match db.hexists(key, field) {
+                    Ok(exists) => Frame::Integer(if exists { 1 } else { 0 }),
+                    Err(e) => Frame::error(e),
+                }
             }
             Command::HLen { key } => {
                 // Get the number of fields in a hash
-                let len = db.hlen(key);
-                let response = Frame::Integer(len as i64);
-                dst.write_frame(&response).await?;
+                match db.hlen(key) {
+                    Ok(len) => Frame::Integer(len as i64),
+                    Err(e) => Frame::error(e),
+                }
+            }
+            Command::ZAdd { key, members } => {
+                // Add members to a sorted set or update their scores
+                match db.zadd(key.clone(), members.clone()) {
+                    Ok(added) => Frame::Integer(added as i64),
+                    Err(e) => Frame::error(e),
+                }
+            }
+            Command::ZRem { key, members } => {
+                // Remove members from a sorted set
+                match db.zrem(key, members.clone()) {
+                    Ok(removed) => Frame::Integer(removed as i64),
+                    Err(e) => Frame::error(e),
+                }
+            }
+            Command::ZScore { key, member } => {
+                // Get the score of a member
+                match db.zscore(key, member) {
+                    Ok(Some(score)) => score_frame(score),
+                    Ok(None) => Frame::Null,
+                    Err(e) => Frame::error(e),
+                }
+            }
+            Command::ZCard { key } => {
+                // Get the number of members of a sorted set
+                match db.zcard(key) {
+                    Ok(card) => Frame::Integer(card as i64),
+                    Err(e) => Frame::error(e),
+                }
+            }
+            Command::ZRange {
+                key,
+                start,
+                stop,
+                with_scores,
+            } => {
+                // Get members by rank, lowest score first
+                match db.zrange(key, *start, *stop) {
+                    Ok(members) => {
+                        let mut result = Vec::new();
+                        for (member, score) in members {
+                            result.push(Frame::Bulk(member));
+                            if *with_scores {
+                                result.push(score_frame(score));
+                            }
+                        }
+                        Frame::Array(result)
+                    }
+                    Err(e) => Frame::error(e),
+                }
+            }
+            Command::ZRangeByLex {
+                key,
+                min,
+                max,
+                limit,
+            } => match db.zrangebylex(key, min, max, *limit) {
+                Ok(members) => Frame::Array(members.into_iter().map(Frame::Bulk).collect()),
+                Err(e) => Frame::error(e),
+            },
+            Command::ZLexCount { key, min, max } => match db.zlexcount(key, min, max) {
+                Ok(count) => Frame::Integer(count as i64),
+                Err(e) => Frame::error(e),
+            },
+            Command::XAdd { key, id, fields } => match db.xadd(key.clone(), *id, fields.clone()) {
+                Ok(id) => Frame::Bulk(Bytes::from(id.to_string())),
+                Err(e) => Frame::error(e),
+            },
+            Command::XLen { key } => match db.xlen(key) {
+                Ok(len) => Frame::Integer(len as i64),
+                Err(e) => Frame::error(e),
+            },
+            Command::XRange {
+                key,
+                start,
+                end,
+                count,
+            } => {
+                // A negative COUNT returns nothing, like COUNT 0
+                let count = count.map_or(usize::MAX, |count| count.max(0) as usize);
+                match db.xrange(key, *start, *end, count) {
+                    Ok(entries) => Frame::Array(
+                        entries
+                            .into_iter()
+                            .map(|(id, fields)| stream_entry_frame(id, fields))
+                            .collect(),
+                    ),
+                    Err(e) => Frame::error(e),
+                }
+            }
+            Command::XRead {
+                keys, ids, count, ..
+            } => {
+                // Inside MULTI: never blocks, so `$` finds nothing
+                let count = count.unwrap_or(usize::MAX);
+                match xread_ids(db, keys, ids).and_then(|ids| db.xread(keys, &ids, count)) {
+                    Ok(streams) => xread_frame(streams),
+                    Err(e) => Frame::error(e),
+                }
             }
             Command::Publish { channel, message } => {
+                if let Err(e) = pubsub.check_message_size(message) {
+                    return Frame::error(e);
+                }
                 // Publish a message to a channel
                 let num_receivers = pubsub.publish(channel, message.clone());
-                let response = Frame::Integer(num_receivers as i64);
-                dst.write_frame(&response).await?;
+                Frame::Integer(num_receivers as i64)
             }
-            Command::Stats => {
-                let stats = metrics.format_stats();
-                let response = Frame::Bulk(Bytes::from(stats));
-                dst.write_frame(&response).await?;
+            Command::SPublish { channel, message } => {
+                if let Err(e) = pubsub.check_message_size(message) {
+                    return Frame::error(e);
+                }
+                // Publish a message to a shard channel
+                let num_receivers = pubsub.spublish(channel, message.clone());
+                Frame::Integer(num_receivers as i64)
+            }
+            Command::PubSubNumSub { channels } => {
+                let mut result = Vec::with_capacity(channels.len() * 2);
+                for channel in channels {
+                    result.push(Frame::Bulk(Bytes::from(channel.clone())));
+                    result.push(Frame::Integer(pubsub.num_subscribers(channel) as i64));
+                }
+                Frame::Array(result)
+            }
+            Command::PubSubNumPat => Frame::Integer(pubsub.num_patterns() as i64),
+            Command::PubSubRetain { channel, count } => {
+                pubsub.retain(channel, *count);
+                Frame::Simple("OK".to_string())
+            }
+            Command::Stats { sections } => {
+                let mut stats = metrics.format_stats();
+                stats.push_str(&format!(
+                    "\r\n# Pubsub\r\npubsub_channels:{}\r\npubsub_patterns:{}\r\n",
+                    pubsub.num_channels(),
+                    pubsub.num_patterns()
+                ));
+                stats.push_str("\r\n");
+                stats.push_str(&metrics.command_stats().format_info());
+                Frame::Bulk(Bytes::from(info_sections(&stats, sections)))
             }
             Command::CmdStat => {
                 let stats = command_metrics.format_cmdstat();
-                let response = Frame::Bulk(Bytes::from(stats));
-                dst.write_frame(&response).await?;
+                Frame::Bulk(Bytes::from(stats))
+            }
+            Command::Role => Frame::Array(vec![
+                Frame::Bulk(Bytes::from_static(b"master")),
+                Frame::Integer(metrics.replication().offset() as i64),
+                // Replicas attached to the stream have no address to report
+                Frame::Array(Vec::new()),
+            ]),
+            Command::LastSave => Frame::Integer((metrics.last_save_ms() / 1000) as i64),
+            Command::Failover { abort: true } => Frame::error("ERR No failover in progress."),
+            Command::Failover { abort: false } => {
+                Frame::error("ERR FAILOVER requires connected replicas.")
+            }
+            Command::MemoryStats => {
+                let stats = db.memory_stats();
+                let fields = [
+                    ("keys.count", stats.keys_count),
+                    ("dataset.bytes", stats.dataset_bytes),
+                    ("overhead.total", stats.overhead_bytes),
+                    ("keys.string", stats.strings),
+                    ("keys.list", stats.lists),
+                    ("keys.set", stats.sets),
+                    ("keys.hash", stats.hashes),
+                    ("keys.zset", stats.zsets),
+                    ("keys.stream", stats.streams),
+                ];
+                let mut result = Vec::with_capacity(fields.len() * 2);
+                for (name, value) in fields {
+                    result.push(Frame::Bulk(Bytes::from_static(name.as_bytes())));
+                    result.push(Frame::Integer(value as i64));
+                }
+                Frame::Array(result)
             }
-            Command::Unknown(cmd) => {
-                let error = Frame::error(format!("ERR unknown command '{}'", cmd));
-                dst.write_frame(&error).await?;
+            Command::CommandGetKeys { args } => match table::lookup(&args[0]) {
+                Some(info) => match info.get_keys(args) {
+                    Ok(keys) => Frame::Array(
+                        keys.into_iter()
+                            .map(|key| Frame::Bulk(Bytes::from(key.clone())))
+                            .collect(),
+                    ),
+                    Err(e) => Frame::error(e),
+                },
+                None => Frame::error("ERR Invalid command specified"),
+            },
+            Command::CommandCount => Frame::Integer(table::COMMAND_TABLE.len() as i64),
+            Command::CommandInfo { names } => {
+                if names.is_empty() {
+                    Frame::Array(
+                        table::COMMAND_TABLE
+                            .iter()
+                            .map(command_info_frame)
+                            .collect(),
+                    )
+                } else {
+                    Frame::Array(
+                        names
+                            .iter()
+                            .map(|name| table::lookup(name).map_or(Frame::Null, command_info_frame))
+                            .collect(),
+                    )
+                }
             }
+            Command::ObjectEncoding { key } => match db.object_encoding(key) {
+                Some(encoding) => Frame::Bulk(Bytes::from_static(encoding.as_bytes())),
+                None => Frame::Null,
+            },
+            Command::DebugSleep { duration } => {
+                std::thread::sleep(*duration);
+                Frame::Simple("OK".to_string())
+            }
+            // A line break would end the error line early
+            Command::DebugError { message } => Frame::error(message.replace(['\r', '\n'], " ")),
+            #[cfg(debug_assertions)]
+            Command::DebugPanic => panic!("DEBUG PANIC"),
+            Command::Cluster { subcommand } => match subcommand.as_str() {
+                "INFO" => Frame::Bulk(Bytes::from_static(
+                    b"cluster_enabled:0\r\n\
+                      cluster_state:ok\r\n\
+                      cluster_slots_assigned:0\r\n\
+                      cluster_known_nodes:1\r\n\
+                      cluster_size:0\r\n",
+                )),
+                "MYID" => Frame::Bulk(Bytes::from_static(cluster_node_id().as_bytes())),
+                "NODES" => Frame::Bulk(Bytes::new()),
+                // SLOTS and SHARDS: no slots are served in standalone mode
+                _ => Frame::Array(Vec::new()),
+            },
+            Command::LatencyLatest => {
+                let latest = metrics.latency().latest();
+                Frame::Array(
+                    latest
+                        .into_iter()
+                        .map(|(event, timestamp, latest_ms, max_ms)| {
+                            Frame::Array(vec![
+                                Frame::Bulk(Bytes::from(event)),
+                                Frame::Integer(timestamp as i64),
+                                Frame::Integer(latest_ms as i64),
+                                Frame::Integer(max_ms as i64),
+                            ])
+                        })
+                        .collect(),
+                )
+            }
+            Command::LatencyHistory { event } => {
+                let history = metrics.latency().history(event);
+                Frame::Array(
+                    history
+                        .into_iter()
+                        .map(|(timestamp, ms)| {
+                            Frame::Array(vec![
+                                Frame::Integer(timestamp as i64),
+                                Frame::Integer(ms as i64),
+                            ])
+                        })
+                        .collect(),
+                )
+            }
+            Command::LatencyReset { events } => {
+                let reset = metrics.latency().reset(events);
+                Frame::Integer(reset as i64)
+            }
+            Command::ClientId => Frame::Integer(ctx.client_id as i64),
+            Command::AclWhoAmI => Frame::Bulk(Bytes::from(ctx.user.to_string())),
+            Command::AclCat { category: None } => Frame::Array(
+                table::CATEGORIES
+                    .iter()
+                    .map(|(name, _)| Frame::Bulk(Bytes::from_static(name.as_bytes())))
+                    .collect(),
+            ),
+            Command::AclCat {
+                category: Some(name),
+            } => match table::category(name) {
+                Some(bit) => Frame::Array(
+                    table::COMMAND_TABLE
+                        .iter()
+                        .filter(|info| info.categories() & bit != 0)
+                        .map(|info| Frame::Bulk(Bytes::from_static(info.name.as_bytes())))
+                        .collect(),
+                ),
+                None => Frame::error(format!("ERR Unknown category '{}'", name)),
+            },
+            Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch { .. }
+            | Command::Unwatch
+            | Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::SSubscribe { .. }
+            | Command::SUnsubscribe { .. }
+            | Command::Select { .. }
+            | Command::ClientSetName { .. }
+            | Command::ClientGetName
+            | Command::ClientInfo
+            | Command::ClientReply { .. }
+            | Command::Quit
+            | Command::Reset
+            | Command::Monitor
+            | Command::Auth { .. }
+            | Command::AclSetUser { .. }
+            | Command::AclUsers
+            | Command::ConfigGet { .. }
+            | Command::ConfigSet { .. }
+            | Command::ConfigRewrite
+            | Command::BgRewriteAof
+            | Command::Save
+            | Command::WaitAof { .. }
+            | Command::Idempotent { .. } => Frame::error(format!(
+                "ERR '{}' must be handled by the connection",
+                self.name().to_lowercase()
+            )),
+            Command::Unknown(cmd) => Frame::error(format!("ERR unknown command '{}'", cmd)),
+        }
+    }
+
+    /// Whether this is a write command naming a zero-length key, refused
+    /// when `reject-empty-keys` is on
+    ///
+    /// `frame` is the frame the command was parsed from; the keys are found
+    /// through the command table.
+    pub fn writes_empty_key(&self, frame: &Frame) -> bool {
+        if let Command::Idempotent { frame, command, .. } = self {
+            return command.writes_empty_key(frame);
+        }
+        let Frame::Array(args) = frame else {
+            return false;
+        };
+        let info = match args.first() {
+            Some(Frame::Bulk(name)) => std::str::from_utf8(name).ok().and_then(table::lookup),
+            Some(Frame::Simple(name)) => table::lookup(name),
+            _ => None,
+        };
+        match info {
+            Some(info) if self.is_write_command() => info.get_keys(args).is_ok_and(|keys| {
+                keys.iter().any(|key| match key {
+                    Frame::Bulk(key) => key.is_empty(),
+                    Frame::Simple(key) => key.is_empty(),
+                    _ => false,
+                })
+            }),
+            _ => false,
         }
-        Ok(())
     }
 
     /// Check if this command modifies data (for AOF logging)
     pub fn is_write_command(&self) -> bool {
+        if let Command::Idempotent { command, .. } = self {
+            return command.is_write_command();
+        }
+
+        #[cfg(feature = "json")]
+        if matches!(self, Command::JsonSet { .. }) {
+            return true;
+        }
+
         matches!(
             self,
             Command::Set { .. }
+                | Command::SetEx { .. }
+                | Command::GetEx { .. }
+                | Command::MSet { .. }
+                | Command::Expire { .. }
+                | Command::ExpireAt { .. }
+                | Command::Append { .. }
+                | Command::SetRange { .. }
+                | Command::SetBit { .. }
+                | Command::Incr { .. }
+                | Command::Decr { .. }
+                | Command::IncrBy { .. }
+                | Command::DecrBy { .. }
+                | Command::IncrByFloat { .. }
                 | Command::Del { .. }
+                | Command::Copy { .. }
+                | Command::Unlink { .. }
                 | Command::FlushDb
                 | Command::LPush { .. }
                 | Command::RPush { .. }
+                | Command::LInsert { .. }
                 | Command::LPop { .. }
+                | Command::BPop { .. }
                 | Command::RPop { .. }
                 | Command::SAdd { .. }
                 | Command::SRem { .. }
+                | Command::SPop { .. }
                 | Command::HSet { .. }
                 | Command::HDel { .. }
+                | Command::ZAdd { .. }
+                | Command::ZRem { .. }
+                | Command::XAdd { .. }
         )
     }
 
+    /// Whether this write may need more memory, so is refused while
+    /// `maxmemory` is exceeded (Redis' `denyoom` flag)
+    pub fn denied_on_oom(&self) -> bool {
+        match self {
+            Command::Idempotent { command, .. } => command.denied_on_oom(),
+            Command::Del { .. }
+            | Command::Unlink { .. }
+            | Command::FlushDb
+            | Command::LPop { .. }
+            | Command::BPop { .. }
+            | Command::RPop { .. }
+            | Command::SRem { .. }
+            | Command::SPop { .. }
+            | Command::HDel { .. }
+            | Command::ZRem { .. } => false,
+            _ => self.is_write_command(),
+        }
+    }
+
+    /// Whether applying the command, which produced `reply`, changed any
+    /// data
+    ///
+    /// Write commands that turned out to be no-ops (DEL of a missing key,
+    /// SREM of an absent member, EXPIRE of a missing key, a failed command)
+    /// are not logged to the AOF, so this is decided from the reply.
+    pub fn changed_data(&self, reply: &Frame) -> bool {
+        if !self.is_write_command() || matches!(reply, Frame::Error(_)) {
+            return false;
+        }
+
+        match self {
+            Command::Idempotent { command, .. } => command.changed_data(reply),
+            // Replies 0 (or -1 for LINSERT without the pivot) when nothing
+            // changed. HSET and ZADD are not here since updating a field or
+            // a score replies 0.
+            Command::Del { .. }
+            | Command::Unlink { .. }
+            | Command::SAdd { .. }
+            | Command::SRem { .. }
+            | Command::HDel { .. }
+            | Command::ZRem { .. }
+            | Command::Expire { .. }
+            | Command::ExpireAt { .. }
+            | Command::Copy { .. }
+            | Command::LInsert { .. } => matches!(reply, Frame::Integer(n) if *n > 0),
+            Command::Set { .. }
+            | Command::LPop { .. }
+            | Command::RPop { .. }
+            | Command::BPop { .. } => !matches!(reply, Frame::Null),
+            Command::GetEx {
+                expires_at,
+                persist,
+                ..
+            } => (expires_at.is_some() || *persist) && !matches!(reply, Frame::Null),
+            Command::SPop { .. } => match reply {
+                Frame::Array(members) => !members.is_empty(),
+                reply => !matches!(reply, Frame::Null),
+            },
+            _ => true,
+        }
+    }
+
+    /// Keys changed by a write command that produced `reply`, for the change
+    /// observer; `None` stands for the whole database (FLUSHDB)
+    ///
+    /// DEL and UNLINK report every key they were given, including ones
+    /// that did not exist, since the reply only counts them.
+    fn changed_keys(&self, reply: &Frame) -> Vec<Option<Bytes>> {
+        match self {
+            Command::Idempotent { command, .. } => command.changed_keys(reply),
+            Command::Set { key, .. }
+            | Command::SetEx { key, .. }
+            | Command::GetEx { key, .. }
+            | Command::Expire { key, .. }
+            | Command::ExpireAt { key, .. }
+            | Command::Append { key, .. }
+            | Command::SetRange { key, .. }
+            | Command::SetBit { key, .. }
+            | Command::Incr { key }
+            | Command::Decr { key }
+            | Command::IncrBy { key, .. }
+            | Command::DecrBy { key, .. }
+            | Command::IncrByFloat { key, .. }
+            | Command::LPush { key, .. }
+            | Command::RPush { key, .. }
+            | Command::LInsert { key, .. }
+            | Command::LPop { key }
+            | Command::RPop { key }
+            | Command::SAdd { key, .. }
+            | Command::SRem { key, .. }
+            | Command::SPop { key, .. }
+            | Command::HSet { key, .. }
+            | Command::HDel { key, .. }
+            | Command::ZAdd { key, .. }
+            | Command::ZRem { key, .. }
+            | Command::XAdd { key, .. } => vec![Some(key.clone())],
+            #[cfg(feature = "json")]
+            Command::JsonSet { key, .. } => vec![Some(key.clone())],
+            Command::Copy { destination, .. } => vec![Some(destination.clone())],
+            Command::Del { keys } | Command::Unlink { keys } => {
+                keys.iter().cloned().map(Some).collect()
+            }
+            Command::MSet { pairs } => pairs.iter().map(|(key, _)| Some(key.clone())).collect(),
+            // Replies [key, value] with the list popped from
+            Command::BPop { .. } => match reply {
+                Frame::Array(items) => match items.first() {
+                    Some(Frame::Bulk(key)) => vec![Some(key.clone())],
+                    _ => Vec::new(),
+                },
+                _ => Vec::new(),
+            },
+            Command::FlushDb => vec![None],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Run INCR/DECR/INCRBY/DECRBY against the database
+    fn apply_incr(&self, db: &Db) -> Result<i64, &'static str> {
+        match self {
+            Command::Incr { key } => db.incr_by(key, 1),
+            Command::Decr { key } => db.incr_by(key, -1),
+            Command::IncrBy { key, delta } => db.incr_by(key, *delta),
+            Command::DecrBy { key, delta } => {
+                let delta = delta.checked_neg().ok_or("ERR decrement would overflow")?;
+                db.incr_by(key, delta)
+            }
+            _ => unreachable!("not an increment command"),
+        }
+    }
+
+    /// Frames to log to the AOF and send to replicas in place of the
+    /// received one, given the `reply` the command produced
+    ///
+    /// `None` logs the command as it was received. SETEX, PSETEX and SET
+    /// with an expiration carry a TTL relative to when they ran, so they are
+    /// logged as SET followed by PEXPIREAT: replaying them later restores
+    /// the original deadline instead of restarting the countdown. GETEX
+    /// setting an expiration, and the EXPIRE family, are logged as that
+    /// PEXPIREAT alone. SPOP picks
+    /// members at random, so it is logged as SREM of the members it popped.
+    /// `XADD *` is logged with the ID it generated.
+    pub fn propagate(&self, reply: &Frame) -> Option<Vec<Frame>> {
+        match self {
+            Command::XAdd {
+                key,
+                id: None,
+                fields,
+            } => {
+                let Frame::Bulk(id) = reply else {
+                    return None;
+                };
+                let mut xadd = vec![
+                    Frame::Bulk(Bytes::from_static(b"XADD")),
+                    Frame::Bulk(key.clone()),
+                    Frame::Bulk(id.clone()),
+                ];
+                for (field, value) in fields {
+                    xadd.push(Frame::Bulk(field.clone()));
+                    xadd.push(Frame::Bulk(value.clone()));
+                }
+                Some(vec![Frame::Array(xadd)])
+            }
+            Command::SPop { key, .. } => {
+                let members = match reply {
+                    Frame::Bulk(member) => vec![member.clone()],
+                    Frame::Array(members) => members
+                        .iter()
+                        .filter_map(|member| match member {
+                            Frame::Bulk(member) => Some(member.clone()),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                let mut srem = vec![
+                    Frame::Bulk(Bytes::from_static(b"SREM")),
+                    Frame::Bulk(key.clone()),
+                ];
+                srem.extend(members.into_iter().map(Frame::Bulk));
+                Some(vec![Frame::Array(srem)])
+            }
+            Command::SetEx {
+                key,
+                value,
+                expires_at,
+                ..
+            }
+            | Command::Set {
+                key,
+                value,
+                expires_at: Some(expires_at),
+                ..
+            } => Some(vec![
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from_static(b"SET")),
+                    Frame::Bulk(key.clone()),
+                    Frame::Bulk(value.clone()),
+                ]),
+                pexpireat_frame(key, *expires_at),
+            ]),
+            Command::GetEx {
+                key,
+                expires_at: Some(expires_at),
+                ..
+            }
+            | Command::Expire {
+                key, expires_at, ..
+            } => Some(vec![pexpireat_frame(key, *expires_at)]),
+            Command::ExpireAt { key, unix_ms, .. } => Some(vec![Frame::Array(vec![
+                Frame::Bulk(Bytes::from_static(b"PEXPIREAT")),
+                Frame::Bulk(key.clone()),
+                Frame::Bulk(Bytes::from((*unix_ms).max(0).to_string())),
+            ])]),
+            _ => None,
+        }
+    }
+
     /// Replay a command without sending a response (for AOF restore)
     pub fn replay(&self, db: &Db) -> Result<(), String> {
         match self {
@@ -1106,16 +3815,84 @@ impl Command {
                 key,
                 value,
                 expires_at,
+                nx,
+                xx,
+            } => {
+                let exists = (*nx || *xx).then_some(*xx);
+                db.set_string(key.clone(), value.clone(), *expires_at, exists);
+                Ok(())
+            }
+            Command::SetEx {
+                key,
+                value,
+                expires_at,
+                ..
+            } => {
+                db.write_string(key.clone(), value.clone(), Some(*expires_at));
+                Ok(())
+            }
+            Command::GetEx {
+                key,
+                expires_at,
+                persist,
             } => {
-                db.write_string(key.clone(), value.clone(), *expires_at);
+                db.getex(key, *expires_at, *persist);
                 Ok(())
             }
+            Command::MSet { pairs } => {
+                db.mset(pairs.clone());
+                Ok(())
+            }
+            Command::Expire {
+                key, expires_at, ..
+            } => {
+                db.expire_at(key, *expires_at);
+                Ok(())
+            }
+            Command::ExpireAt { key, unix_ms, .. } => {
+                db.expire_at(key, clock::unix_ms_to_instant((*unix_ms).max(0) as u64));
+                Ok(())
+            }
+            Command::Append { key, value } => db
+                .append(key, value.clone())
+                .map(|_| ())
+                .map_err(String::from),
+            Command::SetRange { key, offset, value } => db
+                .setrange(key, *offset, value.clone())
+                .map(|_| ())
+                .map_err(String::from),
+            Command::SetBit { key, offset, on } => db
+                .setbit(key, *offset, *on)
+                .map(|_| ())
+                .map_err(String::from),
+            Command::Incr { .. }
+            | Command::Decr { .. }
+            | Command::IncrBy { .. }
+            | Command::DecrBy { .. } => self.apply_incr(db).map(|_| ()).map_err(String::from),
+            Command::IncrByFloat { key, delta } => db
+                .incr_by_float(key, *delta)
+                .map(|_| ())
+                .map_err(String::from),
             Command::Del { keys } => {
                 for key in keys {
                     db.delete(key);
                 }
                 Ok(())
             }
+            Command::Unlink { keys } => {
+                for key in keys {
+                    db.unlink(key);
+                }
+                Ok(())
+            }
+            Command::Copy {
+                source,
+                destination,
+                replace,
+            } => {
+                db.copy(source, destination, *replace);
+                Ok(())
+            }
             Command::FlushDb => {
                 db.flushdb();
                 Ok(())
@@ -1136,22 +3913,57 @@ impl Command {
                 db.rpop(key);
                 Ok(())
             }
-            Command::SAdd { key, members } => {
-                db.sadd(key.clone(), members.clone());
+            Command::BPop { keys, left, .. } => {
+                db.pop_first(keys, *left);
                 Ok(())
             }
-            Command::SRem { key, members } => {
-                db.srem(key, members.clone());
+            Command::LInsert {
+                key,
+                before,
+                pivot,
+                value,
+            } => db
+                .linsert(key, *before, pivot, value.clone())
+                .map(|_| ())
+                .map_err(String::from),
+            Command::SAdd { key, members } => {
+                db.sadd(key.clone(), members.clone());
                 Ok(())
             }
+            Command::SRem { key, members } => db
+                .srem(key, members.clone())
+                .map(|_| ())
+                .map_err(String::from),
+            Command::SPop { key, count } => db
+                .spop(key, count.unwrap_or(1))
+                .map(|_| ())
+                .map_err(String::from),
             Command::HSet { key, field, value } => {
                 db.hset(key.clone(), field.clone(), value.clone());
                 Ok(())
             }
-            Command::HDel { key, fields } => {
-                db.hdel(key, fields.clone());
-                Ok(())
-            }
+            #[cfg(feature = "json")]
+            Command::JsonSet { key, path, value } => db
+                .json_set(key.clone(), path, value.clone())
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Command::HDel { key, fields } => db
+                .hdel(key, fields.clone())
+                .map(|_| ())
+                .map_err(String::from),
+            Command::ZAdd { key, members } => db
+                .zadd(key.clone(), members.clone())
+                .map(|_| ())
+                .map_err(String::from),
+            Command::ZRem { key, members } => db
+                .zrem(key, members.clone())
+                .map(|_| ())
+                .map_err(String::from),
+            Command::XAdd { key, id, fields } => db
+                .xadd(key.clone(), *id, fields.clone())
+                .map(|_| ())
+                .map_err(String::from),
+            Command::Idempotent { command, .. } => command.replay(db),
             _ => Ok(()), // Read-only commands don't need replay
         }
     }
@@ -0,0 +1,735 @@
+use crate::persistence::LOADING_ERR;
+
+/// Most arguments, including the name, a variadic command accepts
+pub const MAX_VARIADIC_ARGS: usize = 1024 * 1024;
+
+/// Flag: the command changes data, so it is refused while loading
+pub const WRITE: u8 = 1 << 0;
+
+/// Flag: the command is allowed while the connection is subscribed
+pub const PUBSUB: u8 = 1 << 1;
+
+/// Flag: the command is refused inside MULTI
+pub const NO_MULTI: u8 = 1 << 2;
+
+/// Flag names, as listed by `COMMAND INFO`
+pub static FLAGS: &[(&str, u8)] = &[("write", WRITE), ("pubsub", PUBSUB), ("no-multi", NO_MULTI)];
+
+/// ACL categories a command belongs to, see `CATEGORIES`
+///
+/// `@write` follows from the `WRITE` flag and `@slow` from the absence of
+/// `@fast`, so neither is tagged by hand; see `CommandInfo::categories`.
+pub const CAT_KEYSPACE: u32 = 1 << 0;
+pub const CAT_READ: u32 = 1 << 1;
+pub const CAT_WRITE: u32 = 1 << 2;
+pub const CAT_SET: u32 = 1 << 3;
+pub const CAT_SORTEDSET: u32 = 1 << 4;
+pub const CAT_LIST: u32 = 1 << 5;
+pub const CAT_HASH: u32 = 1 << 6;
+pub const CAT_STRING: u32 = 1 << 7;
+pub const CAT_BITMAP: u32 = 1 << 8;
+pub const CAT_JSON: u32 = 1 << 9;
+pub const CAT_ADMIN: u32 = 1 << 10;
+pub const CAT_FAST: u32 = 1 << 11;
+pub const CAT_SLOW: u32 = 1 << 12;
+pub const CAT_PUBSUB: u32 = 1 << 13;
+pub const CAT_BLOCKING: u32 = 1 << 14;
+pub const CAT_CONNECTION: u32 = 1 << 15;
+pub const CAT_TRANSACTION: u32 = 1 << 16;
+pub const CAT_DANGEROUS: u32 = 1 << 17;
+pub const CAT_STREAM: u32 = 1 << 18;
+
+/// ACL category names, as listed by `ACL CAT`
+pub static CATEGORIES: &[(&str, u32)] = &[
+    ("keyspace", CAT_KEYSPACE),
+    ("read", CAT_READ),
+    ("write", CAT_WRITE),
+    ("set", CAT_SET),
+    ("sortedset", CAT_SORTEDSET),
+    ("list", CAT_LIST),
+    ("hash", CAT_HASH),
+    ("string", CAT_STRING),
+    ("bitmap", CAT_BITMAP),
+    ("json", CAT_JSON),
+    ("admin", CAT_ADMIN),
+    ("fast", CAT_FAST),
+    ("slow", CAT_SLOW),
+    ("pubsub", CAT_PUBSUB),
+    ("blocking", CAT_BLOCKING),
+    ("connection", CAT_CONNECTION),
+    ("transaction", CAT_TRANSACTION),
+    ("dangerous", CAT_DANGEROUS),
+    ("stream", CAT_STREAM),
+];
+
+/// The category called `name` (case-insensitive, without the `@`)
+pub fn category(name: &str) -> Option<u32> {
+    CATEGORIES
+        .iter()
+        .find(|(category, _)| category.eq_ignore_ascii_case(name))
+        .map(|&(_, bit)| bit)
+}
+
+/// Number of arguments, including the name, a command accepts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arity {
+    Exact(usize),
+
+    AtLeast(usize),
+
+    /// `min`, then any number of whole groups of `step` (MSET's key value
+    /// pairs)
+    Groups {
+        min: usize,
+        step: usize,
+    },
+}
+
+impl Arity {
+    /// From the Redis convention, where a negative arity means "at least"
+    pub const fn from_redis(arity: i32) -> Arity {
+        if arity >= 0 {
+            Arity::Exact(arity as usize)
+        } else {
+            Arity::AtLeast(arity.unsigned_abs() as usize)
+        }
+    }
+
+    /// In the Redis convention, as reported by `COMMAND INFO`
+    pub const fn to_redis(self) -> i32 {
+        match self {
+            Arity::Exact(n) => n as i32,
+            Arity::AtLeast(min) | Arity::Groups { min, .. } => -(min as i32),
+        }
+    }
+
+    /// Whether `argc` arguments (including the name) satisfy the arity
+    pub fn accepts(self, argc: usize) -> bool {
+        match self {
+            Arity::Exact(n) => argc == n,
+            Arity::AtLeast(min) => argc >= min,
+            Arity::Groups { min, step } => argc >= min && (argc - min).is_multiple_of(step),
+        }
+    }
+}
+
+/// State of a connection that limits which commands it may run, see
+/// `CommandInfo::refusal`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandContext {
+    Normal,
+
+    /// Subscribed to at least one channel (RESP2)
+    Subscribe,
+
+    /// Queueing commands between MULTI and EXEC
+    Multi,
+
+    /// The AOF is still being replayed
+    Loading,
+}
+
+/// Static metadata for one command
+///
+/// Mirrors the fields Redis reports from `COMMAND INFO`: the arity and the
+/// positions of the key arguments, counted from the command name at 0.
+#[derive(Debug)]
+pub struct CommandInfo {
+    /// Lowercase command name
+    pub name: &'static str,
+
+    pub arity: Arity,
+
+    /// Index of the first key argument, 0 if the command takes no keys
+    pub first_key: usize,
+
+    /// Index of the last key argument; negative counts from the end
+    pub last_key: i32,
+
+    /// Distance between consecutive key arguments
+    pub step: usize,
+
+    /// Most arguments accepted, including the name; more are refused
+    /// before the command is parsed
+    pub max_args: usize,
+
+    /// `WRITE`, `PUBSUB` and `NO_MULTI` combined
+    pub flags: u8,
+
+    /// `CAT_*` categories tagged by hand, see `categories`
+    acl_categories: u32,
+}
+
+const fn info(
+    name: &'static str,
+    arity: i32,
+    first_key: usize,
+    last_key: i32,
+    step: usize,
+) -> CommandInfo {
+    CommandInfo {
+        name,
+        arity: Arity::from_redis(arity),
+        first_key,
+        last_key,
+        step,
+        max_args: if arity >= 0 {
+            arity as usize
+        } else {
+            MAX_VARIADIC_ARGS
+        },
+        flags: 0,
+        acl_categories: 0,
+    }
+}
+
+impl CommandInfo {
+    /// Lower the argument limit of a command whose negative arity only
+    /// stands for optional arguments
+    const fn with_max_args(self, max_args: usize) -> CommandInfo {
+        CommandInfo { max_args, ..self }
+    }
+
+    /// Accept the arguments past the minimum only in groups of `step`
+    ///
+    /// `max_args` is rounded down to the last whole group.
+    const fn with_arg_groups(self, step: usize) -> CommandInfo {
+        let min = match self.arity {
+            Arity::AtLeast(min) => min,
+            _ => panic!("argument groups need a variadic arity"),
+        };
+        CommandInfo {
+            arity: Arity::Groups { min, step },
+            max_args: self.max_args - (self.max_args - min) % step,
+            ..self
+        }
+    }
+
+    const fn with_flags(self, flags: u8) -> CommandInfo {
+        CommandInfo {
+            flags: self.flags | flags,
+            ..self
+        }
+    }
+
+    const fn with_categories(self, categories: u32) -> CommandInfo {
+        CommandInfo {
+            acl_categories: self.acl_categories | categories,
+            ..self
+        }
+    }
+}
+
+/// Every command understood by `Command::from_frame`
+pub static COMMAND_TABLE: &[CommandInfo] = &[
+    info("ping", -1, 0, 0, 0)
+        .with_max_args(2)
+        .with_flags(PUBSUB)
+        .with_categories(CAT_FAST | CAT_CONNECTION),
+    info("echo", 2, 0, 0, 0).with_categories(CAT_FAST | CAT_CONNECTION),
+    info("set", -3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING),
+    info("setex", 4, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING),
+    info("psetex", 4, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING),
+    info("mset", -3, 1, -1, 2)
+        .with_arg_groups(2)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING),
+    info("append", 3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING | CAT_FAST),
+    info("setrange", 4, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING),
+    info("getrange", 4, 1, 1, 1).with_categories(CAT_READ | CAT_STRING),
+    info("lcs", -3, 1, 2, 1).with_categories(CAT_READ | CAT_STRING),
+    info("setbit", 4, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_BITMAP),
+    info("getbit", 3, 1, 1, 1).with_categories(CAT_READ | CAT_BITMAP | CAT_FAST),
+    info("incr", 2, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING | CAT_FAST),
+    info("decr", 2, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING | CAT_FAST),
+    info("incrby", 3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING | CAT_FAST),
+    info("decrby", 3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING | CAT_FAST),
+    info("incrbyfloat", 3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING | CAT_FAST),
+    info("get", 2, 1, 1, 1).with_categories(CAT_READ | CAT_STRING | CAT_FAST),
+    info("getex", -2, 1, 1, 1)
+        .with_max_args(4)
+        .with_flags(WRITE)
+        .with_categories(CAT_STRING | CAT_FAST),
+    info("expire", 3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_KEYSPACE | CAT_FAST),
+    info("pexpire", 3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_KEYSPACE | CAT_FAST),
+    info("expireat", 3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_KEYSPACE | CAT_FAST),
+    info("pexpireat", 3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_KEYSPACE | CAT_FAST),
+    info("ttl", 2, 1, 1, 1).with_categories(CAT_READ | CAT_KEYSPACE | CAT_FAST),
+    info("pttl", 2, 1, 1, 1).with_categories(CAT_READ | CAT_KEYSPACE | CAT_FAST),
+    info("expiretime", 2, 1, 1, 1).with_categories(CAT_READ | CAT_KEYSPACE | CAT_FAST),
+    info("pexpiretime", 2, 1, 1, 1).with_categories(CAT_READ | CAT_KEYSPACE | CAT_FAST),
+    info("del", -2, 1, -1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_KEYSPACE),
+    info("unlink", -2, 1, -1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_KEYSPACE | CAT_FAST),
+    info("exists", 2, 1, 1, 1).with_categories(CAT_READ | CAT_KEYSPACE | CAT_FAST),
+    info("copy", -3, 1, 2, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_KEYSPACE),
+    info("type", 2, 1, 1, 1).with_categories(CAT_READ | CAT_KEYSPACE | CAT_FAST),
+    info("dbsize", 1, 0, 0, 0).with_categories(CAT_READ | CAT_KEYSPACE | CAT_FAST),
+    info("flushdb", 1, 0, 0, 0)
+        .with_flags(WRITE)
+        .with_categories(CAT_KEYSPACE | CAT_DANGEROUS),
+    info("select", 2, 0, 0, 0)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_CONNECTION | CAT_FAST),
+    info("keys", 2, 0, 0, 0).with_categories(CAT_READ | CAT_KEYSPACE | CAT_DANGEROUS),
+    info("countkeys", 2, 0, 0, 0).with_categories(CAT_READ | CAT_KEYSPACE | CAT_DANGEROUS),
+    info("scan", -2, 0, 0, 0).with_categories(CAT_READ | CAT_KEYSPACE),
+    info("lpush", -3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_LIST | CAT_FAST),
+    info("rpush", -3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_LIST | CAT_FAST),
+    info("lpop", 2, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_LIST | CAT_FAST),
+    info("rpop", 2, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_LIST | CAT_FAST),
+    info("blpop", -3, 1, -2, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_LIST | CAT_BLOCKING),
+    info("brpop", -3, 1, -2, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_LIST | CAT_BLOCKING),
+    info("lrange", 4, 1, 1, 1).with_categories(CAT_READ | CAT_LIST),
+    info("llen", 2, 1, 1, 1).with_categories(CAT_READ | CAT_LIST | CAT_FAST),
+    info("linsert", 5, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_LIST),
+    info("sadd", -3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_SET | CAT_FAST),
+    info("srem", -3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_SET | CAT_FAST),
+    info("spop", -2, 1, 1, 1)
+        .with_max_args(3)
+        .with_flags(WRITE)
+        .with_categories(CAT_SET | CAT_FAST),
+    info("srandmember", -2, 1, 1, 1)
+        .with_max_args(3)
+        .with_categories(CAT_READ | CAT_SET),
+    info("smembers", 2, 1, 1, 1).with_categories(CAT_READ | CAT_SET),
+    info("sismember", 3, 1, 1, 1).with_categories(CAT_READ | CAT_SET | CAT_FAST),
+    info("scard", 2, 1, 1, 1).with_categories(CAT_READ | CAT_SET | CAT_FAST),
+    info("hset", 4, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_HASH | CAT_FAST),
+    info("hget", 3, 1, 1, 1).with_categories(CAT_READ | CAT_HASH | CAT_FAST),
+    info("hgetall", 2, 1, 1, 1).with_categories(CAT_READ | CAT_HASH),
+    info("hdel", -3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_HASH | CAT_FAST),
+    info("hexists", 3, 1, 1, 1).with_categories(CAT_READ | CAT_HASH | CAT_FAST),
+    info("hlen", 2, 1, 1, 1).with_categories(CAT_READ | CAT_HASH | CAT_FAST),
+    info("zadd", -4, 1, 1, 1)
+        .with_arg_groups(2)
+        .with_flags(WRITE)
+        .with_categories(CAT_SORTEDSET | CAT_FAST),
+    info("zrem", -3, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_SORTEDSET | CAT_FAST),
+    info("zscore", 3, 1, 1, 1).with_categories(CAT_READ | CAT_SORTEDSET | CAT_FAST),
+    info("zcard", 2, 1, 1, 1).with_categories(CAT_READ | CAT_SORTEDSET | CAT_FAST),
+    info("zrange", -4, 1, 1, 1)
+        .with_max_args(5)
+        .with_categories(CAT_READ | CAT_SORTEDSET),
+    info("zrangebylex", -4, 1, 1, 1)
+        .with_max_args(7)
+        .with_categories(CAT_READ | CAT_SORTEDSET),
+    info("zlexcount", 4, 1, 1, 1).with_categories(CAT_READ | CAT_SORTEDSET | CAT_FAST),
+    info("xadd", -5, 1, 1, 1)
+        .with_arg_groups(2)
+        .with_flags(WRITE)
+        .with_categories(CAT_STREAM | CAT_FAST),
+    info("xlen", 2, 1, 1, 1).with_categories(CAT_READ | CAT_STREAM | CAT_FAST),
+    info("xrange", -4, 1, 1, 1)
+        .with_max_args(6)
+        .with_categories(CAT_READ | CAT_STREAM),
+    // Keys follow STREAMS, so their positions are not fixed
+    info("xread", -4, 0, 0, 0).with_categories(CAT_READ | CAT_STREAM),
+    #[cfg(feature = "json")]
+    info("json.set", 4, 1, 1, 1)
+        .with_flags(WRITE)
+        .with_categories(CAT_JSON),
+    #[cfg(feature = "json")]
+    info("json.get", -2, 1, 1, 1)
+        .with_max_args(3)
+        .with_categories(CAT_READ | CAT_JSON),
+    info("publish", 3, 0, 0, 0).with_categories(CAT_PUBSUB | CAT_FAST),
+    info("subscribe", -2, 0, 0, 0)
+        .with_flags(NO_MULTI | PUBSUB)
+        .with_categories(CAT_PUBSUB),
+    info("unsubscribe", -1, 0, 0, 0)
+        .with_flags(NO_MULTI | PUBSUB)
+        .with_categories(CAT_PUBSUB),
+    info("spublish", 3, 0, 0, 0).with_categories(CAT_PUBSUB | CAT_FAST),
+    info("ssubscribe", -2, 0, 0, 0)
+        .with_flags(NO_MULTI | PUBSUB)
+        .with_categories(CAT_PUBSUB),
+    info("sunsubscribe", -1, 0, 0, 0)
+        .with_flags(NO_MULTI | PUBSUB)
+        .with_categories(CAT_PUBSUB),
+    info("pubsub", -2, 0, 0, 0).with_categories(CAT_PUBSUB),
+    info("stats", -1, 0, 0, 0).with_categories(CAT_DANGEROUS),
+    info("info", -1, 0, 0, 0).with_categories(CAT_DANGEROUS),
+    info("cmdstat", -1, 0, 0, 0).with_categories(CAT_DANGEROUS),
+    info("cmdstats", -1, 0, 0, 0).with_categories(CAT_DANGEROUS),
+    info("role", 1, 0, 0, 0).with_categories(CAT_ADMIN | CAT_FAST | CAT_DANGEROUS),
+    info("failover", -1, 0, 0, 0).with_categories(CAT_ADMIN | CAT_DANGEROUS),
+    info("memory", -2, 0, 0, 0)
+        .with_max_args(2)
+        .with_categories(CAT_READ),
+    info("object", -2, 2, 2, 1).with_categories(CAT_READ | CAT_KEYSPACE),
+    info("debug", -2, 0, 0, 0).with_categories(CAT_ADMIN | CAT_DANGEROUS),
+    info("cluster", 2, 0, 0, 0),
+    info("client", -2, 0, 0, 0)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_CONNECTION),
+    info("config", -2, 0, 0, 0)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_ADMIN | CAT_DANGEROUS),
+    info("bgrewriteaof", 1, 0, 0, 0)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_ADMIN | CAT_DANGEROUS),
+    info("save", 1, 0, 0, 0)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_ADMIN | CAT_DANGEROUS),
+    info("lastsave", 1, 0, 0, 0).with_categories(CAT_ADMIN | CAT_FAST | CAT_DANGEROUS),
+    info("waitaof", 4, 0, 0, 0)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_CONNECTION),
+    info("latency", -2, 0, 0, 0).with_categories(CAT_ADMIN | CAT_DANGEROUS),
+    info("command", -1, 0, 0, 0).with_categories(CAT_CONNECTION),
+    info("multi", 1, 0, 0, 0).with_categories(CAT_TRANSACTION | CAT_FAST),
+    info("exec", 1, 0, 0, 0).with_categories(CAT_TRANSACTION),
+    info("discard", 1, 0, 0, 0).with_categories(CAT_TRANSACTION | CAT_FAST),
+    info("watch", -2, 1, -1, 1)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_TRANSACTION | CAT_FAST),
+    info("unwatch", 1, 0, 0, 0).with_categories(CAT_TRANSACTION | CAT_FAST),
+    info("idempotent", -3, 0, 0, 0)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_TRANSACTION),
+    info("quit", -1, 0, 0, 0)
+        .with_flags(PUBSUB)
+        .with_categories(CAT_CONNECTION | CAT_FAST),
+    info("auth", -2, 0, 0, 0)
+        .with_max_args(3)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_CONNECTION | CAT_FAST),
+    info("acl", -2, 0, 0, 0)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_ADMIN | CAT_DANGEROUS),
+    info("reset", 1, 0, 0, 0)
+        .with_flags(PUBSUB)
+        .with_categories(CAT_CONNECTION | CAT_FAST),
+    info("monitor", 1, 0, 0, 0)
+        .with_flags(NO_MULTI)
+        .with_categories(CAT_ADMIN | CAT_DANGEROUS),
+];
+
+/// Legacy names accepted in place of a command, as `(alias, command)`
+///
+/// An alias is parsed as the command it stands for and reported under
+/// that command's name, so it needs no entry of its own in the table.
+pub static COMMAND_ALIASES: &[(&str, &str)] = &[("substr", "getrange")];
+
+/// The command `name` is an alias for (case-insensitive)
+pub fn resolve_alias(name: &str) -> Option<&'static str> {
+    COMMAND_ALIASES
+        .iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+        .map(|(_, command)| *command)
+}
+
+/// Find a command by name or alias (case-insensitive)
+pub fn lookup(name: &str) -> Option<&'static CommandInfo> {
+    let name = resolve_alias(name).unwrap_or(name);
+    COMMAND_TABLE
+        .iter()
+        .find(|info| info.name.eq_ignore_ascii_case(name))
+}
+
+impl CommandInfo {
+    /// Whether `argc` arguments (including the name) satisfy the arity
+    pub fn accepts(&self, argc: usize) -> bool {
+        self.arity.accepts(argc)
+    }
+
+    /// The error to refuse the command with when sent with `argc`
+    /// arguments (including the name), checked before it is parsed
+    pub fn check_args(&self, argc: usize) -> Result<(), String> {
+        // Past any sane count nothing else is looked at
+        if argc > MAX_VARIADIC_ARGS {
+            return Err("ERR Too many arguments".to_string());
+        }
+        if argc > self.max_args || !self.accepts(argc) {
+            return Err(format!(
+                "ERR wrong number of arguments for '{}' command",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    /// Every ACL category of the command, as `CAT_*` bits
+    pub fn categories(&self) -> u32 {
+        let mut categories = self.acl_categories;
+        if self.flags & WRITE != 0 {
+            categories |= CAT_WRITE;
+        }
+        if categories & CAT_FAST == 0 {
+            categories |= CAT_SLOW;
+        }
+        categories
+    }
+
+    /// The error to refuse the command with in `context`, `None` if it
+    /// may run there
+    pub fn refusal(&self, context: CommandContext) -> Option<String> {
+        match context {
+            CommandContext::Subscribe if self.flags & PUBSUB == 0 => Some(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                self.name
+            )),
+            CommandContext::Multi if self.flags & NO_MULTI != 0 => Some(format!(
+                "ERR {} inside MULTI is not allowed",
+                self.name.to_uppercase()
+            )),
+            CommandContext::Loading if self.flags & WRITE != 0 => Some(LOADING_ERR.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Extract the key arguments from a full command (name at index 0)
+    pub fn get_keys<'a, T>(&self, args: &'a [T]) -> Result<Vec<&'a T>, &'static str> {
+        if self.first_key == 0 {
+            return Err("ERR The command has no key arguments");
+        }
+        if !self.accepts(args.len()) {
+            return Err("ERR Invalid arguments specified for command");
+        }
+
+        let last = if self.last_key < 0 {
+            args.len() as i64 + self.last_key as i64
+        } else {
+            self.last_key as i64
+        };
+        if last < self.first_key as i64 || last as usize >= args.len() {
+            return Err("ERR Invalid arguments specified for command");
+        }
+        // Every key needs its whole group, e.g. no MSET key without a value
+        if self.step == 0 || !(last as usize - self.first_key + 1).is_multiple_of(self.step) {
+            return Err("ERR Invalid arguments specified for command");
+        }
+
+        Ok(args[self.first_key..=last as usize]
+            .iter()
+            .step_by(self.step)
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn get_keys(list: &[&str]) -> Result<Vec<String>, &'static str> {
+        let args = args(list);
+        let info = lookup(&args[0]).expect("unknown command");
+        info.get_keys(&args)
+            .map(|keys| keys.into_iter().cloned().collect())
+    }
+
+    #[test]
+    fn test_get_keys() {
+        assert_eq!(
+            get_keys(&["set", "foo", "bar"]),
+            Ok(vec!["foo".to_string()])
+        );
+        assert_eq!(
+            get_keys(&["MSET", "a", "1", "b", "2"]),
+            Ok(vec!["a".to_string(), "b".to_string()])
+        );
+        assert_eq!(
+            get_keys(&["del", "x", "y", "z"]),
+            Ok(vec!["x".to_string(), "y".to_string(), "z".to_string()])
+        );
+        assert_eq!(
+            get_keys(&["object", "encoding", "k"]),
+            Ok(vec!["k".to_string()])
+        );
+
+        let invalid = "ERR Invalid arguments specified for command";
+        assert_eq!(get_keys(&["mset", "a", "1", "b"]), Err(invalid));
+        // Checked even when the arity alone lets the count through
+        let loose_mset = info("mset", -3, 1, -1, 2);
+        assert_eq!(
+            loose_mset.get_keys(&args(&["mset", "a", "1", "b"])),
+            Err(invalid)
+        );
+        assert_eq!(
+            loose_mset.get_keys(&args(&["mset", "a", "1", "b", "2"])),
+            Ok(vec![&"a".to_string(), &"b".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_max_args_allows_the_arity() {
+        for info in COMMAND_TABLE {
+            assert!(
+                info.accepts(info.max_args),
+                "{} refuses its own maximum",
+                info.name
+            );
+        }
+        assert_eq!(lookup("get").unwrap().max_args, 2);
+        assert_eq!(lookup("mset").unwrap().max_args, MAX_VARIADIC_ARGS - 1);
+    }
+
+    #[test]
+    fn test_arity() {
+        assert_eq!(Arity::from_redis(2), Arity::Exact(2));
+        assert_eq!(Arity::from_redis(-3), Arity::AtLeast(3));
+        for (name, redis) in [("get", 2), ("del", -2), ("mset", -3), ("zadd", -4)] {
+            assert_eq!(lookup(name).unwrap().arity.to_redis(), redis, "{}", name);
+        }
+
+        let mset = lookup("mset").unwrap().arity;
+        assert_eq!(mset, Arity::Groups { min: 3, step: 2 });
+        assert!(!mset.accepts(2));
+        assert!(mset.accepts(3));
+        assert!(!mset.accepts(4));
+        assert!(mset.accepts(5));
+    }
+
+    #[test]
+    fn test_aliases_resolve_to_commands() {
+        assert_eq!(lookup("SUBSTR").map(|info| info.name), Some("getrange"));
+        for (alias, command) in COMMAND_ALIASES {
+            assert!(lookup(command).is_some(), "{} has no command", alias);
+            assert!(
+                COMMAND_TABLE.iter().all(|info| info.name != *alias),
+                "{} is also a command",
+                alias
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_keys_errors() {
+        assert_eq!(
+            get_keys(&["ping"]),
+            Err("ERR The command has no key arguments")
+        );
+        assert_eq!(
+            get_keys(&["get"]),
+            Err("ERR Invalid arguments specified for command")
+        );
+        assert_eq!(
+            get_keys(&["get", "a", "b"]),
+            Err("ERR Invalid arguments specified for command")
+        );
+    }
+
+    #[test]
+    fn test_refusal_by_context() {
+        let get = lookup("get").unwrap();
+        assert_eq!(get.refusal(CommandContext::Normal), None);
+        assert_eq!(
+            get.refusal(CommandContext::Subscribe).as_deref(),
+            Some("ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context")
+        );
+        assert_eq!(get.refusal(CommandContext::Loading), None);
+        assert_eq!(
+            lookup("ping").unwrap().refusal(CommandContext::Subscribe),
+            None
+        );
+        assert_eq!(
+            lookup("set")
+                .unwrap()
+                .refusal(CommandContext::Loading)
+                .as_deref(),
+            Some(LOADING_ERR)
+        );
+        assert_eq!(
+            lookup("watch")
+                .unwrap()
+                .refusal(CommandContext::Multi)
+                .as_deref(),
+            Some("ERR WATCH inside MULTI is not allowed")
+        );
+    }
+
+    #[test]
+    fn test_write_flag_matches_commands() {
+        use crate::cmd::Command;
+        use crate::frame::Frame;
+
+        for args in [
+            &["set", "k", "v"][..],
+            &["mset", "k", "v"],
+            &["incr", "k"],
+            &["del", "k"],
+            &["lpush", "k", "v"],
+            &["blpop", "k", "0"],
+            &["hset", "k", "f", "v"],
+            &["zadd", "k", "1", "m"],
+            &["get", "k"],
+            &["lrange", "k", "0", "-1"],
+            &["zscore", "k", "m"],
+            &["publish", "c", "m"],
+        ] {
+            let frame = Frame::Array(args.iter().map(|arg| Frame::Bulk((*arg).into())).collect());
+            let command = Command::from_frame(frame).unwrap();
+            let info = lookup(args[0]).unwrap();
+            assert_eq!(
+                info.flags & WRITE != 0,
+                command.is_write_command(),
+                "{}",
+                args[0]
+            );
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use super::Command;
+use std::fmt;
+
+/// A parsed command whose arguments don't make sense together
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// Two options that cannot be given together, e.g. `SET k v NX XX`
+    ExclusiveOptions(&'static str, &'static str),
+
+    /// Any other check, with the exact Redis error message
+    Invalid(&'static str),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::ExclusiveOptions(a, b) => write!(
+                f,
+                "ERR {} and {} options at the same time are not compatible",
+                a, b
+            ),
+            CommandError::Invalid(message) => f.write_str(message),
+        }
+    }
+}
+
+impl From<CommandError> for String {
+    fn from(err: CommandError) -> String {
+        err.to_string()
+    }
+}
+
+impl Command {
+    /// Check the arguments of a parsed command without touching any `Db`
+    ///
+    /// Parsing only checks that each argument is well formed; whether they
+    /// fit together is decided here. `from_frame` runs it on every command,
+    /// tools can call it on commands they build themselves.
+    pub fn validate(&self) -> Result<(), CommandError> {
+        match self {
+            Command::Set {
+                nx: true, xx: true, ..
+            } => Err(CommandError::ExclusiveOptions("NX", "XX")),
+            Command::Lcs {
+                len: true,
+                idx: true,
+                ..
+            } => Err(CommandError::Invalid(
+                "ERR If you want both the length and indexes, please just use IDX.",
+            )),
+            Command::Idempotent { command, .. } => command.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::Frame;
+    use bytes::Bytes;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        let frame = Frame::Array(
+            args.iter()
+                .map(|arg| Frame::Bulk(Bytes::from(arg.to_string())))
+                .collect(),
+        );
+        Command::from_frame(frame)
+    }
+
+    #[test]
+    fn test_set_nx_xx_are_exclusive() {
+        let command = Command::Set {
+            key: Bytes::from("k"),
+            value: Bytes::from("v"),
+            expires_at: None,
+            nx: true,
+            xx: true,
+        };
+        assert_eq!(
+            command.validate(),
+            Err(CommandError::ExclusiveOptions("NX", "XX"))
+        );
+        assert_eq!(
+            parse(&["SET", "k", "v", "NX", "XX"]).err().unwrap(),
+            "ERR NX and XX options at the same time are not compatible"
+        );
+        assert!(parse(&["SET", "k", "v", "XX"]).unwrap().validate().is_ok());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::util::lock;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Counters of one command
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CommandCounters {
+    /// Times the command ran, failed or not
+    pub calls: u64,
+
+    /// Total time spent running it, in microseconds
+    pub usec: u64,
+
+    /// Times it was refused before running (ACL, OOM, MULTI context, ...)
+    pub rejected_calls: u64,
+
+    /// Times it ran and replied with an error
+    pub failed_calls: u64,
+}
+
+/// Per-command statistics behind `INFO commandstats`
+///
+/// Unlike the `CMDSTAT` telemetry, whose strategy may turn it off, these
+/// are always kept, and count refusals and errors as Redis does.
+#[derive(Debug, Default)]
+pub struct CommandStats {
+    commands: Mutex<HashMap<&'static str, CommandCounters>>,
+}
+
+impl CommandStats {
+    /// Count a run of `name` that took `usec` microseconds
+    pub fn record_call(&self, name: &'static str, usec: u64, failed: bool) {
+        let mut commands = lock(&self.commands);
+        let counters = commands.entry(name).or_default();
+        counters.calls += 1;
+        counters.usec += usec;
+        counters.failed_calls += failed as u64;
+    }
+
+    /// Count a refusal of `name` before it ran
+    pub fn record_rejected(&self, name: &'static str) {
+        lock(&self.commands).entry(name).or_default().rejected_calls += 1;
+    }
+
+    /// Counters of `name`, all 0 if it never ran
+    pub fn get(&self, name: &str) -> CommandCounters {
+        lock(&self.commands).get(name).copied().unwrap_or_default()
+    }
+
+    /// The `# Commandstats` section of INFO, one line per command seen
+    pub fn format_info(&self) -> String {
+        let mut commands: Vec<_> = lock(&self.commands)
+            .iter()
+            .map(|(name, counters)| (name.to_lowercase(), *counters))
+            .collect();
+        commands.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut info = String::from("# Commandstats\r\n");
+        for (name, counters) in commands {
+            let per_call = match counters.calls {
+                0 => 0.0,
+                calls => counters.usec as f64 / calls as f64,
+            };
+            info.push_str(&format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}\r\n",
+                name,
+                counters.calls,
+                counters.usec,
+                per_call,
+                counters.rejected_calls,
+                counters.failed_calls
+            ));
+        }
+        info
+    }
+}
@@ -0,0 +1,1169 @@
+use crate::command_metrics::MetricsStrategy;
+use crate::connection::{OutputBufferLimit, DEFAULT_PROTO_INLINE_MAX_SIZE};
+use crate::db::{
+    eviction_policy, DEFAULT_HASH_MAX_LISTPACK_ENTRIES, DEFAULT_LIST_MAX_LISTPACK_SIZE,
+    DEFAULT_MAXMEMORY_POLICY, DEFAULT_SET_MAX_INTSET_ENTRIES, DEFAULT_ZSET_MAX_LISTPACK_ENTRIES,
+};
+use crate::idempotency::{DEFAULT_IDEMPOTENCY_CACHE_SIZE, DEFAULT_IDEMPOTENCY_TTL_SECS};
+use crate::notify::NotifyFlags;
+use crate::ratelimit::{RateLimit, RateLimitMode};
+use crate::snapshot::{self, SavePoint};
+use crate::util::glob_match;
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::level_filters::LevelFilter;
+
+/// Default for `maxclients` (same as Redis)
+pub const DEFAULT_MAXCLIENTS: u64 = 10_000;
+
+/// Default for `databases` (same as Redis)
+pub const DEFAULT_DATABASES: usize = 16;
+
+/// Default for `tcp-backlog` (same as Redis)
+pub const DEFAULT_TCP_BACKLOG: u32 = 511;
+
+/// Reply to CONFIG REWRITE when no config file was loaded
+pub const NO_CONFIG_FILE_ERR: &str = "ERR The server is running without a config file";
+
+/// Configuration of a running server, changed by CONFIG SET
+pub type SharedConfig = Arc<RwLock<ServerConfig>>;
+
+/// How much the server logs, in Redis' `loglevel` terms
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogLevel {
+    Debug,
+    Verbose,
+    #[default]
+    Notice,
+    Warning,
+    Nothing,
+}
+
+impl LogLevel {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "debug" => Some(LogLevel::Debug),
+            "verbose" => Some(LogLevel::Verbose),
+            "notice" => Some(LogLevel::Notice),
+            "warning" => Some(LogLevel::Warning),
+            "nothing" => Some(LogLevel::Nothing),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LogLevel::Debug => "debug",
+            LogLevel::Verbose => "verbose",
+            LogLevel::Notice => "notice",
+            LogLevel::Warning => "warning",
+            LogLevel::Nothing => "nothing",
+        }
+    }
+
+    /// Most verbose `tracing` level that is logged
+    pub fn filter(&self) -> LevelFilter {
+        match self {
+            LogLevel::Debug => LevelFilter::TRACE,
+            LogLevel::Verbose => LevelFilter::DEBUG,
+            LogLevel::Notice => LevelFilter::INFO,
+            LogLevel::Warning => LevelFilter::WARN,
+            LogLevel::Nothing => LevelFilter::OFF,
+        }
+    }
+}
+
+/// Server configuration
+///
+/// Every setting can be overridden through a `RUSTREDIS_*` environment
+/// variable or a config file directive (see `DIRECTIVES`); anything not
+/// set falls back to the defaults below.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Skip AOF persistence entirely (RUSTREDIS_DISABLE_AOF)
+    pub disable_aof: bool,
+
+    /// Path of the append-only file (RUSTREDIS_AOF_PATH)
+    pub aof_path: String,
+
+    /// Refuse write commands with a MISCONF error while the AOF cannot be
+    /// written or fsynced (RUSTREDIS_AOF_STOP_WRITES_ON_ERROR, default on)
+    pub aof_stop_writes_on_error: bool,
+
+    /// Replay the AOF in the background at startup, serving reads of the
+    /// keys loaded so far and refusing writes with LOADING until it is done
+    /// (RUSTREDIS_AOF_BACKGROUND_LOAD)
+    pub aof_background_load: bool,
+
+    /// Path of the snapshot file (RUSTREDIS_RDB_PATH)
+    pub rdb_path: String,
+
+    /// `<seconds> <changes>` points that trigger a background snapshot,
+    /// none by default (RUSTREDIS_SAVE, e.g. "900 1 300 10")
+    pub save_points: Vec<SavePoint>,
+
+    /// Per-command metrics collection strategy (RUSTREDIS_METRICS_STRATEGY)
+    pub metrics_strategy: MetricsStrategy,
+
+    /// Free large values on a background thread when they are removed by
+    /// DEL (RUSTREDIS_LAZYFREE_LAZY_USER_DEL). UNLINK always does this.
+    pub lazyfree_lazy_user_del: bool,
+
+    /// Minimum duration in milliseconds recorded by the latency monitor,
+    /// 0 disables it (RUSTREDIS_LATENCY_MONITOR_THRESHOLD)
+    pub latency_monitor_threshold_ms: u64,
+
+    /// Hashes with more fields than this switch from the listpack to the
+    /// hashtable encoding (RUSTREDIS_HASH_MAX_LISTPACK_ENTRIES)
+    pub hash_max_listpack_entries: usize,
+
+    /// Lists with more elements than this switch from the listpack to the
+    /// quicklist encoding (RUSTREDIS_LIST_MAX_LISTPACK_SIZE)
+    pub list_max_listpack_size: usize,
+
+    /// Integer-only sets with more members than this switch from the intset
+    /// to the hashtable encoding (RUSTREDIS_SET_MAX_INTSET_ENTRIES)
+    pub set_max_intset_entries: usize,
+
+    /// Sorted sets with more members than this switch from the listpack to
+    /// the skiplist encoding (RUSTREDIS_ZSET_MAX_LISTPACK_ENTRIES)
+    pub zset_max_listpack_entries: usize,
+
+    /// Seconds until keys created without an expiry expire, 0 to keep them
+    /// forever (RUSTREDIS_DEFAULT_TTL_SECONDS)
+    pub default_ttl_seconds: u64,
+
+    /// Seconds a subscribed connection may stay silent before it is sent a
+    /// keepalive, 0 to never send one (RUSTREDIS_PUBSUB_KEEPALIVE_SECS)
+    pub pubsub_keepalive_secs: u64,
+
+    /// Periodically remove expired keys nobody accesses, one sweeper per
+    /// database (RUSTREDIS_ACTIVE_EXPIRE, default on)
+    pub active_expire: bool,
+
+    /// Pending reply bytes at which a client is disconnected, as
+    /// "<hard> <soft> <soft-seconds>"; 0 disables a limit
+    /// (RUSTREDIS_CLIENT_OUTPUT_BUFFER_LIMIT)
+    pub client_output_buffer_limit: OutputBufferLimit,
+
+    /// Longest inline command accepted, in bytes; longer ones close the
+    /// connection with a protocol error (RUSTREDIS_PROTO_INLINE_MAX_SIZE)
+    pub proto_inline_max_size: usize,
+
+    /// Largest message PUBLISH and SPUBLISH accept, in bytes, 0 for no
+    /// limit (RUSTREDIS_PROTO_MAX_PUBSUB_MESSAGE_SIZE)
+    pub proto_max_pubsub_message_size: usize,
+
+    /// Number Pub/Sub messages per channel, sent as a fourth element of
+    /// the message reply (RUSTREDIS_PUBSUB_MESSAGE_SEQUENCE)
+    pub pubsub_message_sequence: bool,
+
+    /// Strings at least this long are stored compressed when that saves
+    /// space, 0 to never compress; needs the `compression` feature
+    /// (RUSTREDIS_STRING_COMPRESSION_THRESHOLD)
+    pub string_compression_threshold: usize,
+
+    /// Refuse write commands naming a zero-length key, which Redis accepts
+    /// (RUSTREDIS_REJECT_EMPTY_KEYS)
+    pub reject_empty_keys: bool,
+
+    /// Sort the replies of KEYS, SMEMBERS and HGETALL so tests can compare
+    /// them; off by default since sorting costs time
+    /// (RUSTREDIS_DETERMINISTIC_ITERATION)
+    pub deterministic_iteration: bool,
+
+    /// Show keyspace and expiry notifications to MONITOR clients as
+    /// internal commands (RUSTREDIS_MONITOR_INTERNAL_EVENTS)
+    pub monitor_internal_events: bool,
+
+    /// Most keys KEYS replies with; past it KEYS fails and SCAN has to be
+    /// used, 0 for no limit (RUSTREDIS_KEYS_MAX_RESULTS)
+    pub keys_max_results: usize,
+
+    /// Approximate bytes the keys of all databases may use before keys are
+    /// evicted, 0 for no limit (RUSTREDIS_MAXMEMORY)
+    pub maxmemory: u64,
+
+    /// Which keys go once `maxmemory` is exceeded, see `db::eviction_policy`
+    /// (RUSTREDIS_MAXMEMORY_POLICY, default `noeviction`)
+    pub maxmemory_policy: String,
+
+    /// Connections the listening socket queues before they are accepted
+    /// (RUSTREDIS_TCP_BACKLOG)
+    pub tcp_backlog: u32,
+
+    /// Set SO_REUSEADDR on the listening socket, so a restarted server can
+    /// bind its port while old connections are in TIME_WAIT
+    /// (RUSTREDIS_REUSEADDR, default on)
+    pub reuseaddr: bool,
+
+    /// Connected clients above which new connections are rejected, 0 for
+    /// no limit (RUSTREDIS_MAXCLIENTS)
+    pub maxclients: u64,
+
+    /// Number of databases SELECT can switch between, at least 1; only read
+    /// at startup (RUSTREDIS_DATABASES)
+    pub databases: usize,
+
+    /// Only serve loopback clients when listening on all interfaces, since
+    /// there is no password to protect the server otherwise
+    /// (RUSTREDIS_PROTECTED_MODE, default on)
+    pub protected_mode: bool,
+
+    /// Commands per second allowed on one connection, 0 for no limit
+    /// (RUSTREDIS_PER_CLIENT_MAX_OPS_PER_SEC), and whether excess commands
+    /// are delayed or rejected (RUSTREDIS_PER_CLIENT_RATE_LIMIT_MODE,
+    /// `delay` or `error`)
+    pub per_client_rate_limit: RateLimit,
+
+    /// Number of IDEMPOTENT tokens whose replies are remembered
+    /// (RUSTREDIS_IDEMPOTENCY_CACHE_SIZE)
+    pub idempotency_cache_size: usize,
+
+    /// Seconds an IDEMPOTENT token is remembered for
+    /// (RUSTREDIS_IDEMPOTENCY_TTL)
+    pub idempotency_ttl_secs: u64,
+
+    /// Keyspace event classes published over Pub/Sub, in the Redis flag
+    /// syntax (RUSTREDIS_NOTIFY_KEYSPACE_EVENTS)
+    pub notify_keyspace_events: NotifyFlags,
+
+    /// Port of the Prometheus `/metrics` HTTP endpoint, disabled when unset
+    /// (RUSTREDIS_METRICS_PORT)
+    pub metrics_port: Option<u16>,
+
+    /// Port of the health check endpoint, disabled when unset
+    /// (RUSTREDIS_HEALTH_PORT)
+    pub health_port: Option<u16>,
+
+    /// Number of Tokio worker threads, `None` for one per core
+    /// (RUSTREDIS_IO_THREADS, `--io-threads`)
+    pub io_threads: Option<usize>,
+
+    /// Run everything on a single-threaded `current_thread` runtime
+    /// (RUSTREDIS_SINGLE_THREAD, `--single-thread`)
+    pub single_thread: bool,
+
+    /// Log verbosity: `debug`, `verbose`, `notice` or `warning`, or
+    /// `nothing` (RUSTREDIS_LOGLEVEL)
+    pub loglevel: LogLevel,
+
+    /// File logs are appended to instead of stdout (RUSTREDIS_LOGFILE)
+    pub logfile: Option<String>,
+
+    /// File the settings were loaded from, written back by CONFIG REWRITE
+    /// (`--config`)
+    pub config_file: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            disable_aof: false,
+            aof_path: "appendonly.aof".to_string(),
+            aof_stop_writes_on_error: true,
+            aof_background_load: false,
+            rdb_path: "dump.rdb".to_string(),
+            save_points: Vec::new(),
+            metrics_strategy: MetricsStrategy::Sharded2Key,
+            lazyfree_lazy_user_del: false,
+            latency_monitor_threshold_ms: 0,
+            hash_max_listpack_entries: DEFAULT_HASH_MAX_LISTPACK_ENTRIES,
+            list_max_listpack_size: DEFAULT_LIST_MAX_LISTPACK_SIZE,
+            set_max_intset_entries: DEFAULT_SET_MAX_INTSET_ENTRIES,
+            zset_max_listpack_entries: DEFAULT_ZSET_MAX_LISTPACK_ENTRIES,
+            default_ttl_seconds: 0,
+            pubsub_keepalive_secs: 0,
+            active_expire: true,
+            client_output_buffer_limit: OutputBufferLimit::default(),
+            proto_inline_max_size: DEFAULT_PROTO_INLINE_MAX_SIZE,
+            proto_max_pubsub_message_size: 0,
+            pubsub_message_sequence: false,
+            string_compression_threshold: 0,
+            reject_empty_keys: false,
+            deterministic_iteration: false,
+            monitor_internal_events: false,
+            keys_max_results: 0,
+            maxmemory: 0,
+            maxmemory_policy: DEFAULT_MAXMEMORY_POLICY.to_string(),
+            tcp_backlog: DEFAULT_TCP_BACKLOG,
+            reuseaddr: true,
+            maxclients: DEFAULT_MAXCLIENTS,
+            databases: DEFAULT_DATABASES,
+            protected_mode: true,
+            per_client_rate_limit: RateLimit::default(),
+            idempotency_cache_size: DEFAULT_IDEMPOTENCY_CACHE_SIZE,
+            idempotency_ttl_secs: DEFAULT_IDEMPOTENCY_TTL_SECS,
+            notify_keyspace_events: NotifyFlags::default(),
+            metrics_port: None,
+            health_port: None,
+            io_threads: None,
+            single_thread: false,
+            loglevel: LogLevel::default(),
+            logfile: None,
+            config_file: None,
+        }
+    }
+}
+
+/// A setting of the config file, CONFIG GET and CONFIG SET
+///
+/// Names are those of the environment variables without the `RUSTREDIS_`
+/// prefix, in lowercase with dashes, which for most settings is also their
+/// Redis name.
+struct Directive {
+    name: &'static str,
+    get: fn(&ServerConfig) -> String,
+    set: fn(&mut ServerConfig, &str) -> Result<(), String>,
+
+    /// Whether CONFIG SET may change it while the server runs; the others
+    /// are only read at startup
+    mutable: bool,
+}
+
+const DIRECTIVES: &[Directive] = &[
+    Directive {
+        name: "disable-aof",
+        get: |c| yes_no(c.disable_aof),
+        set: |c, v| {
+            c.disable_aof = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "aof-path",
+        get: |c| c.aof_path.clone(),
+        set: |c, v| {
+            c.aof_path = v.to_string();
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "aof-stop-writes-on-error",
+        get: |c| yes_no(c.aof_stop_writes_on_error),
+        set: |c, v| {
+            c.aof_stop_writes_on_error = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "aof-background-load",
+        get: |c| yes_no(c.aof_background_load),
+        set: |c, v| {
+            c.aof_background_load = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "rdb-path",
+        get: |c| c.rdb_path.clone(),
+        set: |c, v| {
+            c.rdb_path = v.to_string();
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "save",
+        get: |c| {
+            c.save_points
+                .iter()
+                .map(|point| format!("{} {}", point.seconds, point.changes))
+                .collect::<Vec<_>>()
+                .join(" ")
+        },
+        set: |c, v| {
+            c.save_points = snapshot::parse_save_points(v)?;
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "metrics-strategy",
+        get: |c| c.metrics_strategy.name().to_string(),
+        set: |c, v| {
+            c.metrics_strategy = MetricsStrategy::from_str_loose(v);
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "lazyfree-lazy-user-del",
+        get: |c| yes_no(c.lazyfree_lazy_user_del),
+        set: |c, v| {
+            c.lazyfree_lazy_user_del = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "latency-monitor-threshold",
+        get: |c| c.latency_monitor_threshold_ms.to_string(),
+        set: |c, v| {
+            c.latency_monitor_threshold_ms = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "hash-max-listpack-entries",
+        get: |c| c.hash_max_listpack_entries.to_string(),
+        set: |c, v| {
+            c.hash_max_listpack_entries = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "list-max-listpack-size",
+        get: |c| c.list_max_listpack_size.to_string(),
+        set: |c, v| {
+            c.list_max_listpack_size = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "set-max-intset-entries",
+        get: |c| c.set_max_intset_entries.to_string(),
+        set: |c, v| {
+            c.set_max_intset_entries = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "zset-max-listpack-entries",
+        get: |c| c.zset_max_listpack_entries.to_string(),
+        set: |c, v| {
+            c.zset_max_listpack_entries = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "default-ttl-seconds",
+        get: |c| c.default_ttl_seconds.to_string(),
+        set: |c, v| {
+            c.default_ttl_seconds = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "pubsub-keepalive-secs",
+        get: |c| c.pubsub_keepalive_secs.to_string(),
+        set: |c, v| {
+            c.pubsub_keepalive_secs = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "active-expire",
+        get: |c| yes_no(c.active_expire),
+        set: |c, v| {
+            c.active_expire = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "client-output-buffer-limit",
+        get: |c| {
+            let limit = c.client_output_buffer_limit;
+            format!("{} {} {}", limit.hard, limit.soft, limit.soft_seconds)
+        },
+        set: |c, v| {
+            c.client_output_buffer_limit = OutputBufferLimit::parse(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "proto-inline-max-size",
+        get: |c| c.proto_inline_max_size.to_string(),
+        set: |c, v| {
+            c.proto_inline_max_size = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "proto-max-pubsub-message-size",
+        get: |c| c.proto_max_pubsub_message_size.to_string(),
+        set: |c, v| {
+            c.proto_max_pubsub_message_size = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "pubsub-message-sequence",
+        get: |c| yes_no(c.pubsub_message_sequence),
+        set: |c, v| {
+            c.pubsub_message_sequence = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "string-compression-threshold",
+        get: |c| c.string_compression_threshold.to_string(),
+        set: |c, v| {
+            c.string_compression_threshold = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "reject-empty-keys",
+        get: |c| yes_no(c.reject_empty_keys),
+        set: |c, v| {
+            c.reject_empty_keys = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "deterministic-iteration",
+        get: |c| yes_no(c.deterministic_iteration),
+        set: |c, v| {
+            c.deterministic_iteration = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "monitor-internal-events",
+        get: |c| yes_no(c.monitor_internal_events),
+        set: |c, v| {
+            c.monitor_internal_events = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "keys-max-results",
+        get: |c| c.keys_max_results.to_string(),
+        set: |c, v| {
+            c.keys_max_results = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "maxmemory",
+        get: |c| c.maxmemory.to_string(),
+        set: |c, v| {
+            c.maxmemory = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "maxmemory-policy",
+        get: |c| c.maxmemory_policy.clone(),
+        set: |c, v| {
+            let policy = v.to_ascii_lowercase();
+            if eviction_policy(&policy).is_none() {
+                return Err(format!("unknown maxmemory policy '{}'", v));
+            }
+            c.maxmemory_policy = policy;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "tcp-backlog",
+        get: |c| c.tcp_backlog.to_string(),
+        set: |c, v| {
+            c.tcp_backlog = parse_number(v)?;
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "reuseaddr",
+        get: |c| yes_no(c.reuseaddr),
+        set: |c, v| {
+            c.reuseaddr = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "maxclients",
+        get: |c| c.maxclients.to_string(),
+        set: |c, v| {
+            c.maxclients = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "databases",
+        get: |c| c.databases.to_string(),
+        set: |c, v| match parse_number(v)? {
+            0 => Err("argument must be at least 1".to_string()),
+            databases => {
+                c.databases = databases;
+                Ok(())
+            }
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "protected-mode",
+        get: |c| yes_no(c.protected_mode),
+        set: |c, v| {
+            c.protected_mode = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "per-client-max-ops-per-sec",
+        get: |c| c.per_client_rate_limit.max_ops_per_sec.to_string(),
+        set: |c, v| {
+            c.per_client_rate_limit.max_ops_per_sec = parse_number(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "per-client-rate-limit-mode",
+        get: |c| c.per_client_rate_limit.mode.name().to_string(),
+        set: |c, v| {
+            c.per_client_rate_limit.mode = RateLimitMode::parse(v)
+                .ok_or_else(|| "argument must be 'delay' or 'error'".to_string())?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "idempotency-cache-size",
+        get: |c| c.idempotency_cache_size.to_string(),
+        set: |c, v| {
+            c.idempotency_cache_size = parse_number(v)?;
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "idempotency-ttl",
+        get: |c| c.idempotency_ttl_secs.to_string(),
+        set: |c, v| {
+            c.idempotency_ttl_secs = parse_number(v)?;
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "notify-keyspace-events",
+        get: |c| c.notify_keyspace_events.spec(),
+        set: |c, v| {
+            c.notify_keyspace_events = NotifyFlags::parse(v)?;
+            Ok(())
+        },
+        mutable: true,
+    },
+    Directive {
+        name: "metrics-port",
+        get: |c| {
+            c.metrics_port
+                .map(|port| port.to_string())
+                .unwrap_or_default()
+        },
+        set: |c, v| {
+            c.metrics_port = match v {
+                "" => None,
+                port => Some(parse_number(port)?),
+            };
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "health-port",
+        get: |c| {
+            c.health_port
+                .map(|port| port.to_string())
+                .unwrap_or_default()
+        },
+        set: |c, v| {
+            c.health_port = match v {
+                "" => None,
+                port => Some(parse_number(port)?),
+            };
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "io-threads",
+        get: |c| c.io_threads.unwrap_or(0).to_string(),
+        set: |c, v| {
+            c.io_threads = Some(parse_number(v)?).filter(|&n| n > 0);
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "loglevel",
+        get: |c| c.loglevel.name().to_string(),
+        set: |c, v| {
+            c.loglevel = LogLevel::parse(v).ok_or_else(|| {
+                "argument must be one of 'debug', 'verbose', 'notice', 'warning', 'nothing'"
+                    .to_string()
+            })?;
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "logfile",
+        get: |c| c.logfile.clone().unwrap_or_default(),
+        set: |c, v| {
+            c.logfile = Some(v.to_string()).filter(|path| !path.is_empty());
+            Ok(())
+        },
+        mutable: false,
+    },
+    Directive {
+        name: "single-thread",
+        get: |c| yes_no(c.single_thread),
+        set: |c, v| {
+            c.single_thread = parse_yes_no(v)?;
+            Ok(())
+        },
+        mutable: false,
+    },
+];
+
+fn directive(name: &str) -> Option<&'static Directive> {
+    DIRECTIVES
+        .iter()
+        .find(|directive| directive.name.eq_ignore_ascii_case(name))
+}
+
+impl ServerConfig {
+    /// Build a configuration from the defaults and the process environment
+    pub fn from_env() -> Self {
+        let mut config = ServerConfig::default();
+
+        if let Some(flag) = env_flag("RUSTREDIS_DISABLE_AOF") {
+            config.disable_aof = flag;
+        }
+        if let Ok(path) = std::env::var("RUSTREDIS_AOF_PATH") {
+            config.aof_path = path;
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_AOF_STOP_WRITES_ON_ERROR") {
+            config.aof_stop_writes_on_error = flag;
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_AOF_BACKGROUND_LOAD") {
+            config.aof_background_load = flag;
+        }
+        if let Ok(path) = std::env::var("RUSTREDIS_RDB_PATH") {
+            config.rdb_path = path;
+        }
+        if let Some(points) = std::env::var("RUSTREDIS_SAVE")
+            .ok()
+            .and_then(|spec| snapshot::parse_save_points(&spec).ok())
+        {
+            config.save_points = points;
+        }
+        if let Ok(strategy) = std::env::var("RUSTREDIS_METRICS_STRATEGY") {
+            config.metrics_strategy = MetricsStrategy::from_str_loose(&strategy);
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_LAZYFREE_LAZY_USER_DEL") {
+            config.lazyfree_lazy_user_del = flag;
+        }
+        if let Some(ms) = env_u64("RUSTREDIS_LATENCY_MONITOR_THRESHOLD") {
+            config.latency_monitor_threshold_ms = ms;
+        }
+        if let Some(entries) = env_u64("RUSTREDIS_HASH_MAX_LISTPACK_ENTRIES") {
+            config.hash_max_listpack_entries = entries as usize;
+        }
+        if let Some(size) = env_u64("RUSTREDIS_LIST_MAX_LISTPACK_SIZE") {
+            config.list_max_listpack_size = size as usize;
+        }
+        if let Some(entries) = env_u64("RUSTREDIS_SET_MAX_INTSET_ENTRIES") {
+            config.set_max_intset_entries = entries as usize;
+        }
+        if let Some(entries) = env_u64("RUSTREDIS_ZSET_MAX_LISTPACK_ENTRIES") {
+            config.zset_max_listpack_entries = entries as usize;
+        }
+        if let Some(secs) = env_u64("RUSTREDIS_DEFAULT_TTL_SECONDS") {
+            config.default_ttl_seconds = secs;
+        }
+        if let Some(secs) = env_u64("RUSTREDIS_PUBSUB_KEEPALIVE_SECS") {
+            config.pubsub_keepalive_secs = secs;
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_ACTIVE_EXPIRE") {
+            config.active_expire = flag;
+        }
+        if let Some(limit) = std::env::var("RUSTREDIS_CLIENT_OUTPUT_BUFFER_LIMIT")
+            .ok()
+            .and_then(|spec| OutputBufferLimit::parse(&spec).ok())
+        {
+            config.client_output_buffer_limit = limit;
+        }
+        if let Some(size) = env_u64("RUSTREDIS_PROTO_INLINE_MAX_SIZE") {
+            config.proto_inline_max_size = size as usize;
+        }
+        if let Some(size) = env_u64("RUSTREDIS_PROTO_MAX_PUBSUB_MESSAGE_SIZE") {
+            config.proto_max_pubsub_message_size = size as usize;
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_PUBSUB_MESSAGE_SEQUENCE") {
+            config.pubsub_message_sequence = flag;
+        }
+        if let Some(size) = env_u64("RUSTREDIS_STRING_COMPRESSION_THRESHOLD") {
+            config.string_compression_threshold = size as usize;
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_REJECT_EMPTY_KEYS") {
+            config.reject_empty_keys = flag;
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_DETERMINISTIC_ITERATION") {
+            config.deterministic_iteration = flag;
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_MONITOR_INTERNAL_EVENTS") {
+            config.monitor_internal_events = flag;
+        }
+        if let Some(max) = env_u64("RUSTREDIS_KEYS_MAX_RESULTS") {
+            config.keys_max_results = max as usize;
+        }
+        if let Some(bytes) = env_u64("RUSTREDIS_MAXMEMORY") {
+            config.maxmemory = bytes;
+        }
+        if let Some(policy) = std::env::var("RUSTREDIS_MAXMEMORY_POLICY")
+            .ok()
+            .map(|policy| policy.to_ascii_lowercase())
+            .filter(|policy| eviction_policy(policy).is_some())
+        {
+            config.maxmemory_policy = policy;
+        }
+        if let Some(backlog) = env_u64("RUSTREDIS_TCP_BACKLOG") {
+            config.tcp_backlog = u32::try_from(backlog).unwrap_or(u32::MAX);
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_REUSEADDR") {
+            config.reuseaddr = flag;
+        }
+        if let Some(clients) = env_u64("RUSTREDIS_MAXCLIENTS") {
+            config.maxclients = clients;
+        }
+        if let Some(databases) = env_u64("RUSTREDIS_DATABASES").filter(|&n| n > 0) {
+            config.databases = databases as usize;
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_PROTECTED_MODE") {
+            config.protected_mode = flag;
+        }
+        if let Some(ops) = env_u64("RUSTREDIS_PER_CLIENT_MAX_OPS_PER_SEC") {
+            config.per_client_rate_limit.max_ops_per_sec = ops;
+        }
+        if let Some(mode) = std::env::var("RUSTREDIS_PER_CLIENT_RATE_LIMIT_MODE")
+            .ok()
+            .and_then(|mode| RateLimitMode::parse(&mode))
+        {
+            config.per_client_rate_limit.mode = mode;
+        }
+        if let Some(size) = env_u64("RUSTREDIS_IDEMPOTENCY_CACHE_SIZE") {
+            config.idempotency_cache_size = size as usize;
+        }
+        if let Some(secs) = env_u64("RUSTREDIS_IDEMPOTENCY_TTL") {
+            config.idempotency_ttl_secs = secs;
+        }
+        if let Some(flags) = std::env::var("RUSTREDIS_NOTIFY_KEYSPACE_EVENTS")
+            .ok()
+            .and_then(|spec| NotifyFlags::parse(&spec).ok())
+        {
+            config.notify_keyspace_events = flags;
+        }
+        if let Some(port) = env_u64("RUSTREDIS_METRICS_PORT") {
+            config.metrics_port = u16::try_from(port).ok();
+        }
+        if let Some(port) = env_u64("RUSTREDIS_HEALTH_PORT") {
+            config.health_port = u16::try_from(port).ok();
+        }
+        if let Some(threads) = env_u64("RUSTREDIS_IO_THREADS") {
+            config.io_threads = Some(threads as usize).filter(|&n| n > 0);
+        }
+        if let Some(flag) = env_flag("RUSTREDIS_SINGLE_THREAD") {
+            config.single_thread = flag;
+        }
+        if let Some(level) = std::env::var("RUSTREDIS_LOGLEVEL")
+            .ok()
+            .and_then(|level| LogLevel::parse(&level))
+        {
+            config.loglevel = level;
+        }
+        if let Ok(path) = std::env::var("RUSTREDIS_LOGFILE") {
+            config.logfile = Some(path).filter(|path| !path.is_empty());
+        }
+
+        config
+    }
+
+    /// Apply the directives of a config file on top of the current settings
+    ///
+    /// Each line is `<directive> <value>`, blank lines and lines starting
+    /// with `#` are ignored. The file is remembered for CONFIG REWRITE.
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("can't open config file '{}': {}", path, e))?;
+        for (number, line) in contents.lines().enumerate() {
+            let Some((name, value)) = parse_line(line) else {
+                continue;
+            };
+            let directive = directive(name)
+                .ok_or_else(|| format!("{} line {}: bad directive '{}'", path, number + 1, name))?;
+            (directive.set)(self, value)
+                .map_err(|e| format!("{} line {}: {}", path, number + 1, e))?;
+        }
+        self.config_file = Some(path.to_string());
+        Ok(())
+    }
+
+    /// Directives whose name matches the glob `pattern`, with their values
+    /// (CONFIG GET)
+    pub fn get_matching(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        let pattern = pattern.to_ascii_lowercase();
+        DIRECTIVES
+            .iter()
+            .filter(|directive| glob_match(pattern.as_bytes(), directive.name.as_bytes()))
+            .map(|directive| (directive.name, (directive.get)(self)))
+            .collect()
+    }
+
+    /// Change a setting of the running server (CONFIG SET)
+    ///
+    /// Returns the error reply for unknown directives, settings only read
+    /// at startup, and invalid values.
+    pub fn set_runtime(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let directive = directive(name).ok_or_else(|| {
+            format!(
+                "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            )
+        })?;
+        if !directive.mutable {
+            return Err(format!(
+                "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                directive.name
+            ));
+        }
+        (directive.set)(self, value).map_err(|e| {
+            format!(
+                "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                directive.name, e
+            )
+        })
+    }
+
+    /// Write the current settings back to the config file (CONFIG REWRITE)
+    ///
+    /// Comments and the order of existing directives are kept: each one is
+    /// rewritten in place and repeated ones are dropped. Settings that are
+    /// not in the file yet are appended, unless they have their default
+    /// value. The file is replaced atomically.
+    pub fn rewrite(&self) -> Result<(), String> {
+        let path = self.config_file.as_deref().ok_or(NO_CONFIG_FILE_ERR)?;
+        let line = |directive: &Directive| {
+            let value = (directive.get)(self);
+            if value.is_empty() {
+                format!("{} \"\"", directive.name)
+            } else {
+                format!("{} {}", directive.name, value)
+            }
+        };
+
+        // A missing file is recreated
+        let existing = std::fs::read_to_string(path).unwrap_or_default();
+        let mut written = HashSet::new();
+        let mut lines = Vec::new();
+        for original in existing.lines() {
+            match parse_line(original).and_then(|(name, _)| directive(name)) {
+                Some(directive) => {
+                    if written.insert(directive.name) {
+                        lines.push(line(directive));
+                    }
+                }
+                None => lines.push(original.to_string()),
+            }
+        }
+
+        let default = ServerConfig::default();
+        let mut appended = DIRECTIVES
+            .iter()
+            .filter(|directive| {
+                !written.contains(directive.name)
+                    && (directive.get)(self) != (directive.get)(&default)
+            })
+            .peekable();
+        if appended.peek().is_some() {
+            lines.push("# Generated by CONFIG REWRITE".to_string());
+            lines.extend(appended.map(line));
+        }
+
+        let mut contents = lines.join("\n");
+        contents.push('\n');
+        let tmp_path = format!("{}.tmp", path);
+        std::fs::write(&tmp_path, contents)
+            .and_then(|()| std::fs::rename(&tmp_path, path))
+            .map_err(|e| format!("ERR Rewriting config file: {}", e))
+    }
+
+    /// Apply command line flags on top of the current settings
+    ///
+    /// Supports `--config <file>`, `--io-threads <n>` and `--single-thread`.
+    /// The config file is applied at its position among the flags.
+    pub fn apply_args<I>(&mut self, args: I) -> Result<(), String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--io-threads" => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| "--io-threads requires a value".to_string())?;
+                    let threads: usize = value
+                        .parse()
+                        .map_err(|_| format!("invalid --io-threads value '{}'", value))?;
+                    if threads == 0 {
+                        return Err("--io-threads must be at least 1".to_string());
+                    }
+                    self.io_threads = Some(threads);
+                }
+                "--single-thread" => self.single_thread = true,
+                "--config" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| "--config requires a file".to_string())?;
+                    self.load_file(&path)?;
+                }
+                other => return Err(format!("unknown argument '{}'", other)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Expiry given to keys created without one, if any
+    pub fn default_ttl(&self) -> Option<Duration> {
+        (self.default_ttl_seconds > 0).then(|| Duration::from_secs(self.default_ttl_seconds))
+    }
+
+    /// Idle time after which subscribed connections are sent a keepalive
+    pub fn pubsub_keepalive(&self) -> Option<Duration> {
+        (self.pubsub_keepalive_secs > 0).then(|| Duration::from_secs(self.pubsub_keepalive_secs))
+    }
+
+    /// Build the Tokio runtime described by this configuration
+    pub fn build_runtime(&self) -> std::io::Result<tokio::runtime::Runtime> {
+        if self.single_thread {
+            return tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build();
+        }
+
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        if let Some(threads) = self.io_threads {
+            builder.worker_threads(threads);
+        }
+        builder.enable_all().build()
+    }
+}
+
+/// Read a boolean environment variable ("1", "true" and "yes" are true)
+fn env_flag(name: &str) -> Option<bool> {
+    std::env::var(name).ok().map(|v| parse_bool(&v))
+}
+
+/// Read an unsigned integer environment variable, ignoring unparsable values
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok().and_then(|v| v.trim().parse().ok())
+}
+
+fn parse_bool(value: &str) -> bool {
+    let normalized = value.to_ascii_lowercase();
+    normalized == "1" || normalized == "true" || normalized == "yes"
+}
+
+/// Split a config file line into directive and value, `None` for blank
+/// lines and comments
+///
+/// The value is the rest of the line; `""` stands for an empty value.
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+    Some((name, value))
+}
+
+fn yes_no(flag: bool) -> String {
+    if flag { "yes" } else { "no" }.to_string()
+}
+
+/// Strict boolean for config files and CONFIG SET
+fn parse_yes_no(value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err("argument must be 'yes' or 'no'".to_string()),
+    }
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("argument couldn't be parsed into an integer: '{}'", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_apply_args() {
+        let mut config = ServerConfig::default();
+        config
+            .apply_args(args(&["--io-threads", "3", "--single-thread"]))
+            .unwrap();
+        assert_eq!(config.io_threads, Some(3));
+        assert!(config.single_thread);
+
+        assert!(config.apply_args(args(&["--io-threads", "0"])).is_err());
+        assert!(config.apply_args(args(&["--io-threads"])).is_err());
+        assert!(config.apply_args(args(&["--bogus"])).is_err());
+    }
+
+    #[test]
+    fn test_databases_directive() {
+        let mut config = ServerConfig::default();
+        assert_eq!(config.databases, DEFAULT_DATABASES);
+        (directive("databases").unwrap().set)(&mut config, "4").unwrap();
+        assert_eq!(config.databases, 4);
+        assert!((directive("databases").unwrap().set)(&mut config, "0").is_err());
+        // Only read at startup
+        assert!(config.set_runtime("databases", "8").is_err());
+        assert_eq!(config.databases, 4);
+    }
+}
@@ -1,25 +1,140 @@
-use crate::frame::{Error as FrameError, Frame};
-use bytes::BytesMut;
+use crate::frame::{Error as FrameError, Frame, PartialFrame};
+use bytes::{Buf, BytesMut};
 use std::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
 use tokio::net::TcpStream;
+use tracing::warn;
+
+/// Default for `proto-inline-max-size` (same as Redis)
+pub const DEFAULT_PROTO_INLINE_MAX_SIZE: usize = 64 * 1024;
+
+/// Limits on replies that were produced but not yet written to the socket
+///
+/// Mirrors Redis' `client-output-buffer-limit`: a connection is closed as
+/// soon as its pending output exceeds `hard` bytes, or once it has stayed
+/// above `soft` bytes for `soft_seconds`. A limit of 0 disables that check.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OutputBufferLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    /// Parse `"<hard> <soft> <soft-seconds>"`, sizes in bytes
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = spec.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err("expected '<hard> <soft> <soft-seconds>'".to_string());
+        }
+        let number = |s: &str| {
+            s.parse::<u64>()
+                .map_err(|_| format!("invalid output buffer limit value '{}'", s))
+        };
+
+        Ok(OutputBufferLimit {
+            hard: number(parts[0])? as usize,
+            soft: number(parts[1])? as usize,
+            soft_seconds: number(parts[2])?,
+        })
+    }
+}
 
 /// Connection wrapper around a TcpStream that handles buffered reading/writing
 /// and frame parsing/serialization
+///
+/// Replies are encoded into an output buffer and written without waiting
+/// for the peer: whatever the socket does not accept right away is sent
+/// while the next command is being read. A client that stops reading
+/// therefore grows the output buffer, which is bounded by the
+/// `OutputBufferLimit`.
 pub struct Connection {
-    /// The underlying TCP stream wrapped in a buffered writer
-    stream: BufWriter<TcpStream>,
+    /// The underlying TCP stream
+    stream: TcpStream,
 
     /// Read buffer for incoming data
     buffer: BytesMut,
+
+    /// How far the frame at the start of `buffer` has been checked
+    partial: PartialFrame,
+
+    /// Encoded replies not yet accepted by the socket
+    output: BytesMut,
+
+    /// Closing thresholds for `output`
+    output_limit: OutputBufferLimit,
+
+    /// When `output` last went above the soft limit
+    soft_limit_since: Option<Instant>,
+
+    /// Longest inline command accepted, in bytes
+    inline_max_len: usize,
+
+    /// Whether `write_frame` sends anything (CLIENT REPLY)
+    replies: bool,
+
+    /// Whether replies are kept in `output` instead of being written
+    held: bool,
 }
 
 impl Connection {
     /// Create a new Connection from a TcpStream
     pub fn new(socket: TcpStream) -> Connection {
         Connection {
-            stream: BufWriter::new(socket),
+            stream: socket,
             buffer: BytesMut::with_capacity(4096),
+            partial: PartialFrame::default(),
+            output: BytesMut::with_capacity(4096),
+            output_limit: OutputBufferLimit::default(),
+            soft_limit_since: None,
+            inline_max_len: DEFAULT_PROTO_INLINE_MAX_SIZE,
+            replies: true,
+            held: false,
+        }
+    }
+
+    /// Close the connection when pending output exceeds `limit`
+    pub fn with_output_limit(mut self, limit: OutputBufferLimit) -> Self {
+        self.output_limit = limit;
+        self
+    }
+
+    /// Refuse inline commands longer than `max_len` bytes
+    pub fn with_inline_max_len(mut self, max_len: usize) -> Self {
+        self.inline_max_len = max_len;
+        self
+    }
+
+    /// Send frames passed to `write_frame`, or silently drop them
+    pub fn set_replies(&mut self, on: bool) {
+        self.replies = on;
+    }
+
+    /// Keep replies in the output buffer until released
+    ///
+    /// For replies that must not reach the client before something else
+    /// happened, e.g. the writes they acknowledge were logged. Held replies
+    /// still count towards the output limit; once released they are
+    /// written while the next command is being read.
+    pub fn hold_replies(&mut self, hold: bool) {
+        self.held = hold;
+    }
+
+    /// Number of reply bytes waiting to be written to the socket
+    pub fn pending_output(&self) -> usize {
+        self.output.len()
+    }
+
+    /// Wait until the peer closes the connection
+    ///
+    /// For commands that block without reading. Only returns on EOF or an
+    /// error; anything the client sends in the meantime stays unread.
+    pub async fn closed(&self) {
+        let mut byte = [0u8; 1];
+        match self.stream.peek(&mut byte).await {
+            Ok(0) | Err(_) => {}
+            Ok(_) => std::future::pending().await,
         }
     }
 
@@ -28,6 +143,8 @@ impl Connection {
     /// Returns `Ok(Some(frame))` if a frame was read
     /// Returns `Ok(None)` if the connection was closed
     /// Returns `Err` on IO or parsing errors
+    ///
+    /// Pending output is written while waiting for more input.
     pub async fn read_frame(&mut self) -> Result<Option<Frame>, io::Error> {
         loop {
             // Try to parse a frame from the buffer
@@ -36,7 +153,31 @@ impl Connection {
             }
 
             // Not enough data, read more from the socket
-            let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+            let bytes_read = if self.output.is_empty() {
+                self.stream.read_buf(&mut self.buffer).await?
+            } else {
+                let soft_deadline = self.soft_limit_deadline();
+                tokio::select! {
+                    ready = self.stream.readable() => {
+                        ready?;
+                        match self.stream.try_read_buf(&mut self.buffer) {
+                            Ok(n) => n,
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    ready = self.stream.writable() => {
+                        ready?;
+                        self.try_flush()?;
+                        self.check_output_limit()?;
+                        continue;
+                    }
+                    _ = sleep_until(soft_deadline) => {
+                        self.check_output_limit()?;
+                        continue;
+                    }
+                }
+            };
 
             // If 0 bytes read, the connection is closed
             if bytes_read == 0 {
@@ -52,71 +193,126 @@ impl Connection {
         }
     }
 
+    /// The next frame if it has already been received, without waiting
+    ///
+    /// Lets the caller look ahead in a pipeline. Malformed data is left in
+    /// the buffer, so the next `read_frame` reports it.
+    pub fn buffered_frame(&mut self) -> Option<Frame> {
+        self.parse_frame().ok().flatten()
+    }
+
     /// Try to parse a frame from the buffer
+    ///
+    /// Data that does not start with a RESP type byte is parsed as an
+    /// inline command. Malformed data fails with `InvalidData` and a
+    /// "Protocol error" message.
     fn parse_frame(&mut self) -> Result<Option<Frame>, io::Error> {
-        match Frame::parse(&mut self.buffer) {
+        let result = match self.buffer.first() {
+            Some(&byte) if !Frame::is_type_byte(byte) => {
+                Frame::parse_inline(&mut self.buffer, self.inline_max_len)
+            }
+            _ => Frame::parse_resumable(&mut self.buffer, &mut self.partial),
+        };
+        match result {
             Ok(frame) => Ok(frame),
             Err(FrameError::Incomplete) => Ok(None),
-            Err(FrameError::Invalid(msg)) => Err(io::Error::new(io::ErrorKind::InvalidData, msg)),
+            Err(FrameError::Invalid(msg)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Protocol error: {}", msg),
+            )),
             Err(FrameError::Io(e)) => Err(e),
         }
     }
 
     /// Write a frame to the connection
+    ///
+    /// The frame is written as far as the socket accepts it without
+    /// blocking; the rest stays in the output buffer. Fails if that buffer
+    /// is over the output limit. Nothing is written while replies are off,
+    /// and nothing reaches the socket while they are held.
     pub async fn write_frame(&mut self, frame: &Frame) -> Result<(), io::Error> {
-        // Serialize the frame to the writer
-        self.write_value(frame).await?;
+        if !self.replies {
+            return Ok(());
+        }
+
+        // Serialize the frame to the output buffer
+        frame.encode(&mut self.output);
 
-        // Flush the buffer to ensure data is sent
-        self.stream.flush().await?;
+        if !self.held {
+            self.try_flush()?;
+        }
+        self.check_output_limit()
+    }
 
+    /// Write all pending output, waiting for the socket as needed
+    ///
+    /// Replies are otherwise written while waiting for the next request; a
+    /// connection that is about to be dropped must be flushed explicitly.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            self.stream.writable().await?;
+            self.try_flush()?;
+        }
         Ok(())
     }
 
-    /// Serialize a frame value to the writer
-    async fn write_value(&mut self, frame: &Frame) -> Result<(), io::Error> {
-        match frame {
-            Frame::Simple(s) => {
-                self.stream.write_u8(b'+').await?;
-                self.stream.write_all(s.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Error(e) => {
-                self.stream.write_u8(b'-').await?;
-                self.stream.write_all(e.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
+    /// Write pending output until the socket would block
+    fn try_flush(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.try_write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.output.advance(n),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
             }
-            Frame::Integer(n) => {
-                self.stream.write_u8(b':').await?;
-                self.stream.write_all(n.to_string().as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Null => {
-                self.stream.write_all(b"$-1\r\n").await?;
-            }
-            Frame::Bulk(data) => {
-                self.stream.write_u8(b'$').await?;
-                self.stream
-                    .write_all(data.len().to_string().as_bytes())
-                    .await?;
-                self.stream.write_all(b"\r\n").await?;
-                self.stream.write_all(data).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Array(frames) => {
-                self.stream.write_u8(b'*').await?;
-                self.stream
-                    .write_all(frames.len().to_string().as_bytes())
-                    .await?;
-                self.stream.write_all(b"\r\n").await?;
-
-                // Recursively write each frame in the array
-                for frame in frames {
-                    Box::pin(self.write_value(frame)).await?;
-                }
+        }
+        Ok(())
+    }
+
+    /// Fail if the pending output is over the hard limit, or has been over
+    /// the soft limit for too long
+    fn check_output_limit(&mut self) -> io::Result<()> {
+        let pending = self.output.len();
+        let limit = self.output_limit;
+
+        let reason = if limit.hard > 0 && pending > limit.hard {
+            Some(format!("{} bytes exceed the hard limit", pending))
+        } else if limit.soft > 0 && pending > limit.soft {
+            let since = *self.soft_limit_since.get_or_insert_with(Instant::now);
+            (since.elapsed() >= Duration::from_secs(limit.soft_seconds)).then(|| {
+                format!(
+                    "over the soft limit for {} seconds ({} bytes)",
+                    limit.soft_seconds, pending
+                )
+            })
+        } else {
+            self.soft_limit_since = None;
+            None
+        };
+
+        match reason {
+            Some(reason) => {
+                warn!("Closing client: output buffer {}", reason);
+                Err(io::Error::other(format!(
+                    "client output buffer limit reached: {}",
+                    reason
+                )))
             }
+            None => Ok(()),
         }
+    }
 
-        Ok(())
+    /// When the current soft limit violation turns into a disconnect
+    fn soft_limit_deadline(&self) -> Option<Instant> {
+        self.soft_limit_since
+            .map(|since| since + Duration::from_secs(self.output_limit.soft_seconds))
+    }
+}
+
+/// Sleep until `deadline`, or forever if there is none
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
     }
 }
@@ -1,15 +1,87 @@
+use crate::clock;
+use crate::notify::Notifier;
+use crate::util::{
+    glob_match, lock, normalize_range, parse_redis_float, parse_redis_int, read, write,
+};
 use bytes::Bytes;
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::collections::BTreeSet;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+mod blocking;
+#[cfg(feature = "compression")]
+mod compression;
+mod eviction;
+mod hash;
+#[cfg(feature = "json")]
+pub mod json;
+mod keyspace;
+mod lcs;
+mod list;
+mod observer;
+mod random;
+mod set;
+mod stream;
+mod zset;
+
+pub use blocking::BlockedClient;
+#[cfg(feature = "compression")]
+pub use compression::{CompressedString, DEFAULT_STRING_COMPRESSION_THRESHOLD};
+pub use eviction::{
+    policy as eviction_policy, AllKeysLru, AllKeysRandom, Candidate, EvictionPolicy, NoEviction,
+    VolatileRandom, VolatileTtl, DEFAULT_MAXMEMORY_POLICY, OOM_ERR,
+};
+pub use hash::{HashValue, DEFAULT_HASH_MAX_LISTPACK_ENTRIES};
+pub use lcs::{Lcs, LcsMatch, LCS_MAX_CELLS};
+pub use list::{ListValue, Quicklist, DEFAULT_LIST_MAX_LISTPACK_SIZE};
+pub use observer::{ChangeEvent, ChangeObserver};
+pub use random::DbRng;
+pub use set::{SetValue, DEFAULT_SET_MAX_INTSET_ENTRIES};
+pub use stream::{StreamEntry, StreamId, StreamValue, INVALID_STREAM_ID_ERR};
+pub use zset::{
+    LexBound, Score, ZSetValue, DEFAULT_ZSET_MAX_LISTPACK_ENTRIES, INVALID_LEX_RANGE_ERR,
+};
+
+use blocking::KeyWaiters;
+#[cfg(feature = "json")]
+use json::JsonPath;
+use keyspace::Keyspace;
+use observer::ChangeFeed;
+
+/// Error returned when a command is used against a key of another type
+pub const WRONGTYPE_ERR: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// Reply to KEYS when more keys match than `keys-max-results` allows
+pub const KEYS_TOO_LARGE_ERR: &str = "ERR KEYS result set too large, use SCAN";
+
+/// Most members SRANDMEMBER picks when repeats are allowed
+pub const MAX_REPEATED_PICKS: usize = 1024 * 1024;
+
+/// Largest string SETRANGE/APPEND/SETBIT may produce (512MB, like Redis)
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// Values with more elements than this are freed on the lazy-free thread
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Longest string Redis stores as `embstr`; longer ones are `raw`
+const EMBSTR_SIZE_LIMIT: usize = 44;
 
 /// Value types supported by the database
 #[derive(Clone, Debug)]
 pub enum Value {
     String(Bytes),
-    List(VecDeque<Bytes>),
-    Set(HashSet<String>),
-    Hash(HashMap<String, Bytes>),
+    List(ListValue),
+    Set(SetValue),
+    Hash(HashValue),
+    ZSet(ZSetValue),
+    Stream(StreamValue),
+    #[cfg(feature = "json")]
+    Json(serde_json::Value),
+    /// A large string, see `Db::set_string_compression_threshold`
+    #[cfg(feature = "compression")]
+    Compressed(CompressedString),
 }
 
 impl Value {
@@ -19,8 +91,248 @@ impl Value {
             Value::List(_) => "list",
             Value::Set(_) => "set",
             Value::Hash(_) => "hash",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+            #[cfg(feature = "json")]
+            Value::Json(_) => "ReJSON-RL",
+            #[cfg(feature = "compression")]
+            Value::Compressed(_) => "string",
         }
     }
+
+    /// The bytes of a string value, decompressed if it is stored compressed
+    ///
+    /// Returns None for the other types.
+    pub fn as_string(&self) -> Option<Bytes> {
+        match self {
+            Value::String(bytes) => Some(bytes.clone()),
+            #[cfg(feature = "compression")]
+            Value::Compressed(compressed) => Some(compressed.decompress()),
+            _ => None,
+        }
+    }
+
+    /// Approximate number of payload bytes held by this value
+    ///
+    /// Counts the raw bytes of strings, list elements, set members, hash
+    /// fields/values, sorted set members and scores, and stream IDs and
+    /// fields/values. Allocator and container overhead is not included.
+    pub fn approx_size(&self) -> usize {
+        match self {
+            Value::String(bytes) => bytes.len(),
+            #[cfg(feature = "compression")]
+            Value::Compressed(compressed) => compressed.stored_len(),
+            Value::List(list) => list.iter().map(|item| item.len()).sum(),
+            Value::Set(SetValue::IntSet(ints)) => ints.len() * std::mem::size_of::<i64>(),
+            Value::Set(SetValue::Table(set)) => set.iter().map(|member| member.len()).sum(),
+            Value::Hash(hash) => hash
+                .iter()
+                .map(|(field, value)| field.len() + value.len())
+                .sum(),
+            Value::ZSet(zset) => zset
+                .iter()
+                .map(|(member, _)| member.len() + std::mem::size_of::<f64>())
+                .sum(),
+            Value::Stream(stream) => stream
+                .iter()
+                .map(|(_, fields)| {
+                    std::mem::size_of::<StreamId>()
+                        + fields
+                            .iter()
+                            .map(|(field, value)| field.len() + value.len())
+                            .sum::<usize>()
+                })
+                .sum(),
+            #[cfg(feature = "json")]
+            Value::Json(document) => serde_json::to_vec(document).map(|v| v.len()).unwrap_or(0),
+        }
+    }
+
+    /// Number of allocations that dropping this value will release
+    ///
+    /// Used to decide whether a value is large enough to be freed
+    /// on the background lazy-free thread.
+    pub fn free_effort(&self) -> usize {
+        match self {
+            Value::String(_) => 1,
+            #[cfg(feature = "compression")]
+            Value::Compressed(_) => 1,
+            Value::List(list) => list.len(),
+            Value::Set(set) => set.len(),
+            Value::Hash(hash) => hash.len(),
+            Value::ZSet(zset) => zset.len(),
+            Value::Stream(stream) => stream.len(),
+            #[cfg(feature = "json")]
+            Value::Json(_) => 1,
+        }
+    }
+
+    fn as_list_mut(&mut self) -> Option<&mut ListValue> {
+        match self {
+            Value::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    fn as_set_mut(&mut self) -> Option<&mut SetValue> {
+        match self {
+            Value::Set(set) => Some(set),
+            _ => None,
+        }
+    }
+
+    fn as_hash_mut(&mut self) -> Option<&mut HashValue> {
+        match self {
+            Value::Hash(hash) => Some(hash),
+            _ => None,
+        }
+    }
+
+    fn as_zset_mut(&mut self) -> Option<&mut ZSetValue> {
+        match self {
+            Value::ZSet(zset) => Some(zset),
+            _ => None,
+        }
+    }
+
+    fn as_stream_mut(&mut self) -> Option<&mut StreamValue> {
+        match self {
+            Value::Stream(stream) => Some(stream),
+            _ => None,
+        }
+    }
+
+    /// Internal representation name reported by `OBJECT ENCODING`
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(bytes) => {
+                let is_int = parse_redis_int(bytes).is_ok();
+                if is_int {
+                    "int"
+                } else if bytes.len() <= EMBSTR_SIZE_LIMIT {
+                    "embstr"
+                } else {
+                    "raw"
+                }
+            }
+            #[cfg(feature = "compression")]
+            Value::Compressed(_) => "compressed",
+            Value::List(list) => list.encoding(),
+            Value::Set(set) => set.encoding(),
+            Value::Hash(hash) => hash.encoding(),
+            Value::ZSet(zset) => zset.encoding(),
+            Value::Stream(_) => "stream",
+            #[cfg(feature = "json")]
+            Value::Json(_) => "json",
+        }
+    }
+
+    /// Copy the value without sharing any storage with `self`
+    ///
+    /// The derived `Clone` is already independent for mutation, but its
+    /// `Bytes` still point into the original buffers, which then stay
+    /// allocated for as long as the copy lives. COPY uses this instead so
+    /// a copy owns its memory outright. The encoding (intset, listpack,
+    /// ...) is preserved.
+    pub fn deep_clone(&self) -> Value {
+        let copy = |bytes: &Bytes| Bytes::copy_from_slice(bytes);
+        match self {
+            Value::String(bytes) => Value::String(copy(bytes)),
+            #[cfg(feature = "compression")]
+            Value::Compressed(compressed) => Value::Compressed(compressed.deep_clone()),
+            Value::List(list) => Value::List(list.map(copy)),
+            Value::Set(SetValue::IntSet(ints)) => Value::Set(SetValue::IntSet(ints.clone())),
+            Value::Set(SetValue::Table(table)) => {
+                Value::Set(SetValue::Table(table.iter().map(copy).collect()))
+            }
+            Value::Hash(HashValue::Listpack(entries)) => Value::Hash(HashValue::Listpack(
+                entries
+                    .iter()
+                    .map(|(field, value)| (copy(field), copy(value)))
+                    .collect(),
+            )),
+            Value::Hash(HashValue::Table(table)) => Value::Hash(HashValue::Table(
+                table
+                    .iter()
+                    .map(|(field, value)| (copy(field), copy(value)))
+                    .collect(),
+            )),
+            Value::ZSet(ZSetValue::Listpack(entries)) => Value::ZSet(ZSetValue::Listpack(
+                entries
+                    .iter()
+                    .map(|(score, member)| (*score, copy(member)))
+                    .collect(),
+            )),
+            Value::ZSet(ZSetValue::Skiplist { ordered, .. }) => {
+                let ordered: BTreeSet<_> = ordered
+                    .iter()
+                    .map(|(score, member)| (*score, copy(member)))
+                    .collect();
+                let scores = ordered
+                    .iter()
+                    .map(|(score, member)| (member.clone(), *score))
+                    .collect();
+                Value::ZSet(ZSetValue::Skiplist { scores, ordered })
+            }
+            Value::Stream(stream) => Value::Stream(stream.map(copy)),
+            #[cfg(feature = "json")]
+            Value::Json(document) => Value::Json(document.clone()),
+        }
+    }
+}
+
+/// Point-in-time copy of a database, taken by `Db::snapshot_clone`
+///
+/// Holds every entry that was live when it was taken as
+/// `(key, value, expires_at)`, unaffected by later writes to the database.
+#[derive(Clone, Debug, Default)]
+pub struct DbSnapshot {
+    entries: Vec<(Bytes, Value, Option<Instant>)>,
+}
+
+impl DbSnapshot {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Value and expiration of `key` at the time of the snapshot
+    pub fn get(&self, key: &[u8]) -> Option<(&Value, Option<Instant>)> {
+        self.entries
+            .iter()
+            .find(|(existing, _, _)| existing == key)
+            .map(|(_, value, expires_at)| (value, *expires_at))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &Value, Option<Instant>)> {
+        self.entries
+            .iter()
+            .map(|(key, value, expires_at)| (key, value, *expires_at))
+    }
+}
+
+/// Whole-database memory report returned by `MEMORY STATS`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryStats {
+    /// Total number of keys
+    pub keys_count: usize,
+
+    /// Sum of `Value::approx_size` over all values
+    pub dataset_bytes: usize,
+
+    /// Key bytes plus the fixed per-entry bookkeeping cost
+    pub overhead_bytes: usize,
+
+    /// Number of keys holding each value type
+    pub strings: usize,
+    pub lists: usize,
+    pub sets: usize,
+    pub hashes: usize,
+    pub zsets: usize,
+    pub streams: usize,
 }
 
 /// Shared database handle
@@ -31,6 +343,29 @@ impl Value {
 pub struct Db {
     /// The shared state containing the actual HashMap
     shared: Arc<Mutex<DbState>>,
+
+    /// Background dropper for large deleted values
+    lazyfree: Arc<LazyFree>,
+
+    /// Where keyspace events go, `None` when they are disabled
+    notifier: Arc<RwLock<Option<Notifier>>>,
+
+    /// Where changes are reported to an embedder, see `set_change_observer`
+    changes: Arc<ChangeFeed>,
+
+    /// Clients blocked on list keys
+    waiters: Arc<KeyWaiters>,
+
+    /// Taken shared by every operation and exclusively by `transaction`
+    exclusive: Arc<RwLock<()>>,
+
+    /// Whether this handle is the one passed into `transaction`, which
+    /// already holds `exclusive`
+    in_transaction: bool,
+
+    /// Times the state has been locked, for tests of batched access
+    #[cfg(test)]
+    locks: Arc<AtomicUsize>,
 }
 
 /// Database entry with optional expiration
@@ -40,12 +375,337 @@ struct Entry {
 
     /// Optional expiration time
     expires_at: Option<Instant>,
+
+    /// Identifies this incarnation of the key; a key that is deleted and
+    /// recreated gets a new generation
+    generation: u64,
+
+    /// Bumped on every mutation of the value
+    version: u64,
+
+    /// When a command last read or wrote the key, for LRU eviction
+    last_access: Instant,
+
+    /// Memory held by the value and this entry when last measured, see
+    /// `eviction::measure`
+    size: usize,
 }
 
 /// The actual database state
-struct DbState {
+///
+/// Only reachable from outside through `EvictionPolicy::pick_victims`.
+pub struct DbState {
     /// Key-value storage supporting multiple data types
-    entries: HashMap<String, Entry>,
+    entries: Keyspace,
+
+    /// Last generation handed out to a newly created entry
+    next_generation: u64,
+
+    /// Hashes with more fields than this use a hashtable encoding
+    hash_max_listpack_entries: usize,
+
+    /// Lists with more elements than this use a quicklist encoding
+    list_max_listpack_size: usize,
+
+    /// Integer sets with more members than this use a hashtable encoding
+    set_max_intset_entries: usize,
+
+    /// Sorted sets with more members than this use a skiplist encoding
+    zset_max_listpack_entries: usize,
+
+    /// Expiry given to keys created without one, `None` to keep them forever
+    default_ttl: Option<Duration>,
+
+    /// Sort the replies of KEYS, SMEMBERS and HGETALL
+    deterministic_iteration: bool,
+
+    /// Source of the picks of SPOP and SRANDMEMBER
+    rng: DbRng,
+
+    /// Most keys KEYS returns before failing instead, 0 for no limit
+    keys_max_results: usize,
+
+    /// Strings at least this long are stored compressed, 0 to never do so
+    #[cfg(feature = "compression")]
+    string_compression_threshold: usize,
+
+    /// Keys removed because they expired, not yet announced
+    expired: Vec<Bytes>,
+
+    /// Keys whose values may have changed in place, measured again before
+    /// the lock is released
+    touched: Vec<Bytes>,
+}
+
+impl DbState {
+    /// Insert a brand-new entry, replacing any existing one
+    fn insert(&mut self, key: Bytes, value: Value, expires_at: Option<Instant>) {
+        #[cfg(feature = "compression")]
+        let value = match value {
+            Value::String(bytes)
+                if self.string_compression_threshold > 0
+                    && bytes.len() >= self.string_compression_threshold =>
+            {
+                CompressedString::compress(bytes).map_or_else(Value::String, Value::Compressed)
+            }
+            value => value,
+        };
+        self.next_generation += 1;
+        let entry = Entry {
+            size: eviction::measure(&value),
+            value,
+            expires_at: expires_at.or_else(|| self.default_expiry()),
+            generation: self.next_generation,
+            version: 0,
+            last_access: Instant::now(),
+        };
+        self.entries.insert(key, entry);
+    }
+
+    /// Get the entry for `key` if it exists, removing it if it has expired
+    fn live_entry_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        let expired = self
+            .entries
+            .get(key)?
+            .expires_at
+            .is_some_and(|expires_at| Instant::now() >= expires_at);
+        if expired {
+            self.remove_expired(key);
+            return None;
+        }
+        let entry = self.entries.get_mut(key)?;
+        entry.last_access = Instant::now();
+        Some(entry)
+    }
+
+    /// The live String value at `key`, see `Db::read_string`
+    fn read_string(&mut self, key: &[u8]) -> Option<Bytes> {
+        self.live_entry_mut(key)?.value.as_string()
+    }
+
+    /// `live_entry_mut` for commands that modify a string in place
+    ///
+    /// A compressed string is stored uncompressed from then on, until it is
+    /// overwritten.
+    fn live_string_entry_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        self.touch(key);
+        let entry = self.live_entry_mut(key)?;
+        #[cfg(feature = "compression")]
+        if let Value::Compressed(compressed) = &entry.value {
+            entry.value = Value::String(compressed.decompress());
+        }
+        Some(entry)
+    }
+
+    /// Get the live value at `key` as the type `as_type` selects
+    ///
+    /// Returns `Ok(None)` if the key does not exist and `WRONGTYPE_ERR` if it
+    /// holds another type. The entry's version comes along so that
+    /// mutations can bump it.
+    fn live_value_mut<T>(
+        &mut self,
+        key: &[u8],
+        as_type: impl FnOnce(&mut Value) -> Option<&mut T>,
+    ) -> Result<Option<(&mut T, &mut u64)>, &'static str> {
+        self.touch(key);
+        match self.live_entry_mut(key) {
+            Some(Entry { value, version, .. }) => match as_type(value) {
+                Some(value) => Ok(Some((value, version))),
+                None => Err(WRONGTYPE_ERR),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Remove a key whose TTL elapsed, queueing its `expired` event
+    fn remove_expired(&mut self, key: &[u8]) {
+        if self.entries.remove(key).is_some() {
+            self.expired.push(Bytes::copy_from_slice(key));
+        }
+    }
+
+    /// Get the entry for `key`, creating it from `make` if it doesn't exist
+    ///
+    /// A live entry is returned as is, keeping its expiration. An entry
+    /// whose expiration has passed is removed first, so it is replaced
+    /// rather than modified and taking its stale deadline along.
+    fn entry_or_insert_with(&mut self, key: Bytes, make: impl FnOnce() -> Value) -> &mut Entry {
+        self.live_entry_mut(&key);
+        self.touched.push(key.clone());
+        let expires_at = self.default_expiry();
+        let next_generation = &mut self.next_generation;
+        self.entries.get_or_insert_with(key, || {
+            *next_generation += 1;
+            let value = make();
+            Entry {
+                size: eviction::measure(&value),
+                value,
+                expires_at,
+                generation: *next_generation,
+                version: 0,
+                last_access: Instant::now(),
+            }
+        })
+    }
+
+    /// Expiry of a key created now without an explicit one
+    fn default_expiry(&self) -> Option<Instant> {
+        self.default_ttl.map(|ttl| Instant::now() + ttl)
+    }
+
+    /// Note that the value at `key` is about to be changed in place
+    fn touch(&mut self, key: &[u8]) {
+        self.touched.push(Bytes::copy_from_slice(key));
+    }
+
+    /// Measure the values of the touched keys again, see `used_memory`
+    fn measure_touched(&mut self) {
+        for key in std::mem::take(&mut self.touched) {
+            self.entries.measure_small(&key);
+        }
+    }
+}
+
+/// Lock on `DbState` that announces expired keys once released
+///
+/// Keys can expire deep inside any operation, while the lock is held.
+/// They are only queued there; the events are published after the mutex
+/// is unlocked so Pub/Sub is never entered under the database lock.
+struct StateGuard<'a> {
+    state: Option<MutexGuard<'a, DbState>>,
+    notifier: &'a RwLock<Option<Notifier>>,
+
+    /// Keeps a transaction from starting while the operation runs; `None`
+    /// inside the transaction itself
+    _shared: Option<RwLockReadGuard<'a, ()>>,
+}
+
+impl Deref for StateGuard<'_> {
+    type Target = DbState;
+
+    fn deref(&self) -> &DbState {
+        self.state.as_ref().expect("state guard already released")
+    }
+}
+
+impl DerefMut for StateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut DbState {
+        self.state.as_mut().expect("state guard already released")
+    }
+}
+
+impl Drop for StateGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.as_mut() {
+            state.measure_touched();
+        }
+        let expired = match self.state.as_mut() {
+            Some(state) if !state.expired.is_empty() => std::mem::take(&mut state.expired),
+            _ => return,
+        };
+        // Unlock first
+        self.state = None;
+
+        if let Some(notifier) = read(self.notifier).as_ref() {
+            for key in &expired {
+                notifier.expired(key);
+            }
+        }
+    }
+}
+
+/// Up to `count` distinct items of `items`, in random order
+fn pick_distinct(rng: &mut DbRng, mut items: Vec<Bytes>, count: usize) -> Vec<Bytes> {
+    let count = count.min(items.len());
+    // Partial Fisher-Yates: the first `count` slots end up a random pick
+    for i in 0..count {
+        let pick = i + rng.index(items.len() - i);
+        items.swap(i, pick);
+    }
+    items.truncate(count);
+    items
+}
+
+/// `current + delta` formatted for INCRBYFLOAT
+fn float_sum(current: f64, delta: f64) -> Result<Bytes, &'static str> {
+    let value = current + delta;
+    if !value.is_finite() {
+        return Err("ERR increment would produce NaN or Infinity");
+    }
+    Ok(Bytes::from(value.to_string()))
+}
+
+/// Snapshot of a key taken by WATCH: `(generation, version)`, or `None`
+/// if the key did not exist
+pub type WatchVersion = Option<(u64, u64)>;
+
+/// Hands large values to a background thread so they are not dropped
+/// by the task that removed them
+struct LazyFree {
+    /// Whether DEL should defer freeing (UNLINK always does)
+    lazy_user_del: AtomicBool,
+
+    /// Channel to the drop thread, created on first use
+    sender: Mutex<Option<mpsc::Sender<Box<dyn Send>>>>,
+
+    /// Objects queued but not yet dropped
+    pending: Arc<AtomicUsize>,
+
+    /// Objects dropped by the background thread so far
+    freed: Arc<AtomicUsize>,
+}
+
+impl LazyFree {
+    fn new() -> Self {
+        LazyFree {
+            lazy_user_del: AtomicBool::new(false),
+            sender: Mutex::new(None),
+            pending: Arc::new(AtomicUsize::new(0)),
+            freed: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Free a value, deferring the drop to the background thread if it is large
+    fn free(&self, value: Value) {
+        if value.free_effort() > LAZYFREE_THRESHOLD {
+            self.defer(Box::new(value));
+        }
+    }
+
+    /// Free a keyspace taken out of a database, in the background unless
+    /// it is tiny
+    fn free_keyspace(&self, keyspace: Keyspace) {
+        if keyspace.len() > LAZYFREE_THRESHOLD {
+            self.defer(Box::new(keyspace));
+        }
+    }
+
+    fn defer(&self, garbage: Box<dyn Send>) {
+        let mut sender = lock(&self.sender);
+        let sender = sender.get_or_insert_with(|| {
+            let (tx, rx) = mpsc::channel::<Box<dyn Send>>();
+            let pending = Arc::clone(&self.pending);
+            let freed = Arc::clone(&self.freed);
+            std::thread::Builder::new()
+                .name("lazyfree".to_string())
+                .spawn(move || {
+                    for garbage in rx {
+                        drop(garbage);
+                        pending.fetch_sub(1, Ordering::Relaxed);
+                        freed.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+                .expect("failed to spawn lazyfree thread");
+            tx
+        });
+
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        if let Err(mpsc::SendError(garbage)) = sender.send(garbage) {
+            // Drop thread is gone; free inline instead
+            self.pending.fetch_sub(1, Ordering::Relaxed);
+            drop(garbage);
+        }
+    }
 }
 
 impl Db {
@@ -53,66 +713,479 @@ impl Db {
     pub fn new() -> Db {
         Db {
             shared: Arc::new(Mutex::new(DbState {
-                entries: HashMap::new(),
+                entries: Keyspace::new(),
+                next_generation: 0,
+                hash_max_listpack_entries: DEFAULT_HASH_MAX_LISTPACK_ENTRIES,
+                list_max_listpack_size: DEFAULT_LIST_MAX_LISTPACK_SIZE,
+                set_max_intset_entries: DEFAULT_SET_MAX_INTSET_ENTRIES,
+                zset_max_listpack_entries: DEFAULT_ZSET_MAX_LISTPACK_ENTRIES,
+                default_ttl: None,
+                deterministic_iteration: false,
+                rng: DbRng::default(),
+                keys_max_results: 0,
+                #[cfg(feature = "compression")]
+                string_compression_threshold: DEFAULT_STRING_COMPRESSION_THRESHOLD,
+                expired: Vec::new(),
+                touched: Vec::new(),
             })),
+            lazyfree: Arc::new(LazyFree::new()),
+            notifier: Arc::new(RwLock::new(None)),
+            changes: Arc::new(ChangeFeed::default()),
+            waiters: Arc::new(KeyWaiters::default()),
+            exclusive: Arc::new(RwLock::new(())),
+            in_transaction: false,
+            #[cfg(test)]
+            locks: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Take the random picks of SPOP and SRANDMEMBER from `rng`
+    ///
+    /// For tests: with `DbRng::seeded` and integer members (or
+    /// deterministic iteration, which sorts the members first), the picks
+    /// are the same on every run.
+    pub fn with_rng(self, rng: DbRng) -> Db {
+        self.lock().rng = rng;
+        self
+    }
+
+    /// Lock the database state
+    fn lock(&self) -> StateGuard<'_> {
+        #[cfg(test)]
+        self.locks.fetch_add(1, Ordering::Relaxed);
+        let shared = if self.in_transaction {
+            None
+        } else {
+            Some(read(&self.exclusive))
+        };
+        StateGuard {
+            state: Some(lock(&self.shared)),
+            notifier: &self.notifier,
+            _shared: shared,
         }
     }
 
+    /// Run `f` with the database to itself (MULTI/EXEC)
+    ///
+    /// Every operation normally locks the state on its own, so other
+    /// clients could run between two of them. Here other clients' operations
+    /// wait until `f` returns, while the handle passed to `f` offers all the
+    /// usual operations without waiting. That handle must not be kept
+    /// beyond `f`, and `f` must not use `self`, which would deadlock.
+    pub fn transaction<R>(&self, f: impl FnOnce(&Db) -> R) -> R {
+        let result = {
+            let _exclusive = write(&self.exclusive);
+            let db = Db {
+                in_transaction: true,
+                ..self.clone()
+            };
+            f(&db)
+        };
+        self.changes.flush_deferred();
+        result
+    }
+
+    /// Publish keyspace events through `notifier`, or stop publishing them
+    pub fn set_notifier(&self, notifier: Option<Notifier>) {
+        *write(&self.notifier) = notifier;
+    }
+
+    /// Report every change made to this database, the one at `index`, to
+    /// `observer`, or stop reporting them
+    ///
+    /// Meant for embedders streaming changes elsewhere (CDC); unlike the
+    /// AOF it sees each write as a structured `ChangeEvent`.
+    pub fn set_change_observer(&self, index: usize, observer: Option<ChangeObserver>) {
+        self.changes.set(index, observer);
+    }
+
+    /// Whether a change observer is registered
+    pub fn has_change_observer(&self) -> bool {
+        self.changes.is_enabled()
+    }
+
+    /// Report a change made by `command` to the change observer, if any
+    ///
+    /// Must be called without the database locked. Inside a transaction the
+    /// event is delivered once the transaction ends.
+    pub fn record_change(&self, command: &'static str, key: Option<Bytes>) {
+        self.changes.publish(command, key, self.in_transaction);
+    }
+
+    /// Enable or disable lazy freeing of large values removed by DEL
+    pub fn set_lazyfree_lazy_user_del(&self, enabled: bool) {
+        self.lazyfree
+            .lazy_user_del
+            .store(enabled, Ordering::Relaxed);
+    }
+
+    /// Set the field count above which hashes are promoted to a hashtable
+    pub fn set_hash_max_listpack_entries(&self, entries: usize) {
+        self.lock().hash_max_listpack_entries = entries;
+    }
+
+    /// Set the element count above which lists are promoted to a quicklist
+    pub fn set_list_max_listpack_size(&self, size: usize) {
+        self.lock().list_max_listpack_size = size;
+    }
+
+    /// Set the member count above which integer sets become a hashtable
+    pub fn set_set_max_intset_entries(&self, entries: usize) {
+        self.lock().set_max_intset_entries = entries;
+    }
+
+    /// Set the member count above which sorted sets become a skiplist
+    pub fn set_zset_max_listpack_entries(&self, entries: usize) {
+        self.lock().zset_max_listpack_entries = entries;
+    }
+
+    /// Give keys created from now on without an expiry one `ttl` away
+    ///
+    /// Existing keys and writes with an explicit expiry are unaffected.
+    pub fn set_default_ttl(&self, ttl: Option<Duration>) {
+        self.lock().default_ttl = ttl;
+    }
+
+    /// Return keys, set members and hash fields sorted by their bytes
+    /// instead of in hash order, for tests that compare replies
+    pub fn set_deterministic_iteration(&self, enabled: bool) {
+        self.lock().deterministic_iteration = enabled;
+    }
+
+    /// Make KEYS fail with `KEYS_TOO_LARGE_ERR` once more than `max` keys
+    /// match, 0 for no limit
+    pub fn set_keys_max_results(&self, max: usize) {
+        self.lock().keys_max_results = max;
+    }
+
+    /// Store strings written from now on compressed if they are at least
+    /// `threshold` bytes long and compress well; 0 disables compression
+    #[cfg(feature = "compression")]
+    pub fn set_string_compression_threshold(&self, threshold: usize) {
+        self.lock().string_compression_threshold = threshold;
+    }
+
+    /// Number of values waiting to be dropped by the lazy-free thread
+    pub fn lazyfree_pending_objects(&self) -> usize {
+        self.lazyfree.pending.load(Ordering::Relaxed)
+    }
+
+    /// Number of values and flushed keyspaces dropped by the lazy-free
+    /// thread so far
+    pub fn lazyfreed_objects(&self) -> usize {
+        self.lazyfree.freed.load(Ordering::Relaxed)
+    }
+
     /// Read a String value from the database
     ///
     /// Returns None if:
     /// - The key doesn't exist
     /// - The key has expired
     /// - The key contains a non-String value
-    pub fn read_string(&self, key: &str) -> Option<Bytes> {
-        let mut state = self.shared.lock().unwrap();
+    pub fn read_string(&self, key: &[u8]) -> Option<Bytes> {
+        self.lock().read_string(key)
+    }
+
+    /// `read_string` for several keys under a single lock
+    ///
+    /// Serves a pipelined run of GETs without locking once per command.
+    pub fn read_strings<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) -> Vec<Option<Bytes>> {
+        let mut state = self.lock();
+        keys.into_iter().map(|key| state.read_string(key)).collect()
+    }
 
-        // Check if the entry exists
-        let entry = state.entries.get(key)?;
+    /// Number of times the state has been locked so far
+    #[cfg(test)]
+    pub(crate) fn lock_count(&self) -> usize {
+        self.locks.load(Ordering::Relaxed)
+    }
 
-        // Check if the entry has expired
-        if let Some(expires_at) = entry.expires_at {
-            if Instant::now() >= expires_at {
-                // Remove expired entry
-                state.entries.remove(key);
-                return None;
+    /// Write a String value to the database with optional expiration
+    pub fn write_string(&self, key: Bytes, value: Bytes, expires_at: Option<Instant>) {
+        let mut state = self.lock();
+        state.insert(key, Value::String(value), expires_at);
+    }
+
+    /// Read the String at `key` and set its expiration to `expires_at`, or
+    /// remove it with `persist` (GETEX)
+    ///
+    /// A deadline that has already passed deletes the key. Returns `None`,
+    /// changing nothing, if the key does not exist or is not a String.
+    pub fn getex(&self, key: &[u8], expires_at: Option<Instant>, persist: bool) -> Option<Bytes> {
+        let mut state = self.lock();
+        let value = state.read_string(key)?;
+        if expires_at.is_some_and(|at| at <= Instant::now()) {
+            state.entries.remove(key);
+        } else if expires_at.is_some() || persist {
+            let entry = state.live_entry_mut(key)?;
+            entry.expires_at = expires_at;
+            entry.version += 1;
+        }
+        Some(value)
+    }
+
+    /// Write a String value (SET), only if `key` exists (`Some(true)`, XX)
+    /// or only if it does not (`Some(false)`, NX)
+    ///
+    /// A deadline that has already passed deletes the key instead, as if it
+    /// was written and expired right away. Returns whether the condition
+    /// held.
+    pub fn set_string(
+        &self,
+        key: Bytes,
+        value: Bytes,
+        expires_at: Option<Instant>,
+        exists: Option<bool>,
+    ) -> bool {
+        let mut state = self.lock();
+        if exists.is_some_and(|exists| state.live_entry_mut(&key).is_some() != exists) {
+            return false;
+        }
+        if expires_at.is_some_and(|at| at <= Instant::now()) {
+            state.entries.remove(&key);
+        } else {
+            state.insert(key, Value::String(value), expires_at);
+        }
+        true
+    }
+
+    /// Append to the String at `key`, creating it if missing
+    ///
+    /// Returns the new length.
+    pub fn append(&self, key: &[u8], value: Bytes) -> Result<usize, &'static str> {
+        let mut state = self.lock();
+
+        match state.live_string_entry_mut(key) {
+            Some(Entry {
+                value: Value::String(current),
+                version,
+                ..
+            }) => {
+                if current.len() + value.len() > MAX_STRING_LEN {
+                    return Err("ERR string exceeds maximum allowed size (proto-max-bulk-len)");
+                }
+                let mut buf = Vec::with_capacity(current.len() + value.len());
+                buf.extend_from_slice(current);
+                buf.extend_from_slice(&value);
+                *current = Bytes::from(buf);
+                *version += 1;
+                Ok(current.len())
+            }
+            Some(_) => Err(WRONGTYPE_ERR),
+            None => {
+                let len = value.len();
+                state.insert(Bytes::copy_from_slice(key), Value::String(value), None);
+                Ok(len)
             }
         }
+    }
 
-        // Return value only if it's a String type
-        match &entry.value {
-            Value::String(bytes) => Some(bytes.clone()),
-            _ => None,
+    /// Overwrite part of the String at `key` starting at `offset`
+    ///
+    /// The string is zero-padded if it is shorter than `offset`. A missing
+    /// key is created unless `value` is empty. Returns the new length.
+    pub fn setrange(&self, key: &[u8], offset: usize, value: Bytes) -> Result<usize, &'static str> {
+        let mut state = self.lock();
+
+        let existing = match state.live_string_entry_mut(key) {
+            Some(Entry {
+                value: Value::String(current),
+                ..
+            }) => Some(current.len()),
+            Some(_) => return Err(WRONGTYPE_ERR),
+            None => None,
+        };
+        if value.is_empty() {
+            return Ok(existing.unwrap_or(0));
         }
+        if offset + value.len() > MAX_STRING_LEN {
+            return Err("ERR string exceeds maximum allowed size (proto-max-bulk-len)");
+        }
+
+        let entry =
+            state.entry_or_insert_with(Bytes::copy_from_slice(key), || Value::String(Bytes::new()));
+        let current = match &mut entry.value {
+            Value::String(current) => current,
+            _ => unreachable!("type checked above"),
+        };
+        let mut buf = current.to_vec();
+        if buf.len() < offset + value.len() {
+            buf.resize(offset + value.len(), 0);
+        }
+        buf[offset..offset + value.len()].copy_from_slice(&value);
+        *current = Bytes::from(buf);
+        entry.version += 1;
+        Ok(current.len())
     }
 
-    /// Write a String value to the database with optional expiration
-    pub fn write_string(&self, key: String, value: Bytes, expires_at: Option<Instant>) {
-        let mut state = self.shared.lock().unwrap();
+    /// Substring of the String at `key`, with Redis's inclusive, negative-aware range
+    pub fn getrange(&self, key: &[u8], start: isize, end: isize) -> Result<Bytes, &'static str> {
+        let mut state = self.lock();
 
-        let entry = Entry {
-            value: Value::String(value),
-            expires_at,
+        let current = match state.live_entry_mut(key) {
+            Some(entry) => entry.value.as_string().ok_or(WRONGTYPE_ERR)?,
+            None => return Ok(Bytes::new()),
         };
 
-        state.entries.insert(key, entry);
+        let (start, end) = normalize_range(start, end, current.len());
+        Ok(current.slice(start..end))
+    }
+
+    /// Longest common subsequence of the Strings at `key1` and `key2`
+    ///
+    /// Missing keys count as empty strings. The values are compared after
+    /// the lock is released; inputs whose DP table would exceed
+    /// `LCS_MAX_CELLS` are refused.
+    pub fn lcs(&self, key1: &[u8], key2: &[u8]) -> Result<Lcs, &'static str> {
+        let (a, b) = {
+            let mut state = self.lock();
+            let mut read = |key: &[u8]| match state.live_entry_mut(key) {
+                Some(entry) => entry.value.as_string().ok_or(WRONGTYPE_ERR),
+                None => Ok(Bytes::new()),
+            };
+            (read(key1)?, read(key2)?)
+        };
+        lcs::lcs(&a, &b)
+    }
+
+    /// Set or clear one bit of the String at `key`, returning the previous bit
+    pub fn setbit(&self, key: &[u8], offset: u64, on: bool) -> Result<u8, &'static str> {
+        let mut state = self.lock();
+
+        if let Some(entry) = state.live_string_entry_mut(key) {
+            if !matches!(entry.value, Value::String(_)) {
+                return Err(WRONGTYPE_ERR);
+            }
+        }
+
+        let byte = (offset >> 3) as usize;
+        let mask = 1u8 << (7 - (offset & 7));
+        let entry =
+            state.entry_or_insert_with(Bytes::copy_from_slice(key), || Value::String(Bytes::new()));
+        let current = match &mut entry.value {
+            Value::String(current) => current,
+            _ => unreachable!("type checked above"),
+        };
+
+        let mut buf = current.to_vec();
+        if buf.len() <= byte {
+            buf.resize(byte + 1, 0);
+        }
+        let previous = (buf[byte] & mask != 0) as u8;
+        if on {
+            buf[byte] |= mask;
+        } else {
+            buf[byte] &= !mask;
+        }
+        *current = Bytes::from(buf);
+        entry.version += 1;
+        Ok(previous)
+    }
+
+    /// Read one bit of the String at `key` (0 past the end or for missing keys)
+    pub fn getbit(&self, key: &[u8], offset: u64) -> Result<u8, &'static str> {
+        let mut state = self.lock();
+
+        match state.live_entry_mut(key) {
+            Some(entry) => {
+                let current = entry.value.as_string().ok_or(WRONGTYPE_ERR)?;
+                let byte = (offset >> 3) as usize;
+                let mask = 1u8 << (7 - (offset & 7));
+                Ok(current.get(byte).map_or(0, |b| (b & mask != 0) as u8))
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Add `delta` to the integer stored at `key` (missing keys count as 0)
+    ///
+    /// The stored value must parse with `parse_redis_int`; the expiration,
+    /// if any, is kept. Returns the new value.
+    pub fn incr_by(&self, key: &[u8], delta: i64) -> Result<i64, &'static str> {
+        let mut state = self.lock();
+
+        match state.live_string_entry_mut(key) {
+            Some(Entry {
+                value: Value::String(current),
+                version,
+                ..
+            }) => {
+                let value = parse_redis_int(current)
+                    .map_err(|_| "ERR value is not an integer or out of range")?;
+                let value = value
+                    .checked_add(delta)
+                    .ok_or("ERR increment or decrement would overflow")?;
+                *current = Bytes::from(value.to_string());
+                *version += 1;
+                Ok(value)
+            }
+            Some(_) => Err(WRONGTYPE_ERR),
+            None => {
+                let value = Value::String(Bytes::from(delta.to_string()));
+                state.insert(Bytes::copy_from_slice(key), value, None);
+                Ok(delta)
+            }
+        }
+    }
+
+    /// Add `delta` to the float stored at `key`, creating it at 0, and return
+    /// the new value as stored
+    ///
+    /// The value is written in its shortest form that reads back exactly,
+    /// without an exponent and without trailing zeros (`3.0` becomes `3`).
+    pub fn incr_by_float(&self, key: &[u8], delta: f64) -> Result<Bytes, &'static str> {
+        let mut state = self.lock();
+
+        match state.live_string_entry_mut(key) {
+            Some(Entry {
+                value: Value::String(current),
+                version,
+                ..
+            }) => {
+                let value = parse_redis_float(current).ok_or("ERR value is not a valid float")?;
+                let value = float_sum(value, delta)?;
+                *current = value.clone();
+                *version += 1;
+                Ok(value)
+            }
+            Some(_) => Err(WRONGTYPE_ERR),
+            None => {
+                let value = float_sum(0.0, delta)?;
+                state.insert(
+                    Bytes::copy_from_slice(key),
+                    Value::String(value.clone()),
+                    None,
+                );
+                Ok(value)
+            }
+        }
+    }
+
+    /// Write several String values at once, clearing any previous expiration
+    pub fn mset(&self, pairs: Vec<(Bytes, Bytes)>) {
+        let mut state = self.lock();
+        for (key, value) in pairs {
+            state.insert(key, Value::String(value), None);
+        }
     }
 
     /// Get the type of a value
-    pub fn get_type(&self, key: &str) -> Option<&'static str> {
-        let state = self.shared.lock().unwrap();
-        state.entries.get(key).map(|entry| entry.value.type_name())
+    pub fn get_type(&self, key: &[u8]) -> Option<&'static str> {
+        let mut state = self.lock();
+        state
+            .live_entry_mut(key)
+            .map(|entry| entry.value.type_name())
     }
 
     /// Check if a key exists (and hasn't expired)
-    pub fn exists(&self, key: &str) -> bool {
-        let mut state = self.shared.lock().unwrap();
+    pub fn exists(&self, key: &[u8]) -> bool {
+        let mut state = self.lock();
 
         if let Some(entry) = state.entries.get(key) {
             // Check if expired
             if let Some(expires_at) = entry.expires_at {
                 if Instant::now() >= expires_at {
-                    state.entries.remove(key);
+                    state.remove_expired(key);
                     return false;
                 }
             }
@@ -122,147 +1195,302 @@ impl Db {
         }
     }
 
-    /// Delete a key from the database
-    pub fn delete(&self, key: &str) -> bool {
-        let mut state = self.shared.lock().unwrap();
-        state.entries.remove(key).is_some()
+    /// Copy the value and TTL of `source` to `destination`
+    ///
+    /// Returns false, copying nothing, if `source` doesn't exist or
+    /// `destination` does and `replace` is not set.
+    pub fn copy(&self, source: &[u8], destination: &[u8], replace: bool) -> bool {
+        let mut state = self.lock();
+        if !replace && state.live_entry_mut(destination).is_some() {
+            return false;
+        }
+        let (value, expires_at) = match state.live_entry_mut(source) {
+            Some(entry) => (entry.value.deep_clone(), entry.expires_at),
+            None => return false,
+        };
+        state.insert(Bytes::copy_from_slice(destination), value, expires_at);
+        true
+    }
+
+    // ===== Expiration =====
+
+    /// Set the expiration of an existing key, returning false if it is missing
+    pub fn expire_at(&self, key: &[u8], expires_at: Instant) -> bool {
+        let mut state = self.lock();
+        match state.live_entry_mut(key) {
+            Some(entry) => {
+                entry.expires_at = Some(expires_at);
+                entry.version += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remaining time to live in milliseconds
+    ///
+    /// Returns -2 if the key does not exist and -1 if it has no expiration.
+    pub fn pttl(&self, key: &[u8]) -> i64 {
+        let mut state = self.lock();
+        match state.live_entry_mut(key) {
+            Some(entry) => match entry.expires_at {
+                Some(expires_at) => {
+                    let remaining = expires_at.saturating_duration_since(Instant::now());
+                    remaining.as_millis() as i64
+                }
+                None => -1,
+            },
+            None => -2,
+        }
+    }
+
+    /// Remaining time to live in seconds (see `pttl`)
+    pub fn ttl(&self, key: &[u8]) -> i64 {
+        match self.pttl(key) {
+            // Round to the nearest second like Redis
+            ms if ms >= 0 => (ms + 500) / 1000,
+            code => code,
+        }
+    }
+
+    /// Absolute expiration as a Unix timestamp in milliseconds
+    ///
+    /// Returns -2 if the key does not exist and -1 if it has no expiration.
+    pub fn pexpiretime(&self, key: &[u8]) -> i64 {
+        let mut state = self.lock();
+        match state.live_entry_mut(key) {
+            Some(entry) => match entry.expires_at {
+                Some(expires_at) => clock::instant_to_unix_ms(expires_at) as i64,
+                None => -1,
+            },
+            None => -2,
+        }
+    }
+
+    /// Absolute expiration as a Unix timestamp in seconds (see `pexpiretime`)
+    pub fn expiretime(&self, key: &[u8]) -> i64 {
+        match self.pexpiretime(key) {
+            ms if ms >= 0 => ms / 1000,
+            code => code,
+        }
+    }
+
+    /// Delete a key from the database
+    ///
+    /// Large values are freed in the background when
+    /// `lazyfree-lazy-user-del` is enabled.
+    pub fn delete(&self, key: &[u8]) -> bool {
+        let lazy = self.lazyfree.lazy_user_del.load(Ordering::Relaxed);
+        self.remove(key, lazy)
+    }
+
+    /// Delete a key, always freeing large values in the background
+    pub fn unlink(&self, key: &[u8]) -> bool {
+        self.remove(key, true)
+    }
+
+    fn remove(&self, key: &[u8], lazy: bool) -> bool {
+        // Move the value out under the lock so it is dropped after release
+        let removed = {
+            let mut state = self.lock();
+            state.entries.remove(key)
+        };
+
+        match removed {
+            Some(entry) => {
+                if lazy {
+                    self.lazyfree.free(entry.value);
+                }
+                true
+            }
+            None => false,
+        }
     }
 
     // ===== List Operations =====
 
     /// Push values to the left (head) of a list
-    pub fn lpush(&self, key: String, values: Vec<Bytes>) -> usize {
-        let mut state = self.shared.lock().unwrap();
+    pub fn lpush(&self, key: Bytes, values: Vec<Bytes>) -> usize {
+        let mut state = self.lock();
 
-        let entry = state.entries.entry(key).or_insert_with(|| Entry {
-            value: Value::List(VecDeque::new()),
-            expires_at: None,
-        });
+        let max_listpack_size = state.list_max_listpack_size;
+        let entry = state.entry_or_insert_with(key.clone(), || Value::List(ListValue::new()));
 
-        match &mut entry.value {
+        let len = match &mut entry.value {
             Value::List(list) => {
                 for value in values.into_iter().rev() {
-                    list.push_front(value);
+                    list.push_front(value, max_listpack_size);
                 }
+                entry.version += 1;
                 list.len()
             }
             _ => 0, // Type error: key exists but isn't a list
+        };
+
+        drop(state);
+        if len > 0 {
+            self.waiters.wake(&key);
         }
+        len
     }
 
     /// Push values to the right (tail) of a list
-    pub fn rpush(&self, key: String, values: Vec<Bytes>) -> usize {
-        let mut state = self.shared.lock().unwrap();
+    pub fn rpush(&self, key: Bytes, values: Vec<Bytes>) -> usize {
+        let mut state = self.lock();
 
-        let entry = state.entries.entry(key).or_insert_with(|| Entry {
-            value: Value::List(VecDeque::new()),
-            expires_at: None,
-        });
+        let max_listpack_size = state.list_max_listpack_size;
+        let entry = state.entry_or_insert_with(key.clone(), || Value::List(ListValue::new()));
 
-        match &mut entry.value {
+        let len = match &mut entry.value {
             Value::List(list) => {
                 for value in values {
-                    list.push_back(value);
+                    list.push_back(value, max_listpack_size);
                 }
+                entry.version += 1;
                 list.len()
             }
             _ => 0,
+        };
+
+        drop(state);
+        if len > 0 {
+            self.waiters.wake(&key);
         }
+        len
     }
 
     /// Pop a value from the left (head) of a list
-    pub fn lpop(&self, key: &str) -> Option<Bytes> {
-        let mut state = self.shared.lock().unwrap();
+    pub fn lpop(&self, key: &[u8]) -> Option<Bytes> {
+        let mut state = self.lock();
 
-        state
-            .entries
-            .get_mut(key)
-            .and_then(|entry| match &mut entry.value {
-                Value::List(list) => list.pop_front(),
-                _ => None,
-            })
+        let (list, version) = state.live_value_mut(key, Value::as_list_mut).ok()??;
+        let value = list.pop_front();
+        if value.is_some() {
+            *version += 1;
+        }
+        value
     }
 
     /// Pop a value from the right (tail) of a list
-    pub fn rpop(&self, key: &str) -> Option<Bytes> {
-        let mut state = self.shared.lock().unwrap();
+    pub fn rpop(&self, key: &[u8]) -> Option<Bytes> {
+        let mut state = self.lock();
 
-        state
-            .entries
-            .get_mut(key)
-            .and_then(|entry| match &mut entry.value {
-                Value::List(list) => list.pop_back(),
-                _ => None,
-            })
+        let (list, version) = state.live_value_mut(key, Value::as_list_mut).ok()??;
+        let value = list.pop_back();
+        if value.is_some() {
+            *version += 1;
+        }
+        value
+    }
+
+    /// Pop from the first non-empty list among `keys` (BLPOP/BRPOP)
+    ///
+    /// Returns the key that was popped from together with the value.
+    pub fn pop_first(&self, keys: &[Bytes], left: bool) -> Option<(Bytes, Bytes)> {
+        keys.iter().find_map(|key| {
+            let value = if left { self.lpop(key) } else { self.rpop(key) }?;
+            Some((key.clone(), value))
+        })
+    }
+
+    /// Register a client blocked until one of `keys` is pushed or, for a
+    /// stream, added to
+    ///
+    /// Register before trying to pop or read, so that a push racing with
+    /// the attempt still wakes the client.
+    pub fn block_on(&self, keys: &[Bytes]) -> BlockedClient {
+        self.waiters.register(keys)
+    }
+
+    /// Number of keys that have blocked clients
+    pub fn blocked_keys(&self) -> usize {
+        self.waiters.len()
     }
 
     /// Get a range of elements from a list
-    pub fn lrange(&self, key: &str, start: isize, stop: isize) -> Option<Vec<Bytes>> {
-        let state = self.shared.lock().unwrap();
+    pub fn lrange(&self, key: &[u8], start: isize, stop: isize) -> Option<Vec<Bytes>> {
+        let mut state = self.lock();
 
-        state.entries.get(key).and_then(|entry| {
-            match &entry.value {
+        state
+            .live_entry_mut(key)
+            .and_then(|entry| match &entry.value {
                 Value::List(list) => {
-                    let len = list.len() as isize;
-
-                    // Handle negative indices
-                    let start = if start < 0 {
-                        (len + start).max(0)
-                    } else {
-                        start.min(len)
-                    } as usize;
-                    let stop = if stop < 0 {
-                        (len + stop).max(-1) + 1
-                    } else {
-                        (stop + 1).min(len)
-                    } as usize;
-
-                    if start >= stop {
-                        Some(Vec::new())
-                    } else {
-                        Some(
-                            list.iter()
-                                .skip(start)
-                                .take(stop - start)
-                                .cloned()
-                                .collect(),
-                        )
-                    }
+                    let (start, end) = normalize_range(start, stop, list.len());
+                    Some(list.range(start, end))
                 }
                 _ => None,
-            }
-        })
+            })
     }
 
     /// Get the length of a list
-    pub fn llen(&self, key: &str) -> Option<usize> {
-        let state = self.shared.lock().unwrap();
+    pub fn llen(&self, key: &[u8]) -> Option<usize> {
+        let mut state = self.lock();
 
-        state.entries.get(key).and_then(|entry| match &entry.value {
-            Value::List(list) => Some(list.len()),
-            _ => None,
-        })
+        state
+            .live_entry_mut(key)
+            .and_then(|entry| match &entry.value {
+                Value::List(list) => Some(list.len()),
+                _ => None,
+            })
+    }
+
+    /// Insert `value` before or after the first occurrence of `pivot`
+    ///
+    /// Returns the new length, 0 if the key does not exist and -1 if the
+    /// pivot is not in the list.
+    pub fn linsert(
+        &self,
+        key: &[u8],
+        before: bool,
+        pivot: &[u8],
+        value: Bytes,
+    ) -> Result<i64, &'static str> {
+        let mut state = self.lock();
+        let max_listpack_size = state.list_max_listpack_size;
+
+        state.touch(key);
+        match state.live_entry_mut(key) {
+            Some(Entry {
+                value: Value::List(list),
+                version,
+                ..
+            }) => {
+                let position = list.iter().position(|item| item.as_ref() == pivot);
+                match position {
+                    Some(index) => {
+                        let index = if before { index } else { index + 1 };
+                        list.insert(index, value, max_listpack_size);
+                        *version += 1;
+                        Ok(list.len() as i64)
+                    }
+                    None => Ok(-1),
+                }
+            }
+            Some(_) => Err(WRONGTYPE_ERR),
+            None => Ok(0),
+        }
     }
 
     // ===== Set Operations =====
 
     /// Add members to a set
-    pub fn sadd(&self, key: String, members: Vec<String>) -> usize {
-        let mut state = self.shared.lock().unwrap();
+    pub fn sadd(&self, key: Bytes, members: Vec<Bytes>) -> usize {
+        let mut state = self.lock();
+        let max_intset_entries = state.set_max_intset_entries;
 
-        let entry = state.entries.entry(key).or_insert_with(|| Entry {
-            value: Value::Set(HashSet::new()),
-            expires_at: None,
-        });
+        let entry = state.entry_or_insert_with(key, || Value::Set(SetValue::new()));
 
         match &mut entry.value {
             Value::Set(set) => {
                 let mut added = 0;
                 for member in members {
-                    if set.insert(member) {
+                    if set.insert(member, max_intset_entries) {
                         added += 1;
                     }
                 }
+                if added > 0 {
+                    entry.version += 1;
+                }
                 added
             }
             _ => 0,
@@ -270,164 +1498,682 @@ impl Db {
     }
 
     /// Remove members from a set
-    pub fn srem(&self, key: &str, members: Vec<String>) -> usize {
-        let mut state = self.shared.lock().unwrap();
+    ///
+    /// Returns the number of members removed, 0 if the key does not exist.
+    pub fn srem(&self, key: &[u8], members: Vec<Bytes>) -> Result<usize, &'static str> {
+        let mut state = self.lock();
 
-        state
-            .entries
-            .get_mut(key)
-            .map(|entry| match &mut entry.value {
-                Value::Set(set) => {
-                    let mut removed = 0;
-                    for member in members {
-                        if set.remove(&member) {
-                            removed += 1;
-                        }
-                    }
-                    removed
-                }
-                _ => 0,
-            })
-            .unwrap_or(0)
+        let Some((set, version)) = state.live_value_mut(key, Value::as_set_mut)? else {
+            return Ok(0);
+        };
+        let mut removed = 0;
+        for member in members {
+            if set.remove(&member) {
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            *version += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Remove up to `count` random members from a set and return them
+    ///
+    /// The members are picked from a copy of the set, so this is O(n).
+    pub fn spop(&self, key: &[u8], count: usize) -> Result<Vec<Bytes>, &'static str> {
+        let mut state = self.lock();
+
+        let sorted = state.deterministic_iteration;
+        let mut members: Vec<Bytes> = match state.live_value_mut(key, Value::as_set_mut)? {
+            Some((set, _)) => set.iter().collect(),
+            None => return Ok(Vec::new()),
+        };
+        if sorted {
+            members.sort_unstable();
+        }
+        let members = pick_distinct(&mut state.rng, members, count);
+        if let Some((set, version)) = state.live_value_mut(key, Value::as_set_mut)? {
+            for member in &members {
+                set.remove(member);
+            }
+            if !members.is_empty() {
+                *version += 1;
+            }
+        }
+        Ok(members)
     }
 
-    /// Get all members of a set
-    pub fn smembers(&self, key: &str) -> Option<Vec<String>> {
-        let state = self.shared.lock().unwrap();
+    /// Random members of a set, without removing them
+    ///
+    /// A non-negative `count` picks up to that many distinct members; a
+    /// negative one picks exactly `-count`, possibly the same one several
+    /// times, up to `MAX_REPEATED_PICKS`. The members are picked from a copy
+    /// of the set, so this is O(n).
+    pub fn srandmember(&self, key: &[u8], count: i64) -> Result<Vec<Bytes>, &'static str> {
+        // Built under the lock, so the picks are bounded up front
+        if count < 0 && count.unsigned_abs() > MAX_REPEATED_PICKS as u64 {
+            return Err("ERR value is out of range");
+        }
+        let mut state = self.lock();
 
-        state.entries.get(key).and_then(|entry| match &entry.value {
-            Value::Set(set) => Some(set.iter().cloned().collect()),
-            _ => None,
-        })
+        let sorted = state.deterministic_iteration;
+        let mut members: Vec<Bytes> = match state.live_value_mut(key, Value::as_set_mut)? {
+            Some((set, _)) => set.iter().collect(),
+            None => return Ok(Vec::new()),
+        };
+        if sorted {
+            members.sort_unstable();
+        }
+        let rng = &mut state.rng;
+        match usize::try_from(count) {
+            Ok(count) => Ok(pick_distinct(rng, members, count)),
+            Err(_) => Ok((0..count.unsigned_abs())
+                .map(|_| members[rng.index(members.len())].clone())
+                .collect()),
+        }
+    }
+
+    /// Get all members of a set, none if the key does not exist
+    pub fn smembers(&self, key: &[u8]) -> Result<Vec<Bytes>, &'static str> {
+        let mut state = self.lock();
+
+        let sorted = state.deterministic_iteration;
+        let mut members: Vec<Bytes> = state
+            .live_value_mut(key, Value::as_set_mut)?
+            .map(|(set, _)| set.iter().collect())
+            .unwrap_or_default();
+        if sorted {
+            members.sort_unstable();
+        }
+        Ok(members)
     }
 
     /// Check if a member exists in a set
-    pub fn sismember(&self, key: &str, member: &str) -> bool {
-        let state = self.shared.lock().unwrap();
+    pub fn sismember(&self, key: &[u8], member: &[u8]) -> Result<bool, &'static str> {
+        let mut state = self.lock();
 
-        state
-            .entries
-            .get(key)
-            .map(|entry| match &entry.value {
-                Value::Set(set) => set.contains(member),
-                _ => false,
-            })
-            .unwrap_or(false)
+        Ok(state
+            .live_value_mut(key, Value::as_set_mut)?
+            .is_some_and(|(set, _)| set.contains(member)))
     }
 
     /// Get the cardinality (size) of a set
-    pub fn scard(&self, key: &str) -> usize {
-        let state = self.shared.lock().unwrap();
+    pub fn scard(&self, key: &[u8]) -> Result<usize, &'static str> {
+        let mut state = self.lock();
 
-        state
-            .entries
-            .get(key)
-            .map(|entry| match &entry.value {
-                Value::Set(set) => set.len(),
-                _ => 0,
-            })
-            .unwrap_or(0)
+        Ok(state
+            .live_value_mut(key, Value::as_set_mut)?
+            .map_or(0, |(set, _)| set.len()))
     }
 
     // ===== Hash Operations =====
 
     /// Set a field in a hash
-    pub fn hset(&self, key: String, field: String, value: Bytes) -> bool {
-        let mut state = self.shared.lock().unwrap();
+    pub fn hset(&self, key: Bytes, field: Bytes, value: Bytes) -> bool {
+        let mut state = self.lock();
 
-        let entry = state.entries.entry(key).or_insert_with(|| Entry {
-            value: Value::Hash(HashMap::new()),
-            expires_at: None,
-        });
+        let max_listpack_entries = state.hash_max_listpack_entries;
+        let entry = state.entry_or_insert_with(key, || Value::Hash(HashValue::new()));
 
         match &mut entry.value {
-            Value::Hash(hash) => hash.insert(field, value).is_none(),
+            Value::Hash(hash) => {
+                entry.version += 1;
+                hash.insert(field, value, max_listpack_entries)
+            }
             _ => false,
         }
     }
 
     /// Get a field from a hash
-    pub fn hget(&self, key: &str, field: &str) -> Option<Bytes> {
-        let state = self.shared.lock().unwrap();
+    pub fn hget(&self, key: &[u8], field: &[u8]) -> Result<Option<Bytes>, &'static str> {
+        let mut state = self.lock();
 
-        state.entries.get(key).and_then(|entry| match &entry.value {
-            Value::Hash(hash) => hash.get(field).cloned(),
-            _ => None,
-        })
+        Ok(state
+            .live_value_mut(key, Value::as_hash_mut)?
+            .and_then(|(hash, _)| hash.get(field).cloned()))
     }
 
-    /// Get all fields and values from a hash
-    pub fn hgetall(&self, key: &str) -> Option<Vec<(String, Bytes)>> {
-        let state = self.shared.lock().unwrap();
+    /// Get all fields and values from a hash, none if the key does not exist
+    pub fn hgetall(&self, key: &[u8]) -> Result<Vec<(Bytes, Bytes)>, &'static str> {
+        let mut state = self.lock();
+
+        let sorted = state.deterministic_iteration;
+        let mut fields: Vec<(Bytes, Bytes)> = state
+            .live_value_mut(key, Value::as_hash_mut)?
+            .map(|(hash, _)| hash.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default();
+        if sorted {
+            fields.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        Ok(fields)
+    }
 
-        state.entries.get(key).and_then(|entry| match &entry.value {
-            Value::Hash(hash) => Some(hash.iter().map(|(k, v)| (k.clone(), v.clone())).collect()),
-            _ => None,
-        })
+    /// Delete fields from a hash
+    ///
+    /// Returns the number of fields deleted, 0 if the key does not exist.
+    pub fn hdel(&self, key: &[u8], fields: Vec<Bytes>) -> Result<usize, &'static str> {
+        let mut state = self.lock();
+
+        let Some((hash, version)) = state.live_value_mut(key, Value::as_hash_mut)? else {
+            return Ok(0);
+        };
+        let mut deleted = 0;
+        for field in fields {
+            if hash.remove(&field).is_some() {
+                deleted += 1;
+            }
+        }
+        if deleted > 0 {
+            *version += 1;
+        }
+        Ok(deleted)
     }
 
-    /// Delete a field from a hash
-    pub fn hdel(&self, key: &str, fields: Vec<String>) -> usize {
-        let mut state = self.shared.lock().unwrap();
+    /// Check if a field exists in a hash
+    pub fn hexists(&self, key: &[u8], field: &[u8]) -> Result<bool, &'static str> {
+        let mut state = self.lock();
 
-        state
-            .entries
-            .get_mut(key)
-            .map(|entry| match &mut entry.value {
-                Value::Hash(hash) => {
-                    let mut deleted = 0;
-                    for field in fields {
-                        if hash.remove(&field).is_some() {
-                            deleted += 1;
-                        }
-                    }
-                    deleted
+        Ok(state
+            .live_value_mut(key, Value::as_hash_mut)?
+            .is_some_and(|(hash, _)| hash.contains_key(field)))
+    }
+
+    /// Get the number of fields in a hash
+    pub fn hlen(&self, key: &[u8]) -> Result<usize, &'static str> {
+        let mut state = self.lock();
+
+        Ok(state
+            .live_value_mut(key, Value::as_hash_mut)?
+            .map_or(0, |(hash, _)| hash.len()))
+    }
+
+    // ===== Sorted Set Operations =====
+
+    /// Add members to a sorted set, or update the scores of existing ones
+    ///
+    /// Returns the number of members added.
+    pub fn zadd(&self, key: Bytes, members: Vec<(Score, Bytes)>) -> Result<usize, &'static str> {
+        let mut state = self.lock();
+        let max_listpack_entries = state.zset_max_listpack_entries;
+
+        let add = |zset: &mut ZSetValue| {
+            let mut added = 0;
+            let mut changed = false;
+            for (score, member) in members {
+                if zset.score(&member) == Some(score) {
+                    continue;
                 }
-                _ => 0,
-            })
-            .unwrap_or(0)
+                changed = true;
+                if zset.insert(member, score, max_listpack_entries) {
+                    added += 1;
+                }
+            }
+            (added, changed)
+        };
+
+        if let Some((zset, version)) = state.live_value_mut(&key, Value::as_zset_mut)? {
+            let (added, changed) = add(zset);
+            if changed {
+                *version += 1;
+            }
+            return Ok(added);
+        }
+        let mut zset = ZSetValue::new();
+        let (added, _) = add(&mut zset);
+        state.insert(key, Value::ZSet(zset), None);
+        Ok(added)
     }
 
-    /// Check if a field exists in a hash
-    pub fn hexists(&self, key: &str, field: &str) -> bool {
-        let state = self.shared.lock().unwrap();
+    /// Remove members from a sorted set
+    ///
+    /// Returns the number of members removed, 0 if the key does not exist.
+    pub fn zrem(&self, key: &[u8], members: Vec<Bytes>) -> Result<usize, &'static str> {
+        let mut state = self.lock();
+
+        let Some((zset, version)) = state.live_value_mut(key, Value::as_zset_mut)? else {
+            return Ok(0);
+        };
+        let mut removed = 0;
+        for member in members {
+            if zset.remove(&member).is_some() {
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            *version += 1;
+        }
+        Ok(removed)
+    }
 
+    /// Get the score of a member of a sorted set
+    pub fn zscore(&self, key: &[u8], member: &[u8]) -> Result<Option<Score>, &'static str> {
+        let mut state = self.lock();
+
+        Ok(state
+            .live_value_mut(key, Value::as_zset_mut)?
+            .and_then(|(zset, _)| zset.score(member)))
+    }
+
+    /// Get the number of members of a sorted set
+    pub fn zcard(&self, key: &[u8]) -> Result<usize, &'static str> {
+        let mut state = self.lock();
+
+        Ok(state
+            .live_value_mut(key, Value::as_zset_mut)?
+            .map_or(0, |(zset, _)| zset.len()))
+    }
+
+    /// Get the members ranked `start` to `stop` (inclusive, negative counts
+    /// from the end) with their scores, lowest score first
+    pub fn zrange(
+        &self,
+        key: &[u8],
+        start: isize,
+        stop: isize,
+    ) -> Result<Vec<(Bytes, Score)>, &'static str> {
+        let mut state = self.lock();
+
+        let Some((zset, _)) = state.live_value_mut(key, Value::as_zset_mut)? else {
+            return Ok(Vec::new());
+        };
+        let (start, end) = normalize_range(start, stop, zset.len());
+
+        Ok(zset
+            .iter()
+            .skip(start)
+            .take(end - start)
+            .map(|(member, score)| (member.clone(), score))
+            .collect())
+    }
+
+    /// Members between `min` and `max` of a sorted set whose members all
+    /// share one score, in bytewise order
+    ///
+    /// With `limit = Some((offset, count))` the first `offset` members are
+    /// skipped and at most `count` returned; a negative count means all the
+    /// rest, a negative offset an empty reply.
+    pub fn zrangebylex(
+        &self,
+        key: &[u8],
+        min: &LexBound,
+        max: &LexBound,
+        limit: Option<(i64, i64)>,
+    ) -> Result<Vec<Bytes>, &'static str> {
+        let mut state = self.lock();
+
+        let Some((zset, _)) = state.live_value_mut(key, Value::as_zset_mut)? else {
+            return Ok(Vec::new());
+        };
+        let (offset, count) = match limit {
+            Some((offset, _)) if offset < 0 => return Ok(Vec::new()),
+            Some((offset, count)) => (
+                offset as usize,
+                usize::try_from(count).unwrap_or(usize::MAX),
+            ),
+            None => (0, usize::MAX),
+        };
+
+        Ok(zset
+            .lex_range(min, max)
+            .skip(offset)
+            .take(count)
+            .cloned()
+            .collect())
+    }
+
+    /// Number of members between `min` and `max`, see `zrangebylex`
+    pub fn zlexcount(
+        &self,
+        key: &[u8],
+        min: &LexBound,
+        max: &LexBound,
+    ) -> Result<usize, &'static str> {
+        let mut state = self.lock();
+
+        Ok(state
+            .live_value_mut(key, Value::as_zset_mut)?
+            .map_or(0, |(zset, _)| zset.lex_range(min, max).count()))
+    }
+
+    // ===== Stream Operations =====
+
+    /// Add an entry to a stream, creating the stream if needed
+    ///
+    /// `id` of `None` stands for `*`: the entry gets the next ID at the
+    /// current time, see `StreamValue::next_id`. Returns the entry's ID.
+    pub fn xadd(
+        &self,
+        key: Bytes,
+        id: Option<StreamId>,
+        fields: Vec<(Bytes, Bytes)>,
+    ) -> Result<StreamId, &'static str> {
+        let mut state = self.lock();
+
+        let add = |stream: &mut StreamValue| {
+            let id = match id {
+                Some(id) => id,
+                None => stream.next_id(clock::unix_time_ms()).ok_or(
+                    "ERR The stream has exhausted the last possible ID, unable to add more items",
+                )?,
+            };
+            stream.add(id, fields).map(|()| id)
+        };
+
+        let id = match state.live_value_mut(&key, Value::as_stream_mut)? {
+            Some((stream, version)) => {
+                let id = add(stream)?;
+                *version += 1;
+                id
+            }
+            None => {
+                let mut stream = StreamValue::new();
+                let id = add(&mut stream)?;
+                state.insert(key.clone(), Value::Stream(stream), None);
+                id
+            }
+        };
+
+        drop(state);
+        self.waiters.wake(&key);
+        Ok(id)
+    }
+
+    /// Get the number of entries in a stream
+    pub fn xlen(&self, key: &[u8]) -> Result<usize, &'static str> {
+        let mut state = self.lock();
+
+        Ok(state
+            .live_value_mut(key, Value::as_stream_mut)?
+            .map_or(0, |(stream, _)| stream.len()))
+    }
+
+    /// Entries of a stream with IDs from `start` to `end` (inclusive), in
+    /// ID order, at most `count` of them
+    pub fn xrange(
+        &self,
+        key: &[u8],
+        start: StreamId,
+        end: StreamId,
+        count: usize,
+    ) -> Result<Vec<StreamEntry>, &'static str> {
+        let mut state = self.lock();
+
+        let Some((stream, _)) = state.live_value_mut(key, Value::as_stream_mut)? else {
+            return Ok(Vec::new());
+        };
+
+        Ok(stream
+            .range(start, end)
+            .take(count)
+            .map(|(id, fields)| (*id, fields.clone()))
+            .collect())
+    }
+
+    /// ID of the last entry added to a stream, `0-0` if there is none
+    pub fn xlast_id(&self, key: &[u8]) -> Result<StreamId, &'static str> {
+        let mut state = self.lock();
+
+        Ok(state
+            .live_value_mut(key, Value::as_stream_mut)?
+            .map_or(StreamId::MIN, |(stream, _)| stream.last_id()))
+    }
+
+    /// Entries of each stream in `keys` with IDs larger than the matching
+    /// one in `ids`, at most `count` per stream (XREAD)
+    ///
+    /// Streams without such entries are left out.
+    pub fn xread(
+        &self,
+        keys: &[Bytes],
+        ids: &[StreamId],
+        count: usize,
+    ) -> Result<Vec<(Bytes, Vec<StreamEntry>)>, &'static str> {
+        let mut state = self.lock();
+
+        let mut streams = Vec::new();
+        for (key, id) in keys.iter().zip(ids) {
+            let Some((stream, _)) = state.live_value_mut(key, Value::as_stream_mut)? else {
+                continue;
+            };
+            let entries: Vec<_> = stream
+                .after(*id)
+                .take(count)
+                .map(|(id, fields)| (*id, fields.clone()))
+                .collect();
+            if !entries.is_empty() {
+                streams.push((key.clone(), entries));
+            }
+        }
+        Ok(streams)
+    }
+
+    // ===== JSON Operations =====
+
+    /// Set the value at `path` in the JSON document at `key`
+    ///
+    /// A missing key can only be created with a root path. Returns
+    /// `Ok(false)` if the path does not match anything in the document.
+    #[cfg(feature = "json")]
+    pub fn json_set(
+        &self,
+        key: Bytes,
+        path: &JsonPath,
+        value: serde_json::Value,
+    ) -> Result<bool, &'static str> {
+        let mut state = self.lock();
+
+        let live = state.entries.get(&key).is_some_and(|entry| {
+            entry
+                .expires_at
+                .map(|expires_at| Instant::now() < expires_at)
+                .unwrap_or(true)
+        });
+        if !live {
+            if !path.is_root() {
+                return Err("ERR new objects must be created at the root");
+            }
+            state.insert(key, Value::Json(value), None);
+            return Ok(true);
+        }
+
+        state.touch(&key);
+        let entry = state.entries.get_mut(&key).expect("checked above");
+        match &mut entry.value {
+            Value::Json(document) => {
+                let updated = path.set(document, value);
+                if updated {
+                    entry.version += 1;
+                }
+                Ok(updated)
+            }
+            _ => Err(WRONGTYPE_ERR),
+        }
+    }
+
+    /// Serialize the value at `path` in the JSON document at `key`
+    ///
+    /// Returns `Ok(None)` if the key is missing or the path does not match.
+    #[cfg(feature = "json")]
+    pub fn json_get(&self, key: &[u8], path: &JsonPath) -> Result<Option<String>, &'static str> {
+        let state = self.lock();
+
+        let entry = match state.entries.get(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if let Some(expires_at) = entry.expires_at {
+            if Instant::now() >= expires_at {
+                return Ok(None);
+            }
+        }
+
+        match &entry.value {
+            Value::Json(document) => Ok(path.get(document).map(|value| value.to_string())),
+            _ => Err(WRONGTYPE_ERR),
+        }
+    }
+
+    // ===== Database Utility Operations =====
+
+    /// Get the total number of keys in the database
+    pub fn dbsize(&self) -> usize {
+        let state = self.lock();
+        state.entries.len()
+    }
+
+    /// Internal encoding of the value at `key`, if it exists
+    pub fn object_encoding(&self, key: &[u8]) -> Option<&'static str> {
+        let state = self.lock();
         state
             .entries
             .get(key)
-            .map(|entry| match &entry.value {
-                Value::Hash(hash) => hash.contains_key(field),
-                _ => false,
+            .filter(|entry| {
+                entry
+                    .expires_at
+                    .map(|expires_at| Instant::now() < expires_at)
+                    .unwrap_or(true)
             })
-            .unwrap_or(false)
+            .map(|entry| entry.value.encoding())
     }
 
-    /// Get the number of fields in a hash
-    pub fn hlen(&self, key: &str) -> usize {
-        let state = self.shared.lock().unwrap();
-
+    /// Snapshot the identity of a key for WATCH
+    ///
+    /// Expired keys are reported as missing. Comparing two snapshots tells
+    /// whether the key was modified, deleted, or deleted and recreated.
+    pub fn watch_version(&self, key: &[u8]) -> WatchVersion {
+        let state = self.lock();
         state
             .entries
             .get(key)
-            .map(|entry| match &entry.value {
-                Value::Hash(hash) => hash.len(),
-                _ => 0,
+            .filter(|entry| {
+                entry
+                    .expires_at
+                    .map(|expires_at| Instant::now() < expires_at)
+                    .unwrap_or(true)
             })
-            .unwrap_or(0)
+            .map(|entry| (entry.generation, entry.version))
     }
 
-    // ===== Database Utility Operations =====
+    /// Tally per-type key counts and byte accounting for the whole database
+    pub fn memory_stats(&self) -> MemoryStats {
+        let state = self.lock();
+        let mut stats = MemoryStats::default();
 
-    /// Get the total number of keys in the database
-    pub fn dbsize(&self) -> usize {
-        let state = self.shared.lock().unwrap();
-        state.entries.len()
+        for (key, entry) in state.entries.iter() {
+            stats.keys_count += 1;
+            stats.dataset_bytes += entry.value.approx_size();
+            stats.overhead_bytes += key.len() + std::mem::size_of::<Entry>();
+
+            match &entry.value {
+                Value::String(_) => stats.strings += 1,
+                #[cfg(feature = "compression")]
+                Value::Compressed(_) => stats.strings += 1,
+                Value::List(_) => stats.lists += 1,
+                Value::Set(_) => stats.sets += 1,
+                Value::Hash(_) => stats.hashes += 1,
+                Value::ZSet(_) => stats.zsets += 1,
+                Value::Stream(_) => stats.streams += 1,
+                #[cfg(feature = "json")]
+                Value::Json(_) => {}
+            }
+        }
+
+        stats
+    }
+
+    /// Approximate bytes used by this database, what `maxmemory` limits
+    ///
+    /// The dataset and overhead bytes of `memory_stats` together, kept as
+    /// a running total. Keys added or removed count at once, and so do
+    /// changes to small values. Measuring a large collection walks all its
+    /// elements, so changes to those only count once `remeasure` gets to
+    /// them.
+    pub fn used_memory(&self) -> usize {
+        self.lock().entries.used()
+    }
+
+    /// Measure again the values of about `count` keys starting at `cursor`
+    ///
+    /// Keeps `used_memory` close for values changed in place. Walks the
+    /// keyspace in SCAN order and returns the cursor for the next step, 0
+    /// after a full pass.
+    pub fn remeasure(&self, cursor: u64, count: usize) -> u64 {
+        self.lock().entries.remeasure(cursor, count.max(1))
+    }
+
+    /// Evict keys chosen by `policy` to free at least `need` bytes
+    ///
+    /// Returns the keys evicted and the bytes freed, which falls short of
+    /// `need` when the policy runs out of eligible keys. Large values are
+    /// freed in the background, like UNLINK. Each key is announced as an
+    /// `evicted` keyspace event and reported to the change observer as a
+    /// DEL; logging that DEL is up to the caller.
+    pub fn evict(&self, policy: &dyn EvictionPolicy, need: usize) -> (Vec<Bytes>, usize) {
+        let mut removed = Vec::new();
+        let mut freed = 0;
+        {
+            let mut state = self.lock();
+            while freed < need {
+                let Some(key) = policy.pick_victim(&state) else {
+                    break;
+                };
+                let Some(entry) = state.entries.remove(&key) else {
+                    break;
+                };
+                freed += eviction::footprint(&key, &entry);
+                removed.push((key, entry));
+            }
+        }
+
+        let mut evicted = Vec::with_capacity(removed.len());
+        for (key, entry) in removed {
+            self.lazyfree.free(entry.value);
+            evicted.push(key);
+        }
+
+        if let Some(notifier) = read(&self.notifier).as_ref() {
+            for key in &evicted {
+                notifier.evicted(key);
+            }
+        }
+        if self.has_change_observer() {
+            for key in &evicted {
+                self.record_change("DEL", Some(key.clone()));
+            }
+        }
+        (evicted, freed)
+    }
+
+    /// Take a point-in-time copy of every live entry
+    ///
+    /// The keyspace is copied under the lock, giving a background save a
+    /// consistent view it can serialize while writers carry on. Values are
+    /// cloned with `Clone`, not `deep_clone`: strings and the elements of
+    /// lists, sets and hashes are immutable `Bytes` shared with the live
+    /// database, so only the collections' own storage is copied. A write
+    /// replaces the shared buffer instead of changing it, leaving the
+    /// snapshot's view intact.
+    pub fn snapshot_clone(&self) -> DbSnapshot {
+        let state = self.lock();
+        let now = Instant::now();
+        let entries = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires_at.is_none_or(|at| now < at))
+            .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.expires_at))
+            .collect();
+        DbSnapshot { entries }
     }
 
     /// Clear all keys from the database
+    ///
+    /// Only swaps in an empty keyspace under the lock; dropping the old one
+    /// can take seconds for millions of keys, so the lazy-free thread does
+    /// it while other clients carry on.
     pub fn flushdb(&self) {
-        let mut state = self.shared.lock().unwrap();
-        state.entries.clear();
+        let old = std::mem::replace(&mut self.lock().entries, Keyspace::new());
+        self.lazyfree.free_keyspace(old);
     }
 
     /// Get all keys matching a pattern
@@ -436,53 +2182,97 @@ impl Db {
     /// - h?llo matches hello, hallo, hxllo
     /// - h*llo matches hllo, heeeello
     /// - h[ae]llo matches hello and hallo
-    pub fn keys(&self, pattern: &str) -> Vec<String> {
-        let state = self.shared.lock().unwrap();
-
-        // Convert glob pattern to regex
-        let regex_pattern = Self::glob_to_regex(pattern);
-        let re = match regex::Regex::new(&regex_pattern) {
-            Ok(r) => r,
-            Err(_) => return Vec::new(),
-        };
+    ///
+    /// Past `keys-max-results` matches this gives up with
+    /// `KEYS_TOO_LARGE_ERR` instead of copying any more keys.
+    pub fn keys(&self, pattern: &[u8]) -> Result<Vec<Bytes>, &'static str> {
+        let state = self.lock();
+
+        let now = Instant::now();
+        let max = state.keys_max_results;
+        let mut keys = Vec::new();
+        let matching = state
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires_at.is_none_or(|at| now < at))
+            .filter(|(key, _)| glob_match(pattern, key));
+        for (key, _) in matching {
+            if max > 0 && keys.len() == max {
+                return Err(KEYS_TOO_LARGE_ERR);
+            }
+            keys.push(key.clone());
+        }
+        if state.deterministic_iteration {
+            keys.sort_unstable();
+        }
+        Ok(keys)
+    }
+
+    /// Count the keys matching a glob pattern
+    ///
+    /// Same result as `keys(pattern).len()`, but no key is copied.
+    pub fn count_keys(&self, pattern: &[u8]) -> usize {
+        let state = self.lock();
 
+        let now = Instant::now();
         state
             .entries
-            .keys()
-            .filter(|key| re.is_match(key))
-            .cloned()
-            .collect()
-    }
-
-    /// Convert a glob pattern to a regex pattern
-    fn glob_to_regex(pattern: &str) -> String {
-        let mut regex = String::from("^");
-        let mut chars = pattern.chars().peekable();
-
-        while let Some(c) = chars.next() {
-            match c {
-                '*' => regex.push_str(".*"),
-                '?' => regex.push('.'),
-                '[' => {
-                    regex.push('[');
-                    while let Some(&next_c) = chars.peek() {
-                        chars.next();
-                        regex.push(next_c);
-                        if next_c == ']' {
-                            break;
-                        }
-                    }
-                }
-                '.' | '+' | '^' | '$' | '(' | ')' | '{' | '}' | '|' | '\\' => {
-                    regex.push('\\');
-                    regex.push(c);
-                }
-                _ => regex.push(c),
-            }
+            .iter()
+            .filter(|(_, entry)| entry.expires_at.is_none_or(|at| now < at))
+            .filter(|(key, _)| glob_match(pattern, key))
+            .count()
+    }
+
+    /// Incrementally iterate the keyspace (SCAN)
+    ///
+    /// Visits about `count` keys starting at `cursor` and returns those
+    /// that are live and match `pattern` and `type_name`, together with
+    /// the cursor for the next call (0 when done). Filtering happens after
+    /// the keys are visited, so a call may return no keys before the end.
+    /// A key that exists for the whole iteration is returned exactly once.
+    pub fn scan(
+        &self,
+        cursor: u64,
+        count: usize,
+        pattern: Option<&[u8]>,
+        type_name: Option<&str>,
+    ) -> (u64, Vec<Bytes>) {
+        let state = self.lock();
+
+        let now = Instant::now();
+        let (next, visited) = state.entries.scan(cursor, count.max(1));
+        let keys = visited
+            .into_iter()
+            .filter(|(_, entry)| entry.expires_at.is_none_or(|at| now < at))
+            .filter(|(_, entry)| type_name.is_none_or(|t| entry.value.type_name() == t))
+            .filter(|(key, _)| pattern.is_none_or(|p| glob_match(p, key)))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        (next, keys)
+    }
+
+    /// Remove expired keys among about `count` keys starting at `cursor`
+    ///
+    /// One step of active expiration: keys that are never accessed again
+    /// would otherwise stay in memory forever. Walks the keyspace in SCAN
+    /// order and returns the cursor for the next step (0 after a full
+    /// pass) together with the number of keys removed.
+    pub fn active_expire_cycle(&self, cursor: u64, count: usize) -> (u64, usize) {
+        let mut state = self.lock();
+
+        let now = Instant::now();
+        let (next, visited) = state.entries.scan(cursor, count.max(1));
+        let expired: Vec<Bytes> = visited
+            .into_iter()
+            .filter(|(_, entry)| entry.expires_at.is_some_and(|at| now >= at))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            state.remove_expired(key);
         }
 
-        regex.push('$');
-        regex
+        (next, expired.len())
     }
 }
 
@@ -493,4 +2283,5 @@ impl Default for Db {
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests;
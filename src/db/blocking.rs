@@ -0,0 +1,84 @@
+use crate::util::lock;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// Clients blocked on list or stream keys (BLPOP/BRPOP, XREAD BLOCK)
+///
+/// Every blocked client owns a `Notify` registered under each key it
+/// waits for. A push to a key signals all of its clients, which then race
+/// to pop; the ones that lose simply wait again. `Notify` keeps a permit
+/// when nobody is waiting yet, so a push that lands between a client's
+/// failed pop and its wait is not lost. XADD signals a stream key the
+/// same way; readers do not consume anything, so none of them lose.
+///
+/// The registry is separate from the keyspace: FLUSHDB removes the data
+/// but a client blocked on a flushed key stays registered and is woken by
+/// the next push to that key.
+#[derive(Default)]
+pub(super) struct KeyWaiters {
+    waiters: Mutex<HashMap<Bytes, Vec<Arc<Notify>>>>,
+}
+
+impl KeyWaiters {
+    /// Register a client waiting on `keys`
+    pub(super) fn register(self: &Arc<Self>, keys: &[Bytes]) -> BlockedClient {
+        let notify = Arc::new(Notify::new());
+        let mut waiters = lock(&self.waiters);
+        for key in keys {
+            waiters
+                .entry(key.clone())
+                .or_default()
+                .push(Arc::clone(&notify));
+        }
+
+        BlockedClient {
+            registry: Arc::clone(self),
+            keys: keys.to_vec(),
+            notify,
+        }
+    }
+
+    /// Signal every client blocked on `key`
+    pub(super) fn wake(&self, key: &[u8]) {
+        if let Some(clients) = lock(&self.waiters).get(key) {
+            for notify in clients {
+                notify.notify_one();
+            }
+        }
+    }
+
+    /// Number of keys with at least one blocked client
+    pub(super) fn len(&self) -> usize {
+        lock(&self.waiters).len()
+    }
+}
+
+/// Registration of one blocked client, removed from the registry on drop
+pub struct BlockedClient {
+    registry: Arc<KeyWaiters>,
+    keys: Vec<Bytes>,
+    notify: Arc<Notify>,
+}
+
+impl BlockedClient {
+    /// Wait until one of the keys was pushed to since the last wait
+    pub async fn wait(&self) {
+        self.notify.notified().await
+    }
+}
+
+impl Drop for BlockedClient {
+    fn drop(&mut self) {
+        let mut waiters = lock(&self.registry.waiters);
+        for key in &self.keys {
+            if let Some(clients) = waiters.get_mut(key) {
+                clients.retain(|notify| !Arc::ptr_eq(notify, &self.notify));
+                if clients.is_empty() {
+                    waiters.remove(key);
+                }
+            }
+        }
+    }
+}
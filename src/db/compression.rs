@@ -0,0 +1,68 @@
+use bytes::Bytes;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+/// Strings at least this long are compressed by default (0 disables it)
+pub const DEFAULT_STRING_COMPRESSION_THRESHOLD: usize = 0;
+
+/// A string value stored deflate-compressed
+///
+/// Only values that actually shrink are stored this way, see `compress`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompressedString {
+    data: Bytes,
+
+    /// Length of the original value
+    len: usize,
+}
+
+impl CompressedString {
+    /// Compress `value`, or hand it back if compressing would not save space
+    pub fn compress(value: Bytes) -> Result<CompressedString, Bytes> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        // Writing into a Vec cannot fail
+        encoder.write_all(&value).unwrap();
+        let data = encoder.finish().unwrap();
+        if data.len() >= value.len() {
+            return Err(value);
+        }
+        Ok(CompressedString {
+            data: Bytes::from(data),
+            len: value.len(),
+        })
+    }
+
+    /// The original value
+    pub fn decompress(&self) -> Bytes {
+        let mut value = Vec::with_capacity(self.len);
+        // Only ever built from data `compress` produced
+        DeflateDecoder::new(&self.data[..])
+            .read_to_end(&mut value)
+            .expect("corrupt compressed string");
+        Bytes::from(value)
+    }
+
+    /// Length of the original value
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of bytes actually stored
+    pub fn stored_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Copy without sharing storage with `self`, see `Value::deep_clone`
+    pub(super) fn deep_clone(&self) -> CompressedString {
+        CompressedString {
+            data: Bytes::copy_from_slice(&self.data),
+            len: self.len,
+        }
+    }
+}
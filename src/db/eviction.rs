@@ -0,0 +1,165 @@
+use super::{DbRng, DbState, Entry, Value};
+use bytes::Bytes;
+use std::time::Instant;
+
+/// `maxmemory-policy` used unless configured otherwise
+pub const DEFAULT_MAXMEMORY_POLICY: &str = "noeviction";
+
+/// Reply to writes refused because `maxmemory` is exceeded and nothing can
+/// be evicted
+pub const OOM_ERR: &str = "OOM command not allowed when used memory > 'maxmemory'.";
+
+/// Keys sampled for each eviction by the LRU and TTL policies, Redis's
+/// default `maxmemory-samples`
+pub const MAXMEMORY_SAMPLES: usize = 5;
+
+/// Chooses the keys to evict once `maxmemory` is exceeded
+///
+/// A policy only picks keys, `Db::evict` removes them. New policies are
+/// registered by name in `policy`.
+pub trait EvictionPolicy: Send + Sync {
+    /// The next key of `db` to evict, `None` when no key is eligible
+    ///
+    /// Called once per key until enough memory is freed, each time with
+    /// the keys picked before already removed.
+    fn pick_victim(&self, db: &DbState) -> Option<Bytes>;
+}
+
+/// A key as seen by an eviction policy
+pub struct Candidate<'a> {
+    pub key: &'a Bytes,
+
+    /// Bytes freed by evicting the key, as counted by `Db::used_memory`
+    pub size: usize,
+
+    pub expires_at: Option<Instant>,
+
+    /// When a command last read or wrote the key
+    pub last_access: Instant,
+}
+
+impl DbState {
+    /// Up to `count` keys that `eligible` accepts, to pick a victim from
+    ///
+    /// Like Redis, the sample is neighbouring keys from a random starting
+    /// point rather than keys picked one by one; the SCAN order spreads
+    /// keys evenly. The fewer keys are eligible, the further this walks.
+    pub fn sample(
+        &self,
+        count: usize,
+        eligible: impl Fn(&Candidate<'_>) -> bool,
+    ) -> Vec<Candidate<'_>> {
+        self.entries
+            .iter_from(DbRng::Entropy.next_u64())
+            .map(|(key, entry)| Candidate {
+                key,
+                size: footprint(key, entry),
+                expires_at: entry.expires_at,
+                last_access: entry.last_access,
+            })
+            .filter(|candidate| eligible(candidate))
+            .take(count)
+            .collect()
+    }
+}
+
+/// Approximate memory held by one entry: key, value and bookkeeping
+///
+/// The value is counted as it was last measured, see `measure`.
+pub(super) fn footprint(key: &[u8], entry: &Entry) -> usize {
+    key.len() + entry.size
+}
+
+/// Elements above which a value changed in place is not measured again
+/// right away, as measuring walks every element
+pub(super) const MEASURE_ON_WRITE_MAX: usize = 128;
+
+/// Approximate memory held by `value` and its entry, key aside
+pub(super) fn measure(value: &Value) -> usize {
+    std::mem::size_of::<Entry>() + value.approx_size()
+}
+
+/// The policy called `name` in `maxmemory-policy`, `None` if there is none
+pub fn policy(name: &str) -> Option<Box<dyn EvictionPolicy>> {
+    Some(match name {
+        "noeviction" => Box::new(NoEviction),
+        "allkeys-random" => Box::new(AllKeysRandom),
+        "allkeys-lru" => Box::new(AllKeysLru),
+        "volatile-random" => Box::new(VolatileRandom),
+        "volatile-ttl" => Box::new(VolatileTtl),
+        _ => return None,
+    })
+}
+
+/// Never evict; writes are refused with `OOM_ERR` instead
+pub struct NoEviction;
+
+impl EvictionPolicy for NoEviction {
+    fn pick_victim(&self, _db: &DbState) -> Option<Bytes> {
+        None
+    }
+}
+
+/// Evict any keys, chosen at random
+pub struct AllKeysRandom;
+
+impl EvictionPolicy for AllKeysRandom {
+    fn pick_victim(&self, db: &DbState) -> Option<Bytes> {
+        first(db.sample(1, |_| true))
+    }
+}
+
+/// Evict the keys accessed least recently first
+///
+/// Approximated by sampling, as in Redis: the least recently used of
+/// `MAXMEMORY_SAMPLES` keys goes.
+pub struct AllKeysLru;
+
+impl EvictionPolicy for AllKeysLru {
+    fn pick_victim(&self, db: &DbState) -> Option<Bytes> {
+        let candidates = db.sample(MAXMEMORY_SAMPLES, |_| true);
+        best(candidates, |candidate| candidate.last_access)
+    }
+}
+
+/// Evict keys with an expiry, chosen at random
+pub struct VolatileRandom;
+
+impl EvictionPolicy for VolatileRandom {
+    fn pick_victim(&self, db: &DbState) -> Option<Bytes> {
+        first(db.sample(1, |candidate| candidate.expires_at.is_some()))
+    }
+}
+
+/// Evict keys with an expiry, those closest to expiring first
+///
+/// Approximated by sampling like `AllKeysLru`.
+pub struct VolatileTtl;
+
+impl EvictionPolicy for VolatileTtl {
+    fn pick_victim(&self, db: &DbState) -> Option<Bytes> {
+        let candidates = db.sample(MAXMEMORY_SAMPLES, |candidate| {
+            candidate.expires_at.is_some()
+        });
+        best(candidates, |candidate| candidate.expires_at)
+    }
+}
+
+/// Key of the first of `candidates`
+fn first(candidates: Vec<Candidate<'_>>) -> Option<Bytes> {
+    candidates
+        .into_iter()
+        .next()
+        .map(|candidate| candidate.key.clone())
+}
+
+/// Key of the candidate `rank` puts first
+fn best<R: Ord>(
+    candidates: Vec<Candidate<'_>>,
+    rank: impl Fn(&Candidate<'_>) -> R,
+) -> Option<Bytes> {
+    candidates
+        .into_iter()
+        .min_by_key(rank)
+        .map(|candidate| candidate.key.clone())
+}
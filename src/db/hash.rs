@@ -0,0 +1,105 @@
+use bytes::Bytes;
+use std::collections::HashMap;
+
+/// Default for `hash-max-listpack-entries` (same as Redis)
+pub const DEFAULT_HASH_MAX_LISTPACK_ENTRIES: usize = 128;
+
+/// Hash value with two internal representations
+///
+/// Small hashes are kept as a flat, insertion-ordered vector of field/value
+/// pairs (the equivalent of a Redis listpack). Once a hash grows past
+/// `hash-max-listpack-entries` it is promoted to a `HashMap`. Like Redis,
+/// a promoted hash is never converted back when it shrinks.
+#[derive(Clone, Debug)]
+pub enum HashValue {
+    Listpack(Vec<(Bytes, Bytes)>),
+    Table(HashMap<Bytes, Bytes>),
+}
+
+impl HashValue {
+    pub fn new() -> Self {
+        HashValue::Listpack(Vec::new())
+    }
+
+    /// Name reported by `OBJECT ENCODING`
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            HashValue::Listpack(_) => "listpack",
+            HashValue::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            HashValue::Listpack(entries) => entries.len(),
+            HashValue::Table(table) => table.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, field: &[u8]) -> Option<&Bytes> {
+        match self {
+            HashValue::Listpack(entries) => entries
+                .iter()
+                .find(|(existing, _)| existing == field)
+                .map(|(_, value)| value),
+            HashValue::Table(table) => table.get(field),
+        }
+    }
+
+    pub fn contains_key(&self, field: &[u8]) -> bool {
+        self.get(field).is_some()
+    }
+
+    /// Set a field, promoting to a hashtable once `max_listpack_entries` is exceeded
+    ///
+    /// Returns true if the field is new.
+    pub fn insert(&mut self, field: Bytes, value: Bytes, max_listpack_entries: usize) -> bool {
+        match self {
+            HashValue::Listpack(entries) => {
+                if let Some(slot) = entries.iter_mut().find(|(existing, _)| *existing == field) {
+                    slot.1 = value;
+                    return false;
+                }
+                entries.push((field, value));
+                if entries.len() > max_listpack_entries {
+                    let table = std::mem::take(entries).into_iter().collect();
+                    *self = HashValue::Table(table);
+                }
+                true
+            }
+            HashValue::Table(table) => table.insert(field, value).is_none(),
+        }
+    }
+
+    /// Remove a field, returning its value if it existed
+    pub fn remove(&mut self, field: &[u8]) -> Option<Bytes> {
+        match self {
+            HashValue::Listpack(entries) => {
+                let index = entries.iter().position(|(existing, _)| existing == field)?;
+                // Keep the remaining fields in insertion order
+                Some(entries.remove(index).1)
+            }
+            HashValue::Table(table) => table.remove(field),
+        }
+    }
+
+    /// Iterate over field/value pairs (insertion order for listpacks)
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&Bytes, &Bytes)> + '_> {
+        match self {
+            HashValue::Listpack(entries) => {
+                Box::new(entries.iter().map(|(field, value)| (field, value)))
+            }
+            HashValue::Table(table) => Box::new(table.iter()),
+        }
+    }
+}
+
+impl Default for HashValue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
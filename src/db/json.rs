@@ -0,0 +1,189 @@
+use serde_json::Value as JsonValue;
+
+/// One step of a JSON path
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// A parsed JSON path
+///
+/// Supports the root (`$` or `.`) followed by dot members and bracket
+/// members/indices, e.g. `$.user.name`, `$.items[0]`, `$["odd key"]` or the
+/// legacy form `.user.name`. Wildcards, slices and filters are not supported.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JsonPath {
+    segments: Vec<PathSegment>,
+}
+
+impl JsonPath {
+    /// The path of the whole document
+    pub fn root() -> Self {
+        JsonPath {
+            segments: Vec::new(),
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let invalid = || format!("ERR invalid JSON path '{}'", path);
+
+        let rest = path.strip_prefix('$').unwrap_or(path);
+        let mut chars = rest.chars().peekable();
+        let mut segments = Vec::new();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '.' => {
+                    let mut key = String::new();
+                    while let Some(&next) = chars.peek() {
+                        if next == '.' || next == '[' {
+                            break;
+                        }
+                        key.push(next);
+                        chars.next();
+                    }
+                    // A lone "." is the legacy root path
+                    if key.is_empty() {
+                        if chars.peek().is_some() || !segments.is_empty() {
+                            return Err(invalid());
+                        }
+                        continue;
+                    }
+                    segments.push(PathSegment::Key(key));
+                }
+                '[' => {
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some(']') => break,
+                            Some(next) => inner.push(next),
+                            None => return Err(invalid()),
+                        }
+                    }
+                    let quoted = inner.len() >= 2
+                        && ((inner.starts_with('"') && inner.ends_with('"'))
+                            || (inner.starts_with('\'') && inner.ends_with('\'')));
+                    if quoted {
+                        segments.push(PathSegment::Key(inner[1..inner.len() - 1].to_string()));
+                    } else {
+                        let index = inner.trim().parse().map_err(|_| invalid())?;
+                        segments.push(PathSegment::Index(index));
+                    }
+                }
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(JsonPath { segments })
+    }
+
+    /// Resolve the path inside `document`
+    pub fn get<'a>(&self, document: &'a JsonValue) -> Option<&'a JsonValue> {
+        self.segments
+            .iter()
+            .try_fold(document, |current, segment| match segment {
+                PathSegment::Key(key) => current.as_object()?.get(key),
+                PathSegment::Index(index) => current.as_array()?.get(*index),
+            })
+    }
+
+    /// Store `value` at the path inside `document`
+    ///
+    /// The parent of the target must already exist. A missing last member
+    /// of an object is created; array indices must be in bounds. Returns
+    /// false if the path does not match.
+    pub fn set(&self, document: &mut JsonValue, value: JsonValue) -> bool {
+        let (last, parents) = match self.segments.split_last() {
+            Some(split) => split,
+            None => {
+                *document = value;
+                return true;
+            }
+        };
+
+        let mut current = document;
+        for segment in parents {
+            let next = match segment {
+                PathSegment::Key(key) => current.as_object_mut().and_then(|o| o.get_mut(key)),
+                PathSegment::Index(index) => current.as_array_mut().and_then(|a| a.get_mut(*index)),
+            };
+            current = match next {
+                Some(next) => next,
+                None => return false,
+            };
+        }
+
+        match last {
+            PathSegment::Key(key) => match current.as_object_mut() {
+                Some(object) => {
+                    object.insert(key.clone(), value);
+                    true
+                }
+                None => false,
+            },
+            PathSegment::Index(index) => {
+                match current.as_array_mut().and_then(|a| a.get_mut(*index)) {
+                    Some(slot) => {
+                        *slot = value;
+                        true
+                    }
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_paths() {
+        assert!(JsonPath::parse("$").unwrap().is_root());
+        assert!(JsonPath::parse(".").unwrap().is_root());
+        assert_eq!(
+            JsonPath::parse("$.items[1]['a b']").unwrap().segments,
+            vec![
+                PathSegment::Key("items".to_string()),
+                PathSegment::Index(1),
+                PathSegment::Key("a b".to_string()),
+            ]
+        );
+        assert_eq!(
+            JsonPath::parse(".user.name").unwrap(),
+            JsonPath::parse("$.user.name").unwrap()
+        );
+        assert!(JsonPath::parse("$.items[x]").is_err());
+        assert!(JsonPath::parse("$.items[0").is_err());
+    }
+
+    #[test]
+    fn test_set_nested() {
+        let mut document = json!({"user": {"tags": ["a", "b"]}});
+
+        assert!(JsonPath::parse("$.user.name")
+            .unwrap()
+            .set(&mut document, json!("ann")));
+        assert!(JsonPath::parse("$.user.tags[1]")
+            .unwrap()
+            .set(&mut document, json!("c")));
+        assert!(!JsonPath::parse("$.user.tags[5]")
+            .unwrap()
+            .set(&mut document, json!("d")));
+        assert!(!JsonPath::parse("$.missing.name")
+            .unwrap()
+            .set(&mut document, json!(1)));
+
+        assert_eq!(
+            document,
+            json!({"user": {"name": "ann", "tags": ["a", "c"]}})
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use super::eviction::{footprint, measure, MEASURE_ON_WRITE_MAX};
+use super::Entry;
+use bytes::Bytes;
+use std::collections::hash_map::{self, DefaultHasher, Iter};
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// Key -> entry map with a stable iteration order for SCAN
+///
+/// A `HashMap` gives no way to resume an iteration after it was mutated, so
+/// every key is also indexed by a fixed 64-bit hash. SCAN walks that index
+/// in hash order and the cursor is simply the next hash to visit. Because a
+/// key's position never depends on the other keys, a key that exists for
+/// the whole iteration is returned exactly once, no matter how many keys
+/// are added or removed in between. (Redis gets a weaker "at least once"
+/// guarantee from reverse-binary cursors over its bucket array; without
+/// bucket access that scheme is not available on `std::HashMap`.)
+///
+/// The price is a second copy of every key and an O(log n) index update
+/// whenever a key is created or removed; overwrites and in-place updates do
+/// not touch the index.
+///
+/// The memory held by the entries is kept as a running total, so `maxmemory`
+/// is checked without walking the keyspace.
+pub(super) struct Keyspace {
+    map: HashMap<Bytes, Entry>,
+    order: BTreeSet<(u64, Bytes)>,
+
+    /// Sum of `footprint` over all entries
+    used: usize,
+}
+
+/// Position of `key` in the SCAN order
+fn scan_hash(key: &[u8]) -> u64 {
+    // DefaultHasher::new() uses fixed keys, so the order is stable
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Keyspace {
+    pub(super) fn new() -> Self {
+        Keyspace {
+            map: HashMap::new(),
+            order: BTreeSet::new(),
+            used: 0,
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Approximate bytes held by all entries, see `remeasure`
+    pub(super) fn used(&self) -> usize {
+        self.used
+    }
+
+    pub(super) fn get(&self, key: &[u8]) -> Option<&Entry> {
+        self.map.get(key)
+    }
+
+    pub(super) fn get_mut(&mut self, key: &[u8]) -> Option<&mut Entry> {
+        self.map.get_mut(key)
+    }
+
+    pub(super) fn iter(&self) -> Iter<'_, Bytes, Entry> {
+        self.map.iter()
+    }
+
+    pub(super) fn insert(&mut self, key: Bytes, entry: Entry) -> Option<Entry> {
+        self.used += footprint(&key, &entry);
+        if !self.map.contains_key(&key) {
+            self.order.insert((scan_hash(&key), key.clone()));
+        }
+        let key_len = key.len();
+        let old = self.map.insert(key, entry)?;
+        self.used -= key_len + old.size;
+        Some(old)
+    }
+
+    pub(super) fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.map.remove(key)?;
+        self.order
+            .remove(&(scan_hash(key), Bytes::copy_from_slice(key)));
+        self.used -= footprint(key, &entry);
+        Some(entry)
+    }
+
+    /// Get the entry for `key`, inserting `make()` if it doesn't exist
+    pub(super) fn get_or_insert_with(
+        &mut self,
+        key: Bytes,
+        make: impl FnOnce() -> Entry,
+    ) -> &mut Entry {
+        match self.map.entry(key) {
+            hash_map::Entry::Occupied(slot) => slot.into_mut(),
+            hash_map::Entry::Vacant(slot) => {
+                self.order
+                    .insert((scan_hash(slot.key()), slot.key().clone()));
+                let entry = make();
+                self.used += footprint(slot.key(), &entry);
+                slot.insert(entry)
+            }
+        }
+    }
+
+    /// Every entry in SCAN order, from `position` round to just before it
+    pub(super) fn iter_from(&self, position: u64) -> impl Iterator<Item = (&Bytes, &Entry)> {
+        let start = (position, Bytes::new());
+        self.order
+            .range(start.clone()..)
+            .chain(self.order.range(..start))
+            .filter_map(|(_, key)| Some((key, self.map.get(key)?)))
+    }
+
+    /// Measure the value at `key` again, unless it is a large collection
+    ///
+    /// Those are only measured by `remeasure`; see `MEASURE_ON_WRITE_MAX`.
+    pub(super) fn measure_small(&mut self, key: &[u8]) {
+        if let Some(entry) = self.map.get_mut(key) {
+            if entry.value.free_effort() <= MEASURE_ON_WRITE_MAX {
+                let size = measure(&entry.value);
+                self.used = self.used - entry.size + size;
+                entry.size = size;
+            }
+        }
+    }
+
+    /// Measure the values of about `count` keys again, starting at `cursor`
+    ///
+    /// Large values changed in place are not measured as they change, so
+    /// `used` lags behind until they are visited here. Walks the keyspace like
+    /// `scan` and returns the cursor to continue from, 0 after a full pass.
+    pub(super) fn remeasure(&mut self, cursor: u64, count: usize) -> u64 {
+        let mut last_hash = None;
+
+        let keys = self.order.range((cursor, Bytes::new())..);
+        for (visited, (hash, key)) in keys.enumerate() {
+            if visited >= count && last_hash != Some(*hash) {
+                return *hash;
+            }
+            last_hash = Some(*hash);
+            if let Some(entry) = self.map.get_mut(key) {
+                let size = measure(&entry.value);
+                self.used = self.used - entry.size + size;
+                entry.size = size;
+            }
+        }
+
+        0
+    }
+
+    /// Visit keys in SCAN order starting at `cursor`
+    ///
+    /// Returns at least `count` keys (unless the end is reached) and the
+    /// cursor to continue from, 0 once the whole keyspace was visited.
+    /// Keys sharing a hash are always returned together, so the cursor
+    /// never points into the middle of a collision group.
+    pub(super) fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(&Bytes, &Entry)>) {
+        let mut found = Vec::with_capacity(count);
+        let mut last_hash = None;
+
+        for (hash, key) in self.order.range((cursor, Bytes::new())..) {
+            if found.len() >= count && last_hash != Some(*hash) {
+                return (*hash, found);
+            }
+            last_hash = Some(*hash);
+            if let Some(entry) = self.map.get(key) {
+                found.push((key, entry));
+            }
+        }
+
+        (0, found)
+    }
+}
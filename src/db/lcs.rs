@@ -0,0 +1,85 @@
+/// Largest DP table `lcs` will build, in cells (`(len(a) + 1) * (len(b) + 1)`)
+///
+/// The table takes four bytes per cell, so this caps LCS at 64 MiB of memory
+/// and a comparable amount of CPU time; two 4 KiB strings fit comfortably.
+pub const LCS_MAX_CELLS: usize = 1 << 24;
+
+/// Error returned when the inputs exceed `LCS_MAX_CELLS`
+pub const LCS_TOO_LARGE_ERR: &str = "ERR LCS inputs are too large";
+
+/// One run of consecutive matching bytes, as inclusive ranges into both inputs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LcsMatch {
+    pub a: (usize, usize),
+    pub b: (usize, usize),
+}
+
+impl LcsMatch {
+    /// Number of bytes in the run
+    pub fn match_len(&self) -> usize {
+        self.a.1 - self.a.0 + 1
+    }
+}
+
+/// Longest common subsequence of two byte strings
+#[derive(Debug, PartialEq)]
+pub struct Lcs {
+    pub sequence: Vec<u8>,
+
+    /// Runs making up `sequence`, from the end of the inputs to the start
+    /// (the order Redis reports them in)
+    pub matches: Vec<LcsMatch>,
+}
+
+/// Compute the LCS of `a` and `b` with the standard dynamic program
+///
+/// Ties are broken the same way as in Redis, so `LCS ... IDX` reports the
+/// same ranges.
+pub fn lcs(a: &[u8], b: &[u8]) -> Result<Lcs, &'static str> {
+    let width = b.len() + 1;
+    let cells = (a.len() + 1)
+        .checked_mul(width)
+        .filter(|&cells| cells <= LCS_MAX_CELLS)
+        .ok_or(LCS_TOO_LARGE_ERR)?;
+
+    // table[i * width + j] is the LCS length of a[..i] and b[..j]
+    let mut table = vec![0u32; cells];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            table[i * width + j] = if a[i - 1] == b[j - 1] {
+                table[(i - 1) * width + j - 1] + 1
+            } else {
+                table[(i - 1) * width + j].max(table[i * width + j - 1])
+            };
+        }
+    }
+
+    let mut sequence = Vec::with_capacity(table[cells - 1] as usize);
+    let mut matches: Vec<LcsMatch> = Vec::new();
+    let (mut i, mut j) = (a.len(), b.len());
+    while i > 0 && j > 0 {
+        if a[i - 1] == b[j - 1] {
+            sequence.push(a[i - 1]);
+            match matches.last_mut() {
+                // Extends the current run backwards
+                Some(run) if run.a.0 == i && run.b.0 == j => {
+                    run.a.0 = i - 1;
+                    run.b.0 = j - 1;
+                }
+                _ => matches.push(LcsMatch {
+                    a: (i - 1, i - 1),
+                    b: (j - 1, j - 1),
+                }),
+            }
+            i -= 1;
+            j -= 1;
+        } else if table[(i - 1) * width + j] > table[i * width + j - 1] {
+            i -= 1;
+        } else {
+            j -= 1;
+        }
+    }
+    sequence.reverse();
+
+    Ok(Lcs { sequence, matches })
+}
@@ -0,0 +1,238 @@
+use bytes::Bytes;
+use std::collections::VecDeque;
+
+/// Default for `list-max-listpack-size`, counted in elements
+pub const DEFAULT_LIST_MAX_LISTPACK_SIZE: usize = 128;
+
+/// List value with two internal representations
+///
+/// Small lists are kept in a single contiguous vector (the equivalent of a
+/// Redis listpack). Once a list grows past `list-max-listpack-size` it
+/// becomes a quicklist: a deque of nodes holding up to that many elements
+/// each, so pushes and pops at either end never move the whole list. Like
+/// hashes, a quicklist is never converted back when it shrinks.
+#[derive(Clone, Debug)]
+pub enum ListValue {
+    Listpack(Vec<Bytes>),
+    Quicklist(Quicklist),
+}
+
+/// Segmented list representation, see `ListValue`
+#[derive(Clone, Debug, Default)]
+pub struct Quicklist {
+    /// None of the nodes is empty
+    nodes: VecDeque<VecDeque<Bytes>>,
+
+    /// Total number of elements
+    len: usize,
+}
+
+impl ListValue {
+    pub fn new() -> Self {
+        ListValue::Listpack(Vec::new())
+    }
+
+    /// Name reported by `OBJECT ENCODING`
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            ListValue::Listpack(_) => "listpack",
+            ListValue::Quicklist(_) => "quicklist",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ListValue::Listpack(items) => items.len(),
+            ListValue::Quicklist(quicklist) => quicklist.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add `value` at the head, converting to a quicklist once
+    /// `max_listpack_size` is exceeded
+    pub fn push_front(&mut self, value: Bytes, max_listpack_size: usize) {
+        match self {
+            ListValue::Listpack(items) => {
+                items.insert(0, value);
+                self.convert_if_larger(max_listpack_size);
+            }
+            ListValue::Quicklist(quicklist) => quicklist.push_front(value, max_listpack_size),
+        }
+    }
+
+    /// Add `value` at the tail, converting to a quicklist once
+    /// `max_listpack_size` is exceeded
+    pub fn push_back(&mut self, value: Bytes, max_listpack_size: usize) {
+        match self {
+            ListValue::Listpack(items) => {
+                items.push(value);
+                self.convert_if_larger(max_listpack_size);
+            }
+            ListValue::Quicklist(quicklist) => quicklist.push_back(value, max_listpack_size),
+        }
+    }
+
+    pub fn pop_front(&mut self) -> Option<Bytes> {
+        match self {
+            ListValue::Listpack(items) if items.is_empty() => None,
+            ListValue::Listpack(items) => Some(items.remove(0)),
+            ListValue::Quicklist(quicklist) => quicklist.pop_front(),
+        }
+    }
+
+    pub fn pop_back(&mut self) -> Option<Bytes> {
+        match self {
+            ListValue::Listpack(items) => items.pop(),
+            ListValue::Quicklist(quicklist) => quicklist.pop_back(),
+        }
+    }
+
+    /// Insert `value` so that it ends up at `index` (at most `len()`)
+    pub fn insert(&mut self, index: usize, value: Bytes, max_listpack_size: usize) {
+        match self {
+            ListValue::Listpack(items) => {
+                items.insert(index, value);
+                self.convert_if_larger(max_listpack_size);
+            }
+            ListValue::Quicklist(quicklist) => quicklist.insert(index, value, max_listpack_size),
+        }
+    }
+
+    /// Copies of the elements at `start..end`
+    pub fn range(&self, start: usize, end: usize) -> Vec<Bytes> {
+        let count = end.saturating_sub(start);
+        match self {
+            ListValue::Listpack(items) => items[start.min(items.len())..]
+                .iter()
+                .take(count)
+                .cloned()
+                .collect(),
+            ListValue::Quicklist(quicklist) => {
+                // Skip whole nodes before `start`
+                let mut skip = start;
+                let mut range = Vec::with_capacity(count.min(quicklist.len));
+                for node in &quicklist.nodes {
+                    if range.len() == count {
+                        break;
+                    }
+                    if skip >= node.len() {
+                        skip -= node.len();
+                        continue;
+                    }
+                    let wanted = count - range.len();
+                    range.extend(node.iter().skip(skip).take(wanted).cloned());
+                    skip = 0;
+                }
+                range
+            }
+        }
+    }
+
+    /// Iterate over the elements from head to tail
+    pub fn iter(&self) -> Box<dyn Iterator<Item = &Bytes> + '_> {
+        match self {
+            ListValue::Listpack(items) => Box::new(items.iter()),
+            ListValue::Quicklist(quicklist) => Box::new(quicklist.nodes.iter().flatten()),
+        }
+    }
+
+    /// Copy with the same representation, see `Value::deep_clone`
+    pub(super) fn map(&self, f: impl Fn(&Bytes) -> Bytes) -> ListValue {
+        match self {
+            ListValue::Listpack(items) => ListValue::Listpack(items.iter().map(f).collect()),
+            ListValue::Quicklist(quicklist) => ListValue::Quicklist(Quicklist {
+                nodes: quicklist
+                    .nodes
+                    .iter()
+                    .map(|node| node.iter().map(&f).collect())
+                    .collect(),
+                len: quicklist.len,
+            }),
+        }
+    }
+
+    fn convert_if_larger(&mut self, max_listpack_size: usize) {
+        if let ListValue::Listpack(items) = self {
+            if items.len() > max_listpack_size {
+                let mut quicklist = Quicklist::default();
+                for item in std::mem::take(items) {
+                    quicklist.push_back(item, max_listpack_size);
+                }
+                *self = ListValue::Quicklist(quicklist);
+            }
+        }
+    }
+}
+
+impl Default for ListValue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Quicklist {
+    fn push_front(&mut self, value: Bytes, node_size: usize) {
+        match self.nodes.front_mut() {
+            Some(node) if node.len() < node_size.max(1) => node.push_front(value),
+            _ => self.nodes.push_front(VecDeque::from([value])),
+        }
+        self.len += 1;
+    }
+
+    fn push_back(&mut self, value: Bytes, node_size: usize) {
+        match self.nodes.back_mut() {
+            Some(node) if node.len() < node_size.max(1) => node.push_back(value),
+            _ => self.nodes.push_back(VecDeque::from([value])),
+        }
+        self.len += 1;
+    }
+
+    fn pop_front(&mut self) -> Option<Bytes> {
+        let node = self.nodes.front_mut()?;
+        let value = node.pop_front();
+        if node.is_empty() {
+            self.nodes.pop_front();
+        }
+        self.len -= 1;
+        value
+    }
+
+    fn pop_back(&mut self) -> Option<Bytes> {
+        let node = self.nodes.back_mut()?;
+        let value = node.pop_back();
+        if node.is_empty() {
+            self.nodes.pop_back();
+        }
+        self.len -= 1;
+        value
+    }
+
+    /// Insert into the node holding `index`, splitting it in two if it
+    /// grows past `node_size`
+    fn insert(&mut self, mut index: usize, value: Bytes, node_size: usize) {
+        if index == self.len {
+            return self.push_back(value, node_size);
+        }
+        let position = self
+            .nodes
+            .iter()
+            .position(|node| {
+                if index < node.len() {
+                    return true;
+                }
+                index -= node.len();
+                false
+            })
+            .expect("index within the list");
+        let node = &mut self.nodes[position];
+        node.insert(index, value);
+        if node.len() > node_size.max(1) {
+            let tail = node.split_off(node.len() / 2);
+            self.nodes.insert(position + 1, tail);
+        }
+        self.len += 1;
+    }
+}
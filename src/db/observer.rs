@@ -0,0 +1,71 @@
+use crate::util::{lock, read, write};
+use bytes::Bytes;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// A write applied to a database, as passed to a change observer
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeEvent {
+    /// Name of the command that made the change, e.g. `"SET"`
+    pub command: &'static str,
+
+    /// The key that changed, `None` when the whole database did (FLUSHDB)
+    pub key: Option<Bytes>,
+
+    /// Index of the database the change was made in
+    pub db: usize,
+}
+
+/// Callback invoked with every change made to a database
+pub type ChangeObserver = Arc<dyn Fn(&ChangeEvent) + Send + Sync>;
+
+/// The observer registered on a database
+///
+/// Events are delivered on the task that made the change, after the
+/// database lock is released, so the callback may use the database itself.
+/// Inside MULTI/EXEC other clients' operations are held up until the
+/// transaction ends; its events are deferred until then.
+#[derive(Default)]
+pub(super) struct ChangeFeed {
+    observer: RwLock<Option<(usize, ChangeObserver)>>,
+
+    /// Events of the running transaction
+    deferred: Mutex<Vec<ChangeEvent>>,
+}
+
+impl ChangeFeed {
+    pub(super) fn set(&self, db: usize, observer: Option<ChangeObserver>) {
+        *write(&self.observer) = observer.map(|observer| (db, observer));
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        read(&self.observer).is_some()
+    }
+
+    /// Deliver a change now, or once the transaction ends if `defer` is set
+    pub(super) fn publish(&self, command: &'static str, key: Option<Bytes>, defer: bool) {
+        // Not held while the callback runs, which may replace the observer
+        let Some((db, observer)) = read(&self.observer).clone() else {
+            return;
+        };
+        let event = ChangeEvent { command, key, db };
+        if defer {
+            lock(&self.deferred).push(event);
+        } else {
+            observer(&event);
+        }
+    }
+
+    /// Deliver the events deferred by a finished transaction
+    pub(super) fn flush_deferred(&self) {
+        let events = std::mem::take(&mut *lock(&self.deferred));
+        if events.is_empty() {
+            return;
+        }
+        let Some((_, observer)) = read(&self.observer).clone() else {
+            return;
+        };
+        for event in &events {
+            observer(event);
+        }
+    }
+}
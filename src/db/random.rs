@@ -0,0 +1,42 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Where SPOP and SRANDMEMBER take their random picks from
+///
+/// Databases draw from fresh entropy for every pick. A seeded source, set
+/// with `Db::with_rng`, makes the picks a fixed sequence so tests can check
+/// them, and check a distribution without depending on the thread they run
+/// on.
+#[derive(Clone, Debug, Default)]
+pub enum DbRng {
+    #[default]
+    Entropy,
+
+    /// SplitMix64 state
+    Seeded(u64),
+}
+
+impl DbRng {
+    /// A source whose picks depend only on `seed`
+    pub fn seeded(seed: u64) -> Self {
+        DbRng::Seeded(seed)
+    }
+
+    /// Random number in `0..len`; `len` must not be 0
+    pub fn index(&mut self, len: usize) -> usize {
+        (self.next_u64() % len as u64) as usize
+    }
+
+    pub(super) fn next_u64(&mut self) -> u64 {
+        match self {
+            DbRng::Entropy => RandomState::new().build_hasher().finish(),
+            DbRng::Seeded(state) => {
+                *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^ (z >> 31)
+            }
+        }
+    }
+}
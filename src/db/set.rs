@@ -0,0 +1,110 @@
+use crate::util::parse_redis_int;
+use bytes::Bytes;
+use std::collections::HashSet;
+
+/// Default for `set-max-intset-entries` (same as Redis)
+pub const DEFAULT_SET_MAX_INTSET_ENTRIES: usize = 512;
+
+/// Set value with two internal representations
+///
+/// Sets whose members are all integers (in the strict Redis sense, see
+/// `parse_redis_int`) are kept as a sorted `Vec<i64>`, the equivalent of a
+/// Redis intset. Adding a non-integer member or growing past
+/// `set-max-intset-entries` converts the set to a `HashSet`. Like Redis, a
+/// converted set is never turned back into an intset.
+#[derive(Clone, Debug)]
+pub enum SetValue {
+    IntSet(Vec<i64>),
+    Table(HashSet<Bytes>),
+}
+
+impl SetValue {
+    pub fn new() -> Self {
+        SetValue::IntSet(Vec::new())
+    }
+
+    /// Name reported by `OBJECT ENCODING`
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            SetValue::IntSet(_) => "intset",
+            SetValue::Table(_) => "hashtable",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            SetValue::IntSet(ints) => ints.len(),
+            SetValue::Table(table) => table.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        match self {
+            SetValue::IntSet(ints) => parse_redis_int(member)
+                .map(|n| ints.binary_search(&n).is_ok())
+                .unwrap_or(false),
+            SetValue::Table(table) => table.contains(member),
+        }
+    }
+
+    /// Add a member, converting to a hashtable when needed
+    ///
+    /// Returns true if the member is new.
+    pub fn insert(&mut self, member: Bytes, max_intset_entries: usize) -> bool {
+        if let SetValue::IntSet(ints) = self {
+            if let Ok(n) = parse_redis_int(&member) {
+                match ints.binary_search(&n) {
+                    Ok(_) => return false,
+                    Err(index) if ints.len() < max_intset_entries => {
+                        ints.insert(index, n);
+                        return true;
+                    }
+                    // Full: fall through to the conversion
+                    Err(_) => {}
+                }
+            }
+            let table = ints.iter().map(|n| Bytes::from(n.to_string())).collect();
+            *self = SetValue::Table(table);
+        }
+
+        match self {
+            SetValue::Table(table) => table.insert(member),
+            SetValue::IntSet(_) => unreachable!("converted above"),
+        }
+    }
+
+    /// Remove a member, returning true if it was present
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        match self {
+            SetValue::IntSet(ints) => match parse_redis_int(member) {
+                Ok(n) => match ints.binary_search(&n) {
+                    Ok(index) => {
+                        ints.remove(index);
+                        true
+                    }
+                    Err(_) => false,
+                },
+                Err(_) => false,
+            },
+            SetValue::Table(table) => table.remove(member),
+        }
+    }
+
+    /// Iterate over the members (ascending for intsets)
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Bytes> + '_> {
+        match self {
+            SetValue::IntSet(ints) => Box::new(ints.iter().map(|n| Bytes::from(n.to_string()))),
+            SetValue::Table(table) => Box::new(table.iter().cloned()),
+        }
+    }
+}
+
+impl Default for SetValue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,169 @@
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+
+/// Reply to a stream command given a malformed ID
+pub const INVALID_STREAM_ID_ERR: &str =
+    "ERR Invalid stream ID specified as stream command argument";
+
+/// ID of a stream entry, `<ms>-<seq>`
+///
+/// Ordered by milliseconds, then by sequence number.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parse `<ms>-<seq>`, or `<ms>` alone with `default_seq` as the
+    /// sequence number
+    pub fn parse(id: &[u8], default_seq: u64) -> Result<StreamId, &'static str> {
+        let id = std::str::from_utf8(id).map_err(|_| INVALID_STREAM_ID_ERR)?;
+        let number = |part: &str| part.parse::<u64>().map_err(|_| INVALID_STREAM_ID_ERR);
+        match id.split_once('-') {
+            Some((ms, seq)) => Ok(StreamId {
+                ms: number(ms)?,
+                seq: number(seq)?,
+            }),
+            None => Ok(StreamId {
+                ms: number(id)?,
+                seq: default_seq,
+            }),
+        }
+    }
+
+    /// Parse an XRANGE bound: `-`, `+`, or an ID whose sequence number
+    /// defaults to the lowest (`start`) or highest (`end`) one
+    pub fn parse_bound(bound: &[u8], start: bool) -> Result<StreamId, &'static str> {
+        match bound {
+            b"-" => Ok(StreamId::MIN),
+            b"+" => Ok(StreamId::MAX),
+            id => StreamId::parse(id, if start { 0 } else { u64::MAX }),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// A stream entry as returned by range queries: its ID and field/value pairs
+pub type StreamEntry = (StreamId, Vec<(Bytes, Bytes)>);
+
+/// Stream value: entries of field/value pairs in ID order
+///
+/// IDs only ever grow. `last_id` is the largest ID handed out, so an entry
+/// added in the same millisecond as the previous one gets the next
+/// sequence number.
+#[derive(Clone, Debug, Default)]
+pub struct StreamValue {
+    entries: BTreeMap<StreamId, Vec<(Bytes, Bytes)>>,
+    last_id: StreamId,
+}
+
+impl StreamValue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Largest ID ever added, which `XREAD ... $` reads after
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// The ID `XADD *` assigns at `now_ms`
+    ///
+    /// Never goes backwards: if the clock is not past the last ID's
+    /// millisecond, the last ID's sequence number is incremented instead.
+    /// `None` once no larger ID exists.
+    pub fn next_id(&self, now_ms: u64) -> Option<StreamId> {
+        if now_ms > self.last_id.ms {
+            return Some(StreamId { ms: now_ms, seq: 0 });
+        }
+        match self.last_id.seq.checked_add(1) {
+            Some(seq) => Some(StreamId {
+                ms: self.last_id.ms,
+                seq,
+            }),
+            None => self
+                .last_id
+                .ms
+                .checked_add(1)
+                .map(|ms| StreamId { ms, seq: 0 }),
+        }
+    }
+
+    /// Add an entry, whose ID must be larger than every ID added before
+    pub fn add(&mut self, id: StreamId, fields: Vec<(Bytes, Bytes)>) -> Result<(), &'static str> {
+        if id == StreamId::MIN {
+            return Err("ERR The ID specified in XADD must be greater than 0-0");
+        }
+        if id <= self.last_id {
+            return Err(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item",
+            );
+        }
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        Ok(())
+    }
+
+    /// Entries with IDs from `start` to `end`, both inclusive, in ID order
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl Iterator<Item = (&StreamId, &Vec<(Bytes, Bytes)>)> {
+        // BTreeMap::range panics on an inverted range
+        (start <= end)
+            .then(|| self.entries.range(start..=end))
+            .into_iter()
+            .flatten()
+    }
+
+    /// Entries with IDs larger than `id`, in ID order
+    pub fn after(&self, id: StreamId) -> impl Iterator<Item = (&StreamId, &Vec<(Bytes, Bytes)>)> {
+        self.entries.range((Bound::Excluded(id), Bound::Unbounded))
+    }
+
+    /// Every entry in ID order
+    pub fn iter(&self) -> impl Iterator<Item = (&StreamId, &Vec<(Bytes, Bytes)>)> {
+        self.entries.iter()
+    }
+
+    /// Copy the value, mapping every field and value through `copy`
+    pub fn map(&self, copy: impl Fn(&Bytes) -> Bytes) -> StreamValue {
+        StreamValue {
+            entries: self
+                .entries
+                .iter()
+                .map(|(id, fields)| {
+                    let fields = fields
+                        .iter()
+                        .map(|(field, value)| (copy(field), copy(value)))
+                        .collect();
+                    (*id, fields)
+                })
+                .collect(),
+            last_id: self.last_id,
+        }
+    }
+}
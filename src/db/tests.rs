@@ -1,6 +1,8 @@
 #[cfg(test)]
 mod tests {
     use super::super::*;
+    use crate::notify::NotifyFlags;
+    use crate::pubsub::PubSub;
     use bytes::Bytes;
 
     #[test]
@@ -8,14 +10,11 @@ mod tests {
         let db = Db::new();
 
         // Test write and read
-        db.write_string("key1".to_string(), Bytes::from("value1"), None);
-        assert_eq!(
-            db.read_string("key1").unwrap(),
-            Bytes::from("value1")
-        );
+        db.write_string(Bytes::from("key1"), Bytes::from("value1"), None);
+        assert_eq!(db.read_string(b"key1").unwrap(), Bytes::from("value1"));
 
         // Test non-existent key
-        assert!(db.read_string("nonexistent").is_none());
+        assert!(db.read_string(b"nonexistent").is_none());
     }
 
     #[test]
@@ -27,28 +26,28 @@ mod tests {
         // Then b is pushed to front, then a is pushed to front
         // Result: [a, b] (a at head)
         let len = db.lpush(
-            "mylist".to_string(),
+            Bytes::from("mylist"),
             vec![Bytes::from("a"), Bytes::from("b")],
         );
         assert_eq!(len, 2);
 
         // Test RPUSH - adds to tail
-        let len = db.rpush("mylist".to_string(), vec![Bytes::from("c")]);
+        let len = db.rpush(Bytes::from("mylist"), vec![Bytes::from("c")]);
         assert_eq!(len, 3);
 
         // Test LRANGE - list is now [a, b, c]
-        let range = db.lrange("mylist", 0, -1).unwrap();
+        let range = db.lrange(b"mylist", 0, -1).unwrap();
         assert_eq!(range.len(), 3);
         assert_eq!(range[0], Bytes::from("a"));
         assert_eq!(range[1], Bytes::from("b"));
         assert_eq!(range[2], Bytes::from("c"));
 
         // Test LPOP - removes from head (a)
-        let value = db.lpop("mylist").unwrap();
+        let value = db.lpop(b"mylist").unwrap();
         assert_eq!(value, Bytes::from("a"));
 
         // Test LLEN - should have 2 items left
-        assert_eq!(db.llen("mylist").unwrap(), 2);
+        assert_eq!(db.llen(b"mylist").unwrap(), 2);
     }
 
     #[test]
@@ -57,22 +56,107 @@ mod tests {
 
         // Test SADD
         let added = db.sadd(
-            "myset".to_string(),
-            vec!["a".to_string(), "b".to_string(), "c".to_string()],
+            Bytes::from("myset"),
+            vec![Bytes::from("a"), Bytes::from("b"), Bytes::from("c")],
         );
         assert_eq!(added, 3);
 
         // Test SISMEMBER
-        assert!(db.sismember("myset", "a"));
-        assert!(!db.sismember("myset", "d"));
+        assert!(db.sismember(b"myset", b"a").unwrap());
+        assert!(!db.sismember(b"myset", b"d").unwrap());
 
         // Test SCARD
-        assert_eq!(db.scard("myset"), 3);
+        assert_eq!(db.scard(b"myset").unwrap(), 3);
 
         // Test SREM
-        let removed = db.srem("myset", vec!["b".to_string()]);
+        let removed = db.srem(b"myset", vec![Bytes::from("b")]).unwrap();
         assert_eq!(removed, 1);
-        assert_eq!(db.scard("myset"), 2);
+        assert_eq!(db.scard(b"myset").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_spop() {
+        let db = Db::new();
+        let members: Vec<Bytes> = (0..10).map(|i| Bytes::from(format!("m{}", i))).collect();
+        db.sadd(Bytes::from("s"), members.clone());
+
+        let first = db.spop(b"s", 3).unwrap();
+        assert_eq!(first.len(), 3);
+        assert_eq!(db.scard(b"s").unwrap(), 7);
+        for member in &first {
+            assert!(members.contains(member));
+            assert!(!db.sismember(b"s", member).unwrap());
+        }
+
+        // Asking for more than there are pops the rest
+        let mut rest = db.spop(b"s", 100).unwrap();
+        assert_eq!(rest.len(), 7);
+        rest.extend(first);
+        rest.sort();
+        assert_eq!(rest, members);
+        assert_eq!(db.spop(b"s", 1), Ok(Vec::new()));
+        assert_eq!(db.spop(b"missing", 1), Ok(Vec::new()));
+
+        db.write_string(Bytes::from("str"), Bytes::from("v"), None);
+        assert_eq!(db.spop(b"str", 1), Err(WRONGTYPE_ERR));
+    }
+
+    #[test]
+    fn test_seeded_srandmember_picks() {
+        let seeded = |seed| {
+            let db = Db::new().with_rng(DbRng::seeded(seed));
+            let members: Vec<Bytes> = (1..=10).map(|i| Bytes::from(i.to_string())).collect();
+            db.sadd(Bytes::from("s"), members);
+            db
+        };
+        let picks = |db: &Db| -> Vec<Bytes> {
+            (0..6)
+                .flat_map(|_| db.srandmember(b"s", 1).unwrap())
+                .collect()
+        };
+
+        let db = seeded(42);
+        let expected: Vec<Bytes> = ["4", "2", "9", "5", "1", "3"]
+            .into_iter()
+            .map(Bytes::from)
+            .collect();
+        assert_eq!(picks(&db), expected);
+        // The same seed picks the same members, whichever thread asks
+        let other = std::thread::scope(|scope| scope.spawn(|| picks(&seeded(42))).join().unwrap());
+        assert_eq!(other, expected);
+
+        // Repeats allowed, and spread evenly
+        let draws = db.srandmember(b"s", -10_000).unwrap();
+        assert_eq!(draws.len(), 10_000);
+        for member in 1..=10 {
+            let hits = draws.iter().filter(|d| **d == member.to_string()).count();
+            assert!(
+                (800..1200).contains(&hits),
+                "{} drawn {} times",
+                member,
+                hits
+            );
+        }
+        // Distinct picks stop at the size of the set
+        let mut all = db.srandmember(b"s", 20).unwrap();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 10);
+        assert_eq!(db.scard(b"s").unwrap(), 10);
+        assert_eq!(db.srandmember(b"missing", 1), Ok(Vec::new()));
+        // Repeated picks are bounded before anything is allocated
+        assert_eq!(
+            db.srandmember(b"s", -(MAX_REPEATED_PICKS as i64))
+                .unwrap()
+                .len(),
+            MAX_REPEATED_PICKS
+        );
+        for count in [-(MAX_REPEATED_PICKS as i64) - 1, -10_000_000_000, i64::MIN] {
+            assert_eq!(
+                db.srandmember(b"s", count),
+                Err("ERR value is out of range")
+            );
+        }
     }
 
     #[test]
@@ -81,28 +165,28 @@ mod tests {
 
         // Test HSET
         let is_new = db.hset(
-            "user:1".to_string(),
-            "name".to_string(),
+            Bytes::from("user:1"),
+            Bytes::from("name"),
             Bytes::from("Alice"),
         );
         assert!(is_new);
 
         // Test HGET
-        let value = db.hget("user:1", "name").unwrap();
+        let value = db.hget(b"user:1", b"name").unwrap().unwrap();
         assert_eq!(value, Bytes::from("Alice"));
 
         // Test HEXISTS
-        assert!(db.hexists("user:1", "name"));
-        assert!(!db.hexists("user:1", "age"));
+        assert!(db.hexists(b"user:1", b"name").unwrap());
+        assert!(!db.hexists(b"user:1", b"age").unwrap());
 
         // Test HLEN
-        db.hset("user:1".to_string(), "age".to_string(), Bytes::from("30"));
-        assert_eq!(db.hlen("user:1"), 2);
+        db.hset(Bytes::from("user:1"), Bytes::from("age"), Bytes::from("30"));
+        assert_eq!(db.hlen(b"user:1").unwrap(), 2);
 
         // Test HDEL
-        let deleted = db.hdel("user:1", vec!["age".to_string()]);
+        let deleted = db.hdel(b"user:1", vec![Bytes::from("age")]).unwrap();
         assert_eq!(deleted, 1);
-        assert_eq!(db.hlen("user:1"), 1);
+        assert_eq!(db.hlen(b"user:1").unwrap(), 1);
     }
 
     #[test]
@@ -110,25 +194,25 @@ mod tests {
         let db = Db::new();
 
         // Add some keys
-        db.write_string("key1".to_string(), Bytes::from("val1"), None);
-        db.write_string("key2".to_string(), Bytes::from("val2"), None);
-        db.lpush("list1".to_string(), vec![Bytes::from("item")]);
+        db.write_string(Bytes::from("key1"), Bytes::from("val1"), None);
+        db.write_string(Bytes::from("key2"), Bytes::from("val2"), None);
+        db.lpush(Bytes::from("list1"), vec![Bytes::from("item")]);
 
         // Test DBSIZE
         assert_eq!(db.dbsize(), 3);
 
         // Test EXISTS
-        assert!(db.exists("key1"));
-        assert!(!db.exists("nonexistent"));
+        assert!(db.exists(b"key1"));
+        assert!(!db.exists(b"nonexistent"));
 
         // Test TYPE
-        assert_eq!(db.get_type("key1"), Some("string"));
-        assert_eq!(db.get_type("list1"), Some("list"));
-        assert_eq!(db.get_type("nonexistent"), None);
+        assert_eq!(db.get_type(b"key1"), Some("string"));
+        assert_eq!(db.get_type(b"list1"), Some("list"));
+        assert_eq!(db.get_type(b"nonexistent"), None);
 
         // Test DEL
-        assert!(db.delete("key1"));
-        assert!(!db.delete("nonexistent"));
+        assert!(db.delete(b"key1"));
+        assert!(!db.delete(b"nonexistent"));
         assert_eq!(db.dbsize(), 2);
 
         // Test FLUSHDB
@@ -141,21 +225,21 @@ mod tests {
         let db = Db::new();
 
         // Add various keys
-        db.write_string("user:1".to_string(), Bytes::from("a"), None);
-        db.write_string("user:2".to_string(), Bytes::from("b"), None);
-        db.write_string("session:1".to_string(), Bytes::from("c"), None);
-        db.write_string("data".to_string(), Bytes::from("d"), None);
+        db.write_string(Bytes::from("user:1"), Bytes::from("a"), None);
+        db.write_string(Bytes::from("user:2"), Bytes::from("b"), None);
+        db.write_string(Bytes::from("session:1"), Bytes::from("c"), None);
+        db.write_string(Bytes::from("data"), Bytes::from("d"), None);
 
         // Test wildcard pattern
-        let keys = db.keys("user:*");
+        let keys = db.keys(b"user:*").unwrap();
         assert_eq!(keys.len(), 2);
 
         // Test all keys
-        let all_keys = db.keys("*");
+        let all_keys = db.keys(b"*").unwrap();
         assert_eq!(all_keys.len(), 4);
 
         // Test single char wildcard
-        let keys = db.keys("user:?");
+        let keys = db.keys(b"user:?").unwrap();
         assert_eq!(keys.len(), 2);
     }
 
@@ -166,16 +250,42 @@ mod tests {
 
         // Set a key with 1 second expiration
         let expires_at = Instant::now() + Duration::from_millis(100);
-        db.write_string("temp".to_string(), Bytes::from("value"), Some(expires_at));
+        db.write_string(Bytes::from("temp"), Bytes::from("value"), Some(expires_at));
 
         // Should exist immediately
-        assert!(db.read_string("temp").is_some());
+        assert!(db.read_string(b"temp").is_some());
 
         // Wait for expiration
         std::thread::sleep(Duration::from_millis(150));
 
         // Should be expired and return None
-        assert!(db.read_string("temp").is_none());
+        assert!(db.read_string(b"temp").is_none());
+    }
+
+    #[test]
+    fn test_expired_list_is_gone() {
+        use std::time::{Duration, Instant};
+
+        let db = Db::new();
+        let expired = |db: &Db| {
+            db.rpush(
+                Bytes::from("list"),
+                vec![Bytes::from("a"), Bytes::from("b")],
+            );
+            db.expire_at(b"list", Instant::now() + Duration::from_millis(10));
+            std::thread::sleep(Duration::from_millis(20));
+        };
+
+        expired(&db);
+        assert_eq!(db.lrange(b"list", 0, -1), None);
+        assert_eq!(db.llen(b"list"), None);
+        assert_eq!(db.get_type(b"list"), None);
+
+        expired(&db);
+        assert_eq!(db.lpop(b"list"), None);
+        expired(&db);
+        assert_eq!(db.rpop(b"list"), None);
+        assert!(!db.exists(b"list"));
     }
 
     #[test]
@@ -183,12 +293,1219 @@ mod tests {
         let db = Db::new();
 
         // Create a list
-        db.lpush("mylist".to_string(), vec![Bytes::from("item")]);
+        db.lpush(Bytes::from("mylist"), vec![Bytes::from("item")]);
 
         // Try to read as string - should return None
-        assert!(db.read_string("mylist").is_none());
+        assert!(db.read_string(b"mylist").is_none());
 
         // Type should be "list"
-        assert_eq!(db.get_type("mylist"), Some("list"));
+        assert_eq!(db.get_type(b"mylist"), Some("list"));
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        let db = Db::new();
+
+        db.write_string(Bytes::from("s1"), Bytes::from("hello"), None);
+        db.write_string(Bytes::from("s2"), Bytes::from("world!"), None);
+        db.lpush(Bytes::from("l1"), vec![Bytes::from("a"), Bytes::from("bc")]);
+        db.sadd(
+            Bytes::from("set1"),
+            vec![Bytes::from("x"), Bytes::from("yz")],
+        );
+        db.hset(Bytes::from("h1"), Bytes::from("field"), Bytes::from("v"));
+
+        let stats = db.memory_stats();
+
+        assert_eq!(stats.keys_count, db.dbsize());
+        assert_eq!(stats.strings, 2);
+        assert_eq!(stats.lists, 1);
+        assert_eq!(stats.sets, 1);
+        assert_eq!(stats.hashes, 1);
+
+        // 5 + 6 (strings) + 3 (list) + 3 (set) + 6 (hash)
+        assert_eq!(stats.dataset_bytes, 23);
+        assert!(stats.overhead_bytes > 0);
+    }
+
+    #[test]
+    fn test_volatile_ttl_evicts_soonest_to_expire() {
+        use std::time::{Duration, Instant};
+
+        let db = Db::new();
+        let now = Instant::now();
+        db.write_string(
+            Bytes::from("later"),
+            Bytes::from("v"),
+            Some(now + Duration::from_secs(60)),
+        );
+        db.write_string(
+            Bytes::from("soon"),
+            Bytes::from("v"),
+            Some(now + Duration::from_secs(5)),
+        );
+        db.write_string(Bytes::from("forever"), Bytes::from("v"), None);
+
+        // Freeing a single byte takes a single key
+        assert_eq!(db.evict(&VolatileTtl, 1).0.len(), 1);
+        assert!(db.read_string(b"soon").is_none());
+        assert!(db.read_string(b"later").is_some());
+
+        // Keys without an expiry are never picked
+        let (evicted, _) = db.evict(&VolatileTtl, usize::MAX);
+        assert_eq!(evicted, vec![Bytes::from("later")]);
+        assert_eq!(db.dbsize(), 1);
+        assert!(db.read_string(b"forever").is_some());
+    }
+
+    #[test]
+    fn test_random_eviction_picks_only_eligible_keys() {
+        use std::time::{Duration, Instant};
+
+        let db = Db::new();
+        let expires_at = Some(Instant::now() + Duration::from_secs(60));
+        for i in 0..10 {
+            db.write_string(
+                Bytes::from(format!("volatile{}", i)),
+                Bytes::from("v"),
+                expires_at,
+            );
+            db.write_string(
+                Bytes::from(format!("persistent{}", i)),
+                Bytes::from("v"),
+                None,
+            );
+        }
+        let used = db.used_memory();
+
+        let (evicted, freed) = db.evict(&VolatileRandom, usize::MAX);
+        assert_eq!(evicted.len(), 10);
+        assert_eq!(db.dbsize(), 10);
+        assert!(db.keys(b"volatile*").unwrap().is_empty());
+        assert_eq!(db.used_memory(), used - freed);
+
+        // allkeys-random stops once enough is freed
+        let (evicted, freed) = db.evict(&AllKeysRandom, 1);
+        assert_eq!(evicted.len(), 1);
+        assert!(freed > 0);
+        assert_eq!(db.dbsize(), 9);
+        assert!(db.evict(&NoEviction, usize::MAX).0.is_empty());
+    }
+
+    #[test]
+    fn test_used_memory_is_a_running_total() {
+        let db = Db::new();
+        let measured = |db: &Db| {
+            let stats = db.memory_stats();
+            stats.dataset_bytes + stats.overhead_bytes
+        };
+
+        db.write_string(Bytes::from("s"), Bytes::from("value"), None);
+        db.lpush(Bytes::from("l"), vec![Bytes::from("a")]);
+        assert_eq!(db.used_memory(), measured(&db));
+
+        db.lpush(Bytes::from("l"), vec![Bytes::from("0123456789")]);
+        assert_eq!(db.used_memory(), measured(&db));
+
+        // A large collection changed in place counts once measured again
+        let items: Vec<Bytes> = (0..200).map(|i| Bytes::from(i.to_string())).collect();
+        db.rpush(Bytes::from("l"), items);
+        let before = db.used_memory();
+        db.lpush(Bytes::from("l"), vec![Bytes::from("0123456789")]);
+        assert_eq!(db.used_memory(), before);
+        assert_eq!(db.remeasure(0, 100), 0);
+        assert_eq!(db.used_memory(), measured(&db));
+
+        assert!(db.delete(b"l"));
+        assert_eq!(db.used_memory(), measured(&db));
+        db.flushdb();
+        assert_eq!(db.used_memory(), 0);
+    }
+
+    #[test]
+    fn test_allkeys_lru_evicts_least_recently_used() {
+        let db = Db::new();
+        db.write_string(Bytes::from("old"), Bytes::from("v"), None);
+        db.write_string(Bytes::from("new"), Bytes::from("v"), None);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        db.read_string(b"old");
+
+        db.evict(&AllKeysLru, 1);
+        assert!(db.read_string(b"new").is_none());
+        assert!(db.read_string(b"old").is_some());
+    }
+
+    #[test]
+    fn test_lazyfree_large_delete() {
+        use std::time::{Duration, Instant};
+
+        let db = Db::new();
+        db.set_lazyfree_lazy_user_del(true);
+
+        let values = (0..10_000).map(|i| Bytes::from(i.to_string())).collect();
+        db.rpush(Bytes::from("biglist"), values);
+        db.write_string(Bytes::from("small"), Bytes::from("v"), None);
+
+        assert!(db.delete(b"biglist"));
+        assert!(db.unlink(b"small"));
+        assert!(!db.exists(b"biglist"));
+        assert!(!db.exists(b"small"));
+
+        // The background thread should drain the defer queue
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while db.lazyfreed_objects() == 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(db.lazyfree_pending_objects(), 0);
+        // Only the big list went to the background thread
+        assert_eq!(db.lazyfreed_objects(), 1);
+    }
+
+    #[test]
+    fn test_flushdb_frees_in_background() {
+        use std::time::{Duration, Instant};
+
+        let db = Db::new();
+        for i in 0..10_000 {
+            db.write_string(Bytes::from(format!("key:{}", i)), Bytes::from("v"), None);
+        }
+
+        // Empty as soon as FLUSHDB returns; the old keyspace is dropped later
+        db.flushdb();
+        assert_eq!(db.dbsize(), 0);
+        assert!(db.read_string(b"key:1").is_none());
+        db.write_string(Bytes::from("key:1"), Bytes::from("new"), None);
+        assert_eq!(db.keys(b"*"), Ok(vec![Bytes::from("key:1")]));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while db.lazyfreed_objects() == 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(db.lazyfreed_objects(), 1);
+        assert_eq!(db.lazyfree_pending_objects(), 0);
+
+        // Small keyspaces are not worth a trip to the lazy-free thread
+        db.flushdb();
+        assert_eq!(db.dbsize(), 0);
+        assert_eq!(db.lazyfreed_objects(), 1);
+    }
+
+    #[test]
+    fn test_poisoned_lock_is_recovered() {
+        let db = Db::new();
+        db.write_string(Bytes::from("k"), Bytes::from("v"), None);
+
+        // A command panicking halfway through MULTI/EXEC poisons both locks
+        let handler = db.clone();
+        let result = std::thread::spawn(move || {
+            handler.transaction(|db| {
+                let _state = db.lock();
+                panic!("command handler failed");
+            })
+        })
+        .join();
+        assert!(result.is_err());
+        assert!(db.shared.is_poisoned());
+        assert!(db.exclusive.is_poisoned());
+
+        assert_eq!(db.read_string(b"k").unwrap(), Bytes::from("v"));
+        db.write_string(Bytes::from("k2"), Bytes::from("v2"), None);
+        assert_eq!(db.dbsize(), 2);
+        db.transaction(|db| db.write_string(Bytes::from("k3"), Bytes::from("v3"), None));
+        assert_eq!(db.dbsize(), 3);
+    }
+
+    #[test]
+    fn test_watch_version_tracks_key_lifecycle() {
+        let db = Db::new();
+        assert_eq!(db.watch_version(b"k"), None);
+
+        db.write_string(Bytes::from("k"), Bytes::from("v1"), None);
+        let original = db.watch_version(b"k");
+        assert!(original.is_some());
+
+        // Recreating the key with the same value still changes its version
+        db.delete(b"k");
+        db.write_string(Bytes::from("k"), Bytes::from("v1"), None);
+        assert_ne!(db.watch_version(b"k"), original);
+
+        // In-place mutation bumps the version, no-ops leave it alone
+        db.rpush(Bytes::from("list"), vec![Bytes::from("a")]);
+        let before = db.watch_version(b"list");
+        db.sadd(Bytes::from("other"), vec![Bytes::from("x")]);
+        assert_eq!(db.watch_version(b"list"), before);
+        db.rpush(Bytes::from("list"), vec![Bytes::from("b")]);
+        assert_ne!(db.watch_version(b"list"), before);
+    }
+
+    #[test]
+    fn test_hash_encoding_promotion() {
+        let db = Db::new();
+        db.set_hash_max_listpack_entries(4);
+
+        db.hset(Bytes::from("h"), Bytes::from("a"), Bytes::from("1"));
+        db.hset(Bytes::from("h"), Bytes::from("b"), Bytes::from("2"));
+        assert_eq!(db.object_encoding(b"h"), Some("listpack"));
+        assert_eq!(db.hget(b"h", b"a").unwrap(), Some(Bytes::from("1")));
+
+        // Small hashes keep insertion order
+        let fields: Vec<Bytes> = db
+            .hgetall(b"h")
+            .unwrap()
+            .into_iter()
+            .map(|(f, _)| f)
+            .collect();
+        assert_eq!(fields, vec!["a", "b"]);
+
+        db.hset(Bytes::from("h"), Bytes::from("c"), Bytes::from("3"));
+        db.hset(Bytes::from("h"), Bytes::from("d"), Bytes::from("4"));
+        assert_eq!(db.object_encoding(b"h"), Some("listpack"));
+
+        // Crossing the threshold promotes to a hashtable
+        db.hset(Bytes::from("h"), Bytes::from("e"), Bytes::from("5"));
+        assert_eq!(db.object_encoding(b"h"), Some("hashtable"));
+        assert_eq!(db.hget(b"h", b"a").unwrap(), Some(Bytes::from("1")));
+        assert_eq!(db.hget(b"h", b"e").unwrap(), Some(Bytes::from("5")));
+        assert_eq!(db.hlen(b"h").unwrap(), 5);
+
+        // Shrinking does not convert back
+        assert_eq!(
+            db.hdel(b"h", vec![Bytes::from("a"), Bytes::from("b")])
+                .unwrap(),
+            2
+        );
+        assert_eq!(db.object_encoding(b"h"), Some("hashtable"));
+        assert!(db.hexists(b"h", b"c").unwrap());
+        assert!(!db.hexists(b"h", b"a").unwrap());
+    }
+
+    #[test]
+    fn test_list_encoding_conversion() {
+        let db = Db::new();
+        db.set_list_max_listpack_size(4);
+        let items = |db: &Db| db.lrange(b"l", 0, -1).unwrap();
+
+        db.rpush(Bytes::from("l"), vec![Bytes::from("b"), Bytes::from("c")]);
+        db.lpush(Bytes::from("l"), vec![Bytes::from("a")]);
+        db.rpush(Bytes::from("l"), vec![Bytes::from("d")]);
+        assert_eq!(db.object_encoding(b"l"), Some("listpack"));
+        assert_eq!(items(&db), vec!["a", "b", "c", "d"]);
+
+        // Crossing the threshold converts to a quicklist
+        db.rpush(Bytes::from("l"), vec![Bytes::from("e")]);
+        assert_eq!(db.object_encoding(b"l"), Some("quicklist"));
+        assert_eq!(items(&db), vec!["a", "b", "c", "d", "e"]);
+
+        // Operations spanning several nodes
+        db.lpush(Bytes::from("l"), vec![Bytes::from("0")]);
+        for value in ["f", "g", "h", "i"] {
+            db.rpush(Bytes::from("l"), vec![Bytes::from(value)]);
+        }
+        assert_eq!(db.linsert(b"l", true, b"c", Bytes::from("x")), Ok(11));
+        assert_eq!(db.linsert(b"l", false, b"i", Bytes::from("j")), Ok(12));
+        assert_eq!(
+            items(&db),
+            vec!["0", "a", "b", "x", "c", "d", "e", "f", "g", "h", "i", "j"]
+        );
+        assert_eq!(db.lrange(b"l", 3, 6).unwrap(), vec!["x", "c", "d", "e"]);
+        assert_eq!(db.lrange(b"l", -2, -1).unwrap(), vec!["i", "j"]);
+        assert_eq!(db.llen(b"l"), Some(12));
+
+        // Shrinking does not convert back
+        assert_eq!(db.lpop(b"l"), Some(Bytes::from("0")));
+        assert_eq!(db.rpop(b"l"), Some(Bytes::from("j")));
+        for _ in 0..8 {
+            db.lpop(b"l");
+        }
+        assert_eq!(db.object_encoding(b"l"), Some("quicklist"));
+        assert_eq!(items(&db), vec!["h", "i"]);
+        assert_eq!(db.rpop(b"l"), Some(Bytes::from("i")));
+        assert_eq!(db.lpop(b"l"), Some(Bytes::from("h")));
+        assert_eq!(db.lpop(b"l"), None);
+    }
+
+    #[test]
+    fn test_string_encoding() {
+        let db = Db::new();
+        let encoding = |value: &str| {
+            db.write_string(Bytes::from("s"), Bytes::from(value.to_string()), None);
+            db.object_encoding(b"s")
+        };
+
+        assert_eq!(encoding("123"), Some("int"));
+        assert_eq!(encoding("-9223372036854775808"), Some("int"));
+        // Not in canonical form, or out of range
+        assert_eq!(encoding("0123"), Some("embstr"));
+        assert_eq!(encoding("9223372036854775808"), Some("embstr"));
+
+        assert_eq!(encoding(&"x".repeat(10)), Some("embstr"));
+        assert_eq!(encoding(&"x".repeat(44)), Some("embstr"));
+        assert_eq!(encoding(&"x".repeat(45)), Some("raw"));
+        assert_eq!(encoding(&"x".repeat(50)), Some("raw"));
+    }
+
+    #[test]
+    fn test_set_intset_encoding() {
+        let db = Db::new();
+        let members = |list: &[&str]| {
+            list.iter()
+                .map(|m| Bytes::copy_from_slice(m.as_bytes()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            db.sadd(Bytes::from("s"), members(&["3", "-1", "2", "3"])),
+            3
+        );
+        assert_eq!(db.object_encoding(b"s"), Some("intset"));
+        assert_eq!(db.smembers(b"s"), Ok(members(&["-1", "2", "3"])));
+        assert!(db.sismember(b"s", b"2").unwrap());
+        assert!(!db.sismember(b"s", b"02").unwrap());
+        assert!(!db.sismember(b"s", b"x").unwrap());
+
+        // A non-integer member converts the set to a hashtable
+        assert_eq!(db.sadd(Bytes::from("s"), members(&["x"])), 1);
+        assert_eq!(db.object_encoding(b"s"), Some("hashtable"));
+        assert!(db.sismember(b"s", b"2").unwrap());
+        assert!(db.sismember(b"s", b"x").unwrap());
+        assert_eq!(db.scard(b"s").unwrap(), 4);
+
+        // Removing it does not convert back
+        assert_eq!(db.srem(b"s", members(&["x"])).unwrap(), 1);
+        assert_eq!(db.object_encoding(b"s"), Some("hashtable"));
+
+        // So does growing past set-max-intset-entries
+        db.set_set_max_intset_entries(2);
+        db.sadd(Bytes::from("big"), members(&["1", "2"]));
+        assert_eq!(db.object_encoding(b"big"), Some("intset"));
+        db.sadd(Bytes::from("big"), members(&["3"]));
+        assert_eq!(db.object_encoding(b"big"), Some("hashtable"));
+        assert!(db.sismember(b"big", b"1").unwrap());
+        assert_eq!(db.srem(b"big", members(&["1", "9"])).unwrap(), 1);
+        assert_eq!(db.scard(b"big").unwrap(), 2);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_large_strings_are_stored_compressed() {
+        let db = Db::new();
+        db.set_string_compression_threshold(64);
+        let large = Bytes::from("compressible ".repeat(1000));
+
+        db.write_string(Bytes::from("small"), Bytes::from("compressible"), None);
+        db.write_string(Bytes::from("large"), large.clone(), None);
+        assert_eq!(db.object_encoding(b"small"), Some("embstr"));
+        assert_eq!(db.object_encoding(b"large"), Some("compressed"));
+        assert_eq!(db.read_string(b"large"), Some(large.clone()));
+        assert_eq!(
+            db.getrange(b"large", 0, 11),
+            Ok(Bytes::from("compressible"))
+        );
+
+        let snapshot = db.snapshot_clone();
+        let (stored, _) = snapshot.get(b"large").unwrap();
+        assert_eq!(stored.type_name(), "string");
+        assert!(
+            stored.approx_size() < large.len() / 10,
+            "{}",
+            stored.approx_size()
+        );
+
+        // Modified in place, it is stored uncompressed again
+        assert_eq!(db.append(b"large", Bytes::from("!")), Ok(large.len() + 1));
+        assert_eq!(db.object_encoding(b"large"), Some("raw"));
+        assert_eq!(
+            db.read_string(b"large").unwrap().slice(..large.len()),
+            large
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_set_get() {
+        use super::super::json::JsonPath;
+        use serde_json::json;
+
+        let db = Db::new();
+        let root = JsonPath::parse("$").unwrap();
+        let name = JsonPath::parse("$.user.name").unwrap();
+
+        // Nested paths need an existing document
+        assert!(db
+            .json_set(Bytes::from("doc"), &name, json!("ann"))
+            .is_err());
+
+        let document = json!({"user": {"name": "bob", "age": 30}});
+        assert_eq!(
+            db.json_set(Bytes::from("doc"), &root, document.clone()),
+            Ok(true)
+        );
+        assert_eq!(
+            db.json_get(b"doc", &root).unwrap(),
+            Some(document.to_string())
+        );
+        assert_eq!(
+            db.json_get(b"doc", &name).unwrap(),
+            Some("\"bob\"".to_string())
+        );
+        assert_eq!(
+            db.json_get(b"doc", &JsonPath::parse("$.user.email").unwrap()),
+            Ok(None)
+        );
+
+        assert_eq!(
+            db.json_set(Bytes::from("doc"), &name, json!("ann")),
+            Ok(true)
+        );
+        assert_eq!(
+            db.json_get(b"doc", &name).unwrap(),
+            Some("\"ann\"".to_string())
+        );
+
+        // Setting the root replaces the whole document
+        assert_eq!(
+            db.json_set(Bytes::from("doc"), &root, json!([1, 2])),
+            Ok(true)
+        );
+        assert_eq!(
+            db.json_get(b"doc", &root).unwrap(),
+            Some("[1,2]".to_string())
+        );
+        assert_eq!(db.json_get(b"doc", &name), Ok(None));
+        assert_eq!(db.get_type(b"doc"), Some("ReJSON-RL"));
+    }
+
+    #[test]
+    fn test_ttl_and_expiretime() {
+        use std::time::Duration;
+
+        let db = Db::new();
+        assert_eq!(db.ttl(b"k"), -2);
+        assert!(!db.expire_at(b"k", Instant::now() + Duration::from_secs(10)));
+
+        db.write_string(Bytes::from("k"), Bytes::from("v"), None);
+        assert_eq!(db.ttl(b"k"), -1);
+        assert_eq!(db.expiretime(b"k"), -1);
+
+        let at_ms = crate::clock::unix_time_ms() + 100_000;
+        assert!(db.expire_at(b"k", crate::clock::unix_ms_to_instant(at_ms)));
+        assert_eq!(db.ttl(b"k"), 100);
+        assert_eq!(db.pexpiretime(b"k"), at_ms as i64);
+        assert_eq!(db.expiretime(b"k"), (at_ms / 1000) as i64);
+
+        // An expiration in the past removes the key
+        assert!(db.expire_at(b"k", Instant::now()));
+        assert_eq!(db.pttl(b"k"), -2);
+    }
+
+    #[test]
+    fn test_incr_strict_integer_parsing() {
+        let db = Db::new();
+
+        assert_eq!(db.incr_by(b"counter", 1), Ok(1));
+        assert_eq!(db.incr_by(b"counter", -3), Ok(-2));
+
+        db.write_string(Bytes::from("negative"), Bytes::from("-10"), None);
+        assert_eq!(db.incr_by(b"negative", 1), Ok(-9));
+
+        for bad in [" 10", "10 ", "+10", "010", "1.5", "abc"] {
+            db.write_string(Bytes::from("bad"), Bytes::from(bad), None);
+            assert_eq!(
+                db.incr_by(b"bad", 1),
+                Err("ERR value is not an integer or out of range"),
+                "{:?}",
+                bad
+            );
+        }
+
+        db.write_string(Bytes::from("max"), Bytes::from(i64::MAX.to_string()), None);
+        assert_eq!(
+            db.incr_by(b"max", 1),
+            Err("ERR increment or decrement would overflow")
+        );
+    }
+
+    #[test]
+    fn test_incr_by_float() {
+        let db = Db::new();
+
+        // Created from 0 when missing
+        assert_eq!(db.incr_by_float(b"f", 10.5), Ok(Bytes::from("10.5")));
+        assert_eq!(db.incr_by_float(b"f", 0.1), Ok(Bytes::from("10.6")));
+        assert_eq!(db.read_string(b"f"), Some(Bytes::from("10.6")));
+
+        // Trailing zeros and the decimal point are trimmed, no exponent
+        db.write_string(Bytes::from("g"), Bytes::from("2.5"), None);
+        assert_eq!(db.incr_by_float(b"g", 0.5), Ok(Bytes::from("3")));
+        db.write_string(Bytes::from("g"), Bytes::from("5.0e3"), None);
+        assert_eq!(db.incr_by_float(b"g", 200.0), Ok(Bytes::from("5200")));
+        assert_eq!(db.incr_by_float(b"g", -5200.0), Ok(Bytes::from("0")));
+        assert_eq!(db.incr_by_float(b"i", 1e-7), Ok(Bytes::from("0.0000001")));
+
+        for bad in ["abc", " 1", "1 ", "nan", ""] {
+            db.write_string(Bytes::from("bad"), Bytes::from(bad), None);
+            assert_eq!(
+                db.incr_by_float(b"bad", 1.0),
+                Err("ERR value is not a valid float"),
+                "{:?}",
+                bad
+            );
+        }
+
+        assert_eq!(
+            db.incr_by_float(b"f", f64::INFINITY),
+            Err("ERR increment would produce NaN or Infinity")
+        );
+        db.write_string(Bytes::from("max"), Bytes::from(f64::MAX.to_string()), None);
+        assert_eq!(
+            db.incr_by_float(b"max", f64::MAX),
+            Err("ERR increment would produce NaN or Infinity")
+        );
+        assert_eq!(db.read_string(b"f"), Some(Bytes::from("10.6")));
+
+        db.lpush(Bytes::from("list"), vec![Bytes::from("a")]);
+        assert_eq!(db.incr_by_float(b"list", 1.0), Err(WRONGTYPE_ERR));
+    }
+
+    #[test]
+    fn test_string_commands_wrongtype() {
+        let db = Db::new();
+        db.rpush(Bytes::from("list"), vec![Bytes::from("a")]);
+
+        assert_eq!(db.append(b"list", Bytes::from("x")), Err(WRONGTYPE_ERR));
+        assert_eq!(
+            db.setrange(b"list", 0, Bytes::from("x")),
+            Err(WRONGTYPE_ERR)
+        );
+        assert_eq!(db.getrange(b"list", 0, -1), Err(WRONGTYPE_ERR));
+        assert_eq!(db.setbit(b"list", 0, true), Err(WRONGTYPE_ERR));
+        assert_eq!(db.getbit(b"list", 0), Err(WRONGTYPE_ERR));
+        assert_eq!(db.incr_by(b"list", 1), Err(WRONGTYPE_ERR));
+
+        // The list is untouched
+        assert_eq!(db.get_type(b"list"), Some("list"));
+        assert_eq!(db.llen(b"list"), Some(1));
+    }
+
+    #[test]
+    fn test_set_and_hash_commands_wrongtype() {
+        let db = Db::new();
+        let names = |list: &[&str]| {
+            list.iter()
+                .map(|m| Bytes::copy_from_slice(m.as_bytes()))
+                .collect::<Vec<_>>()
+        };
+        db.write_string(Bytes::from("string"), Bytes::from("v"), None);
+
+        // A missing key is an empty set or hash
+        assert_eq!(db.srem(b"missing", names(&["a"])), Ok(0));
+        assert_eq!(db.hdel(b"missing", names(&["a"])), Ok(0));
+        assert_eq!(db.sismember(b"missing", b"a"), Ok(false));
+        assert_eq!(db.scard(b"missing"), Ok(0));
+        assert_eq!(db.smembers(b"missing"), Ok(vec![]));
+        assert_eq!(db.hexists(b"missing", b"a"), Ok(false));
+        assert_eq!(db.hlen(b"missing"), Ok(0));
+        assert_eq!(db.hget(b"missing", b"a"), Ok(None));
+        assert_eq!(db.hgetall(b"missing"), Ok(vec![]));
+
+        // Any other type is an error
+        assert_eq!(db.srem(b"string", names(&["a"])), Err(WRONGTYPE_ERR));
+        assert_eq!(db.hdel(b"string", names(&["a"])), Err(WRONGTYPE_ERR));
+        assert_eq!(db.sismember(b"string", b"a"), Err(WRONGTYPE_ERR));
+        assert_eq!(db.scard(b"string"), Err(WRONGTYPE_ERR));
+        assert_eq!(db.smembers(b"string"), Err(WRONGTYPE_ERR));
+        assert_eq!(db.hexists(b"string", b"a"), Err(WRONGTYPE_ERR));
+        assert_eq!(db.hlen(b"string"), Err(WRONGTYPE_ERR));
+        assert_eq!(db.hget(b"string", b"a"), Err(WRONGTYPE_ERR));
+        assert_eq!(db.hgetall(b"string"), Err(WRONGTYPE_ERR));
+
+        // Sets and hashes are not interchangeable either
+        db.sadd(Bytes::from("set"), names(&["a"]));
+        db.hset(Bytes::from("hash"), Bytes::from("a"), Bytes::from("1"));
+        assert_eq!(db.hdel(b"set", names(&["a"])), Err(WRONGTYPE_ERR));
+        assert_eq!(db.srem(b"hash", names(&["a"])), Err(WRONGTYPE_ERR));
+        assert_eq!(db.srem(b"set", names(&["a"])), Ok(1));
+        assert_eq!(db.hdel(b"hash", names(&["a"])), Ok(1));
+        assert_eq!(db.read_string(b"string"), Some(Bytes::from("v")));
+    }
+
+    #[test]
+    fn test_string_mutations() {
+        let db = Db::new();
+
+        assert_eq!(db.append(b"s", Bytes::from("Hello")), Ok(5));
+        assert_eq!(db.append(b"s", Bytes::from(" World")), Ok(11));
+        assert_eq!(db.getrange(b"s", 0, 4), Ok(Bytes::from("Hello")));
+        assert_eq!(db.getrange(b"s", -5, -1), Ok(Bytes::from("World")));
+        assert_eq!(db.getrange(b"s", 20, 30), Ok(Bytes::new()));
+        assert_eq!(db.getrange(b"s", -100, -50), Ok(Bytes::new()));
+        assert_eq!(
+            db.getrange(b"s", isize::MIN, isize::MAX),
+            Ok(Bytes::from("Hello World"))
+        );
+
+        assert_eq!(db.setrange(b"s", 6, Bytes::from("Redis")), Ok(11));
+        assert_eq!(db.read_string(b"s"), Some(Bytes::from("Hello Redis")));
+        assert_eq!(db.setrange(b"padded", 3, Bytes::from("x")), Ok(4));
+        assert_eq!(
+            db.read_string(b"padded"),
+            Some(Bytes::from(&b"\0\0\0x"[..]))
+        );
+        assert_eq!(db.setrange(b"missing", 5, Bytes::new()), Ok(0));
+        assert!(!db.exists(b"missing"));
+
+        assert_eq!(db.setbit(b"bits", 7, true), Ok(0));
+        assert_eq!(db.setbit(b"bits", 7, false), Ok(1));
+        assert_eq!(db.setbit(b"bits", 1, true), Ok(0));
+        assert_eq!(db.read_string(b"bits"), Some(Bytes::from(&b"\x40"[..])));
+        assert_eq!(db.getbit(b"bits", 1), Ok(1));
+        assert_eq!(db.getbit(b"bits", 100), Ok(0));
+    }
+
+    #[test]
+    fn test_linsert() {
+        let db = Db::new();
+        db.rpush(
+            Bytes::from("list"),
+            vec![Bytes::from("a"), Bytes::from("c"), Bytes::from("c")],
+        );
+
+        assert_eq!(db.linsert(b"list", true, b"c", Bytes::from("b")), Ok(4));
+        assert_eq!(db.linsert(b"list", false, b"c", Bytes::from("d")), Ok(5));
+        assert_eq!(db.linsert(b"list", true, b"zzz", Bytes::from("x")), Ok(-1));
+        assert_eq!(db.linsert(b"missing", true, b"a", Bytes::from("x")), Ok(0));
+        assert!(!db.exists(b"missing"));
+
+        let range = db.lrange(b"list", 0, -1).unwrap();
+        assert_eq!(
+            range,
+            vec![
+                Bytes::from("a"),
+                Bytes::from("b"),
+                Bytes::from("c"),
+                Bytes::from("d"),
+                Bytes::from("c"),
+            ]
+        );
+
+        db.write_string(Bytes::from("s"), Bytes::from("v"), None);
+        assert_eq!(
+            db.linsert(b"s", true, b"v", Bytes::from("x")),
+            Err(WRONGTYPE_ERR)
+        );
+    }
+
+    #[test]
+    fn test_scan_returns_long_lived_keys_under_mutation() {
+        use std::collections::HashMap;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        let db = Db::new();
+        for i in 0..2000 {
+            db.write_string(Bytes::from(format!("stable:{}", i)), Bytes::from("v"), None);
+        }
+
+        // Churn keys are created and deleted for the whole iteration
+        let done = Arc::new(AtomicBool::new(false));
+        let churn = {
+            let db = db.clone();
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                let mut i = 0u64;
+                while !done.load(Ordering::Relaxed) {
+                    db.write_string(Bytes::from(format!("churn:{}", i)), Bytes::from("v"), None);
+                    if i >= 50 {
+                        db.delete(format!("churn:{}", i - 50).as_bytes());
+                    }
+                    i += 1;
+                }
+            })
+        };
+
+        let mut seen: HashMap<Bytes, usize> = HashMap::new();
+        let mut cursor = 0;
+        loop {
+            let (next, keys) = db.scan(cursor, 10, Some(b"stable:*"), None);
+            for key in keys {
+                *seen.entry(key).or_default() += 1;
+            }
+            std::thread::yield_now();
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        done.store(true, Ordering::Relaxed);
+        churn.join().unwrap();
+
+        assert_eq!(seen.len(), 2000);
+        assert!(seen.values().all(|&n| n == 1));
+    }
+
+    #[test]
+    fn test_scan_filters() {
+        let db = Db::new();
+        db.write_string(Bytes::from("s1"), Bytes::from("v"), None);
+        db.write_string(Bytes::from("s2"), Bytes::from("v"), None);
+        db.rpush(Bytes::from("l1"), vec![Bytes::from("a")]);
+
+        let (next, mut keys) = db.scan(0, 100, None, Some("string"));
+        keys.sort();
+        assert_eq!(next, 0);
+        assert_eq!(keys, vec!["s1", "s2"]);
+
+        let (_, keys) = db.scan(0, 100, Some(b"l*"), None);
+        assert_eq!(keys, vec!["l1"]);
+    }
+
+    #[test]
+    fn test_expired_keys_are_announced_after_unlock() {
+        let db = Db::new();
+        let pubsub = PubSub::new();
+        db.set_notifier(Some(Notifier::new(
+            pubsub.clone(),
+            NotifyFlags::parse("Ex").unwrap(),
+            0,
+        )));
+        let mut events = pubsub.subscribe("__keyevent@0__:expired".to_string());
+        let past = Instant::now();
+
+        // Lazy expiry on access
+        db.write_string(Bytes::from("lazy"), Bytes::from("v"), Some(past));
+        assert_eq!(db.read_string(b"lazy"), None);
+        assert_eq!(events.try_recv().unwrap().payload, Bytes::from("lazy"));
+
+        // Active expiry skips live keys
+        db.write_string(Bytes::from("swept"), Bytes::from("v"), Some(past));
+        db.write_string(Bytes::from("live"), Bytes::from("v"), None);
+        assert_eq!(db.active_expire_cycle(0, 100), (0, 1));
+        assert_eq!(events.try_recv().unwrap().payload, Bytes::from("swept"));
+        assert!(events.try_recv().is_err());
+        assert!(db.exists(b"live"));
+    }
+
+    #[test]
+    fn test_evicted_keys_are_announced_and_observed() {
+        let db = Db::new();
+        let pubsub = PubSub::new();
+        db.set_notifier(Some(Notifier::new(
+            pubsub.clone(),
+            NotifyFlags::parse("Ee").unwrap(),
+            0,
+        )));
+        let mut events = pubsub.subscribe("__keyevent@0__:evicted".to_string());
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let observed = Arc::clone(&changes);
+        db.set_change_observer(
+            3,
+            Some(Arc::new(move |event: &ChangeEvent| {
+                observed.lock().unwrap().push(event.clone())
+            })),
+        );
+
+        db.write_string(Bytes::from("k"), Bytes::from("v"), None);
+        changes.lock().unwrap().clear();
+        let (evicted, _) = db.evict(&AllKeysRandom, usize::MAX);
+        assert_eq!(evicted, vec![Bytes::from("k")]);
+        assert_eq!(events.try_recv().unwrap().payload, Bytes::from("k"));
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![ChangeEvent {
+                command: "DEL",
+                key: Some(Bytes::from("k")),
+                db: 3,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_count_keys_matches_keys() {
+        let db = Db::new();
+        for i in 0..20 {
+            db.write_string(Bytes::from(format!("user:{}", i)), Bytes::from("v"), None);
+        }
+        db.write_string(Bytes::from("session:1"), Bytes::from("v"), None);
+        db.write_string(
+            Bytes::from("user:gone"),
+            Bytes::from("v"),
+            Some(Instant::now()),
+        );
+
+        for pattern in [
+            "*",
+            "user:*",
+            "user:?",
+            "user:1[0-4]",
+            "session:*",
+            "nothing*",
+        ] {
+            let pattern = pattern.as_bytes();
+            assert_eq!(
+                db.count_keys(pattern),
+                db.keys(pattern).unwrap().len(),
+                "{:?}",
+                pattern
+            );
+        }
+        assert_eq!(db.count_keys(b"user:*"), 20);
+    }
+
+    #[tokio::test]
+    async fn test_blocked_clients_survive_flush_and_leave_no_entries() {
+        let db = Db::new();
+        let keys = vec![Bytes::from("a"), Bytes::from("b")];
+        let blocked = db.block_on(&keys);
+        assert_eq!(db.blocked_keys(), 2);
+
+        db.flushdb();
+        assert_eq!(db.blocked_keys(), 2);
+
+        // A push between registering and waiting is not lost
+        db.rpush(Bytes::from("b"), vec![Bytes::from("v")]);
+        tokio::time::timeout(std::time::Duration::from_secs(5), blocked.wait())
+            .await
+            .unwrap();
+        assert_eq!(
+            db.pop_first(&keys, true),
+            Some((Bytes::from("b"), Bytes::from("v")))
+        );
+
+        drop(blocked);
+        assert_eq!(db.blocked_keys(), 0);
+    }
+
+    #[test]
+    fn test_copy_is_independent_of_source() {
+        let db = Db::new();
+        db.write_string(Bytes::from("int"), Bytes::from("12345"), None);
+        db.write_string(Bytes::from("raw"), Bytes::from("some text"), None);
+        db.rpush(
+            Bytes::from("list"),
+            vec![Bytes::from("a"), Bytes::from("b")],
+        );
+        db.sadd(
+            Bytes::from("intset"),
+            vec![Bytes::from("1"), Bytes::from("2")],
+        );
+        db.sadd(Bytes::from("set"), vec![Bytes::from("x"), Bytes::from("y")]);
+        db.hset(Bytes::from("listpack"), Bytes::from("f"), Bytes::from("v"));
+        db.set_hash_max_listpack_entries(0);
+        db.hset(Bytes::from("hashtable"), Bytes::from("f"), Bytes::from("v"));
+
+        let keys = [
+            "int",
+            "raw",
+            "list",
+            "intset",
+            "set",
+            "listpack",
+            "hashtable",
+        ];
+        let encodings: Vec<_> = keys
+            .iter()
+            .map(|key| db.object_encoding(key.as_bytes()))
+            .collect();
+        assert_eq!(
+            encodings,
+            [
+                "int",
+                "embstr",
+                "listpack",
+                "intset",
+                "hashtable",
+                "listpack",
+                "hashtable"
+            ]
+            .map(Some)
+        );
+        for key in keys {
+            let copy = format!("{}:copy", key);
+            assert!(db.copy(key.as_bytes(), copy.as_bytes(), false), "{}", key);
+        }
+
+        // Mutate every original
+        db.append(b"int", Bytes::from("6")).unwrap();
+        db.setrange(b"raw", 0, Bytes::from("SOME")).unwrap();
+        db.lpop(b"list");
+        db.sadd(Bytes::from("intset"), vec![Bytes::from("3")]);
+        db.srem(b"set", vec![Bytes::from("x")]).unwrap();
+        db.hset(
+            Bytes::from("listpack"),
+            Bytes::from("f"),
+            Bytes::from("changed"),
+        );
+        db.hset(
+            Bytes::from("hashtable"),
+            Bytes::from("g"),
+            Bytes::from("new"),
+        );
+
+        assert_eq!(db.read_string(b"int:copy"), Some(Bytes::from("12345")));
+        assert_eq!(db.read_string(b"raw:copy"), Some(Bytes::from("some text")));
+        assert_eq!(
+            db.lrange(b"list:copy", 0, -1),
+            Some(vec![Bytes::from("a"), Bytes::from("b")])
+        );
+        let mut members = db.smembers(b"intset:copy").unwrap();
+        members.sort();
+        assert_eq!(members, ["1", "2"]);
+        let mut members = db.smembers(b"set:copy").unwrap();
+        members.sort();
+        assert_eq!(members, ["x", "y"]);
+        for key in ["listpack:copy", "hashtable:copy"] {
+            assert_eq!(
+                db.hgetall(key.as_bytes()),
+                Ok(vec![(Bytes::from("f"), Bytes::from("v"))])
+            );
+        }
+        for (key, encoding) in keys.iter().zip(&encodings) {
+            assert_eq!(
+                db.object_encoding(format!("{}:copy", key).as_bytes()),
+                *encoding
+            );
+        }
+
+        // The copy doesn't even share the original's buffers
+        let original = Bytes::from("shared");
+        match Value::String(original.clone()).deep_clone() {
+            Value::String(copy) => assert_ne!(copy.as_ptr(), original.as_ptr()),
+            other => panic!("unexpected value: {:?}", other),
+        }
+
+        // Existing destinations are only overwritten with REPLACE
+        assert!(!db.copy(b"raw", b"int:copy", false));
+        assert!(!db.copy(b"missing", b"new", true));
+        assert!(db.copy(b"raw", b"int:copy", true));
+        assert_eq!(db.read_string(b"int:copy"), Some(Bytes::from("SOME text")));
+    }
+
+    #[test]
+    fn test_snapshot_clone_is_point_in_time() {
+        let db = Db::new();
+        db.write_string(Bytes::from("s"), Bytes::from("before"), None);
+        db.rpush(Bytes::from("l"), vec![Bytes::from("a")]);
+        db.hset(Bytes::from("h"), Bytes::from("f"), Bytes::from("1"));
+
+        let snapshot = db.snapshot_clone();
+        db.write_string(Bytes::from("s"), Bytes::from("after"), None);
+        db.append(b"s", Bytes::from("!")).unwrap();
+        db.rpush(Bytes::from("l"), vec![Bytes::from("b")]);
+        db.hset(Bytes::from("h"), Bytes::from("f"), Bytes::from("2"));
+        db.write_string(Bytes::from("new"), Bytes::from("v"), None);
+        db.delete(b"l");
+
+        assert_eq!(snapshot.len(), 3);
+        assert!(snapshot.get(b"new").is_none());
+        match snapshot.get(b"s") {
+            Some((Value::String(value), None)) => assert_eq!(value, &Bytes::from("before")),
+            other => panic!("unexpected entry: {:?}", other),
+        }
+        match snapshot.get(b"l") {
+            Some((Value::List(items), None)) => {
+                assert_eq!(items.range(0, items.len()), [Bytes::from("a")])
+            }
+            other => panic!("unexpected entry: {:?}", other),
+        }
+        match snapshot.get(b"h") {
+            Some((Value::Hash(hash), None)) => {
+                assert_eq!(hash.get(b"f"), Some(&Bytes::from("1")))
+            }
+            other => panic!("unexpected entry: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_in_place_modification_keeps_ttl() {
+        let db = Db::new();
+        let at = Instant::now() + Duration::from_secs(100);
+        let expiring = |key: &str, value: Value| {
+            db.write_string(Bytes::from(key.to_string()), Bytes::from("0"), None);
+            let mut state = db.lock();
+            state.entries.get_mut(key.as_bytes()).unwrap().value = value;
+            drop(state);
+            assert!(db.expire_at(key.as_bytes(), at));
+            db.pexpiretime(key.as_bytes())
+        };
+
+        let string = expiring("string", Value::String(Bytes::from("1")));
+        let list = expiring(
+            "list",
+            Value::List(ListValue::Listpack(vec![Bytes::from("a")])),
+        );
+        let set = expiring("set", Value::Set(SetValue::new()));
+        let hash = expiring("hash", Value::Hash(HashValue::new()));
+        let zset = expiring("zset", Value::ZSet(ZSetValue::new()));
+
+        db.append(b"string", Bytes::from("0")).unwrap();
+        db.setrange(b"string", 0, Bytes::from("2")).unwrap();
+        db.setbit(b"string", 0, true).unwrap();
+        db.write_string(Bytes::from("counter"), Bytes::from("1"), Some(at));
+        db.incr_by(b"counter", 5).unwrap();
+        db.lpush(
+            Bytes::from("list"),
+            vec![Bytes::from("b"), Bytes::from("c")],
+        );
+        db.rpush(Bytes::from("list"), vec![Bytes::from("d")]);
+        db.lpop(b"list");
+        db.sadd(Bytes::from("set"), vec![Bytes::from("m"), Bytes::from("n")]);
+        db.srem(b"set", vec![Bytes::from("n")]).unwrap();
+        db.hset(Bytes::from("hash"), Bytes::from("f"), Bytes::from("v"));
+        db.hset(Bytes::from("hash"), Bytes::from("g"), Bytes::from("v"));
+        db.hdel(b"hash", vec![Bytes::from("g")]).unwrap();
+        let score = Score::new(1.0).unwrap();
+        db.zadd(
+            Bytes::from("zset"),
+            vec![(score, Bytes::from("m")), (score, Bytes::from("n"))],
+        )
+        .unwrap();
+        db.zrem(b"zset", vec![Bytes::from("n")]).unwrap();
+
+        assert_eq!(db.pexpiretime(b"string"), string);
+        assert_eq!(db.pexpiretime(b"counter"), string);
+        assert_eq!(db.pexpiretime(b"list"), list);
+        assert_eq!(db.pexpiretime(b"set"), set);
+        assert_eq!(db.pexpiretime(b"hash"), hash);
+        assert_eq!(db.pexpiretime(b"zset"), zset);
+        assert_eq!(db.lrange(b"list", 0, -1).unwrap().len(), 3);
+
+        // Replacing the value clears the TTL
+        db.write_string(Bytes::from("string"), Bytes::from("new"), None);
+        assert_eq!(db.pexpiretime(b"string"), -1);
+
+        // A key whose TTL passed is replaced, not extended
+        db.write_string(Bytes::from("stale"), Bytes::from("x"), None);
+        let mut state = db.lock();
+        let stale = state.entries.get_mut(&b"stale"[..]).unwrap();
+        stale.value = Value::List(ListValue::Listpack(vec![Bytes::from("old")]));
+        stale.expires_at = Some(Instant::now());
+        drop(state);
+        assert_eq!(db.rpush(Bytes::from("stale"), vec![Bytes::from("new")]), 1);
+        assert_eq!(db.pexpiretime(b"stale"), -1);
+        assert_eq!(db.lrange(b"stale", 0, -1), Some(vec![Bytes::from("new")]));
+    }
+
+    #[test]
+    fn test_zrangebylex() {
+        let db = Db::new();
+        let zero = Score::new(0.0).unwrap();
+        let members = ["a", "b", "c", "d", "e"].map(|member| (zero, Bytes::from(member)));
+        db.zadd(Bytes::from("z"), members.to_vec()).unwrap();
+
+        let range = |min: &str, max: &str, limit| {
+            let (min, max) = (
+                LexBound::parse(min.as_bytes()),
+                LexBound::parse(max.as_bytes()),
+            );
+            db.zrangebylex(b"z", &min.unwrap(), &max.unwrap(), limit)
+                .unwrap()
+        };
+        let members = |list: &[&str]| -> Vec<Bytes> {
+            list.iter()
+                .map(|member| Bytes::from(member.to_string()))
+                .collect()
+        };
+
+        assert_eq!(range("-", "+", None), members(&["a", "b", "c", "d", "e"]));
+        assert_eq!(range("[b", "[d", None), members(&["b", "c", "d"]));
+        assert_eq!(range("(b", "(d", None), members(&["c"]));
+        assert_eq!(range("[b", "+", None), members(&["b", "c", "d", "e"]));
+        assert_eq!(range("-", "(c", None), members(&["a", "b"]));
+        // Bounds need not be members
+        assert_eq!(range("[bb", "[cc", None), members(&["c"]));
+        assert_eq!(range("+", "-", None), members(&[]));
+        assert_eq!(range("[d", "[b", None), members(&[]));
+
+        assert_eq!(range("-", "+", Some((1, 2))), members(&["b", "c"]));
+        assert_eq!(range("-", "+", Some((3, -1))), members(&["d", "e"]));
+        assert_eq!(range("-", "+", Some((-1, 2))), members(&[]));
+
+        let count = |min: &str, max: &str| {
+            let (min, max) = (
+                LexBound::parse(min.as_bytes()),
+                LexBound::parse(max.as_bytes()),
+            );
+            db.zlexcount(b"z", &min.unwrap(), &max.unwrap()).unwrap()
+        };
+        assert_eq!(count("-", "+"), 5);
+        assert_eq!(count("(a", "[c"), 2);
+        assert_eq!(
+            db.zlexcount(b"missing", &LexBound::Min, &LexBound::Max),
+            Ok(0)
+        );
+
+        assert_eq!(LexBound::parse(b"b"), Err(INVALID_LEX_RANGE_ERR));
+        assert_eq!(LexBound::parse(b""), Err(INVALID_LEX_RANGE_ERR));
+        assert_eq!(LexBound::parse(b"["), Ok(LexBound::Inclusive(Bytes::new())));
+    }
+
+    #[test]
+    fn test_stream_auto_ids_are_monotonic() {
+        let mut stream = StreamValue::new();
+        let id = |ms, seq| StreamId { ms, seq };
+
+        // Entries added in the same millisecond get increasing sequence numbers
+        for seq in 0..3 {
+            let next = stream.next_id(1000).unwrap();
+            assert_eq!(next, id(1000, seq));
+            stream.add(next, vec![]).unwrap();
+        }
+        // A clock that went backwards does not make the IDs go backwards
+        assert_eq!(stream.next_id(999), Some(id(1000, 3)));
+        assert_eq!(stream.next_id(1001), Some(id(1001, 0)));
+
+        stream.add(id(5, u64::MAX), vec![]).unwrap_err();
+        stream.add(id(1000, 2), vec![]).unwrap_err();
+        stream.add(StreamId::MIN, vec![]).unwrap_err();
+
+        let mut full = StreamValue::new();
+        full.add(StreamId::MAX, vec![]).unwrap();
+        assert_eq!(full.next_id(0), None);
+
+        let db = Db::new();
+        let first = db.xadd(Bytes::from("s"), None, vec![]).unwrap();
+        let second = db.xadd(Bytes::from("s"), None, vec![]).unwrap();
+        assert!(second > first);
+        assert_eq!(db.xlen(b"s"), Ok(2));
+    }
+
+    #[test]
+    fn test_xrange_returns_entries_in_id_order() {
+        let db = Db::new();
+        let id = |ms, seq| StreamId { ms, seq };
+        let fields = |n: &str| vec![(Bytes::from("n"), Bytes::from(n.to_string()))];
+        for (ms, seq) in [(1, 0), (1, 1), (2, 5), (3, 0)] {
+            let n = format!("{}-{}", ms, seq);
+            db.xadd(Bytes::from("s"), Some(id(ms, seq)), fields(&n))
+                .unwrap();
+        }
+
+        let range = |start: &str, end: &str, count| -> Vec<StreamId> {
+            let start = StreamId::parse_bound(start.as_bytes(), true).unwrap();
+            let end = StreamId::parse_bound(end.as_bytes(), false).unwrap();
+            let entries = db.xrange(b"s", start, end, count).unwrap();
+            entries.into_iter().map(|(id, _)| id).collect()
+        };
+
+        assert_eq!(
+            range("-", "+", usize::MAX),
+            [id(1, 0), id(1, 1), id(2, 5), id(3, 0)]
+        );
+        // An ID without a sequence number covers the whole millisecond
+        assert_eq!(range("1", "2", usize::MAX), [id(1, 0), id(1, 1), id(2, 5)]);
+        assert_eq!(range("1-1", "2-4", usize::MAX), [id(1, 1)]);
+        assert_eq!(range("-", "+", 2), [id(1, 0), id(1, 1)]);
+        assert_eq!(range("3", "1", usize::MAX), []);
+
+        let entries = db.xrange(b"s", id(2, 5), id(2, 5), 1).unwrap();
+        assert_eq!(entries, vec![(id(2, 5), fields("2-5"))]);
+        assert_eq!(
+            db.xrange(b"missing", StreamId::MIN, StreamId::MAX, 10),
+            Ok(vec![])
+        );
+
+        assert_eq!(StreamId::parse(b"1-x", 0), Err(INVALID_STREAM_ID_ERR));
+        db.write_string(Bytes::from("str"), Bytes::from("v"), None);
+        assert!(db.xlen(b"str").is_err());
     }
 }
@@ -0,0 +1,219 @@
+use bytes::Bytes;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+/// Default for `zset-max-listpack-entries` (same as Redis)
+pub const DEFAULT_ZSET_MAX_LISTPACK_ENTRIES: usize = 128;
+
+/// Score of a sorted set member
+///
+/// Scores are never NaN, so `f64::total_cmp` gives the usual numeric order
+/// and makes them usable as ordered keys.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Score(f64);
+
+impl Score {
+    /// Wrap a score, refusing NaN
+    ///
+    /// `-0.0` becomes `0.0` so both sort as the same score, as in Redis.
+    pub fn new(score: f64) -> Option<Score> {
+        (!score.is_nan()).then_some(Score(score + 0.0))
+    }
+
+    pub fn value(self) -> f64 {
+        self.0
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Reply to ZRANGEBYLEX/ZLEXCOUNT with a malformed bound
+pub const INVALID_LEX_RANGE_ERR: &str = "ERR min or max not valid string range item";
+
+/// One end of a ZRANGEBYLEX/ZLEXCOUNT range
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexBound {
+    /// `-`, below every member
+    Min,
+    /// `+`, above every member
+    Max,
+    /// `[member`
+    Inclusive(Bytes),
+    /// `(member`
+    Exclusive(Bytes),
+}
+
+impl LexBound {
+    /// Parse `-`, `+`, `[member` or `(member`
+    pub fn parse(bound: &[u8]) -> Result<LexBound, &'static str> {
+        match bound {
+            b"-" => Ok(LexBound::Min),
+            b"+" => Ok(LexBound::Max),
+            [b'[', member @ ..] => Ok(LexBound::Inclusive(Bytes::copy_from_slice(member))),
+            [b'(', member @ ..] => Ok(LexBound::Exclusive(Bytes::copy_from_slice(member))),
+            _ => Err(INVALID_LEX_RANGE_ERR),
+        }
+    }
+
+    /// Whether `member` is at or above this bound used as a minimum
+    fn min_admits(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => member >= &bound[..],
+            LexBound::Exclusive(bound) => member > &bound[..],
+        }
+    }
+
+    /// Whether `member` is at or below this bound used as a maximum
+    fn max_admits(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member <= &bound[..],
+            LexBound::Exclusive(bound) => member < &bound[..],
+        }
+    }
+}
+
+/// Sorted set value with two internal representations
+///
+/// Members are ordered by score, then bytewise. Small sorted sets are kept
+/// as a vector in that order (the equivalent of a Redis listpack). Once a
+/// sorted set grows past `zset-max-listpack-entries` it is converted to a
+/// member-to-score map plus an ordered index, which is what Redis' skiplist
+/// encoding provides. Like Redis, a converted set is never turned back.
+#[derive(Clone, Debug)]
+pub enum ZSetValue {
+    Listpack(Vec<(Score, Bytes)>),
+    Skiplist {
+        scores: HashMap<Bytes, Score>,
+        ordered: BTreeSet<(Score, Bytes)>,
+    },
+}
+
+impl ZSetValue {
+    pub fn new() -> Self {
+        ZSetValue::Listpack(Vec::new())
+    }
+
+    /// Name reported by `OBJECT ENCODING`
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            ZSetValue::Listpack(_) => "listpack",
+            ZSetValue::Skiplist { .. } => "skiplist",
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ZSetValue::Listpack(entries) => entries.len(),
+            ZSetValue::Skiplist { scores, .. } => scores.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<Score> {
+        match self {
+            ZSetValue::Listpack(entries) => entries
+                .iter()
+                .find(|(_, existing)| existing == member)
+                .map(|(score, _)| *score),
+            ZSetValue::Skiplist { scores, .. } => scores.get(member).copied(),
+        }
+    }
+
+    /// Add a member or update its score, converting to a skiplist once
+    /// `max_listpack_entries` is exceeded
+    ///
+    /// Returns true if the member is new.
+    pub fn insert(&mut self, member: Bytes, score: Score, max_listpack_entries: usize) -> bool {
+        let is_new = self.remove(&member).is_none();
+        match self {
+            ZSetValue::Listpack(entries) => {
+                let index = entries
+                    .binary_search_by(|(s, m)| (*s, m.as_ref()).cmp(&(score, member.as_ref())))
+                    .unwrap_or_else(|index| index);
+                entries.insert(index, (score, member));
+                if entries.len() > max_listpack_entries {
+                    let entries = std::mem::take(entries);
+                    *self = ZSetValue::Skiplist {
+                        scores: entries.iter().map(|(s, m)| (m.clone(), *s)).collect(),
+                        ordered: entries.into_iter().collect(),
+                    };
+                }
+            }
+            ZSetValue::Skiplist { scores, ordered } => {
+                scores.insert(member.clone(), score);
+                ordered.insert((score, member));
+            }
+        }
+        is_new
+    }
+
+    /// Remove a member, returning its score if it was present
+    pub fn remove(&mut self, member: &[u8]) -> Option<Score> {
+        match self {
+            ZSetValue::Listpack(entries) => {
+                let index = entries
+                    .iter()
+                    .position(|(_, existing)| existing == member)?;
+                Some(entries.remove(index).0)
+            }
+            ZSetValue::Skiplist { scores, ordered } => {
+                let (member, score) = scores.remove_entry(member)?;
+                ordered.remove(&(score, member));
+                Some(score)
+            }
+        }
+    }
+
+    /// Members between `min` and `max`, in order
+    ///
+    /// Like Redis this assumes every member has the same score, so that the
+    /// set is ordered bytewise: the range starts at the first member at or
+    /// above `min` and ends before the first one past `max`.
+    pub fn lex_range<'a>(
+        &'a self,
+        min: &'a LexBound,
+        max: &'a LexBound,
+    ) -> impl Iterator<Item = &'a Bytes> + 'a {
+        self.iter()
+            .map(|(member, _)| member)
+            .skip_while(move |member| !min.min_admits(member))
+            .take_while(move |member| max.max_admits(member))
+    }
+
+    /// Iterate over member/score pairs in ascending order
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&Bytes, Score)> + '_> {
+        match self {
+            ZSetValue::Listpack(entries) => {
+                Box::new(entries.iter().map(|(score, member)| (member, *score)))
+            }
+            ZSetValue::Skiplist { ordered, .. } => {
+                Box::new(ordered.iter().map(|(score, member)| (member, *score)))
+            }
+        }
+    }
+}
+
+impl Default for ZSetValue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
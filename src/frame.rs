@@ -1,4 +1,4 @@
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::fmt;
 use std::io::{self, Cursor};
 
@@ -42,6 +42,9 @@ impl std::error::Error for Error {}
 /// - Bulk Strings: $5\r\nhello\r\n
 /// - Arrays: *2\r\n$3\r\nGET\r\n$3\r\nkey\r\n
 /// - Null: $-1\r\n
+///
+/// plus the RESP3 verbatim strings (=15\r\ntxt:Some string\r\n) and big
+/// numbers ((3492890328409238509324850943850943825024385\r\n).
 #[derive(Clone, Debug, PartialEq)]
 pub enum Frame {
     /// Simple string: +OK\r\n
@@ -62,6 +65,13 @@ pub enum Frame {
 
     /// Null bulk string: $-1\r\n
     Null,
+
+    /// RESP3 verbatim string: =15\r\ntxt:Some string\r\n
+    /// `format` is the three byte type, e.g. `txt` or `mkd`
+    Verbatim { format: [u8; 3], data: Bytes },
+
+    /// RESP3 big number, kept as its decimal digits: (12345678901234567890\r\n
+    BigNumber(String),
 }
 
 impl Frame {
@@ -100,73 +110,354 @@ impl Frame {
     /// Returns `Ok(Some(frame))` if a complete frame was parsed
     /// Returns `Ok(None)` if there is not enough data yet (incomplete)
     /// Returns `Err` if the data is malformed
+    ///
+    /// On success the buffer is advanced past the parsed frame.
     pub fn parse(buf: &mut BytesMut) -> Result<Option<Frame>, Error> {
-        // Create a cursor to track position without consuming
-        let mut cursor = Cursor::new(&buf[..]);
+        match Frame::parse_from_slice(&buf[..])? {
+            Some((frame, len)) => {
+                // Advance the buffer past the parsed frame
+                buf.advance(len);
+                Ok(Some(frame))
+            }
+            None => Ok(None),
+        }
+    }
 
-        // Check if we have a complete frame
-        match check_complete(&mut cursor) {
-            Ok(_) => {
-                // We have a complete frame, now parse it
-                let len = cursor.position() as usize;
+    /// Whether `byte` starts a RESP frame
+    ///
+    /// A request starting with any other byte is an inline command.
+    pub fn is_type_byte(byte: u8) -> bool {
+        matches!(byte, b'+' | b'-' | b':' | b'(' | b'$' | b'=' | b'*')
+    }
 
-                // Reset cursor for actual parsing
-                cursor.set_position(0);
+    /// Parse an inline command (`SET key value\r\n`) from the buffer
+    ///
+    /// Inline commands are plain lines split on whitespace, as typed into a
+    /// telnet session; the result is the same array of bulk strings a RESP
+    /// client would send. Blank lines are skipped. A line longer than
+    /// `max_len` bytes, or that many bytes without a newline, is refused
+    /// so a client cannot make the buffer grow without limit.
+    ///
+    /// On success the buffer is advanced past the line.
+    pub fn parse_inline(buf: &mut BytesMut, max_len: usize) -> Result<Option<Frame>, Error> {
+        loop {
+            let Some(newline) = buf.iter().position(|&b| b == b'\n') else {
+                if buf.len() > max_len {
+                    return Err(Error::Invalid("too big inline request".to_string()));
+                }
+                return Ok(None);
+            };
+            if newline > max_len {
+                return Err(Error::Invalid("too big inline request".to_string()));
+            }
 
-                // Parse the frame
-                let frame = parse_frame(&mut cursor)?;
+            let line = buf.split_to(newline + 1);
+            let args: Vec<Frame> = line[..]
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg)))
+                .collect();
+            if !args.is_empty() {
+                return Ok(Some(Frame::Array(args)));
+            }
+        }
+    }
 
-                // Advance the buffer past the parsed frame
-                buf.advance(len);
+    /// Parse the first frame in `data` without consuming anything
+    ///
+    /// Returns the frame together with the number of bytes it occupies, so
+    /// a streaming decoder can drop that prefix and parse the next frame.
+    /// Returns `Ok(None)` if `data` does not yet hold a complete frame.
+    pub fn parse_from_slice(data: &[u8]) -> Result<Option<(Frame, usize)>, Error> {
+        Frame::parse_scanned(data, &mut PartialFrame::default())
+    }
 
+    /// Like `parse`, resuming the completeness check where `partial` left
+    /// it after the previous call on the same, since grown, buffer
+    pub fn parse_resumable(
+        buf: &mut BytesMut,
+        partial: &mut PartialFrame,
+    ) -> Result<Option<Frame>, Error> {
+        match Frame::parse_scanned(&buf[..], partial)? {
+            Some((frame, len)) => {
+                buf.advance(len);
                 Ok(Some(frame))
             }
-            Err(Error::Incomplete) => Ok(None),
-            Err(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    /// Parse the first frame in `data` once `partial` finds it complete
+    fn parse_scanned(
+        data: &[u8],
+        partial: &mut PartialFrame,
+    ) -> Result<Option<(Frame, usize)>, Error> {
+        match partial.scan(data)? {
+            Some(len) => {
+                let frame = parse_frame(&mut Cursor::new(&data[..len]))?;
+                Ok(Some((frame, len)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Render the frame for logging
+    ///
+    /// Like `Display`, except that bulk strings are cut off after `max_len`
+    /// bytes and binary ones show their bytes with `\x00`-style escapes
+    /// instead of only their length.
+    pub fn to_debug_string(&self, max_len: usize) -> String {
+        let mut out = String::new();
+        self.write_debug(&mut out, max_len);
+        out
+    }
+
+    fn write_debug(&self, out: &mut String, max_len: usize) {
+        match self {
+            Frame::Bulk(data) => {
+                out.push_str("Bulk(");
+                push_escaped(out, data, max_len);
+                out.push(')');
+            }
+            Frame::Array(items) => {
+                out.push_str("Array[");
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    item.write_debug(out, max_len);
+                }
+                out.push(']');
+            }
+            other => out.push_str(&other.to_string()),
+        }
+    }
+
+    /// Serialize the frame as RESP into `out`
+    pub fn encode(&self, out: &mut BytesMut) {
+        match self {
+            Frame::Simple(s) => {
+                out.put_u8(b'+');
+                out.put_slice(s.as_bytes());
+                out.put_slice(b"\r\n");
+            }
+            Frame::Error(e) => {
+                out.put_u8(b'-');
+                out.put_slice(e.as_bytes());
+                out.put_slice(b"\r\n");
+            }
+            Frame::Integer(n) => {
+                out.put_u8(b':');
+                put_decimal(out, *n);
+                out.put_slice(b"\r\n");
+            }
+            Frame::Null => {
+                out.put_slice(b"$-1\r\n");
+            }
+            Frame::Bulk(data) => {
+                out.put_u8(b'$');
+                put_decimal(out, data.len() as i64);
+                out.put_slice(b"\r\n");
+                out.put_slice(data);
+                out.put_slice(b"\r\n");
+            }
+            Frame::Array(frames) => {
+                out.put_u8(b'*');
+                put_decimal(out, frames.len() as i64);
+                out.put_slice(b"\r\n");
+
+                // Recursively write each frame in the array
+                for frame in frames {
+                    frame.encode(out);
+                }
+            }
+            Frame::Verbatim { format, data } => {
+                out.put_u8(b'=');
+                put_decimal(out, data.len() as i64 + 4);
+                out.put_slice(b"\r\n");
+                out.put_slice(format);
+                out.put_u8(b':');
+                out.put_slice(data);
+                out.put_slice(b"\r\n");
+            }
+            Frame::BigNumber(n) => {
+                out.put_u8(b'(');
+                out.put_slice(n.as_bytes());
+                out.put_slice(b"\r\n");
+            }
         }
     }
 }
 
-/// Check if a complete frame is available in the buffer
-fn check_complete(cursor: &mut Cursor<&[u8]>) -> Result<(), Error> {
-    if !cursor.has_remaining() {
-        return Err(Error::Incomplete);
+/// Write `n` in decimal through a stack buffer
+///
+/// Integer replies and length prefixes go out with nearly every reply, so
+/// unlike `n.to_string()` this does not allocate.
+fn put_decimal(out: &mut BytesMut, n: i64) {
+    // Enough for i64::MIN: 19 digits and the sign
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    let mut rest = n.unsigned_abs();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        start -= 1;
+        digits[start] = b'-';
+    }
+    out.put_slice(&digits[start..]);
+}
+
+/// How far the completeness check of a frame still arriving has got
+///
+/// A large array sent in many small reads would otherwise be rescanned
+/// from its first byte every time more data came in. The scan instead
+/// resumes after the last element known to be complete, and a bulk string
+/// whose header has been read is not looked at again before all of it can
+/// be there. Only valid for a buffer that keeps the same start and only
+/// grows between calls; reset it whenever a frame is consumed.
+#[derive(Debug, Default)]
+pub struct PartialFrame {
+    /// Bytes of the buffer covered by complete elements and array headers
+    scanned: usize,
+
+    /// Elements still expected by each array being scanned, outermost first
+    pending: Vec<u64>,
+
+    /// Length the buffer needs before another scan can make progress
+    needed: usize,
+
+    /// Bytes looked at by all scans so far, to check the work stays linear
+    examined: usize,
+}
+
+impl PartialFrame {
+    /// Length of the frame at the start of `data`, once it is complete
+    ///
+    /// Returns `Ok(None)` while more data is needed, remembering how far the
+    /// scan got. Resets itself once the frame is complete or malformed.
+    pub fn scan(&mut self, data: &[u8]) -> Result<Option<usize>, Error> {
+        if data.len() < self.needed {
+            return Ok(None);
+        }
+        let result = self.resume(data);
+        if !matches!(result, Ok(None)) {
+            *self = PartialFrame {
+                examined: self.examined,
+                ..PartialFrame::default()
+            };
+        }
+        result
+    }
+
+    /// Bytes looked at by all scans so far
+    pub fn examined(&self) -> usize {
+        self.examined
     }
 
+    fn resume(&mut self, data: &[u8]) -> Result<Option<usize>, Error> {
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(self.scanned as u64);
+        loop {
+            let element = cursor.position() as usize;
+            let checked = check_element(&mut cursor);
+            self.examined += cursor.position() as usize - element;
+            match checked {
+                Ok(Element::Complete) => {}
+                Ok(Element::Array(count)) => {
+                    self.pending.push(count);
+                    self.scanned = cursor.position() as usize;
+                    continue;
+                }
+                Ok(Element::Bulk { end }) => {
+                    self.needed = end;
+                    return Ok(None);
+                }
+                Err(Error::Incomplete) => {
+                    self.needed = data.len() + 1;
+                    return Ok(None);
+                }
+                Err(e) => return Err(e),
+            }
+
+            self.scanned = cursor.position() as usize;
+            // The element may complete the arrays it closes, innermost first
+            loop {
+                match self.pending.last_mut() {
+                    None => return Ok(Some(self.scanned)),
+                    Some(1) => {
+                        self.pending.pop();
+                    }
+                    Some(count) => {
+                        *count -= 1;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What `check_element` found at the cursor
+enum Element {
+    /// A whole frame other than a non-empty array
+    Complete,
+
+    /// The header of an array of that many elements, which follow
+    Array(u64),
+
+    /// A bulk string whose data has not all arrived, ending at `end`
+    Bulk { end: usize },
+}
+
+/// Check the element at the cursor, without its array elements
+fn check_element(cursor: &mut Cursor<&[u8]>) -> Result<Element, Error> {
     match get_u8(cursor)? {
-        b'+' => read_until_crlf(cursor), // Simple String
-        b'-' => read_until_crlf(cursor), // Error
-        b':' => read_until_crlf(cursor), // Integer
-        b'$' => {
-            // Bulk String
+        b'+' => read_until_crlf(cursor)?, // Simple String
+        b'-' => read_until_crlf(cursor)?, // Error
+        b':' => read_until_crlf(cursor)?, // Integer
+        b'(' => read_until_crlf(cursor)?, // Big number
+        b'$' | b'=' => {
+            // Bulk String / Verbatim String
             let len = read_decimal(cursor)?;
-            if len == -1 {
+            match len {
                 // Null bulk string
-                Ok(())
-            } else {
-                // Skip len bytes + \r\n
-                skip(cursor, len as usize + 2)
+                -1 => {}
+                // Skip len bytes (possibly none) + \r\n
+                0.. => {
+                    let end = cursor.position() as usize + len as usize + 2;
+                    if end > cursor.get_ref().len() {
+                        return Ok(Element::Bulk { end });
+                    }
+                    skip(cursor, len as usize)?;
+                    read_crlf(cursor)?;
+                }
+                _ => return Err(Error::Invalid(format!("invalid bulk length: {}", len))),
             }
         }
         b'*' => {
             // Array
             let count = read_decimal(cursor)?;
-            if count == -1 {
-                // Null array (not standard but handle it)
-                Ok(())
-            } else {
-                // Recursively check each element
-                for _ in 0..count {
-                    check_complete(cursor)?;
-                }
-                Ok(())
+            match count {
+                // Null array (not standard but handle it); *0 is an empty array
+                -1 | 0 => {}
+                1.. => return Ok(Element::Array(count as u64)),
+                _ => return Err(Error::Invalid(format!("invalid array length: {}", count))),
             }
         }
-        actual => Err(Error::Invalid(format!(
-            "invalid frame type byte: {}",
-            actual
-        ))),
+        actual => {
+            return Err(Error::Invalid(format!(
+                "invalid frame type byte: {}",
+                actual
+            )))
+        }
     }
+    Ok(Element::Complete)
 }
 
 /// Parse a complete frame from the cursor
@@ -188,13 +479,42 @@ fn parse_frame(cursor: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
             let num = read_decimal(cursor)?;
             Ok(Frame::Integer(num))
         }
+        b'(' => {
+            let line = read_line(cursor)?;
+            let digits = line.strip_prefix(b"-").unwrap_or(line);
+            if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                return Err(Error::Invalid("invalid big number".to_string()));
+            }
+            // Only ASCII digits and '-', so this cannot fail
+            Ok(Frame::BigNumber(String::from_utf8_lossy(line).into_owned()))
+        }
+        b'=' => {
+            let len = read_decimal(cursor)?;
+            if len < 4 {
+                return Err(Error::Invalid(format!(
+                    "invalid verbatim string length: {}",
+                    len
+                )));
+            }
+            let data = read_n_bytes(cursor, len as usize)?;
+            read_crlf(cursor)?;
+            if data[3] != b':' {
+                return Err(Error::Invalid(
+                    "verbatim string without a format prefix".to_string(),
+                ));
+            }
+            Ok(Frame::Verbatim {
+                format: [data[0], data[1], data[2]],
+                data: Bytes::copy_from_slice(&data[4..]),
+            })
+        }
         b'$' => {
             let len = read_decimal(cursor)?;
             if len == -1 {
                 Ok(Frame::Null)
             } else {
                 let data = read_n_bytes(cursor, len as usize)?;
-                skip(cursor, 2)?; // Skip \r\n
+                read_crlf(cursor)?;
                 Ok(Frame::Bulk(Bytes::copy_from_slice(data)))
             }
         }
@@ -276,6 +596,16 @@ fn read_n_bytes<'a>(cursor: &mut Cursor<&'a [u8]>, n: usize) -> Result<&'a [u8],
     Ok(&cursor.get_ref()[start..end])
 }
 
+/// Consume the \r\n that terminates a bulk string
+fn read_crlf(cursor: &mut Cursor<&[u8]>) -> Result<(), Error> {
+    match read_n_bytes(cursor, 2)? {
+        b"\r\n" => Ok(()),
+        _ => Err(Error::Invalid(
+            "bulk string not terminated by CRLF".to_string(),
+        )),
+    }
+}
+
 /// Skip n bytes
 fn skip(cursor: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
     let new_pos = cursor.position() as usize + n;
@@ -288,6 +618,31 @@ fn skip(cursor: &mut Cursor<&[u8]>, n: usize) -> Result<(), Error> {
     Ok(())
 }
 
+/// Append up to `max_len` bytes of `data` to `out`, escaping control
+/// characters and, unless `data` is valid UTF-8, all non-ASCII bytes
+fn push_escaped(out: &mut String, data: &[u8], max_len: usize) {
+    let shown = &data[..data.len().min(max_len)];
+    match std::str::from_utf8(data) {
+        Ok(text) => {
+            // Don't cut a character in half
+            let mut end = shown.len();
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            out.extend(text[..end].chars().flat_map(char::escape_debug));
+        }
+        Err(_) => out.extend(
+            shown
+                .iter()
+                .flat_map(|&byte| std::ascii::escape_default(byte))
+                .map(char::from),
+        ),
+    }
+    if shown.len() < data.len() {
+        out.push_str(&format!("... ({} bytes)", data.len()));
+    }
+}
+
 impl fmt::Display for Frame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -312,6 +667,224 @@ impl fmt::Display for Frame {
                 write!(f, "]")
             }
             Frame::Null => write!(f, "Null"),
+            Frame::Verbatim { format, data } => write!(
+                f,
+                "Verbatim({}:{})",
+                String::from_utf8_lossy(format),
+                String::from_utf8_lossy(data)
+            ),
+            Frame::BigNumber(n) => write!(f, "BigNumber({})", n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_from_slice_reports_consumed_length() {
+        let data = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n:42\r\n";
+
+        let (first, used) = Frame::parse_from_slice(data).unwrap().unwrap();
+        assert_eq!(
+            first,
+            Frame::Array(vec![
+                Frame::Bulk(Bytes::from("GET")),
+                Frame::Bulk(Bytes::from("k")),
+            ])
+        );
+        assert_eq!(used, data.len() - 5);
+
+        let (second, rest) = Frame::parse_from_slice(&data[used..]).unwrap().unwrap();
+        assert_eq!(second, Frame::Integer(42));
+        assert_eq!(used + rest, data.len());
+
+        // A truncated frame is incomplete, not an error
+        assert!(Frame::parse_from_slice(&data[..used - 1])
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_parse_inline() {
+        let mut buf = BytesMut::from(&b"SET  key value\r\n\r\nPING\nGET"[..]);
+        let command = |args: &[&'static str]| {
+            Frame::Array(
+                args.iter()
+                    .map(|arg| Frame::Bulk(Bytes::from(*arg)))
+                    .collect(),
+            )
+        };
+
+        assert_eq!(
+            Frame::parse_inline(&mut buf, 64).unwrap(),
+            Some(command(&["SET", "key", "value"]))
+        );
+        // The blank line is skipped
+        assert_eq!(
+            Frame::parse_inline(&mut buf, 64).unwrap(),
+            Some(command(&["PING"]))
+        );
+        assert_eq!(Frame::parse_inline(&mut buf, 64).unwrap(), None);
+        assert_eq!(&buf[..], b"GET");
+
+        // Too long, with or without the newline
+        let mut buf = BytesMut::from(&b"GET 0123456789"[..]);
+        assert!(matches!(
+            Frame::parse_inline(&mut buf, 8),
+            Err(Error::Invalid(e)) if e == "too big inline request"
+        ));
+        let mut buf = BytesMut::from(&b"GET 0123456789\r\n"[..]);
+        assert!(Frame::parse_inline(&mut buf, 8).is_err());
+    }
+
+    /// Feed `wire` to `parse_resumable` `chunk` bytes at a time, returning
+    /// the frame and the bytes the completeness checks looked at
+    fn parse_in_chunks(wire: &[u8], chunk: usize) -> (Frame, usize) {
+        let mut buf = BytesMut::new();
+        let mut partial = PartialFrame::default();
+        for piece in wire.chunks(chunk) {
+            buf.extend_from_slice(piece);
+            if let Some(frame) = Frame::parse_resumable(&mut buf, &mut partial).unwrap() {
+                assert!(buf.is_empty());
+                return (frame, partial.examined());
+            }
         }
+        panic!("frame never completed");
+    }
+
+    #[test]
+    fn test_chunked_parse_work_is_linear() {
+        // A 1MB bulk: only its header is looked at until all of it is there
+        let data = vec![b'v'; 1024 * 1024];
+        let mut wire = BytesMut::new();
+        Frame::Bulk(Bytes::from(data.clone())).encode(&mut wire);
+        let (frame, examined) = parse_in_chunks(&wire, 64);
+        assert_eq!(frame, Frame::Bulk(Bytes::from(data)));
+        assert!(examined <= wire.len() + 64, "examined {}", examined);
+
+        // An array of many small elements resumes after the last complete one
+        let items: Vec<Frame> = (0..50_000)
+            .map(|i| Frame::Bulk(Bytes::from(i.to_string())))
+            .collect();
+        let mut wire = BytesMut::new();
+        Frame::Array(items.clone()).encode(&mut wire);
+        let (frame, examined) = parse_in_chunks(&wire, 7);
+        assert_eq!(frame, Frame::Array(items));
+        assert!(examined <= 2 * wire.len(), "examined {}", examined);
+    }
+
+    #[test]
+    fn test_parse_advances_buffer() {
+        let mut buf = BytesMut::from(&b"+OK\r\n$-1\r\n+PART"[..]);
+
+        assert_eq!(Frame::parse(&mut buf).unwrap(), Some(Frame::simple("OK")));
+        assert_eq!(Frame::parse(&mut buf).unwrap(), Some(Frame::Null));
+        assert_eq!(Frame::parse(&mut buf).unwrap(), None);
+        assert_eq!(&buf[..], b"+PART");
+    }
+
+    #[test]
+    fn test_parse_empty_bulk_and_array() {
+        let mut buf = BytesMut::from(&b"$0\r\n\r\n*0\r\n*1\r\n$0\r\n\r\n"[..]);
+
+        assert_eq!(
+            Frame::parse(&mut buf).unwrap(),
+            Some(Frame::Bulk(Bytes::new()))
+        );
+        assert_eq!(Frame::parse(&mut buf).unwrap(), Some(Frame::Array(vec![])));
+        assert_eq!(
+            Frame::parse(&mut buf).unwrap(),
+            Some(Frame::Array(vec![Frame::Bulk(Bytes::new())]))
+        );
+        assert!(buf.is_empty());
+
+        // Only the length line of an empty bulk has arrived
+        assert!(Frame::parse_from_slice(b"$0\r\n").unwrap().is_none());
+        assert!(Frame::parse_from_slice(b"$0\r\n\r").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_lengths() {
+        assert!(matches!(
+            Frame::parse_from_slice(b"$-2\r\n"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            Frame::parse_from_slice(b"*-5\r\n"),
+            Err(Error::Invalid(_))
+        ));
+        assert!(matches!(
+            Frame::parse_from_slice(b"$0\r\nab"),
+            Err(Error::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_resp3_verbatim_and_big_number_round_trip() {
+        let cases: [(&[u8], Frame); 3] = [
+            (
+                b"=15\r\ntxt:Some string\r\n",
+                Frame::Verbatim {
+                    format: *b"txt",
+                    data: Bytes::from("Some string"),
+                },
+            ),
+            (
+                b"(3492890328409238509324850943850943825024385\r\n",
+                Frame::BigNumber("3492890328409238509324850943850943825024385".to_string()),
+            ),
+            (
+                b"(-12345678901234567890\r\n",
+                Frame::BigNumber("-12345678901234567890".to_string()),
+            ),
+        ];
+
+        for (wire, frame) in cases {
+            assert_eq!(
+                Frame::parse_from_slice(wire).unwrap(),
+                Some((frame.clone(), wire.len()))
+            );
+            let mut out = BytesMut::new();
+            frame.encode(&mut out);
+            assert_eq!(&out[..], wire);
+        }
+
+        for bad in [
+            &b"=3\r\ntxt\r\n"[..],
+            b"=5\r\ntxt-x\r\n",
+            b"(12a\r\n",
+            b"(-\r\n",
+        ] {
+            assert!(
+                matches!(Frame::parse_from_slice(bad), Err(Error::Invalid(_))),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_debug_string_escapes_binary_bulks() {
+        let frame = Frame::Array(vec![
+            Frame::Bulk(Bytes::from("SET")),
+            Frame::Bulk(Bytes::from_static(b"k\x00\xff\n")),
+            Frame::Bulk(Bytes::from("0123456789")),
+        ]);
+        assert_eq!(
+            frame.to_debug_string(8),
+            "Array[Bulk(SET), Bulk(k\\x00\\xff\\n), Bulk(01234567... (10 bytes))]"
+        );
+        assert_eq!(
+            frame.to_string(),
+            "Array[Bulk(SET), Bulk(4 bytes), Bulk(0123456789)]"
+        );
+
+        // Text is kept, but never cut inside a character
+        assert_eq!(
+            Frame::Bulk(Bytes::from("héllo")).to_debug_string(2),
+            "Bulk(h... (6 bytes))"
+        );
     }
 }
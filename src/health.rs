@@ -0,0 +1,122 @@
+use crate::metrics::{Metrics, SharedMetrics};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::debug;
+
+/// How long a checker gets to send an HTTP request before it is answered
+/// as a plain TCP check
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Largest request head read from a health checker
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// Why the server should not receive traffic, `Ok` if it can
+pub fn status(metrics: &Metrics) -> Result<(), &'static str> {
+    if metrics.loading() {
+        Err("LOADING")
+    } else if metrics.aof_enabled() && !metrics.aof_last_write_ok() {
+        Err("AOF write error")
+    } else {
+        Ok(())
+    }
+}
+
+/// Answer health checks on `listener` until the task is dropped
+///
+/// Checkers that send an HTTP request get `200 OK` or `503 Service
+/// Unavailable`; anything else, including a connection that sends nothing,
+/// gets a single `OK` or `FAIL <reason>` line.
+pub async fn serve(listener: TcpListener, metrics: SharedMetrics) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(socket, &metrics).await {
+                debug!("Health check failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut socket: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    // Read until the end of an HTTP request head, if one is coming at all
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let read_head = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = socket.read(&mut buf).await?;
+            if n == 0 || request.len() + n > MAX_REQUEST_BYTES {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, io::Error>(())
+    };
+    let _ = tokio::time::timeout(REQUEST_TIMEOUT, read_head).await;
+
+    let status = status(metrics);
+    let response = if request.starts_with(b"GET ") || request.starts_with(b"HEAD ") {
+        let (line, body) = match status {
+            Ok(()) => ("200 OK", "OK\n".to_string()),
+            Err(reason) => ("503 Service Unavailable", format!("{}\n", reason)),
+        };
+        format!(
+            "HTTP/1.1 {}\r\n\
+             Content-Type: text/plain\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\
+             \r\n\
+             {}",
+            line,
+            body.len(),
+            body
+        )
+    } else {
+        match status {
+            Ok(()) => "OK\r\n".to_string(),
+            Err(reason) => format!("FAIL {}\r\n", reason),
+        }
+    };
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn check(addr: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_reports_loading_as_unhealthy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Metrics::new();
+        tokio::spawn(serve(listener, metrics.clone()));
+
+        let http = "GET /health HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(check(addr, "").await, "OK\r\n");
+        let response = check(addr, http).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\nOK\n"));
+
+        metrics.set_loading(true);
+        assert_eq!(check(addr, "").await, "FAIL LOADING\r\n");
+        let response = check(addr, http).await;
+        assert!(response.starts_with("HTTP/1.1 503"));
+        assert!(response.ends_with("\r\n\r\nLOADING\n"));
+
+        metrics.set_loading(false);
+        metrics.set_aof_enabled(true);
+        metrics.set_aof_last_write_ok(false);
+        assert_eq!(check(addr, "").await, "FAIL AOF write error\r\n");
+    }
+}
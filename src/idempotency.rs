@@ -0,0 +1,255 @@
+use crate::frame::Frame;
+use crate::util::lock;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// Default number of remembered tokens
+pub const DEFAULT_IDEMPOTENCY_CACHE_SIZE: usize = 10_000;
+
+/// Default time a token is remembered for, in seconds
+pub const DEFAULT_IDEMPOTENCY_TTL_SECS: u64 = 300;
+
+/// Shared cache of `IDEMPOTENT <token> <command>` results
+///
+/// A client that retries a command after a timeout cannot know whether the
+/// first attempt was applied. Tagging both attempts with the same token
+/// makes the retry return the recorded reply instead of running the command
+/// again. The cache is bounded: tokens are forgotten `ttl` after the reply
+/// was recorded, or earlier when more than `capacity` tokens are stored, in
+/// which case the least recently used token goes first.
+///
+/// A token being run is claimed (see `claim`) until its reply is recorded,
+/// so a retry racing with the original attempt waits for it instead of
+/// applying the command a second time. Commands with other tokens go ahead
+/// meanwhile.
+#[derive(Clone)]
+pub struct IdempotencyCache {
+    results: Arc<Mutex<ResultCache>>,
+
+    /// Tokens whose command is running, woken once it is done
+    in_flight: Arc<Mutex<HashMap<String, Arc<Notify>>>>,
+}
+
+/// What `IdempotencyCache::claim` found for a token
+pub enum Lookup {
+    /// The reply recorded for the token
+    Recorded(Frame),
+
+    /// Nothing yet: the caller runs the command
+    Claimed(Claim),
+}
+
+/// The right to run the command of a token, see `IdempotencyCache::claim`
+///
+/// Dropping it without `record` lets the next attempt with the token run
+/// the command instead.
+pub struct Claim {
+    cache: IdempotencyCache,
+    token: String,
+}
+
+impl Claim {
+    /// Record `reply` as the reply for the token
+    pub fn record(self, reply: Frame) {
+        self.cache.lock().insert(self.token.clone(), reply);
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(done) = lock(&self.cache.in_flight).remove(&self.token) {
+            done.notify_waiters();
+        }
+    }
+}
+
+impl IdempotencyCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        IdempotencyCache {
+            results: Arc::new(Mutex::new(ResultCache {
+                capacity,
+                ttl,
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                next_use: 0,
+            })),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The recorded reply for `token`, or the claim to run its command
+    ///
+    /// Waits while another attempt with the same token holds the claim.
+    pub async fn claim(&self, token: &str) -> Lookup {
+        loop {
+            let done = {
+                let mut in_flight = lock(&self.in_flight);
+                if let Some(reply) = self.lock().get(token) {
+                    return Lookup::Recorded(reply);
+                }
+                match in_flight.get(token) {
+                    Some(done) => Arc::clone(done),
+                    None => {
+                        in_flight.insert(token.to_string(), Arc::new(Notify::new()));
+                        return Lookup::Claimed(Claim {
+                            cache: self.clone(),
+                            token: token.to_string(),
+                        });
+                    }
+                }
+            };
+            // Registered before the claim can be released
+            let released = done.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            if lock(&self.in_flight)
+                .get(token)
+                .is_some_and(|current| Arc::ptr_eq(current, &done))
+            {
+                released.await;
+            }
+        }
+    }
+
+    /// Lock the stored replies
+    pub fn lock(&self) -> MutexGuard<'_, ResultCache> {
+        lock(&self.results)
+    }
+}
+
+/// Token -> reply map with LRU eviction and a TTL
+pub struct ResultCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, CachedReply>,
+    /// Last use -> token, oldest first
+    recency: BTreeMap<u64, String>,
+    next_use: u64,
+}
+
+struct CachedReply {
+    reply: Frame,
+    recorded_at: Instant,
+    last_use: u64,
+}
+
+impl ResultCache {
+    /// Number of remembered tokens, including expired ones not yet evicted
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Reply recorded for `token`, if it is still remembered
+    pub fn get(&mut self, token: &str) -> Option<Frame> {
+        let entry = self.entries.get_mut(token)?;
+        if entry.recorded_at.elapsed() >= self.ttl {
+            self.remove(token);
+            return None;
+        }
+
+        self.recency.remove(&entry.last_use);
+        entry.last_use = self.next_use;
+        self.recency.insert(self.next_use, token.to_string());
+        self.next_use += 1;
+        Some(entry.reply.clone())
+    }
+
+    /// Record the reply for `token`, evicting the least recently used
+    /// tokens beyond the capacity
+    pub fn insert(&mut self, token: String, reply: Frame) {
+        self.remove(&token);
+        self.recency.insert(self.next_use, token.clone());
+        self.entries.insert(
+            token,
+            CachedReply {
+                reply,
+                recorded_at: Instant::now(),
+                last_use: self.next_use,
+            },
+        );
+        self.next_use += 1;
+
+        while self.entries.len() > self.capacity {
+            match self.recency.pop_first() {
+                Some((_, oldest)) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+
+    fn remove(&mut self, token: &str) {
+        if let Some(entry) = self.entries.remove(token) {
+            self.recency.remove(&entry.last_use);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let cache = IdempotencyCache::new(2, Duration::from_secs(60));
+        let mut results = cache.lock();
+        results.insert("a".to_string(), Frame::Integer(1));
+        results.insert("b".to_string(), Frame::Integer(2));
+        assert_eq!(results.get("a"), Some(Frame::Integer(1)));
+
+        // "b" is now the least recently used
+        results.insert("c".to_string(), Frame::Integer(3));
+        assert_eq!(results.len(), 2);
+        assert_eq!(results.get("b"), None);
+        assert_eq!(results.get("a"), Some(Frame::Integer(1)));
+        assert_eq!(results.get("c"), Some(Frame::Integer(3)));
+    }
+
+    #[tokio::test]
+    async fn test_claims_are_per_token() {
+        let cache = IdempotencyCache::new(10, Duration::from_secs(60));
+        let claimed = |lookup| match lookup {
+            Lookup::Claimed(claim) => claim,
+            Lookup::Recorded(reply) => panic!("unexpected reply: {:?}", reply),
+        };
+        let first = claimed(cache.claim("a").await);
+
+        // Another token is not held up
+        drop(claimed(cache.claim("b").await));
+
+        // The same token waits for the reply
+        let retry = tokio::spawn({
+            let cache = cache.clone();
+            async move { matches!(cache.claim("a").await, Lookup::Recorded(Frame::Integer(1))) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!retry.is_finished());
+        first.record(Frame::Integer(1));
+        assert!(retry.await.unwrap());
+
+        // Without a reply the next attempt runs the command
+        let second = claimed(cache.claim("b").await);
+        let retry = tokio::spawn({
+            let cache = cache.clone();
+            async move { matches!(cache.claim("b").await, Lookup::Claimed(_)) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        drop(second);
+        assert!(retry.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_tokens_expire() {
+        let cache = IdempotencyCache::new(10, Duration::from_millis(20));
+        let mut results = cache.lock();
+        results.insert("a".to_string(), Frame::Integer(1));
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(results.get("a"), None);
+        assert!(results.is_empty());
+    }
+}
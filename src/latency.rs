@@ -0,0 +1,161 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Number of samples kept per event (same as Redis)
+const LATENCY_HISTORY_LEN: usize = 160;
+
+/// A single latency spike: (unix timestamp in seconds, duration in ms)
+pub type LatencySample = (u64, u64);
+
+/// Recent spikes recorded for one named event
+#[derive(Debug)]
+struct LatencyEvent {
+    /// Bounded ring of the most recent samples, oldest first
+    samples: VecDeque<LatencySample>,
+
+    /// Largest duration ever recorded for this event
+    max_ms: u64,
+}
+
+/// Latency monitor backing the LATENCY command family
+///
+/// Code paths report named events (`command`, `aof-fsync`, ...) with their
+/// duration. Only durations at or above the configured threshold are kept,
+/// and a threshold of 0 disables the monitor entirely.
+#[derive(Debug)]
+pub struct LatencyMonitor {
+    threshold_ms: AtomicU64,
+    events: Mutex<HashMap<String, LatencyEvent>>,
+}
+
+impl LatencyMonitor {
+    /// Create a monitor with the given threshold in milliseconds
+    pub fn new(threshold_ms: u64) -> Self {
+        LatencyMonitor {
+            threshold_ms: AtomicU64::new(threshold_ms),
+            events: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn threshold_ms(&self) -> u64 {
+        self.threshold_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn set_threshold_ms(&self, threshold_ms: u64) {
+        self.threshold_ms.store(threshold_ms, Ordering::Relaxed);
+    }
+
+    /// Record a sample for `event` if it crosses the threshold
+    pub fn add_sample(&self, event: &str, duration_ms: u64) {
+        let threshold = self.threshold_ms();
+        if threshold == 0 || duration_ms < threshold {
+            return;
+        }
+
+        let now = unix_time_secs();
+        let mut events = self.events.lock().unwrap();
+        let entry = events
+            .entry(event.to_string())
+            .or_insert_with(|| LatencyEvent {
+                samples: VecDeque::with_capacity(LATENCY_HISTORY_LEN),
+                max_ms: 0,
+            });
+
+        // Collapse samples taken within the same second, keeping the worst
+        match entry.samples.back_mut() {
+            Some(last) if last.0 == now => last.1 = last.1.max(duration_ms),
+            _ => {
+                if entry.samples.len() == LATENCY_HISTORY_LEN {
+                    entry.samples.pop_front();
+                }
+                entry.samples.push_back((now, duration_ms));
+            }
+        }
+        entry.max_ms = entry.max_ms.max(duration_ms);
+    }
+
+    /// Latest sample of every event: (event, timestamp, latest_ms, max_ms)
+    pub fn latest(&self) -> Vec<(String, u64, u64, u64)> {
+        let events = self.events.lock().unwrap();
+        let mut latest: Vec<_> = events
+            .iter()
+            .filter_map(|(name, event)| {
+                event
+                    .samples
+                    .back()
+                    .map(|&(ts, ms)| (name.clone(), ts, ms, event.max_ms))
+            })
+            .collect();
+        latest.sort_by(|a, b| a.0.cmp(&b.0));
+        latest
+    }
+
+    /// All retained samples for one event, oldest first
+    pub fn history(&self, event: &str) -> Vec<LatencySample> {
+        let events = self.events.lock().unwrap();
+        events
+            .get(event)
+            .map(|event| event.samples.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Forget the given events (all events when empty), returning how many were reset
+    pub fn reset(&self, names: &[String]) -> usize {
+        let mut events = self.events.lock().unwrap();
+        if names.is_empty() {
+            let count = events.len();
+            events.clear();
+            count
+        } else {
+            names
+                .iter()
+                .filter(|name| events.remove(name.as_str()).is_some())
+                .count()
+        }
+    }
+}
+
+impl Default for LatencyMonitor {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+fn unix_time_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold_filters_samples() {
+        let monitor = LatencyMonitor::new(10);
+
+        monitor.add_sample("command", 5);
+        assert!(monitor.latest().is_empty());
+
+        monitor.add_sample("command", 25);
+        monitor.add_sample("command", 15);
+        let latest = monitor.latest();
+        assert_eq!(latest.len(), 1);
+        assert_eq!(latest[0].0, "command");
+        assert_eq!(latest[0].3, 25);
+
+        assert_eq!(monitor.reset(&[]), 1);
+        assert!(monitor.history("command").is_empty());
+    }
+
+    #[test]
+    fn test_disabled_monitor_records_nothing() {
+        let monitor = LatencyMonitor::new(0);
+        monitor.add_sample("command", 1000);
+        assert!(monitor.latest().is_empty());
+    }
+}
@@ -1,9 +1,26 @@
+pub mod acl;
+pub mod client;
+pub mod clock;
 pub mod cmd;
 pub mod command_metrics;
+pub mod commandstats;
+pub mod config;
 pub mod connection;
 pub mod db;
 pub mod db_dashmap;
 pub mod frame;
+pub mod health;
+pub mod idempotency;
+pub mod latency;
 pub mod metrics;
+pub mod monitor;
+pub mod notify;
 pub mod persistence;
+pub mod prometheus;
 pub mod pubsub;
+pub mod ratelimit;
+pub mod replication;
+pub mod server;
+pub mod snapshot;
+pub mod transaction;
+pub mod util;
@@ -1,4 +1,8 @@
-use std::sync::atomic::{AtomicU64, Ordering};
+use crate::clock;
+use crate::commandstats::CommandStats;
+use crate::latency::LatencyMonitor;
+use crate::replication::Replication;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
 
@@ -15,17 +19,57 @@ pub struct Metrics {
     /// Currently active client connections
     active_connections: AtomicU64,
 
+    /// Connections currently waiting in a blocking command (BLPOP/BRPOP)
+    blocked_clients: AtomicU64,
+
     /// Cumulative command execution time in microseconds
     total_command_duration_us: AtomicU64,
 
     /// Cumulative AOF write time in microseconds
     total_aof_write_time_us: AtomicU64,
 
+    /// Number of writes to the AOF file; a pipeline of write commands is
+    /// written at once
+    total_aof_writes: AtomicU64,
+
     /// Cumulative lock wait time in microseconds (Mutex acquisition)
     total_lock_wait_time_us: AtomicU64,
 
+    /// Whether the most recent AOF append succeeded
+    aof_last_write_ok: AtomicBool,
+
+    /// Whether the AOF is enabled
+    aof_enabled: AtomicBool,
+
+    /// Whether the AOF is still being replayed in the background
+    loading: AtomicBool,
+
+    /// Writes since the last successful snapshot, counted towards the save
+    /// points
+    dirty: AtomicU64,
+
+    /// Unix time in milliseconds of the last successful snapshot, or of the
+    /// start if there was none yet (LASTSAVE)
+    last_save_ms: AtomicU64,
+
+    /// Keys removed to stay under `maxmemory`
+    evicted_keys: AtomicU64,
+
+    /// Whether `maxmemory` is exceeded and eviction could not fix it, in
+    /// which case writes are refused
+    over_maxmemory: AtomicBool,
+
     /// Server start time for uptime calculation
     start_time: Instant,
+
+    /// Latency spike history for the LATENCY command
+    latency: Arc<LatencyMonitor>,
+
+    /// Per-command counters for `INFO commandstats`
+    command_stats: CommandStats,
+
+    /// Replication id and offset reported by INFO
+    replication: Replication,
 }
 
 /// Shared metrics handle — cheap to clone via Arc
@@ -37,10 +81,22 @@ impl Metrics {
         Arc::new(Metrics {
             total_commands: AtomicU64::new(0),
             active_connections: AtomicU64::new(0),
+            blocked_clients: AtomicU64::new(0),
             total_command_duration_us: AtomicU64::new(0),
             total_aof_write_time_us: AtomicU64::new(0),
+            total_aof_writes: AtomicU64::new(0),
             total_lock_wait_time_us: AtomicU64::new(0),
+            aof_last_write_ok: AtomicBool::new(true),
+            aof_enabled: AtomicBool::new(false),
+            loading: AtomicBool::new(false),
+            dirty: AtomicU64::new(0),
+            last_save_ms: AtomicU64::new(clock::unix_time_ms()),
+            evicted_keys: AtomicU64::new(0),
+            over_maxmemory: AtomicBool::new(false),
             start_time: Instant::now(),
+            latency: Arc::new(LatencyMonitor::default()),
+            command_stats: CommandStats::default(),
+            replication: Replication::new(),
         })
     }
 
@@ -58,6 +114,14 @@ impl Metrics {
         self.active_connections.fetch_sub(1, Ordering::Relaxed);
     }
 
+    pub fn increment_blocked_clients(&self) {
+        self.blocked_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decrement_blocked_clients(&self) {
+        self.blocked_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
     pub fn add_command_duration_us(&self, us: u64) {
         self.total_command_duration_us
             .fetch_add(us, Ordering::Relaxed);
@@ -68,11 +132,54 @@ impl Metrics {
             .fetch_add(us, Ordering::Relaxed);
     }
 
+    pub fn increment_aof_writes(&self) {
+        self.total_aof_writes.fetch_add(1, Ordering::Relaxed);
+    }
+
     pub fn add_lock_wait_time_us(&self, us: u64) {
         self.total_lock_wait_time_us
             .fetch_add(us, Ordering::Relaxed);
     }
 
+    pub fn set_aof_last_write_ok(&self, ok: bool) {
+        self.aof_last_write_ok.store(ok, Ordering::Relaxed);
+    }
+
+    pub fn set_aof_enabled(&self, enabled: bool) {
+        self.aof_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn set_loading(&self, loading: bool) {
+        self.loading.store(loading, Ordering::Relaxed);
+    }
+
+    /// Record `changes` writes towards the snapshot save points
+    pub fn add_dirty(&self, changes: u64) {
+        self.dirty.fetch_add(changes, Ordering::Relaxed);
+    }
+
+    /// Record a successful snapshot that covered `saved` writes
+    ///
+    /// Only those are forgotten: writes made while the snapshot was taken
+    /// count towards the next one.
+    pub fn record_save(&self, saved: u64) {
+        let _ = self
+            .dirty
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |dirty| {
+                Some(dirty.saturating_sub(saved))
+            });
+        self.last_save_ms
+            .store(clock::unix_time_ms(), Ordering::Relaxed);
+    }
+
+    pub fn add_evicted_keys(&self, keys: u64) {
+        self.evicted_keys.fetch_add(keys, Ordering::Relaxed);
+    }
+
+    pub fn set_over_maxmemory(&self, over: bool) {
+        self.over_maxmemory.store(over, Ordering::Relaxed);
+    }
+
     // ===== Read Operations =====
 
     pub fn total_commands(&self) -> u64 {
@@ -83,6 +190,10 @@ impl Metrics {
         self.active_connections.load(Ordering::Relaxed)
     }
 
+    pub fn blocked_clients(&self) -> u64 {
+        self.blocked_clients.load(Ordering::Relaxed)
+    }
+
     pub fn total_command_duration_us(&self) -> u64 {
         self.total_command_duration_us.load(Ordering::Relaxed)
     }
@@ -91,10 +202,56 @@ impl Metrics {
         self.total_aof_write_time_us.load(Ordering::Relaxed)
     }
 
+    pub fn total_aof_writes(&self) -> u64 {
+        self.total_aof_writes.load(Ordering::Relaxed)
+    }
+
     pub fn total_lock_wait_time_us(&self) -> u64 {
         self.total_lock_wait_time_us.load(Ordering::Relaxed)
     }
 
+    pub fn aof_last_write_ok(&self) -> bool {
+        self.aof_last_write_ok.load(Ordering::Relaxed)
+    }
+
+    pub fn aof_enabled(&self) -> bool {
+        self.aof_enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn loading(&self) -> bool {
+        self.loading.load(Ordering::Relaxed)
+    }
+
+    /// Writes since the last successful snapshot
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::Relaxed)
+    }
+
+    /// Unix time in milliseconds of the last successful snapshot
+    pub fn last_save_ms(&self) -> u64 {
+        self.last_save_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn over_maxmemory(&self) -> bool {
+        self.over_maxmemory.load(Ordering::Relaxed)
+    }
+
+    pub fn latency(&self) -> &Arc<LatencyMonitor> {
+        &self.latency
+    }
+
+    pub fn command_stats(&self) -> &CommandStats {
+        &self.command_stats
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
     // ===== Computed Metrics =====
 
     /// Uptime in seconds
@@ -130,23 +287,40 @@ impl Metrics {
              \r\n\
              # Clients\r\n\
              connected_clients:{}\r\n\
+             blocked_clients:{}\r\n\
              \r\n\
              # Stats\r\n\
              total_commands_processed:{}\r\n\
              instantaneous_ops_per_sec:{:.2}\r\n\
              avg_command_duration_us:{:.2}\r\n\
+             evicted_keys:{}\r\n\
              \r\n\
              # Persistence\r\n\
+             loading:{}\r\n\
+             rdb_changes_since_last_save:{}\r\n\
+             rdb_last_save_time:{}\r\n\
+             aof_enabled:{}\r\n\
              total_aof_write_time_us:{}\r\n\
+             total_aof_writes:{}\r\n\
+             \r\n\
+             {}\
              \r\n\
              # Contention\r\n\
              total_lock_wait_time_us:{}\r\n",
             self.uptime_secs(),
             self.active_connections(),
+            self.blocked_clients(),
             self.total_commands(),
             self.ops_per_second(),
             self.avg_command_duration_us(),
+            self.evicted_keys(),
+            self.loading() as u8,
+            self.dirty(),
+            self.last_save_ms() / 1000,
+            self.aof_enabled() as u8,
             self.total_aof_write_time_us(),
+            self.total_aof_writes(),
+            self.replication.format_info(),
             self.total_lock_wait_time_us(),
         )
     }
@@ -169,10 +343,22 @@ impl Default for Metrics {
         Metrics {
             total_commands: AtomicU64::new(0),
             active_connections: AtomicU64::new(0),
+            blocked_clients: AtomicU64::new(0),
             total_command_duration_us: AtomicU64::new(0),
             total_aof_write_time_us: AtomicU64::new(0),
+            total_aof_writes: AtomicU64::new(0),
             total_lock_wait_time_us: AtomicU64::new(0),
+            aof_last_write_ok: AtomicBool::new(true),
+            aof_enabled: AtomicBool::new(false),
+            loading: AtomicBool::new(false),
+            dirty: AtomicU64::new(0),
+            last_save_ms: AtomicU64::new(clock::unix_time_ms()),
+            evicted_keys: AtomicU64::new(0),
+            over_maxmemory: AtomicBool::new(false),
             start_time: Instant::now(),
+            latency: Arc::new(LatencyMonitor::default()),
+            command_stats: CommandStats::default(),
+            replication: Replication::new(),
         }
     }
 }
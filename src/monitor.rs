@@ -0,0 +1,147 @@
+use crate::cmd::context::Origin;
+use crate::frame::Frame;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+/// Lines a MONITOR client may fall behind by before it skips some
+const FEED_CAPACITY: usize = 1024;
+
+/// The command feed read by MONITOR clients
+///
+/// Every line looks like Redis's, e.g.
+/// `1339518083.107412 [0 127.0.0.1:60866] "SET" "key" "value"`.
+/// Events the server generates itself, such as keyspace notifications,
+/// are tagged `Origin::Internal` and only shown once
+/// `set_show_internal(true)` is called, under `[<db> internal]`.
+#[derive(Clone)]
+pub struct Monitor {
+    sender: broadcast::Sender<String>,
+    show_internal: Arc<AtomicBool>,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Monitor {
+            sender: broadcast::channel(FEED_CAPACITY).0,
+            show_internal: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Start receiving every line fed from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.sender.subscribe()
+    }
+
+    /// Whether internally generated events are fed too
+    pub fn set_show_internal(&self, show: bool) {
+        self.show_internal.store(show, Ordering::Relaxed);
+    }
+
+    /// Show `command` to the MONITOR clients, if any
+    ///
+    /// `command` is the array frame as sent, `addr` the client that sent
+    /// it. Feeding never publishes anything, so internal events cannot
+    /// loop back into the notification system.
+    pub fn feed(&self, origin: Origin, db: usize, addr: Option<SocketAddr>, command: &Frame) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        if origin == Origin::Internal && !self.show_internal.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut line = format!("{}.{:06} [{} ", now.as_secs(), now.subsec_micros(), db);
+        match (origin, addr) {
+            (Origin::Internal, _) => line.push_str("internal]"),
+            (Origin::Client, Some(addr)) => write!(line, "{}]", addr).unwrap(),
+            (Origin::Client, None) => line.push_str("unknown]"),
+        }
+        if let Frame::Array(args) = command {
+            for arg in args {
+                line.push(' ');
+                match arg {
+                    Frame::Bulk(bytes) => push_quoted(&mut line, bytes),
+                    Frame::Simple(text) => push_quoted(&mut line, text.as_bytes()),
+                    other => push_quoted(&mut line, other.to_string().as_bytes()),
+                }
+            }
+        }
+        // Nobody listening any more is not an error
+        let _ = self.sender.send(line);
+    }
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Append `arg` in double quotes, escaped the way Redis prints arguments
+fn push_quoted(line: &mut String, arg: &[u8]) {
+    line.push('"');
+    for &byte in arg {
+        match byte {
+            b'\\' => line.push_str("\\\\"),
+            b'"' => line.push_str("\\\""),
+            b'\n' => line.push_str("\\n"),
+            b'\r' => line.push_str("\\r"),
+            b'\t' => line.push_str("\\t"),
+            0x20..=0x7e => line.push(byte as char),
+            _ => write!(line, "\\x{:02x}", byte).unwrap(),
+        }
+    }
+    line.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Frame {
+        Frame::Array(
+            args.iter()
+                .map(|arg| Frame::Bulk(arg.to_string().into()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_internal_events_are_hidden_by_default() {
+        let monitor = Monitor::new();
+        let mut feed = monitor.subscribe();
+        let addr: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+
+        let publish = command(&["PUBLISH", "__keyevent@0__:expired", "k"]);
+        monitor.feed(Origin::Internal, 0, None, &publish);
+        monitor.feed(
+            Origin::Client,
+            1,
+            Some(addr),
+            &command(&["SET", "a\"b", "x\n"]),
+        );
+        let line = feed.try_recv().unwrap();
+        assert!(
+            line.ends_with(r#" [1 127.0.0.1:6000] "SET" "a\"b" "x\n""#),
+            "{}",
+            line
+        );
+        assert!(feed.try_recv().is_err());
+
+        monitor.set_show_internal(true);
+        monitor.feed(Origin::Internal, 0, None, &publish);
+        let line = feed.try_recv().unwrap();
+        assert!(
+            line.ends_with(r#" [0 internal] "PUBLISH" "__keyevent@0__:expired" "k""#),
+            "{}",
+            line
+        );
+    }
+}
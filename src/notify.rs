@@ -0,0 +1,146 @@
+use crate::cmd::context::Origin;
+use crate::frame::Frame;
+use crate::pubsub::PubSub;
+use bytes::Bytes;
+
+/// Event classes enabled by `notify-keyspace-events`
+///
+/// Parsed from the Redis flag string, e.g. `"Ex"` or `"KEA"`. `K` and `E`
+/// select the `__keyspace@<db>__` and `__keyevent@<db>__` channels, the
+/// remaining letters select event classes. Only expired (`x`) and evicted
+/// (`e`) events, both included in `A`, are generated so far; the other
+/// Redis classes are accepted so existing configurations keep working.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NotifyFlags {
+    pub keyspace: bool,
+    pub keyevent: bool,
+    pub expired: bool,
+    pub evicted: bool,
+}
+
+impl NotifyFlags {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut flags = NotifyFlags::default();
+        for class in spec.chars() {
+            match class {
+                'K' => flags.keyspace = true,
+                'E' => flags.keyevent = true,
+                'x' => flags.expired = true,
+                'e' => flags.evicted = true,
+                'A' => {
+                    flags.expired = true;
+                    flags.evicted = true;
+                }
+                'g' | '$' | 'l' | 's' | 'h' | 'z' | 't' | 'd' | 'm' | 'n' => {}
+                other => return Err(format!("invalid keyspace event class '{}'", other)),
+            }
+        }
+        Ok(flags)
+    }
+
+    /// Flag string that parses back to these flags, e.g. `"Ex"`
+    pub fn spec(&self) -> String {
+        let mut spec = String::new();
+        if self.keyspace {
+            spec.push('K');
+        }
+        if self.keyevent {
+            spec.push('E');
+        }
+        if self.expired {
+            spec.push('x');
+        }
+        if self.evicted {
+            spec.push('e');
+        }
+        spec
+    }
+
+    /// Whether any event would actually be published
+    pub fn is_enabled(&self) -> bool {
+        (self.keyspace || self.keyevent) && (self.expired || self.evicted)
+    }
+}
+
+/// Publishes keyspace events on the Pub/Sub channels Redis uses
+#[derive(Clone)]
+pub struct Notifier {
+    pubsub: PubSub,
+    flags: NotifyFlags,
+    /// Index of the database whose events this notifier publishes
+    db: usize,
+}
+
+impl Notifier {
+    pub fn new(pubsub: PubSub, flags: NotifyFlags, db: usize) -> Self {
+        Notifier { pubsub, flags, db }
+    }
+
+    /// A key was removed because its TTL elapsed
+    pub fn expired(&self, key: &[u8]) {
+        if self.flags.expired {
+            self.notify("expired", key);
+        }
+    }
+
+    /// A key was removed to get back under `maxmemory`
+    pub fn evicted(&self, key: &[u8]) {
+        if self.flags.evicted {
+            self.notify("evicted", key);
+        }
+    }
+
+    fn notify(&self, event: &str, key: &[u8]) {
+        if self.flags.keyspace {
+            self.publish(
+                format!("__keyspace@{}__:{}", self.db, String::from_utf8_lossy(key)),
+                Bytes::copy_from_slice(event.as_bytes()),
+            );
+        }
+        if self.flags.keyevent {
+            self.publish(
+                format!("__keyevent@{}__:{}", self.db, event),
+                Bytes::copy_from_slice(key),
+            );
+        }
+    }
+
+    /// Publish straight to the channel rather than running PUBLISH, so the
+    /// event is only seen by MONITOR as internal
+    fn publish(&self, channel: String, message: Bytes) {
+        self.pubsub.publish(&channel, message.clone());
+        let command = Frame::Array(vec![
+            Frame::Bulk(Bytes::from_static(b"PUBLISH")),
+            Frame::Bulk(Bytes::from(channel)),
+            Frame::Bulk(message),
+        ]);
+        self.pubsub
+            .monitor()
+            .feed(Origin::Internal, self.db, None, &command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(
+            NotifyFlags::parse("Ex"),
+            Ok(NotifyFlags {
+                keyspace: false,
+                keyevent: true,
+                expired: true,
+                evicted: false,
+            })
+        );
+        assert_eq!(NotifyFlags::parse("KA").unwrap().spec(), "Kxe");
+        assert!(NotifyFlags::parse("KEA").unwrap().is_enabled());
+        assert!(NotifyFlags::parse("Ke").unwrap().is_enabled());
+        // Events selected but no channel type
+        assert!(!NotifyFlags::parse("x").unwrap().is_enabled());
+        assert!(!NotifyFlags::parse("").unwrap().is_enabled());
+        assert!(NotifyFlags::parse("Eq").is_err());
+    }
+}
@@ -0,0 +1,191 @@
+use crate::db::Db;
+use crate::metrics::{Metrics, SharedMetrics};
+use std::fmt::Write;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::debug;
+
+/// Largest request head accepted by the metrics responder
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+/// How long a scraper gets to send its request head before the
+/// connection is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Render the shared server stats in the Prometheus text exposition format
+pub fn render(metrics: &Metrics, dbs: &[Db]) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+
+    metric(
+        "redis_uptime_seconds",
+        "gauge",
+        "Seconds since the server started",
+        metrics.uptime_secs(),
+    );
+    metric(
+        "redis_connected_clients",
+        "gauge",
+        "Number of connected clients",
+        metrics.active_connections() as f64,
+    );
+    metric(
+        "redis_commands_total",
+        "counter",
+        "Total number of commands processed",
+        metrics.total_commands() as f64,
+    );
+    metric(
+        "redis_command_duration_seconds_total",
+        "counter",
+        "Cumulative command execution time",
+        metrics.total_command_duration_us() as f64 / 1e6,
+    );
+    metric(
+        "redis_keyspace_keys",
+        "gauge",
+        "Number of keys in all databases",
+        dbs.iter().map(Db::dbsize).sum::<usize>() as f64,
+    );
+    metric(
+        "redis_aof_last_write_status",
+        "gauge",
+        "1 if the last AOF write succeeded, 0 otherwise",
+        if metrics.aof_last_write_ok() {
+            1.0
+        } else {
+            0.0
+        },
+    );
+    metric(
+        "redis_aof_write_seconds_total",
+        "counter",
+        "Cumulative time spent appending to the AOF",
+        metrics.total_aof_write_time_us() as f64 / 1e6,
+    );
+    metric(
+        "redis_aof_writes_total",
+        "counter",
+        "Number of writes to the AOF file",
+        metrics.total_aof_writes() as f64,
+    );
+    metric(
+        "redis_lock_wait_seconds_total",
+        "counter",
+        "Cumulative time spent waiting for the database lock",
+        metrics.total_lock_wait_time_us() as f64 / 1e6,
+    );
+
+    out
+}
+
+/// Serve `GET /metrics` on `listener` until the task is dropped
+///
+/// This is a deliberately tiny HTTP/1.x responder: one request per
+/// connection, any other path gets a 404.
+pub async fn serve(listener: TcpListener, metrics: SharedMetrics, dbs: Vec<Db>) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        let dbs = dbs.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(socket, &metrics, &dbs).await {
+                debug!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut socket: TcpStream, metrics: &Metrics, dbs: &[Db]) -> io::Result<()> {
+    // Read until the end of the request head
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    let read_head = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = socket.read(&mut buf).await?;
+            if n == 0 || request.len() + n > MAX_REQUEST_BYTES {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok::<_, io::Error>(())
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, read_head)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no request head"))??;
+
+    let request_line = request.split(|&b| b == b'\n').next().unwrap_or_default();
+    let mut parts = std::str::from_utf8(request_line)
+        .unwrap_or_default()
+        .split_whitespace();
+    let (method, path) = (parts.next(), parts.next());
+
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(metrics, dbs)),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn fetch(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serves_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Metrics::new();
+        let db = Db::new();
+
+        metrics.increment_commands();
+        db.write_string(bytes::Bytes::from("k"), bytes::Bytes::from("v"), None);
+        tokio::spawn(serve(listener, metrics, vec![db, Db::new()]));
+
+        let response = fetch(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\nredis_commands_total 1\n"));
+        assert!(response.contains("\nredis_keyspace_keys 1\n"));
+
+        let response = fetch(addr, "/other").await;
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        // A client that never finishes its request is dropped
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.is_empty(), "{}", response);
+    }
+}
@@ -1,22 +1,74 @@
+use crate::monitor::Monitor;
+use crate::util::lock;
 use bytes::Bytes;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tracing::warn;
 
 /// Maximum number of messages that can be queued per channel
 const CHANNEL_CAPACITY: usize = 1024;
 
+/// Most messages a retained channel can keep for late subscribers
+pub const MAX_RETAINED_MESSAGES: usize = CHANNEL_CAPACITY;
+
+/// Reply to PUBLISH/SPUBLISH of a message over the size limit
+pub const MESSAGE_TOO_BIG_ERR: &str =
+    "ERR Pub/Sub message exceeds the 'proto-max-pubsub-message-size' limit";
+
+/// A published message as delivered to subscribers
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub payload: Bytes,
+
+    /// Position of the message in its channel, counting from 1, when
+    /// sequence numbers are enabled (see `PubSub::set_message_sequence`)
+    pub sequence: Option<u64>,
+}
+
 /// Pub/Sub manager for handling publish/subscribe operations
 #[derive(Clone)]
 pub struct PubSub {
     /// Shared state containing channels and their subscribers
     shared: Arc<Mutex<PubSubState>>,
+
+    /// Largest message PUBLISH/SPUBLISH accept, 0 for no limit
+    max_message_size: Arc<AtomicUsize>,
+
+    /// Whether published messages are numbered
+    message_sequence: Arc<AtomicBool>,
+
+    /// Command feed of MONITOR clients, delivered alongside the channels
+    monitor: Monitor,
 }
 
 /// Internal state for Pub/Sub
 struct PubSubState {
     /// Map of channel names to broadcast senders
-    channels: HashMap<String, broadcast::Sender<Bytes>>,
+    channels: HashMap<String, broadcast::Sender<Message>>,
+
+    /// Shard channels (SSUBSCRIBE/SPUBLISH), a namespace separate from
+    /// `channels` so a channel name can exist in both
+    shard_channels: HashMap<String, broadcast::Sender<Message>>,
+
+    /// Last sequence number given out per channel and per shard channel.
+    /// Only channels in `channels`/`shard_channels` (or retained ones) are
+    /// numbered, so a counter lives as long as its channel: a client that
+    /// resubscribes can tell which messages it missed until
+    /// `cleanup_empty_channels` drops the channel.
+    sequences: HashMap<String, u64>,
+    shard_sequences: HashMap<String, u64>,
+
+    /// Channels in retained mode, with their most recent messages
+    retained: HashMap<String, Backlog>,
+}
+
+/// The last `capacity` messages published to a retained channel
+struct Backlog {
+    capacity: usize,
+    messages: VecDeque<Message>,
 }
 
 impl PubSub {
@@ -25,7 +77,44 @@ impl PubSub {
         PubSub {
             shared: Arc::new(Mutex::new(PubSubState {
                 channels: HashMap::new(),
+                shard_channels: HashMap::new(),
+                retained: HashMap::new(),
+                sequences: HashMap::new(),
+                shard_sequences: HashMap::new(),
             })),
+            max_message_size: Arc::new(AtomicUsize::new(0)),
+            message_sequence: Arc::new(AtomicBool::new(false)),
+            monitor: Monitor::new(),
+        }
+    }
+
+    /// The command feed of MONITOR clients
+    pub fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    /// Number every message published from now on within its channel
+    ///
+    /// Subscribers can then spot messages they missed, e.g. after falling
+    /// too far behind. Off by default, since it changes the message shape.
+    pub fn set_message_sequence(&self, enabled: bool) {
+        self.message_sequence.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Refuse messages over `size` bytes in PUBLISH/SPUBLISH, 0 for no limit
+    ///
+    /// Every subscriber buffers its own reference to a message until it is
+    /// written out, so one huge message can hold memory across all of them.
+    pub fn set_max_message_size(&self, size: usize) {
+        self.max_message_size.store(size, Ordering::Relaxed);
+    }
+
+    /// Check a message against the size limit before publishing it
+    pub fn check_message_size(&self, message: &[u8]) -> Result<(), &'static str> {
+        match self.max_message_size.load(Ordering::Relaxed) {
+            0 => Ok(()),
+            max if message.len() > max => Err(MESSAGE_TOO_BIG_ERR),
+            _ => Ok(()),
         }
     }
 
@@ -33,39 +122,119 @@ impl PubSub {
     ///
     /// Returns the number of subscribers that received the message
     pub fn publish(&self, channel: &str, message: Bytes) -> usize {
-        let state = self.shared.lock().unwrap();
-
-        if let Some(sender) = state.channels.get(channel) {
-            // Send to all subscribers
-            // receiver_count() includes the sender itself, so subtract 1
-            sender
-                .send(message)
-                .map(|_| sender.receiver_count())
-                .unwrap_or(0)
-        } else {
-            // No subscribers for this channel
-            0
+        let mut state = lock(&self.shared);
+        let state = &mut *state;
+        let numbered = state.channels.contains_key(channel) || state.retained.contains_key(channel);
+        let message = Message {
+            payload: message,
+            sequence: self.next_sequence(&mut state.sequences, numbered, channel),
+        };
+        if let Some(backlog) = state.retained.get_mut(channel) {
+            if backlog.messages.len() == backlog.capacity {
+                backlog.messages.pop_front();
+            }
+            backlog.messages.push_back(message.clone());
+        }
+        publish_to(&state.channels, channel, message)
+    }
+
+    /// Keep the last `count` messages of a channel for new subscribers
+    ///
+    /// Subscribing to a retained channel first delivers its backlog, then
+    /// live messages. A count of 0 turns retention off and drops the
+    /// backlog; shrinking the count drops the oldest messages.
+    pub fn retain(&self, channel: &str, count: usize) {
+        let mut state = lock(&self.shared);
+        if count == 0 {
+            state.retained.remove(channel);
+            return;
+        }
+
+        let count = count.min(MAX_RETAINED_MESSAGES);
+        let backlog = state
+            .retained
+            .entry(channel.to_string())
+            .or_insert_with(|| Backlog {
+                capacity: count,
+                messages: VecDeque::new(),
+            });
+        backlog.capacity = count;
+        while backlog.messages.len() > count {
+            backlog.messages.pop_front();
         }
     }
 
+    /// Publish a message to a shard channel
+    ///
+    /// Only shard subscribers receive it; regular subscribers of a channel
+    /// with the same name do not.
+    pub fn spublish(&self, channel: &str, message: Bytes) -> usize {
+        let mut state = lock(&self.shared);
+        let numbered = state.shard_channels.contains_key(channel);
+        let message = Message {
+            payload: message,
+            sequence: self.next_sequence(&mut state.shard_sequences, numbered, channel),
+        };
+        publish_to(&state.shard_channels, channel, message)
+    }
+
+    /// Sequence number of the next message on `channel`, if enabled
+    ///
+    /// A channel nobody subscribes to is not `numbered`: the message
+    /// reaches no one, and a counter per published name would grow without
+    /// bound.
+    fn next_sequence(
+        &self,
+        sequences: &mut HashMap<String, u64>,
+        numbered: bool,
+        channel: &str,
+    ) -> Option<u64> {
+        if !numbered || !self.message_sequence.load(Ordering::Relaxed) {
+            return None;
+        }
+        let sequence = sequences.entry(channel.to_string()).or_insert(0);
+        *sequence += 1;
+        Some(*sequence)
+    }
+
     /// Subscribe to a channel
     ///
     /// Returns a receiver that will get all messages published to the channel
-    pub fn subscribe(&self, channel: String) -> broadcast::Receiver<Bytes> {
-        let mut state = self.shared.lock().unwrap();
+    pub fn subscribe(&self, channel: String) -> broadcast::Receiver<Message> {
+        let mut state = lock(&self.shared);
+        subscribe_to(&mut state.channels, channel)
+    }
 
-        // Get or create the channel
-        let sender = state
-            .channels
-            .entry(channel)
-            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0);
+    /// Subscribe to a channel, also returning its retained messages
+    ///
+    /// The backlog is taken under the same lock that registers the
+    /// receiver, so every message is either in the backlog or received,
+    /// never both or neither.
+    pub fn subscribe_with_backlog(
+        &self,
+        channel: String,
+    ) -> (broadcast::Receiver<Message>, Vec<Message>) {
+        let mut state = lock(&self.shared);
+        let backlog = state
+            .retained
+            .get(&channel)
+            .map(|backlog| backlog.messages.iter().cloned().collect())
+            .unwrap_or_default();
+        (subscribe_to(&mut state.channels, channel), backlog)
+    }
 
-        sender.subscribe()
+    /// Subscribe to a shard channel
+    pub fn ssubscribe(&self, channel: String) -> broadcast::Receiver<Message> {
+        let mut state = lock(&self.shared);
+        subscribe_to(&mut state.shard_channels, channel)
     }
 
     /// Get the number of subscribers for a channel
+    ///
+    /// `receiver_count()` counts receivers only, so a channel with one
+    /// subscriber reports 1.
     pub fn num_subscribers(&self, channel: &str) -> usize {
-        let state = self.shared.lock().unwrap();
+        let state = lock(&self.shared);
 
         state
             .channels
@@ -74,20 +243,253 @@ impl PubSub {
             .unwrap_or(0)
     }
 
-    /// Get the number of active channels
+    /// Get the number of channels with at least one subscriber
+    ///
+    /// Channels whose last subscriber left stay in the map until
+    /// `cleanup_empty_channels` runs, so they are filtered out here.
     pub fn num_channels(&self) -> usize {
-        let state = self.shared.lock().unwrap();
-        state.channels.len()
+        let state = lock(&self.shared);
+        state
+            .channels
+            .values()
+            .filter(|sender| sender.receiver_count() > 0)
+            .count()
+    }
+
+    /// Get the number of pattern subscriptions
+    ///
+    /// Pattern subscriptions (PSUBSCRIBE) are not supported, so this is
+    /// always 0. It exists so INFO and PUBSUB NUMPAT report the Redis fields.
+    pub fn num_patterns(&self) -> usize {
+        0
     }
 
     /// Clean up empty channels (channels with no subscribers)
     pub fn cleanup_empty_channels(&self) {
-        let mut state = self.shared.lock().unwrap();
+        let mut state = lock(&self.shared);
 
         // Remove channels with no subscribers
         state
             .channels
             .retain(|_, sender| sender.receiver_count() > 0);
+        state
+            .shard_channels
+            .retain(|_, sender| sender.receiver_count() > 0);
+
+        // Drop the sequence counters of the removed channels
+        let state = &mut *state;
+        state.sequences.retain(|channel, _| {
+            state.channels.contains_key(channel) || state.retained.contains_key(channel)
+        });
+        state
+            .shard_sequences
+            .retain(|channel, _| state.shard_channels.contains_key(channel));
+    }
+}
+
+/// Send a message to every subscriber of `channel` in `channels`
+///
+/// Returns the number of subscribers that received the message.
+fn publish_to(
+    channels: &HashMap<String, broadcast::Sender<Message>>,
+    channel: &str,
+    message: Message,
+) -> usize {
+    if let Some(sender) = channels.get(channel) {
+        // Send to all subscribers. The map holds a sender, not a
+        // receiver, so receiver_count() is exactly the subscriber count
+        sender
+            .send(message)
+            .map(|_| sender.receiver_count())
+            .unwrap_or(0)
+    } else {
+        // No subscribers for this channel
+        0
+    }
+}
+
+/// Register a receiver for `channel` in `channels`, creating the channel
+fn subscribe_to(
+    channels: &mut HashMap<String, broadcast::Sender<Message>>,
+    channel: String,
+) -> broadcast::Receiver<Message> {
+    // Get or create the channel
+    let sender = channels
+        .entry(channel)
+        .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0);
+
+    sender.subscribe()
+}
+
+/// All channel subscriptions of a single connection
+///
+/// Every subscribed channel gets a forwarding task that drains its
+/// broadcast receiver into one shared queue. The connection reads from that
+/// queue only, so messages are delivered in arrival order across channels
+/// (no receiver can be starved by `select!` bias) and each channel's own
+/// publish order is preserved.
+pub struct Subscriber {
+    pubsub: PubSub,
+
+    /// Sending half cloned into every forwarding task
+    tx: mpsc::Sender<(bool, String, Message)>,
+
+    /// Merged stream of (sharded, channel, message) triples
+    rx: mpsc::Receiver<(bool, String, Message)>,
+
+    /// Subscribed channels in subscription order, with their forwarders
+    channels: Vec<(String, JoinHandle<()>)>,
+
+    /// Subscribed shard channels in subscription order, with their forwarders
+    shard_channels: Vec<(String, JoinHandle<()>)>,
+}
+
+impl Subscriber {
+    pub fn new(pubsub: PubSub) -> Self {
+        let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+        Subscriber {
+            pubsub,
+            tx,
+            rx,
+            channels: Vec::new(),
+            shard_channels: Vec::new(),
+        }
+    }
+
+    /// Number of subscribed channels
+    pub fn count(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Number of subscribed shard channels
+    pub fn shard_count(&self) -> usize {
+        self.shard_channels.len()
+    }
+
+    /// Names of the subscribed channels, in subscription order
+    pub fn channels(&self) -> Vec<String> {
+        self.channels.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// Names of the subscribed shard channels, in subscription order
+    pub fn shard_channels(&self) -> Vec<String> {
+        self.shard_channels
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Subscribe to a channel, returning the new subscription count
+    ///
+    /// The broadcast receiver is registered before this returns, so any
+    /// message published afterwards will be delivered.
+    pub fn subscribe(&mut self, channel: String) -> usize {
+        self.add(false, channel);
+        self.count()
+    }
+
+    /// Subscribe to a shard channel, returning the new shard subscription count
+    pub fn ssubscribe(&mut self, channel: String) -> usize {
+        self.add(true, channel);
+        self.shard_count()
+    }
+
+    /// Unsubscribe from a channel, returning the new subscription count
+    pub fn unsubscribe(&mut self, channel: &str) -> usize {
+        Self::remove(&mut self.channels, channel);
+        self.count()
+    }
+
+    /// Unsubscribe from a shard channel, returning the new shard subscription count
+    pub fn sunsubscribe(&mut self, channel: &str) -> usize {
+        Self::remove(&mut self.shard_channels, channel);
+        self.shard_count()
+    }
+
+    /// Wait for the next message on any subscribed channel
+    ///
+    /// Returns `(sharded, channel, message)`. Cancel safe, so it can be used
+    /// in `tokio::select!`.
+    pub async fn recv(&mut self) -> (bool, String, Message) {
+        loop {
+            // `self.tx` keeps the queue open, so this never returns None
+            if let Some((sharded, channel, message)) = self.rx.recv().await {
+                let channels = if sharded {
+                    &self.shard_channels
+                } else {
+                    &self.channels
+                };
+                // Drop messages forwarded just before an unsubscribe
+                if channels.iter().any(|(name, _)| *name == channel) {
+                    return (sharded, channel, message);
+                }
+            }
+        }
+    }
+
+    /// Start forwarding a channel or shard channel into the shared queue
+    fn add(&mut self, sharded: bool, channel: String) {
+        let channels = if sharded {
+            &self.shard_channels
+        } else {
+            &self.channels
+        };
+        if channels.iter().any(|(name, _)| *name == channel) {
+            return;
+        }
+
+        let (mut receiver, backlog) = if sharded {
+            (self.pubsub.ssubscribe(channel.clone()), Vec::new())
+        } else {
+            self.pubsub.subscribe_with_backlog(channel.clone())
+        };
+        let tx = self.tx.clone();
+        let name = channel.clone();
+        let forwarder = tokio::spawn(async move {
+            for message in backlog {
+                if tx.send((sharded, name.clone(), message)).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => {
+                        if tx.send((sharded, name.clone(), message)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(
+                            "Subscriber lagged on '{}', skipped {} messages",
+                            name, skipped
+                        );
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        if sharded {
+            self.shard_channels.push((channel, forwarder));
+        } else {
+            self.channels.push((channel, forwarder));
+        }
+    }
+
+    /// Stop forwarding `channel` from `channels`
+    fn remove(channels: &mut Vec<(String, JoinHandle<()>)>, channel: &str) {
+        if let Some(index) = channels.iter().position(|(name, _)| name == channel) {
+            let (_, forwarder) = channels.remove(index);
+            forwarder.abort();
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for (_, forwarder) in self.channels.iter().chain(&self.shard_channels) {
+            forwarder.abort();
+        }
     }
 }
 
@@ -96,3 +498,79 @@ impl Default for PubSub {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_poisoned_lock_is_recovered() {
+        let pubsub = PubSub::new();
+        let mut subscriber = Subscriber::new(pubsub.clone());
+        subscriber.subscribe("news".to_string());
+
+        let shared = Arc::clone(&pubsub.shared);
+        let result = std::thread::spawn(move || {
+            let _state = shared.lock().unwrap();
+            panic!("publisher failed");
+        })
+        .join();
+        assert!(result.is_err());
+
+        assert_eq!(pubsub.publish("news", Bytes::from("hello")), 1);
+        assert_eq!(pubsub.num_subscribers("news"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_message_sequence_reveals_gaps() {
+        let pubsub = PubSub::new();
+        pubsub.set_message_sequence(true);
+        let mut subscriber = Subscriber::new(pubsub.clone());
+        subscriber.subscribe("news".to_string());
+
+        pubsub.publish("news", Bytes::from("first"));
+        pubsub.publish("news", Bytes::from("second"));
+        assert_eq!(subscriber.recv().await.2.sequence, Some(1));
+        assert_eq!(subscriber.recv().await.2.sequence, Some(2));
+
+        // The forwarder cannot run in between, so the channel overflows
+        let published = 3 * CHANNEL_CAPACITY as u64;
+        for _ in 0..published {
+            pubsub.publish("news", Bytes::from("flood"));
+        }
+        let mut last = subscriber.recv().await.2.sequence.unwrap();
+        assert!(last > 3, "no gap after lagging: {}", last);
+        while last < 2 + published {
+            let sequence = subscriber.recv().await.2.sequence.unwrap();
+            assert_eq!(sequence, last + 1);
+            last = sequence;
+        }
+
+        pubsub.set_message_sequence(false);
+        pubsub.publish("news", Bytes::from("plain"));
+        assert_eq!(subscriber.recv().await.2.sequence, None);
+    }
+
+    #[test]
+    fn test_sequences_only_track_subscribed_channels() {
+        let pubsub = PubSub::new();
+        pubsub.set_message_sequence(true);
+        for i in 0..100 {
+            pubsub.publish(&format!("nobody-{}", i), Bytes::from("m"));
+            pubsub.spublish(&format!("nobody-{}", i), Bytes::from("m"));
+        }
+        let state = lock(&pubsub.shared);
+        assert!(state.sequences.is_empty());
+        assert!(state.shard_sequences.is_empty());
+        drop(state);
+
+        let receiver = pubsub.subscribe("news".to_string());
+        pubsub.publish("news", Bytes::from("m"));
+        assert_eq!(lock(&pubsub.shared).sequences.len(), 1);
+
+        // The counter goes with the channel once its subscribers leave
+        drop(receiver);
+        pubsub.cleanup_empty_channels();
+        assert!(lock(&pubsub.shared).sequences.is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+
+/// What happens to a command that exceeds the per-client rate
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RateLimitMode {
+    /// Hold the command until the client is back under the limit
+    #[default]
+    Delay,
+    /// Reply with an error without running the command
+    Error,
+}
+
+impl RateLimitMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "delay" => Some(RateLimitMode::Delay),
+            "error" => Some(RateLimitMode::Error),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RateLimitMode::Delay => "delay",
+            RateLimitMode::Error => "error",
+        }
+    }
+}
+
+/// Per-connection command rate limit (`per-client-max-ops-per-sec`)
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateLimit {
+    /// Commands per second a single connection may run, 0 for no limit
+    pub max_ops_per_sec: u64,
+    pub mode: RateLimitMode,
+}
+
+impl RateLimit {
+    /// A fresh bucket for a new connection, `None` when unlimited
+    pub fn bucket(&self) -> Option<TokenBucket> {
+        (self.max_ops_per_sec > 0).then(|| TokenBucket::new(self.max_ops_per_sec))
+    }
+}
+
+/// Token bucket refilled at `ops_per_sec` tokens per second
+///
+/// Holds at most one second worth of tokens, so an idle client can burst
+/// up to `ops_per_sec` commands before being limited.
+#[derive(Debug)]
+pub struct TokenBucket {
+    ops_per_sec: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(ops_per_sec: u64) -> Self {
+        TokenBucket {
+            ops_per_sec: ops_per_sec as f64,
+            tokens: ops_per_sec as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Take a token for one command
+    ///
+    /// Fails with the time until the next token is available.
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.ops_per_sec).min(self.ops_per_sec);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.ops_per_sec,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_bursts_then_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10);
+        bucket.refilled_at = start;
+
+        for _ in 0..10 {
+            assert_eq!(bucket.try_acquire(start), Ok(()));
+        }
+        let wait = bucket.try_acquire(start).unwrap_err();
+        assert!((wait.as_secs_f64() - 0.1).abs() < 1e-9, "{:?}", wait);
+
+        // One token per 100ms, never more than a second's worth
+        assert_eq!(
+            bucket.try_acquire(start + Duration::from_millis(100)),
+            Ok(())
+        );
+        assert!(bucket
+            .try_acquire(start + Duration::from_millis(100))
+            .is_err());
+        let later = start + Duration::from_secs(60);
+        for _ in 0..10 {
+            assert_eq!(bucket.try_acquire(later), Ok(()));
+        }
+        assert!(bucket.try_acquire(later).is_err());
+    }
+
+    #[test]
+    fn test_disabled_limit_has_no_bucket() {
+        assert!(RateLimit::default().bucket().is_none());
+        assert_eq!(RateLimitMode::parse("ERROR"), Some(RateLimitMode::Error));
+        assert_eq!(RateLimitMode::parse("drop"), None);
+    }
+}
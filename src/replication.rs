@@ -0,0 +1,144 @@
+use crate::frame::Frame;
+use bytes::{Bytes, BytesMut};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+/// Length of the replication id, in hex characters (same as Redis)
+pub const REPLID_LEN: usize = 40;
+
+/// Master side of replication
+///
+/// Every write that changed data is propagated as a stream of commands,
+/// the same ones logged to the AOF. The offset counts the bytes of that
+/// stream, so together with the replication id it identifies a position a
+/// replica can continue from (PSYNC).
+///
+/// Commands with a random or time-dependent outcome are propagated by
+/// their effect (SPOP as SREM of the popped members, SETEX as SET and
+/// PEXPIREAT, see `Command::propagate`), so a replica applying the stream
+/// ends up with the same data as the master and as a replay of the AOF.
+#[derive(Debug)]
+pub struct Replication {
+    /// Random id of this stream, new on every start
+    replid: String,
+
+    stream: Mutex<Stream>,
+}
+
+/// Position in the command stream
+#[derive(Debug, Default)]
+struct Stream {
+    /// Bytes produced so far (`master_repl_offset`)
+    offset: u64,
+
+    /// Database the stream last switched to with SELECT
+    selected_db: Option<usize>,
+
+    /// Attached replicas, each sent the stream from where it attached
+    replicas: Vec<mpsc::UnboundedSender<Bytes>>,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Replication {
+            replid: generate_replid(),
+            stream: Mutex::new(Stream::default()),
+        }
+    }
+
+    /// The replication id (`master_replid`)
+    pub fn replid(&self) -> &str {
+        &self.replid
+    }
+
+    /// Bytes of command stream produced so far (`master_repl_offset`)
+    pub fn offset(&self) -> u64 {
+        self.stream.lock().unwrap().offset
+    }
+
+    /// Number of replicas receiving the stream (`connected_slaves`)
+    pub fn replica_count(&self) -> usize {
+        let mut stream = self.stream.lock().unwrap();
+        stream.replicas.retain(|replica| !replica.is_closed());
+        stream.replicas.len()
+    }
+
+    /// Receive the stream from the current offset on
+    ///
+    /// Each item holds the encoded commands of one `feed`. The replica is
+    /// detached when the receiver is dropped. It is expected to hold the
+    /// data as of the current offset already.
+    pub fn attach_replica(&self) -> mpsc::UnboundedReceiver<Bytes> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut stream = self.stream.lock().unwrap();
+        // The new replica doesn't know which database is selected
+        stream.selected_db = None;
+        stream.replicas.push(tx);
+        rx
+    }
+
+    /// Add commands applied to database `db_index` to the stream
+    ///
+    /// A SELECT goes first when the stream last switched to a different
+    /// database.
+    pub fn feed(&self, db_index: usize, frames: &[Frame]) {
+        let mut encoded = BytesMut::new();
+        let mut stream = self.stream.lock().unwrap();
+        if stream.selected_db != Some(db_index) {
+            Frame::Array(vec![
+                Frame::Bulk(Bytes::from_static(b"SELECT")),
+                Frame::Bulk(Bytes::from(db_index.to_string())),
+            ])
+            .encode(&mut encoded);
+            stream.selected_db = Some(db_index);
+        }
+        for frame in frames {
+            frame.encode(&mut encoded);
+        }
+        stream.offset += encoded.len() as u64;
+
+        if !stream.replicas.is_empty() {
+            let encoded = encoded.freeze();
+            stream
+                .replicas
+                .retain(|replica| replica.send(encoded.clone()).is_ok());
+        }
+    }
+
+    /// The `# Replication` section of INFO
+    pub fn format_info(&self) -> String {
+        format!(
+            "# Replication\r\n\
+             role:master\r\n\
+             connected_slaves:{}\r\n\
+             master_replid:{}\r\n\
+             master_repl_offset:{}\r\n",
+            self.replica_count(),
+            self.replid,
+            self.offset(),
+        )
+    }
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Random hex string of `REPLID_LEN` characters
+///
+/// `RandomState` is seeded from the OS, which is all the randomness needed
+/// to tell two runs apart.
+fn generate_replid() -> String {
+    let mut replid = String::with_capacity(REPLID_LEN);
+    while replid.len() < REPLID_LEN {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(replid.len());
+        replid.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    replid.truncate(REPLID_LEN);
+    replid
+}
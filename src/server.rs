@@ -0,0 +1,1817 @@
+use crate::acl::{Acl, DEFAULT_USER, NOAUTH_ERR, WRONGPASS_ERR};
+use crate::client::{ClientInfo, ReplyMode};
+use crate::cmd::table::{self, CommandContext};
+use crate::cmd::{xread_frame, xread_ids, Command, ExecContext};
+use crate::command_metrics::{self, CommandMetricsCollector, SharedCommandMetrics};
+use crate::config::{ServerConfig, SharedConfig};
+use crate::connection::{Connection, OutputBufferLimit};
+use crate::db::{eviction_policy, ChangeObserver, Db, NoEviction, StreamId, OOM_ERR};
+use crate::frame::Frame;
+use crate::health;
+use crate::idempotency::{IdempotencyCache, Lookup};
+use crate::metrics::{Metrics, SharedMetrics};
+use crate::notify::Notifier;
+use crate::persistence::{Aof, AofRewrite, AofSyncPolicy};
+use crate::prometheus;
+use crate::pubsub::{PubSub, Subscriber};
+use crate::ratelimit::{RateLimit, RateLimitMode};
+use crate::snapshot;
+use crate::transaction::Transaction;
+use anyhow::Result;
+use bytes::Bytes;
+use std::fs::OpenOptions;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{broadcast, OwnedMutexGuard};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+/// Bytes of each bulk string shown when logging received frames
+const DEBUG_FRAME_MAX_LEN: usize = 64;
+
+/// How often the active expire cycle runs (Redis' default `hz 10`)
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Keys checked by each active expire cycle
+const ACTIVE_EXPIRE_KEYS: usize = 200;
+
+/// Keys of each database measured again by each eviction check
+const REMEASURE_KEYS: usize = 200;
+
+/// Sent to non-loopback clients before disconnecting them in protected mode
+const PROTECTED_MODE_ERR: &str = "DENIED RustRedis is running in protected mode because protected mode is enabled and it is listening on all interfaces. In this mode connections are only accepted from the loopback interface. If you want to connect from external computers, either bind the server to a specific interface or disable protected mode by setting RUSTREDIS_PROTECTED_MODE=no and restarting the server, after making sure it is not publicly accessible from the internet.";
+
+/// Build the log subscriber described by `config`
+///
+/// Logs at `loglevel` and above go to `logfile` when one is set (appended,
+/// without colors) and to stdout otherwise. The caller installs it, e.g.
+/// with `tracing::subscriber::set_global_default`.
+pub fn log_subscriber(
+    config: &ServerConfig,
+) -> io::Result<Box<dyn tracing::Subscriber + Send + Sync>> {
+    let builder = tracing_subscriber::fmt()
+        .with_target(false)
+        .with_thread_ids(true)
+        .with_level(true)
+        .with_max_level(config.loglevel.filter());
+
+    Ok(match config.logfile {
+        Some(ref path) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Box::new(
+                builder
+                    .with_ansi(false)
+                    .with_writer(Mutex::new(file))
+                    .finish(),
+            )
+        }
+        None => Box::new(builder.finish()),
+    })
+}
+
+/// An embeddable server instance
+///
+/// Owns the shared state handed to every connection. `run` drives the
+/// accept loop on whatever Tokio runtime it is awaited on, so the caller
+/// decides between a multi-threaded and a `current_thread` runtime.
+pub struct Server {
+    config: ServerConfig,
+    dbs: Vec<Db>,
+    pubsub: PubSub,
+    metrics: SharedMetrics,
+    command_metrics: SharedCommandMetrics,
+    idempotency: IdempotencyCache,
+    acl: Acl,
+}
+
+impl Server {
+    /// Create the shared state for a server with the given configuration
+    pub fn new(config: ServerConfig) -> Self {
+        let pubsub = PubSub::new();
+        let dbs: Vec<Db> = (0..config.databases.max(1)).map(|_| Db::new()).collect();
+        let metrics = Metrics::new();
+        apply_live_config(&config, &dbs, &pubsub, &metrics);
+        if config.lazyfree_lazy_user_del {
+            info!("Lazy free enabled for DEL of large values");
+        }
+
+        let command_metrics = CommandMetricsCollector::new(config.metrics_strategy);
+
+        let idempotency = IdempotencyCache::new(
+            config.idempotency_cache_size,
+            Duration::from_secs(config.idempotency_ttl_secs),
+        );
+
+        Server {
+            config,
+            dbs,
+            pubsub,
+            metrics,
+            command_metrics,
+            idempotency,
+            acl: Acl::new(),
+        }
+    }
+
+    /// The default database, the one connections start out in
+    pub fn db(&self) -> &Db {
+        &self.dbs[0]
+    }
+
+    pub fn metrics(&self) -> &SharedMetrics {
+        &self.metrics
+    }
+
+    /// Listen on `addr` with the configured backlog and SO_REUSEADDR
+    ///
+    /// Must be called from within a Tokio runtime. The listener is what
+    /// `run` expects; binding with `TcpListener::bind` works too but uses
+    /// the OS defaults.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(self.config.reuseaddr)?;
+        socket.bind(addr)?;
+        socket.listen(self.config.tcp_backlog)
+    }
+
+    /// Call `observer` after every change made to any database, or stop
+    /// calling it
+    ///
+    /// See `Db::set_change_observer`. The callback runs on the task of the
+    /// client that made the change, so it should hand the event off rather
+    /// than block.
+    pub fn set_change_observer(&self, observer: Option<ChangeObserver>) {
+        for (index, db) in self.dbs.iter().enumerate() {
+            db.set_change_observer(index, observer.clone());
+        }
+    }
+
+    /// Accept connections on `listener` until `shutdown` completes
+    ///
+    /// Must be called from within a Tokio runtime; background tasks (AOF
+    /// sync, metrics flushing) are spawned onto that runtime.
+    pub async fn run(
+        self,
+        listener: TcpListener,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let Server {
+            config,
+            dbs,
+            pubsub,
+            metrics,
+            command_metrics,
+            idempotency,
+            acl,
+        } = self;
+
+        info!(
+            "Command metrics initialized (strategy: {})",
+            config.metrics_strategy.name()
+        );
+
+        // Start background flush task for ThreadLocalBatched strategy
+        if let Some(tl_collector) = command_metrics.thread_local_collector() {
+            command_metrics::start_thread_local_flush_task(tl_collector);
+            info!("Thread-local metrics flush task started (100ms interval)");
+        }
+
+        // Start background flush task for HdrHistogram strategy
+        if let Some(hdr_collector) = command_metrics.hdr_histogram_collector() {
+            command_metrics::start_hdr_flush_task(hdr_collector);
+            info!("HdrHistogram metrics flush task started (100ms interval)");
+        }
+
+        // Loaded keys keep the expiry they were saved with
+        for db in &dbs {
+            db.set_default_ttl(None);
+        }
+
+        // Restore the last snapshot, then replay the AOF on top of it: the
+        // AOF is emptied on every snapshot, so it only holds later writes
+        // (including a FLUSHDB that wipes what the snapshot restored)
+        match snapshot::load(&dbs, &config.rdb_path) {
+            Ok(keys) => info!("Loaded {} keys from snapshot", keys),
+            // Written with more databases than configured
+            Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                anyhow::bail!("Can't load snapshot {}: {}", config.rdb_path, e)
+            }
+            Err(e) => warn!(
+                "Could not load snapshot (this is normal on first run): {}",
+                e
+            ),
+        }
+        let aof = open_aof(&config, &dbs, &metrics)?;
+        // A background AOF replay restores it once done
+        if !aof.as_ref().is_some_and(|aof| aof.is_loading()) {
+            for db in &dbs {
+                db.set_default_ttl(config.default_ttl());
+            }
+        }
+
+        if !config.save_points.is_empty() {
+            snapshot::start_save_scheduler(
+                dbs.clone(),
+                config.rdb_path.clone().into(),
+                config.save_points.clone(),
+                aof.clone(),
+                Arc::clone(&metrics),
+            );
+            info!("Snapshot save points: {:?}", config.save_points);
+        }
+
+        // Optional Prometheus endpoint on the same interface as the server
+        if let Some(port) = config.metrics_port {
+            let addr = (listener.local_addr()?.ip(), port);
+            let metrics_listener = TcpListener::bind(addr).await?;
+            info!(
+                "Prometheus metrics available on http://{}/metrics",
+                metrics_listener.local_addr()?
+            );
+            tokio::spawn(prometheus::serve(
+                metrics_listener,
+                Arc::clone(&metrics),
+                dbs.clone(),
+            ));
+        }
+
+        // Optional health check endpoint for load balancers
+        if let Some(port) = config.health_port {
+            let addr = (listener.local_addr()?.ip(), port);
+            let health_listener = TcpListener::bind(addr).await?;
+            info!(
+                "Health checks answered on {}",
+                health_listener.local_addr()?
+            );
+            tokio::spawn(health::serve(health_listener, Arc::clone(&metrics)));
+        }
+
+        // Listening on every interface without a password: only serve
+        // clients on this host
+        let all_interfaces = listener.local_addr()?.ip().is_unspecified();
+        if all_interfaces && config.protected_mode {
+            warn!("Protected mode: only loopback clients will be served");
+        }
+
+        // Settings CONFIG SET can change from here on
+        let live_config: SharedConfig = Arc::new(RwLock::new(config));
+        start_active_expire(dbs.clone(), Arc::clone(&live_config));
+        start_eviction(
+            dbs.clone(),
+            Arc::clone(&live_config),
+            aof.clone(),
+            Arc::clone(&metrics),
+        );
+
+        tokio::pin!(shutdown);
+        loop {
+            tokio::select! {
+                // Accept incoming connections
+                result = listener.accept() => {
+                    let (socket, addr) = result?;
+
+                    info!("Accepted connection from: {}", addr);
+
+                    let (maxclients, protected_mode, output_limit, rate_limit) = {
+                        let config = live_config.read().unwrap();
+                        (
+                            config.maxclients,
+                            all_interfaces && config.protected_mode,
+                            config.client_output_buffer_limit,
+                            config.per_client_rate_limit,
+                        )
+                    };
+
+                    // The counter only grows here, so it can't race past the limit
+                    if maxclients > 0 && metrics.active_connections() >= maxclients {
+                        warn!("Rejecting {}: max number of clients reached", addr);
+                        tokio::spawn(reject_connection(socket));
+                        continue;
+                    }
+
+                    // Clone handles for this connection
+                    let dbs = dbs.clone();
+                    let aof = aof.clone();
+                    let pubsub = pubsub.clone();
+                    let metrics = Arc::clone(&metrics);
+                    let command_metrics = Arc::clone(&command_metrics);
+                    let idempotency = idempotency.clone();
+                    let live_config = Arc::clone(&live_config);
+                    let acl = acl.clone();
+
+                    metrics.increment_connections();
+
+                    // Spawn a new task to handle the connection
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(
+                            socket, addr, protected_mode, dbs, aof, pubsub,
+                            Arc::clone(&metrics),
+                            Arc::clone(&command_metrics),
+                            idempotency,
+                            output_limit,
+                            rate_limit,
+                            live_config,
+                            acl,
+                        ).await {
+                            error!("Error handling connection: {}", e);
+                        }
+                        metrics.decrement_connections();
+                    });
+                }
+
+                _ = &mut shutdown => {
+                    info!("Received shutdown signal. Gracefully shutting down...");
+                    // Writes the everysec policy has not synced yet
+                    if let Some(aof) = aof.as_ref() {
+                        if let Err(e) = aof.sync() {
+                            error!("Failed to fsync AOF on shutdown: {}", e);
+                        }
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Completes on CTRL+C or, on Unix, SIGTERM, for use as `run`'s shutdown
+///
+/// On Unix the handlers are installed before this returns, so a signal
+/// arriving before the future is first polled still triggers it instead of
+/// killing the process.
+pub fn shutdown_signal() -> io::Result<impl Future<Output = ()>> {
+    #[cfg(unix)]
+    let (mut interrupt, mut terminate) = {
+        use tokio::signal::unix::{signal, SignalKind};
+        (
+            signal(SignalKind::interrupt())?,
+            signal(SignalKind::terminate())?,
+        )
+    };
+
+    Ok(async move {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    })
+}
+
+/// Push the settings CONFIG SET can change into the running server
+///
+/// Settings read per connection (`maxclients`, the output buffer and rate
+/// limits, protected mode) are picked up by the accept loop instead.
+fn apply_live_config(config: &ServerConfig, dbs: &[Db], pubsub: &PubSub, metrics: &Metrics) {
+    for (index, db) in dbs.iter().enumerate() {
+        db.set_lazyfree_lazy_user_del(config.lazyfree_lazy_user_del);
+        db.set_hash_max_listpack_entries(config.hash_max_listpack_entries);
+        db.set_list_max_listpack_size(config.list_max_listpack_size);
+        db.set_set_max_intset_entries(config.set_max_intset_entries);
+        db.set_zset_max_listpack_entries(config.zset_max_listpack_entries);
+        db.set_default_ttl(config.default_ttl());
+        db.set_deterministic_iteration(config.deterministic_iteration);
+        db.set_keys_max_results(config.keys_max_results);
+        #[cfg(feature = "compression")]
+        db.set_string_compression_threshold(config.string_compression_threshold);
+        let notifier = config
+            .notify_keyspace_events
+            .is_enabled()
+            .then(|| Notifier::new(pubsub.clone(), config.notify_keyspace_events, index));
+        db.set_notifier(notifier);
+    }
+    pubsub.set_max_message_size(config.proto_max_pubsub_message_size);
+    pubsub.set_message_sequence(config.pubsub_message_sequence);
+    pubsub
+        .monitor()
+        .set_show_internal(config.monitor_internal_events);
+    metrics
+        .latency()
+        .set_threshold_ms(config.latency_monitor_threshold_ms);
+}
+
+/// Tell a client over `maxclients` why it is being disconnected
+async fn reject_connection(socket: TcpStream) {
+    let mut connection = Connection::new(socket);
+    let reply = Frame::error("ERR max number of clients reached");
+    let result = match connection.write_frame(&reply).await {
+        Ok(()) => connection.flush().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        debug!("Failed to send maxclients rejection: {}", e);
+    }
+}
+
+/// Start the tasks that remove expired keys nobody accesses
+///
+/// Each database has its own lock, so each gets its own sweeper and they
+/// run in parallel. Every `ACTIVE_EXPIRE_INTERVAL` a sweeper checks the
+/// next `ACTIVE_EXPIRE_KEYS` keys of its database, so a full pass takes
+/// time proportional to the number of keys in that database. All of them
+/// pause while `active-expire` is off.
+fn start_active_expire(dbs: Vec<Db>, config: SharedConfig) -> Vec<JoinHandle<()>> {
+    dbs.into_iter()
+        .map(|db| {
+            let config = Arc::clone(&config);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(ACTIVE_EXPIRE_INTERVAL);
+                let mut cursor = 0;
+                loop {
+                    interval.tick().await;
+                    if !config.read().unwrap().active_expire {
+                        continue;
+                    }
+                    let (next, removed) = db.active_expire_cycle(cursor, ACTIVE_EXPIRE_KEYS);
+                    if removed > 0 {
+                        debug!("Active expire removed {} keys", removed);
+                    }
+                    cursor = next;
+                }
+            })
+        })
+        .collect()
+}
+
+/// Start the task that evicts keys while `maxmemory` is exceeded
+///
+/// Every `ACTIVE_EXPIRE_INTERVAL` the memory used by all databases is
+/// added up, after measuring the next `REMEASURE_KEYS` values of each
+/// again. Over the limit, `maxmemory-policy` picks keys to evict from
+/// each database in turn until enough is freed. If it cannot free enough,
+/// writes are refused with OOM until memory is back under the limit.
+fn start_eviction(
+    dbs: Vec<Db>,
+    config: SharedConfig,
+    aof: Option<Arc<Aof>>,
+    metrics: SharedMetrics,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ACTIVE_EXPIRE_INTERVAL);
+        let mut cursors = vec![0; dbs.len()];
+        loop {
+            interval.tick().await;
+            let (maxmemory, policy) = {
+                let config = config.read().unwrap();
+                (config.maxmemory as usize, config.maxmemory_policy.clone())
+            };
+            if maxmemory > 0 {
+                for (db, cursor) in dbs.iter().zip(&mut cursors) {
+                    *cursor = db.remeasure(*cursor, REMEASURE_KEYS);
+                }
+            }
+            let fits = evict_to(&dbs, maxmemory, &policy, aof.as_deref(), &metrics).await;
+            metrics.set_over_maxmemory(!fits);
+        }
+    })
+}
+
+/// Evict keys until `dbs` use at most `maxmemory` bytes (0 for no limit)
+///
+/// Every evicted key is logged to the AOF and replicas as a DEL, so it
+/// does not come back on restart. Nothing is evicted while the AOF refuses
+/// writes. Returns whether that succeeded.
+async fn evict_to(
+    dbs: &[Db],
+    maxmemory: usize,
+    policy: &str,
+    aof: Option<&Aof>,
+    metrics: &SharedMetrics,
+) -> bool {
+    if maxmemory == 0 {
+        return true;
+    }
+    let used: usize = dbs.iter().map(Db::used_memory).sum();
+    let mut need = used.saturating_sub(maxmemory);
+    if need == 0 {
+        return true;
+    }
+    // Validated when the setting was made
+    let policy = eviction_policy(policy).unwrap_or_else(|| Box::new(NoEviction));
+    for (index, db) in dbs.iter().enumerate() {
+        // Held until the DELs are appended, like any other write
+        let _order = match aof {
+            Some(aof) => Some(aof.lock_order().await),
+            None => None,
+        };
+        if aof.and_then(Aof::write_error).is_some() {
+            return false;
+        }
+        let (evicted, freed) = db.evict(policy.as_ref(), need);
+        if !evicted.is_empty() {
+            debug!("Evicted {} keys ({} bytes)", evicted.len(), freed);
+            metrics.add_evicted_keys(evicted.len() as u64);
+            let dels: Vec<Frame> = evicted
+                .into_iter()
+                .map(|key| {
+                    Frame::Array(vec![
+                        Frame::Bulk(Bytes::from_static(b"DEL")),
+                        Frame::Bulk(key),
+                    ])
+                })
+                .collect();
+            propagate(aof, metrics, index, &dels);
+        }
+        need = need.saturating_sub(freed);
+        if need == 0 {
+            return true;
+        }
+    }
+    false
+}
+
+/// Open the AOF, replay its contents into `dbs` and start background sync
+///
+/// With `aof_background_load` the replay continues on a blocking task
+/// after this returns, and the AOF refuses writes until it is done.
+///
+/// Returns `None` when persistence is disabled or the file cannot be opened,
+/// and fails if the AOF cannot be replayed into `dbs`.
+fn open_aof(
+    config: &ServerConfig,
+    dbs: &[Db],
+    metrics: &SharedMetrics,
+) -> Result<Option<Arc<Aof>>> {
+    // AOF can be explicitly disabled for experiment runs
+    if config.disable_aof {
+        warn!("AOF persistence disabled via RUSTREDIS_DISABLE_AOF");
+        return Ok(None);
+    }
+
+    let aof_path = &config.aof_path;
+    match Aof::new(aof_path, AofSyncPolicy::EverySecond) {
+        Ok(aof) => {
+            let aof = aof
+                .with_latency_monitor(Arc::clone(metrics.latency()))
+                .with_stop_writes_on_error(config.aof_stop_writes_on_error);
+            info!(
+                "AOF persistence enabled with EverySecond sync policy (path: {})",
+                aof_path
+            );
+            let aof = Arc::new(aof);
+            metrics.set_aof_enabled(true);
+
+            // Start background sync task
+            Arc::clone(&aof).start_background_sync();
+
+            if config.aof_background_load {
+                // Serve reads of what is loaded so far, refuse writes
+                // until the replay is done
+                aof.set_loading(true);
+                metrics.set_loading(true);
+                let (aof, dbs) = (Arc::clone(&aof), dbs.to_vec());
+                let metrics = Arc::clone(metrics);
+                let (aof_path, default_ttl) = (aof_path.clone(), config.default_ttl());
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = replay_aof(&aof, &dbs, &aof_path) {
+                        // Still loading, so nothing is written on top
+                        error!("Can't load AOF {}: {}", aof_path, e);
+                        return;
+                    }
+                    for db in &dbs {
+                        db.set_default_ttl(default_ttl);
+                    }
+                    aof.set_loading(false);
+                    metrics.set_loading(false);
+                });
+            } else {
+                replay_aof(&aof, dbs, aof_path)
+                    .map_err(|e| anyhow::anyhow!("Can't load AOF {}: {}", aof_path, e))?;
+            }
+
+            Ok(Some(aof))
+        }
+        Err(e) => {
+            warn!("AOF persistence disabled: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+/// Replay the existing contents of the AOF at `aof_path` into `dbs`
+///
+/// Fails on a SELECT of a database past the configured ones, which the
+/// commands after it cannot be replayed without.
+fn replay_aof(aof: &Aof, dbs: &[Db], aof_path: &str) -> Result<(), String> {
+    match Aof::load(aof_path) {
+        Ok(frames) => {
+            info!("Loaded {} commands from AOF", frames.len());
+            // Replay commands to restore state, following SELECTs
+            let mut selected = 0;
+            for frame in frames {
+                match Command::from_frame(frame) {
+                    Ok(Command::Select { index }) if index < dbs.len() => selected = index,
+                    Ok(Command::Select { index }) => {
+                        return Err(format!(
+                            "it selects DB {} but only {} databases are configured",
+                            index,
+                            dbs.len()
+                        ))
+                    }
+                    Ok(cmd) => {
+                        let _ = cmd.replay(&dbs[selected]);
+                    }
+                    Err(_) => {}
+                }
+            }
+            aof.set_selected_db(selected);
+            info!("AOF replay completed");
+        }
+        Err(e) => {
+            warn!("Could not load AOF (this is normal on first run): {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Finish an AOF rewrite started by BGREWRITEAOF
+///
+/// The base is written on a blocking task while writes carry on; the
+/// files are swapped once the writes buffered in the meantime are added.
+async fn rewrite_aof(aof: Arc<Aof>, rewrite: AofRewrite) {
+    let base = tokio::task::spawn_blocking(move || rewrite.write_base().map(|()| rewrite))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)));
+    let _order = aof.lock_order().await;
+    match base.and_then(|rewrite| aof.finish_rewrite(rewrite)) {
+        Ok(()) => info!("Background AOF rewrite finished"),
+        Err(e) => {
+            aof.abort_rewrite();
+            error!("Background AOF rewrite failed: {}", e);
+        }
+    }
+}
+
+/// Handle a single client connection
+///
+/// `peer_addr` is the client's address as returned by `accept`. With
+/// `protected_mode` set and no password on the default user, clients not
+/// on the loopback interface are sent the DENIED error and disconnected.
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_connection(
+    socket: TcpStream,
+    peer_addr: SocketAddr,
+    protected_mode: bool,
+    dbs: Vec<Db>,
+    aof: Option<Arc<Aof>>,
+    pubsub: PubSub,
+    metrics: SharedMetrics,
+    command_metrics: SharedCommandMetrics,
+    idempotency: IdempotencyCache,
+    output_limit: OutputBufferLimit,
+    rate_limit: RateLimit,
+    config: SharedConfig,
+    acl: Acl,
+) -> Result<()> {
+    let mut client = ClientInfo::new(Some(peer_addr), socket.local_addr().ok());
+    client.authenticated = !acl.default_user_needs_auth();
+
+    // Wrap the socket in our Connection struct
+    let inline_max_len = config.read().unwrap().proto_inline_max_size;
+    let mut connection = Connection::new(socket)
+        .with_output_limit(output_limit)
+        .with_inline_max_len(inline_max_len);
+
+    if protected_mode
+        && !acl.default_user_needs_auth()
+        && !peer_addr.ip().to_canonical().is_loopback()
+    {
+        warn!("Refusing {} in protected mode", peer_addr);
+        connection
+            .write_frame(&Frame::error(PROTECTED_MODE_ERR))
+            .await?;
+        connection.flush().await?;
+        return Ok(());
+    }
+
+    // MULTI/EXEC/WATCH state for this connection
+    let mut transaction = Transaction::new();
+
+    let mut rate_bucket = rate_limit.bucket();
+
+    debug!("Connection handler started");
+
+    // A frame read ahead by the GET fast path, handled next
+    let mut pending = None;
+
+    // Writes of the current pipeline step not yet logged to the AOF
+    let mut aof_batch = None;
+
+    // Process commands in a loop
+    loop {
+        // Read a frame from the connection
+        let next = match pending.take() {
+            Some(frame) => Some(frame),
+            None if aof_batch.is_some() => connection.buffered_frame(),
+            None => None,
+        };
+        let read = match next {
+            Some(frame) => Ok(Some(frame)),
+            None => {
+                // The pipeline step is over: log its writes, then let the
+                // replies go out
+                end_aof_batch(&mut aof_batch, &mut connection);
+                connection.read_frame().await
+            }
+        };
+        let frame = match read {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                // Connection closed, possibly only for writing
+                debug!("Client disconnected");
+                flush_before_close(&mut connection).await;
+                return Ok(());
+            }
+            // Like Redis, explain the protocol error before closing
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                warn!("Closing client: {}", e);
+                connection
+                    .write_frame(&Frame::error(format!("ERR {}", e)))
+                    .await?;
+                connection.flush().await?;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        debug!(
+            "Received frame: {}",
+            frame.to_debug_string(DEBUG_FRAME_MAX_LEN)
+        );
+        // Everything written until the next frame is this command's reply
+        let replying = client.take_reply();
+        connection.set_replies(replying);
+
+        if let Some(bucket) = rate_bucket.as_mut() {
+            match rate_limit.mode {
+                RateLimitMode::Delay => {
+                    while let Err(wait) = bucket.try_acquire(Instant::now()) {
+                        end_aof_batch(&mut aof_batch, &mut connection);
+                        tokio::time::sleep(wait).await;
+                    }
+                }
+                RateLimitMode::Error => {
+                    if bucket.try_acquire(Instant::now()).is_err() {
+                        let reply = Frame::error("ERR max ops per second exceeded for this client");
+                        transaction.flag_error();
+                        connection.write_frame(&reply).await?;
+                        continue;
+                    }
+                }
+            }
+        }
+
+        // Parse the frame into a command
+        let command = match Command::from_frame(frame.clone()) {
+            // Refused right away rather than failing at EXEC
+            Ok(Command::Unknown(name)) if transaction.is_active() => {
+                transaction.flag_error();
+                let reply = Frame::error(format!("ERR unknown command '{}'", name));
+                connection.write_frame(&reply).await?;
+                continue;
+            }
+            Ok(cmd) => cmd,
+            Err(e) => {
+                error!("Failed to parse command: {}", e);
+                // Not queued, so EXEC must not run the rest without it
+                transaction.flag_error();
+                connection.write_frame(&Frame::error(e.to_string())).await?;
+                continue;
+            }
+        };
+        // Only plain writes join the batch; anything else may take the AOF
+        // order lock, block, or read the log
+        let batched = command.is_write_command()
+            && !transaction.is_active()
+            && !matches!(command, Command::Idempotent { .. } | Command::BPop { .. });
+        if !batched {
+            end_aof_batch(&mut aof_batch, &mut connection);
+        }
+        if let Some(e) = context_refusal(&command, transaction.is_active(), metrics.loading())
+            .or_else(|| acl_refusal(&acl, &client, &command))
+        {
+            metrics.command_stats().record_rejected(command.name());
+            transaction.flag_error();
+            connection.write_frame(&Frame::error(e)).await?;
+            continue;
+        }
+        if command.is_write_command()
+            && config.read().unwrap().reject_empty_keys
+            && command.writes_empty_key(&frame)
+        {
+            let reply = Frame::error("ERR empty keys are not allowed (reject-empty-keys is on)");
+            metrics.command_stats().record_rejected(command.name());
+            transaction.flag_error();
+            connection.write_frame(&reply).await?;
+            continue;
+        }
+        if metrics.over_maxmemory() && command.denied_on_oom() {
+            metrics.command_stats().record_rejected(command.name());
+            transaction.flag_error();
+            connection.write_frame(&Frame::error(OOM_ERR)).await?;
+            continue;
+        }
+        client.record_command(command.name());
+        let (db_index, db) = (client.db, &dbs[client.db]);
+        if shown_to_monitor(&command) {
+            ExecContext::new(&client, db, &pubsub, &metrics, &command_metrics).feed_monitor(&frame);
+        }
+
+        // GETs pipelined behind this one are served under a single lock.
+        // Rate-limited clients take the regular path, one token per command.
+        if let Command::Get { ref key } = command {
+            if !transaction.is_active() && rate_bucket.is_none() && replying {
+                let mut keys = vec![key.clone()];
+                while let Some(next) = connection.buffered_frame() {
+                    match Command::from_frame(next.clone()) {
+                        Ok(Command::Get { key }) => {
+                            client.record_command("GET");
+                            ExecContext::new(&client, db, &pubsub, &metrics, &command_metrics)
+                                .feed_monitor(&next);
+                            keys.push(key);
+                        }
+                        _ => {
+                            pending = Some(next);
+                            break;
+                        }
+                    }
+                }
+                let cmd_start = Instant::now();
+                let values = db.read_strings(keys.iter().map(|key| &key[..]));
+                for key in &keys {
+                    let key = std::str::from_utf8(key).ok();
+                    record_timing("GET", key, cmd_start, false, &metrics, &command_metrics);
+                }
+                for value in values {
+                    connection
+                        .write_frame(&value.map_or(Frame::Null, Frame::Bulk))
+                        .await?;
+                }
+                continue;
+            }
+        }
+
+        // Transaction control commands, and queueing while inside MULTI
+        let ok = || Frame::Simple("OK".to_string());
+        let response = match command {
+            Command::Multi => Some(match transaction.begin() {
+                Ok(()) => ok(),
+                Err(e) => Frame::error(e),
+            }),
+            Command::Watch { ref keys } => {
+                transaction.watch(db_index, db, keys);
+                Some(ok())
+            }
+            Command::Unwatch => {
+                transaction.unwatch();
+                Some(ok())
+            }
+            Command::Discard => Some(if transaction.discard() {
+                ok()
+            } else {
+                Frame::error("ERR DISCARD without MULTI")
+            }),
+            Command::Exec => {
+                // Held for the whole transaction, so its writes are logged
+                // together and in the order they were applied
+                let _order = match aof {
+                    Some(ref aof) if transaction.is_active() => Some(aof.lock_order().await),
+                    _ => None,
+                };
+                let stale_elsewhere = transaction.is_stale_elsewhere(&dbs, db_index);
+                // No other client runs between the WATCH check and the
+                // last queued command
+                Some(db.transaction(|db| {
+                    match transaction.take_for_exec(db_index, db, stale_elsewhere) {
+                        Err(e) => Frame::error(e),
+                        // A watched key changed: abort
+                        Ok(None) => Frame::Null,
+                        Ok(Some(queued)) => Frame::Array(
+                            queued
+                                .iter()
+                                .map(|(frame, command)| {
+                                    let aof = aof.as_deref().filter(|_| command.is_write_command());
+                                    if let Some(e) = aof.and_then(Aof::write_error) {
+                                        return Frame::error(e);
+                                    }
+                                    let ctx = ExecContext::new(
+                                        &client,
+                                        db,
+                                        &pubsub,
+                                        &metrics,
+                                        &command_metrics,
+                                    );
+                                    let reply = command.apply_blocking(&ctx);
+                                    propagate_write(
+                                        aof, &metrics, db_index, command, frame, &reply,
+                                    );
+                                    reply
+                                })
+                                .collect(),
+                        ),
+                    }
+                }))
+            }
+            Command::Idempotent {
+                ref token,
+                ref frame,
+                ref command,
+            } => Some({
+                // A concurrent retry waits for the claim instead of running
+                // the command again
+                match idempotency.claim(token).await {
+                    Lookup::Recorded(reply) => reply,
+                    // A refused write is not recorded: the retry may succeed
+                    Lookup::Claimed(claim) => match begin_aof_write(&aof, command).await {
+                        Ok(write) => {
+                            let reply = apply_command(
+                                command,
+                                &ExecContext::new(&client, db, &pubsub, &metrics, &command_metrics),
+                            )
+                            .await;
+                            let aof = write.as_ref().map(|write| write.aof);
+                            propagate_write(aof, &metrics, db_index, command, frame, &reply);
+                            claim.record(reply.clone());
+                            reply
+                        }
+                        Err(reply) => reply,
+                    },
+                }
+            }),
+            Command::Quit => {
+                connection.write_frame(&ok()).await?;
+                connection.flush().await?;
+                return Ok(());
+            }
+            Command::Reset => {
+                transaction.discard();
+                transaction.unwatch();
+                client.reset();
+                client.authenticated = !acl.default_user_needs_auth();
+                connection.set_replies(true);
+                Some(Frame::Simple("RESET".to_string()))
+            }
+            Command::Auth {
+                ref username,
+                ref password,
+            } => Some(match username {
+                None if !acl.default_user_needs_auth() => Frame::error(
+                    "ERR AUTH <password> called without any password configured for the \
+                     default user. Are you sure your configuration is correct?",
+                ),
+                _ => {
+                    let username = username.as_deref().unwrap_or(DEFAULT_USER);
+                    if acl.authenticate(username, password) {
+                        client.user = username.to_string();
+                        client.authenticated = true;
+                        ok()
+                    } else {
+                        Frame::error(WRONGPASS_ERR)
+                    }
+                }
+            }),
+            Command::AclSetUser {
+                ref username,
+                ref rules,
+            } => Some(match acl.set_user(username, rules) {
+                Ok(()) => ok(),
+                Err(e) => Frame::error(e),
+            }),
+            Command::AclUsers => Some(Frame::Array(
+                acl.users()
+                    .into_iter()
+                    .map(|name| Frame::Bulk(Bytes::from(name)))
+                    .collect(),
+            )),
+            _ if transaction.is_active() => None,
+            Command::Select { index } => Some(if index < dbs.len() {
+                client.db = index;
+                ok()
+            } else {
+                Frame::error("ERR DB index is out of range")
+            }),
+            Command::ClientSetName { ref name } => {
+                // An empty name clears it
+                client.name = Some(name.clone()).filter(|name| !name.is_empty());
+                Some(ok())
+            }
+            Command::ClientGetName => Some(match client.name {
+                Some(ref name) => Frame::Bulk(Bytes::from(name.clone())),
+                None => Frame::Null,
+            }),
+            Command::ConfigGet { ref patterns } => {
+                let config = config.read().unwrap();
+                let mut reply = Vec::new();
+                let mut seen = Vec::new();
+                for pattern in patterns {
+                    for (name, value) in config.get_matching(pattern) {
+                        if !seen.contains(&name) {
+                            seen.push(name);
+                            reply.push(Frame::Bulk(Bytes::from_static(name.as_bytes())));
+                            reply.push(Frame::Bulk(Bytes::from(value)));
+                        }
+                    }
+                }
+                Some(Frame::Array(reply))
+            }
+            Command::ConfigSet { ref pairs } => {
+                let mut config = config.write().unwrap();
+                // All or nothing: nothing changes if one setting is refused
+                let mut updated = config.clone();
+                Some(
+                    match pairs
+                        .iter()
+                        .try_for_each(|(name, value)| updated.set_runtime(name, value))
+                    {
+                        Ok(()) => {
+                            *config = updated;
+                            apply_live_config(&config, &dbs, &pubsub, &metrics);
+                            ok()
+                        }
+                        Err(e) => Frame::error(e),
+                    },
+                )
+            }
+            Command::ConfigRewrite => Some(match config.read().unwrap().rewrite() {
+                Ok(()) => ok(),
+                Err(e) => Frame::error(e),
+            }),
+            Command::Save => {
+                let path = config.read().unwrap().rdb_path.clone();
+                Some(
+                    match snapshot::save_now(&dbs, path.as_ref(), aof.as_deref(), &metrics).await {
+                        Ok(_) => ok(),
+                        Err(e) => Frame::error(format!("ERR {}", e)),
+                    },
+                )
+            }
+            Command::BgRewriteAof => Some(match aof {
+                Some(ref aof) => {
+                    let rewrite = {
+                        let _order = aof.lock_order().await;
+                        aof.start_rewrite(&dbs)
+                    };
+                    match rewrite {
+                        Ok(rewrite) => {
+                            tokio::spawn(rewrite_aof(Arc::clone(aof), rewrite));
+                            Frame::Simple(
+                                "Background append only file rewriting started".to_string(),
+                            )
+                        }
+                        Err(e) => Frame::error(e),
+                    }
+                }
+                None => Frame::error("ERR AOF is disabled"),
+            }),
+            Command::ClientReply { mode } => {
+                // OFF and SKIP are not acknowledged
+                client.reply_mode = mode;
+                connection.set_replies(mode == ReplyMode::On);
+                Some(ok())
+            }
+            Command::ClientInfo => {
+                let line = client.describe(transaction.queued_len()) + "\n";
+                Some(Frame::Bulk(Bytes::from(line)))
+            }
+            Command::BPop {
+                ref keys,
+                timeout,
+                left,
+            } => {
+                tokio::select! {
+                    reply = blocking_pop(db, db_index, &aof, &metrics, keys, timeout, left) => Some(reply),
+                    _ = connection.closed() => {
+                        debug!("Client disconnected while blocked");
+                        flush_before_close(&mut connection).await;
+                        return Ok(());
+                    }
+                }
+            }
+            Command::XRead {
+                ref keys,
+                ref ids,
+                count,
+                block: Some(timeout),
+            } => {
+                tokio::select! {
+                    reply = blocking_xread(db, &metrics, keys, ids, count, timeout) => Some(reply),
+                    _ = connection.closed() => {
+                        debug!("Client disconnected while blocked");
+                        flush_before_close(&mut connection).await;
+                        return Ok(());
+                    }
+                }
+            }
+            Command::Monitor => {
+                connection.write_frame(&ok()).await?;
+                run_monitor(&mut connection, &pubsub).await?;
+                debug!("Client disconnected");
+                return Ok(());
+            }
+            Command::WaitAof {
+                numlocal, timeout, ..
+            } => {
+                tokio::select! {
+                    reply = wait_aof(&aof, numlocal, timeout) => Some(reply),
+                    _ = connection.closed() => {
+                        debug!("Client disconnected while blocked");
+                        flush_before_close(&mut connection).await;
+                        return Ok(());
+                    }
+                }
+            }
+            Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::SSubscribe { .. }
+            | Command::SUnsubscribe { .. } => {
+                let keepalive = config.read().unwrap().pubsub_keepalive();
+                let subscribed = run_subscribed(
+                    &mut connection,
+                    &pubsub,
+                    &acl,
+                    &mut client,
+                    command,
+                    keepalive,
+                );
+                if !subscribed.await? {
+                    debug!("Client disconnected");
+                    flush_before_close(&mut connection).await;
+                    return Ok(());
+                }
+                continue;
+            }
+            _ => Some(
+                match join_aof_batch(&mut aof_batch, &aof, &metrics, &command).await {
+                    Ok(()) => {
+                        let reply = apply_command(
+                            &command,
+                            &ExecContext::new(&client, db, &pubsub, &metrics, &command_metrics),
+                        )
+                        .await;
+                        // Logged before the reply is sent, so an acknowledged
+                        // write is in the AOF
+                        match aof_batch {
+                            Some(ref mut batch) => {
+                                connection.hold_replies(true);
+                                batch.propagate_write(db_index, &command, &frame, &reply);
+                            }
+                            None => {
+                                propagate_write(None, &metrics, db_index, &command, &frame, &reply)
+                            }
+                        }
+                        reply
+                    }
+                    Err(reply) => reply,
+                },
+            ),
+        };
+        let response = match response {
+            Some(response) => response,
+            None => {
+                transaction.queue(frame, command);
+                Frame::Simple("QUEUED".to_string())
+            }
+        };
+        connection.write_frame(&response).await?;
+    }
+}
+
+/// Send the replies still owed to a client that closed its side
+///
+/// A client that shuts down writing after its last request still reads
+/// the replies. One that is gone altogether makes this fail, which is
+/// nothing to report.
+async fn flush_before_close(connection: &mut Connection) {
+    if let Err(e) = connection.flush().await {
+        debug!("Dropped pending replies: {}", e);
+    }
+}
+
+/// The error to refuse `command` with, given whether the connection is
+/// inside MULTI and whether the AOF is still loading
+///
+/// The contexts a command may run in are tagged in the command table.
+fn context_refusal(command: &Command, in_multi: bool, loading: bool) -> Option<String> {
+    let info = table::lookup(command.name())?;
+    [
+        (in_multi, CommandContext::Multi),
+        (loading, CommandContext::Loading),
+    ]
+    .into_iter()
+    .filter(|&(active, _)| active)
+    .find_map(|(_, context)| info.refusal(context))
+}
+
+/// The error to refuse `command` with for the connection's ACL user
+///
+/// AUTH, QUIT and RESET are always accepted; anything else needs an
+/// authenticated connection whose user may run it.
+fn acl_refusal(acl: &Acl, client: &ClientInfo, command: &Command) -> Option<String> {
+    match command {
+        Command::Auth { .. } | Command::Quit | Command::Reset => None,
+        _ if !client.authenticated => Some(NOAUTH_ERR.to_string()),
+        Command::Idempotent { command, .. } => acl
+            .refusal(&client.user, "idempotent")
+            .or_else(|| acl.refusal(&client.user, command.name())),
+        _ => acl.refusal(&client.user, command.name()),
+    }
+}
+
+/// Whether MONITOR clients see `command`
+///
+/// Like Redis, admin commands are left out, and so is AUTH, whose
+/// arguments hold a password.
+fn shown_to_monitor(command: &Command) -> bool {
+    match command {
+        Command::Auth { .. } => false,
+        _ => table::lookup(command.name())
+            .is_none_or(|info| info.categories() & table::CAT_ADMIN == 0),
+    }
+}
+
+/// Stream the command feed to a MONITOR client until it disconnects
+///
+/// Anything the client sends meanwhile is left unread, as for the blocking
+/// commands. A client too slow to keep up skips the lines it missed.
+async fn run_monitor(connection: &mut Connection, pubsub: &PubSub) -> Result<()> {
+    let mut feed = pubsub.monitor().subscribe();
+    connection.flush().await?;
+    loop {
+        tokio::select! {
+            line = feed.recv() => match line {
+                Ok(line) => {
+                    connection.write_frame(&Frame::Simple(line)).await?;
+                    connection.flush().await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    debug!("MONITOR client skipped {} lines", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            _ = connection.closed() => return Ok(()),
+        }
+    }
+}
+
+/// Serve a connection in subscribed mode, starting with `command`
+///
+/// Only the commands the command table allows in `CommandContext::Subscribe`
+/// are accepted while subscribed. Returns once the connection is no longer
+/// subscribed to any channel or shard channel, with `false` if the client
+/// disconnected or sent QUIT instead.
+///
+/// A connection that has been silent for `keepalive` is sent the reply to a
+/// PING, so a half-open socket eventually fails to write and is dropped.
+async fn run_subscribed(
+    connection: &mut Connection,
+    pubsub: &PubSub,
+    acl: &Acl,
+    client: &mut ClientInfo,
+    command: Command,
+    keepalive: Option<Duration>,
+) -> Result<bool> {
+    let mut subscriber = Subscriber::new(pubsub.clone());
+    let mut pending = Some(command);
+
+    loop {
+        if let Some(command) = pending.take() {
+            let refusal = table::lookup(command.name())
+                .and_then(|info| info.refusal(CommandContext::Subscribe))
+                .or_else(|| acl_refusal(acl, client, &command));
+            match (refusal, command) {
+                (Some(e), _) => connection.write_frame(&Frame::error(e)).await?,
+                (None, Command::Subscribe { channels }) => {
+                    for channel in channels {
+                        let count = subscriber.subscribe(channel.clone());
+                        let reply = subscription_reply("subscribe", Some(channel), count);
+                        connection.write_frame(&reply).await?;
+                    }
+                }
+                (None, Command::Unsubscribe { channels }) => {
+                    // No arguments means every subscribed channel
+                    let channels = if channels.is_empty() {
+                        subscriber.channels()
+                    } else {
+                        channels
+                    };
+                    if channels.is_empty() {
+                        let reply = subscription_reply("unsubscribe", None, 0);
+                        connection.write_frame(&reply).await?;
+                    }
+                    for channel in channels {
+                        let count = subscriber.unsubscribe(&channel);
+                        let reply = subscription_reply("unsubscribe", Some(channel), count);
+                        connection.write_frame(&reply).await?;
+                    }
+                }
+                (None, Command::SSubscribe { channels }) => {
+                    for channel in channels {
+                        let count = subscriber.ssubscribe(channel.clone());
+                        let reply = subscription_reply("ssubscribe", Some(channel), count);
+                        connection.write_frame(&reply).await?;
+                    }
+                }
+                (None, Command::SUnsubscribe { channels }) => {
+                    // No arguments means every subscribed shard channel
+                    let channels = if channels.is_empty() {
+                        subscriber.shard_channels()
+                    } else {
+                        channels
+                    };
+                    if channels.is_empty() {
+                        let reply = subscription_reply("sunsubscribe", None, 0);
+                        connection.write_frame(&reply).await?;
+                    }
+                    for channel in channels {
+                        let count = subscriber.sunsubscribe(&channel);
+                        let reply = subscription_reply("sunsubscribe", Some(channel), count);
+                        connection.write_frame(&reply).await?;
+                    }
+                }
+                (None, Command::Ping(message)) => {
+                    let reply = subscribed_pong(message.unwrap_or_default());
+                    connection.write_frame(&reply).await?;
+                }
+                (None, Command::Quit) => {
+                    connection
+                        .write_frame(&Frame::Simple("OK".to_string()))
+                        .await?;
+                    connection.flush().await?;
+                    return Ok(false);
+                }
+                (None, Command::Reset) => {
+                    // Dropping the subscriber leaves every channel
+                    client.reset();
+                    client.authenticated = !acl.default_user_needs_auth();
+                    connection.set_replies(true);
+                    let reply = Frame::Simple("RESET".to_string());
+                    connection.write_frame(&reply).await?;
+                    return Ok(true);
+                }
+                (None, Command::Unknown(name)) => {
+                    let reply = Frame::error(format!("ERR unknown command '{}'", name));
+                    connection.write_frame(&reply).await?;
+                }
+                (None, other) => unreachable!("'{}' allowed while subscribed", other.name()),
+            }
+
+            if subscriber.count() == 0 && subscriber.shard_count() == 0 {
+                return Ok(true);
+            }
+            // Messages are delivered unless replies are off altogether
+            connection.set_replies(client.reply_mode != ReplyMode::Off);
+        }
+
+        // Restarted on every message in either direction
+        let idle = async {
+            match keepalive {
+                Some(keepalive) => tokio::time::sleep(keepalive).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = idle => {
+                debug!("Sending keepalive to idle subscriber");
+                connection.write_frame(&subscribed_pong(Bytes::new())).await?;
+            }
+            (sharded, channel, message) = subscriber.recv() => {
+                let kind: &'static [u8] = if sharded { b"smessage" } else { b"message" };
+                let mut reply = vec![
+                    Frame::Bulk(Bytes::from_static(kind)),
+                    Frame::Bulk(Bytes::from(channel)),
+                    Frame::Bulk(message.payload),
+                ];
+                if let Some(sequence) = message.sequence {
+                    reply.push(Frame::Integer(sequence as i64));
+                }
+                connection.write_frame(&Frame::Array(reply)).await?;
+            }
+            frame = connection.read_frame() => {
+                let frame = match frame? {
+                    Some(frame) => frame,
+                    None => return Ok(false),
+                };
+                connection.set_replies(client.take_reply());
+                match Command::from_frame(frame) {
+                    Ok(command) => pending = Some(command),
+                    Err(e) => connection.write_frame(&Frame::error(e)).await?,
+                }
+            }
+        }
+    }
+}
+
+/// Reply to PING while subscribed: [pong, message]
+fn subscribed_pong(message: Bytes) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(b"pong")),
+        Frame::Bulk(message),
+    ])
+}
+
+/// Reply to (S)SUBSCRIBE/(S)UNSUBSCRIBE: [kind, channel, subscription count]
+fn subscription_reply(kind: &'static str, channel: Option<String>, count: usize) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(kind.as_bytes())),
+        channel
+            .map(|channel| Frame::Bulk(Bytes::from(channel)))
+            .unwrap_or(Frame::Null),
+        Frame::Integer(count as i64),
+    ])
+}
+
+/// Apply a command and return its reply, recording its timing
+async fn apply_command(command: &Command, ctx: &ExecContext<'_>) -> Frame {
+    let cmd_start = Instant::now();
+    let reply = command.apply(ctx).await;
+    record_timing(
+        command.name(),
+        command.metrics_key_hint(),
+        cmd_start,
+        matches!(reply, Frame::Error(_)),
+        ctx.metrics,
+        ctx.command_metrics,
+    );
+    reply
+}
+
+/// Update the global and per-command metrics for one command, which
+/// replied with an error if `failed`
+fn record_timing(
+    cmd_name: &'static str,
+    metrics_key_hint: Option<&str>,
+    cmd_start: Instant,
+    failed: bool,
+    metrics: &SharedMetrics,
+    command_metrics: &SharedCommandMetrics,
+) {
+    let duration_us = cmd_start.elapsed().as_micros() as u64;
+    metrics.add_command_duration_us(duration_us);
+    metrics.increment_commands();
+    metrics.latency().add_sample("command", duration_us / 1000);
+    metrics
+        .command_stats()
+        .record_call(cmd_name, duration_us, failed);
+
+    // Record per-command metrics
+    command_metrics.record(cmd_name, metrics_key_hint, duration_us);
+}
+
+/// A write command about to be applied, logged to the AOF afterwards
+///
+/// Holds the AOF order lock (see `Aof::lock_order`) from before the command
+/// is applied until it has been logged by `propagate_write`.
+struct AofWrite<'a> {
+    aof: &'a Aof,
+    _order: OwnedMutexGuard<()>,
+}
+
+/// Prepare to apply `command`, if it is a write and the AOF is enabled
+///
+/// Fails with the MISCONF reply to send instead of running the command when
+/// the AOF cannot be written.
+async fn begin_aof_write<'a>(
+    aof: &'a Option<Arc<Aof>>,
+    command: &Command,
+) -> Result<Option<AofWrite<'a>>, Frame> {
+    let aof = match aof.as_ref().filter(|_| command.is_write_command()) {
+        Some(aof) => aof,
+        None => return Ok(None),
+    };
+    let order = aof.lock_order().await;
+    if let Some(e) = aof.write_error() {
+        return Err(Frame::error(e));
+    }
+    Ok(Some(AofWrite { aof, _order: order }))
+}
+
+/// Writes applied back to back in one pipeline step, logged to the AOF
+/// together
+///
+/// Holds the AOF order lock (see `Aof::lock_order`) from before the first
+/// write is applied until the batch is logged with a single append when it
+/// is dropped. The connection holds back the replies until then, so an
+/// acknowledged write is still in the AOF.
+struct AofBatch<'a> {
+    aof: &'a Aof,
+    metrics: &'a SharedMetrics,
+    _order: OwnedMutexGuard<()>,
+    /// Database the log is in after the batched frames
+    selected_db: usize,
+    frames: Vec<Frame>,
+}
+
+impl AofBatch<'_> {
+    /// Like `propagate_write`, adding the frames to the batch instead of
+    /// appending them right away
+    fn propagate_write(
+        &mut self,
+        db_index: usize,
+        command: &Command,
+        frame: &Frame,
+        reply: &Frame,
+    ) {
+        if let Some(frames) = propagated_frames(command, frame, reply) {
+            self.metrics.replication().feed(db_index, &frames);
+            push_in_db(&mut self.frames, &mut self.selected_db, db_index, frames);
+        }
+    }
+}
+
+impl Drop for AofBatch<'_> {
+    fn drop(&mut self) {
+        if !self.frames.is_empty() && write_aof(self.aof, self.metrics, &self.frames) {
+            self.aof.set_selected_db(self.selected_db);
+        }
+    }
+}
+
+/// Prepare to apply `command` as part of the connection's AOF batch,
+/// starting the batch if there is none
+///
+/// Does nothing unless `command` is a write and the AOF is enabled. Fails
+/// with the MISCONF reply to send instead of running the command when the
+/// AOF cannot be written.
+async fn join_aof_batch<'a>(
+    batch: &mut Option<AofBatch<'a>>,
+    aof: &'a Option<Arc<Aof>>,
+    metrics: &'a SharedMetrics,
+    command: &Command,
+) -> Result<(), Frame> {
+    let aof = match aof.as_ref().filter(|_| command.is_write_command()) {
+        Some(aof) => aof,
+        None => return Ok(()),
+    };
+    if batch.is_none() {
+        *batch = Some(AofBatch {
+            aof,
+            metrics,
+            _order: aof.lock_order().await,
+            selected_db: aof.selected_db(),
+            frames: Vec::new(),
+        });
+    }
+    match aof.write_error() {
+        Some(e) => Err(Frame::error(e)),
+        None => Ok(()),
+    }
+}
+
+/// Log the batched writes, if any, and release the replies held for them
+fn end_aof_batch(batch: &mut Option<AofBatch<'_>>, connection: &mut Connection) {
+    // Dropping the batch appends it
+    batch.take();
+    connection.hold_replies(false);
+}
+
+/// Propagate an applied write command, unless it turned out not to change
+/// any data
+///
+/// See `propagate`. A failed append doesn't undo the command; it makes the
+/// following writes fail with MISCONF, see `Aof::write_error`.
+fn propagate_write(
+    aof: Option<&Aof>,
+    metrics: &SharedMetrics,
+    db_index: usize,
+    command: &Command,
+    frame: &Frame,
+    reply: &Frame,
+) {
+    if let Some(frames) = propagated_frames(command, frame, reply) {
+        propagate(aof, metrics, db_index, &frames);
+    }
+}
+
+/// The frames an applied write command is propagated as, `None` if it
+/// turned out not to change any data
+fn propagated_frames(command: &Command, frame: &Frame, reply: &Frame) -> Option<Vec<Frame>> {
+    if !command.changed_data(reply) {
+        return None;
+    }
+    Some(
+        command
+            .propagate(reply)
+            .unwrap_or_else(|| vec![frame.clone()]),
+    )
+}
+
+/// Feed frames that apply to database `db_index` to the replication stream
+/// and, if given, the AOF
+///
+/// Must be called with the AOF order lock held when `aof` is given.
+fn propagate(aof: Option<&Aof>, metrics: &SharedMetrics, db_index: usize, frames: &[Frame]) {
+    metrics.replication().feed(db_index, frames);
+    if let Some(aof) = aof {
+        write_aof_in_db(aof, metrics, db_index, frames);
+    }
+}
+
+/// Append frames that apply to database `db_index` with a single write
+///
+/// A SELECT is logged first when the log last switched to a different
+/// database. Must be called with the AOF order lock held.
+fn write_aof_in_db(aof: &Aof, metrics: &SharedMetrics, db_index: usize, frames: &[Frame]) {
+    let mut selected_db = aof.selected_db();
+    let mut logged = Vec::with_capacity(frames.len() + 1);
+    push_in_db(&mut logged, &mut selected_db, db_index, frames.to_vec());
+    if write_aof(aof, metrics, &logged) {
+        aof.set_selected_db(selected_db);
+    }
+}
+
+/// Add frames that apply to database `db_index` to `logged`, preceded by a
+/// SELECT unless the log is in that database already
+fn push_in_db(
+    logged: &mut Vec<Frame>,
+    selected_db: &mut usize,
+    db_index: usize,
+    frames: Vec<Frame>,
+) {
+    if *selected_db != db_index {
+        logged.push(Frame::Array(vec![
+            Frame::Bulk(Bytes::from_static(b"SELECT")),
+            Frame::Bulk(Bytes::from(db_index.to_string())),
+        ]));
+        *selected_db = db_index;
+    }
+    logged.extend(frames);
+}
+
+/// Append frames to the AOF, recording the outcome in the metrics
+///
+/// Returns whether the append succeeded.
+fn write_aof(aof: &Aof, metrics: &SharedMetrics, frames: &[Frame]) -> bool {
+    let aof_start = Instant::now();
+    let result = aof.append_all(frames);
+    if let Err(ref e) = result {
+        error!("Failed to append to AOF: {}", e);
+    }
+    metrics.increment_aof_writes();
+    metrics.set_aof_last_write_ok(result.is_ok());
+    metrics.add_aof_write_time_us(aof_start.elapsed().as_micros() as u64);
+    result.is_ok()
+}
+
+/// Keeps a client counted in `blocked_clients` for as long as it lives
+struct BlockedClientCount<'a>(&'a SharedMetrics);
+
+impl<'a> BlockedClientCount<'a> {
+    fn new(metrics: &'a SharedMetrics) -> Self {
+        metrics.increment_blocked_clients();
+        BlockedClientCount(metrics)
+    }
+}
+
+impl Drop for BlockedClientCount<'_> {
+    fn drop(&mut self) {
+        self.0.decrement_blocked_clients();
+    }
+}
+
+/// WAITAOF: wait until everything written so far is fsynced to the AOF
+///
+/// Replies `[local, replicas]`. Replicas do not report their own fsyncs,
+/// so the second count is always 0.
+async fn wait_aof(aof: &Option<Arc<Aof>>, numlocal: u64, timeout: Option<Duration>) -> Frame {
+    let aof = match aof {
+        Some(aof) => aof,
+        None if numlocal > 0 => {
+            return Frame::error(
+                "ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled.",
+            )
+        }
+        None => return Frame::Array(vec![Frame::Integer(0), Frame::Integer(0)]),
+    };
+    let offset = aof.appended_offset();
+    if numlocal > 0 {
+        match timeout {
+            Some(timeout) => {
+                let _ = tokio::time::timeout(timeout, aof.wait_fsynced(offset)).await;
+            }
+            None => aof.wait_fsynced(offset).await,
+        }
+    }
+    let local = aof.is_fsynced(offset) as i64;
+    Frame::Array(vec![Frame::Integer(local), Frame::Integer(0)])
+}
+
+/// Run BLPOP/BRPOP outside of a transaction
+///
+/// Waits until one of the keys can be popped or the timeout expires. The
+/// wait holds no lock; each pop attempt takes the AOF order lock, and a
+/// successful pop is propagated as the LPOP/RPOP it amounts to, since
+/// replaying the blocking command could pop at a different time.
+async fn blocking_pop(
+    db: &Db,
+    db_index: usize,
+    aof: &Option<Arc<Aof>>,
+    metrics: &SharedMetrics,
+    keys: &[Bytes],
+    timeout: Option<Duration>,
+    left: bool,
+) -> Frame {
+    // A timeout too far out to represent is as good as none
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let blocked = db.block_on(keys);
+    // Counted from the first wait until the command returns or is dropped
+    // because the client went away
+    let mut counted = None;
+
+    loop {
+        let order = match aof {
+            Some(aof_writer) => Some((aof_writer, aof_writer.lock_order().await)),
+            None => None,
+        };
+        if let Some(e) = order
+            .as_ref()
+            .and_then(|(aof_writer, _)| aof_writer.write_error())
+        {
+            return Frame::error(e);
+        }
+        if let Some((key, value)) = db.pop_first(keys, left) {
+            metrics.add_dirty(1);
+            db.record_change(if left { "BLPOP" } else { "BRPOP" }, Some(key.clone()));
+            let pop = if left { "LPOP" } else { "RPOP" };
+            let propagated = Frame::Array(vec![
+                Frame::Bulk(Bytes::from_static(pop.as_bytes())),
+                Frame::Bulk(key.clone()),
+            ]);
+            let aof = order.as_ref().map(|(aof_writer, _)| aof_writer.as_ref());
+            propagate(aof, metrics, db_index, &[propagated]);
+            return Frame::Array(vec![Frame::Bulk(key), Frame::Bulk(value)]);
+        }
+        drop(order);
+
+        counted.get_or_insert_with(|| BlockedClientCount::new(metrics));
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline.into(), blocked.wait())
+                    .await
+                    .is_err()
+                {
+                    return Frame::Null;
+                }
+            }
+            None => blocked.wait().await,
+        }
+    }
+}
+
+/// Run XREAD BLOCK outside of a transaction
+///
+/// Waits until one of the streams has entries past its ID or the timeout
+/// expires. `$` is resolved once, before the first attempt, so it means
+/// entries added while the client is blocked. Nothing is written, so
+/// unlike `blocking_pop` there is nothing to log.
+async fn blocking_xread(
+    db: &Db,
+    metrics: &SharedMetrics,
+    keys: &[Bytes],
+    ids: &[Option<StreamId>],
+    count: Option<usize>,
+    timeout: Option<Duration>,
+) -> Frame {
+    // A timeout too far out to represent is as good as none
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    let blocked = db.block_on(keys);
+    let ids = match xread_ids(db, keys, ids) {
+        Ok(ids) => ids,
+        Err(e) => return Frame::error(e),
+    };
+    let mut counted = None;
+
+    loop {
+        match db.xread(keys, &ids, count.unwrap_or(usize::MAX)) {
+            Ok(streams) if streams.is_empty() => {}
+            Ok(streams) => return xread_frame(streams),
+            Err(e) => return Frame::error(e),
+        }
+
+        counted.get_or_insert_with(|| BlockedClientCount::new(metrics));
+        match deadline {
+            Some(deadline) => {
+                if tokio::time::timeout_at(deadline.into(), blocked.wait())
+                    .await
+                    .is_err()
+                {
+                    return Frame::Null;
+                }
+            }
+            None => blocked.wait().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
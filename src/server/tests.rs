@@ -0,0 +1,3543 @@
+use super::*;
+use crate::cmd::table;
+use crate::command_metrics::MetricsStrategy;
+use crate::config::{LogLevel, DEFAULT_DATABASES};
+use crate::db::{ChangeEvent, StreamId};
+use crate::frame::Frame;
+use crate::notify::NotifyFlags;
+use crate::persistence::LOADING_ERR;
+use crate::pubsub;
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+/// Shared handles of a server started for a test
+struct TestServer {
+    addr: SocketAddr,
+    metrics: SharedMetrics,
+}
+
+/// Configuration for test servers: no AOF, no per-command metrics
+fn test_config() -> ServerConfig {
+    ServerConfig {
+        disable_aof: true,
+        metrics_strategy: MetricsStrategy::Disabled,
+        ..ServerConfig::default()
+    }
+}
+
+/// Start a server on an ephemeral port with AOF disabled
+async fn start_server() -> TestServer {
+    start_server_with(test_config()).await
+}
+
+/// Start a server on an ephemeral port with the given configuration
+async fn start_server_with(config: ServerConfig) -> TestServer {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server = Server::new(config);
+    let metrics = Arc::clone(server.metrics());
+    tokio::spawn(server.run(listener, std::future::pending()));
+
+    TestServer { addr, metrics }
+}
+
+/// Open a client connection to a test server
+async fn connect(server: &TestServer) -> Connection {
+    Connection::new(TcpStream::connect(server.addr).await.unwrap())
+}
+
+/// Send a command and wait for its reply
+async fn send(client: &mut Connection, args: &[&str]) -> Frame {
+    let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_bytes()).collect();
+    send_bytes(client, &args).await
+}
+
+/// Send a command with binary arguments and wait for its reply
+async fn send_bytes(client: &mut Connection, args: &[&[u8]]) -> Frame {
+    let frame = Frame::Array(
+        args.iter()
+            .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg)))
+            .collect(),
+    );
+    client.write_frame(&frame).await.unwrap();
+    client
+        .read_frame()
+        .await
+        .unwrap()
+        .expect("connection closed")
+}
+
+#[tokio::test]
+async fn test_latency_latest_reports_slow_command() {
+    let server = start_server().await;
+    server.metrics.latency().set_threshold_ms(10);
+    let mut client = connect(&server).await;
+
+    assert_eq!(
+        send(&mut client, &["DEBUG", "SLEEP", "0.05"]).await,
+        Frame::Simple("OK".to_string())
+    );
+
+    let latest = match send(&mut client, &["LATENCY", "LATEST"]).await {
+        Frame::Array(events) => events,
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    assert_eq!(latest.len(), 1);
+
+    match &latest[0] {
+        Frame::Array(fields) => {
+            assert_eq!(fields[0], Frame::Bulk(Bytes::from("command")));
+            match fields[2] {
+                Frame::Integer(ms) => assert!(ms >= 50, "latest was {}ms", ms),
+                ref other => panic!("unexpected latency field: {:?}", other),
+            }
+        }
+        other => panic!("unexpected event entry: {:?}", other),
+    }
+
+    assert_eq!(
+        send(&mut client, &["LATENCY", "RESET"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        send(&mut client, &["LATENCY", "HISTORY", "command"]).await,
+        Frame::Array(vec![])
+    );
+}
+
+#[tokio::test]
+async fn test_exec_aborts_when_watched_key_recreated() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    let mut other = connect(&server).await;
+    let ok = Frame::Simple("OK".to_string());
+
+    assert_eq!(send(&mut client, &["SET", "k", "v1"]).await, ok);
+    assert_eq!(send(&mut client, &["WATCH", "k"]).await, ok);
+
+    // Another client deletes and recreates the watched key
+    assert_eq!(send(&mut other, &["DEL", "k"]).await, Frame::Integer(1));
+    assert_eq!(send(&mut other, &["SET", "k", "v2"]).await, ok);
+
+    assert_eq!(send(&mut client, &["MULTI"]).await, ok);
+    assert_eq!(
+        send(&mut client, &["SET", "k", "mine"]).await,
+        Frame::Simple("QUEUED".to_string())
+    );
+    assert_eq!(send(&mut client, &["EXEC"]).await, Frame::Null);
+    assert_eq!(
+        send(&mut client, &["GET", "k"]).await,
+        Frame::Bulk(Bytes::from("v2"))
+    );
+
+    // Without interference the transaction goes through
+    assert_eq!(send(&mut client, &["WATCH", "k"]).await, ok);
+    assert_eq!(send(&mut client, &["MULTI"]).await, ok);
+    send(&mut client, &["SET", "k", "mine"]).await;
+    assert_eq!(send(&mut client, &["EXEC"]).await, Frame::Array(vec![ok]));
+}
+
+#[tokio::test]
+async fn test_watched_keys_stay_in_their_database() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    let mut other = connect(&server).await;
+    let ok = Frame::Simple("OK".to_string());
+
+    // Watched in db 0, changed there while the client uses db 1
+    assert_eq!(send(&mut client, &["WATCH", "k"]).await, ok);
+    assert_eq!(send(&mut client, &["SELECT", "1"]).await, ok);
+    assert_eq!(send(&mut other, &["SET", "k", "v"]).await, ok);
+    assert_eq!(send(&mut client, &["MULTI"]).await, ok);
+    send(&mut client, &["SET", "x", "1"]).await;
+    assert_eq!(send(&mut client, &["EXEC"]).await, Frame::Null);
+
+    // The same name in another database is a different key
+    assert_eq!(send(&mut client, &["WATCH", "k"]).await, ok);
+    assert_eq!(send(&mut other, &["SET", "k", "v2"]).await, ok);
+    assert_eq!(send(&mut client, &["MULTI"]).await, ok);
+    send(&mut client, &["SET", "x", "1"]).await;
+    assert_eq!(
+        send(&mut client, &["EXEC"]).await,
+        Frame::Array(vec![ok.clone()])
+    );
+
+    // Switching databases mid-transaction is refused
+    assert_eq!(send(&mut client, &["MULTI"]).await, ok);
+    assert_eq!(
+        send(&mut client, &["SELECT", "0"]).await,
+        Frame::error("ERR SELECT inside MULTI is not allowed")
+    );
+    assert_eq!(
+        send(&mut client, &["EXEC"]).await,
+        Frame::error("EXECABORT Transaction discarded because of previous errors.")
+    );
+}
+
+#[test]
+fn test_exec_watching_each_others_databases() {
+    use std::sync::mpsc;
+
+    let dbs = vec![Db::new(), Db::new()];
+    let (done_tx, done_rx) = mpsc::channel();
+    for (db_index, other) in [(0, 1), (1, 0)] {
+        let dbs = dbs.clone();
+        let done_tx = done_tx.clone();
+        std::thread::spawn(move || {
+            let key = [Bytes::from("w")];
+            for _ in 0..10_000 {
+                // WATCH in the other database, then EXEC in this one the
+                // way the connection runs it
+                let mut transaction = Transaction::new();
+                transaction.watch(other, &dbs[other], &key);
+                transaction.begin().unwrap();
+                let stale_elsewhere = transaction.is_stale_elsewhere(&dbs, db_index);
+                let queued = dbs[db_index].transaction(|db| {
+                    db.write_string(key[0].clone(), Bytes::from("v"), None);
+                    transaction.take_for_exec(db_index, db, stale_elsewhere)
+                });
+                assert!(queued.is_ok());
+            }
+            done_tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..2 {
+        done_rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("EXEC deadlocked");
+    }
+}
+
+#[tokio::test]
+async fn test_multi_nesting_and_bare_exec_discard() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    let ok = Frame::Simple("OK".to_string());
+
+    assert_eq!(
+        send(&mut client, &["EXEC"]).await,
+        Frame::error("ERR EXEC without MULTI")
+    );
+    assert_eq!(
+        send(&mut client, &["DISCARD"]).await,
+        Frame::error("ERR DISCARD without MULTI")
+    );
+
+    // A nested MULTI is refused without dropping what was queued
+    assert_eq!(send(&mut client, &["MULTI"]).await, ok);
+    send(&mut client, &["SET", "k", "v"]).await;
+    assert_eq!(
+        send(&mut client, &["MULTI"]).await,
+        Frame::error("ERR MULTI calls can not be nested")
+    );
+    assert_eq!(send(&mut client, &["EXEC"]).await, Frame::Array(vec![ok]));
+}
+
+#[tokio::test]
+async fn test_exec_aborts_after_queueing_error() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    let ok = Frame::Simple("OK".to_string());
+
+    assert_eq!(send(&mut client, &["MULTI"]).await, ok);
+    send(&mut client, &["SET", "k", "v"]).await;
+    // Wrong number of arguments, and an unknown command
+    assert!(matches!(
+        send(&mut client, &["INCR"]).await,
+        Frame::Error(_)
+    ));
+    assert!(matches!(
+        send(&mut client, &["NOSUCHCOMMAND"]).await,
+        Frame::Error(_)
+    ));
+    assert_eq!(
+        send(&mut client, &["EXEC"]).await,
+        Frame::error("EXECABORT Transaction discarded because of previous errors.")
+    );
+    // Nothing ran, and the connection is out of MULTI
+    assert_eq!(send(&mut client, &["GET", "k"]).await, Frame::Null);
+
+    // DISCARD forgets the error
+    send(&mut client, &["MULTI"]).await;
+    send(&mut client, &["INCR"]).await;
+    assert_eq!(send(&mut client, &["DISCARD"]).await, ok);
+    send(&mut client, &["MULTI"]).await;
+    send(&mut client, &["SET", "k", "v"]).await;
+    assert_eq!(send(&mut client, &["EXEC"]).await, Frame::Array(vec![ok]));
+}
+
+#[tokio::test]
+async fn test_connection_commands_refused_in_multi() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    // These act on the connection, which EXEC does not have at hand
+    for (command, name) in [
+        (&["CLIENT", "SETNAME", "x"][..], "CLIENT"),
+        (&["CLIENT", "INFO"][..], "CLIENT"),
+        (&["SUBSCRIBE", "c"][..], "SUBSCRIBE"),
+        (&["UNSUBSCRIBE"][..], "UNSUBSCRIBE"),
+        (&["SSUBSCRIBE", "c"][..], "SSUBSCRIBE"),
+        (&["SUNSUBSCRIBE"][..], "SUNSUBSCRIBE"),
+        (&["BGREWRITEAOF"][..], "BGREWRITEAOF"),
+        (&["WAITAOF", "0", "0", "0"][..], "WAITAOF"),
+    ] {
+        send(&mut client, &["MULTI"]).await;
+        assert_eq!(
+            send(&mut client, command).await,
+            Frame::error(format!("ERR {} inside MULTI is not allowed", name))
+        );
+        send(&mut client, &["DISCARD"]).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_exec_is_not_interleaved_with_other_clients() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    let mut reader = connect(&server).await;
+
+    send(&mut client, &["MULTI"]).await;
+    send(&mut client, &["SET", "a", "1"]).await;
+    send(&mut client, &["DEBUG", "SLEEP", "0.3"]).await;
+    send(&mut client, &["SET", "b", "1"]).await;
+    let exec = tokio::spawn(async move { send(&mut client, &["EXEC"]).await });
+
+    // Issued while EXEC sleeps between the two SETs, so it only runs once
+    // the transaction has finished and sees the second one
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        send(&mut reader, &["GET", "b"]).await,
+        Frame::Bulk(Bytes::from("1"))
+    );
+    assert!(matches!(exec.await.unwrap(), Frame::Array(replies) if replies.len() == 3));
+}
+
+#[test]
+fn test_server_runs_on_current_thread_runtime() {
+    let config = ServerConfig {
+        single_thread: true,
+        ..test_config()
+    };
+    let runtime = config.build_runtime().unwrap();
+
+    runtime.block_on(async move {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let server = Server::new(config);
+        let handle = tokio::spawn(server.run(listener, async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+        assert_eq!(
+            send(&mut client, &["SET", "k", "v"]).await,
+            Frame::Simple("OK".to_string())
+        );
+        assert_eq!(
+            send(&mut client, &["GET", "k"]).await,
+            Frame::Bulk(Bytes::from("v"))
+        );
+
+        shutdown_tx.send(()).unwrap();
+        handle.await.unwrap().unwrap();
+    });
+}
+
+#[tokio::test]
+async fn test_subscribe_preserves_per_channel_order() {
+    let server = start_server().await;
+    let mut subscriber = connect(&server).await;
+    let mut publisher = connect(&server).await;
+    let channels = ["a", "b", "c"];
+    const PER_CHANNEL: usize = 200;
+
+    // One confirmation per channel
+    send(&mut subscriber, &["SUBSCRIBE", "a", "b", "c"]).await;
+    for _ in 1..channels.len() {
+        subscriber.read_frame().await.unwrap().unwrap();
+    }
+
+    for i in 0..PER_CHANNEL {
+        for channel in channels {
+            let message = i.to_string();
+            assert_eq!(
+                send(&mut publisher, &["PUBLISH", channel, &message]).await,
+                Frame::Integer(1)
+            );
+        }
+    }
+
+    let mut next = [0usize; 3];
+    for _ in 0..PER_CHANNEL * channels.len() {
+        let fields = match subscriber.read_frame().await.unwrap().unwrap() {
+            Frame::Array(fields) => fields,
+            other => panic!("unexpected push: {:?}", other),
+        };
+        assert_eq!(fields[0], Frame::Bulk(Bytes::from("message")));
+        let index = match &fields[1] {
+            Frame::Bulk(channel) => channels
+                .iter()
+                .position(|c| c.as_bytes() == &channel[..])
+                .unwrap(),
+            other => panic!("unexpected channel: {:?}", other),
+        };
+        assert_eq!(fields[2], Frame::Bulk(Bytes::from(next[index].to_string())));
+        next[index] += 1;
+    }
+    assert_eq!(next, [PER_CHANNEL; 3]);
+
+    // Unsubscribing from everything returns to normal mode
+    send(&mut subscriber, &["UNSUBSCRIBE"]).await;
+    for _ in 1..channels.len() {
+        subscriber.read_frame().await.unwrap().unwrap();
+    }
+    assert_eq!(
+        send(&mut subscriber, &["PING"]).await,
+        Frame::Simple("PONG".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_command_getkeys() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    assert_eq!(
+        send(&mut client, &["COMMAND", "GETKEYS", "set", "foo", "bar"]).await,
+        Frame::Array(vec![Frame::Bulk(Bytes::from("foo"))])
+    );
+    assert_eq!(
+        send(
+            &mut client,
+            &["COMMAND", "GETKEYS", "mset", "a", "1", "b", "2"]
+        )
+        .await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("a")),
+            Frame::Bulk(Bytes::from("b")),
+        ])
+    );
+    assert_eq!(
+        send(&mut client, &["COMMAND", "GETKEYS", "ping"]).await,
+        Frame::Error("ERR The command has no key arguments".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["COMMAND", "GETKEYS", "nosuchcommand"]).await,
+        Frame::Error("ERR Invalid command specified".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_bare_command_lists_every_command() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    let all = send(&mut client, &["COMMAND"]).await;
+    assert_eq!(all, send(&mut client, &["COMMAND", "INFO"]).await);
+    match (all, send(&mut client, &["COMMAND", "COUNT"]).await) {
+        (Frame::Array(entries), Frame::Integer(count)) => {
+            assert_eq!(entries.len() as i64, count)
+        }
+        other => panic!("unexpected replies: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_substr_is_an_alias_for_getrange() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    send(&mut client, &["SET", "k", "Hello World"]).await;
+    for (start, end) in [("0", "-1"), ("0", "4"), ("-5", "-1"), ("20", "30")] {
+        assert_eq!(
+            send(&mut client, &["SUBSTR", "k", start, end]).await,
+            send(&mut client, &["GETRANGE", "k", start, end]).await
+        );
+    }
+    assert_eq!(
+        send(&mut client, &["substr", "k", "0", "-1"]).await,
+        Frame::Bulk(Bytes::from("Hello World"))
+    );
+
+    // The alias is reported under the command's own name
+    let getrange = Frame::Array(vec![
+        Frame::Bulk(Bytes::from("getrange")),
+        Frame::Integer(4),
+        Frame::Array(vec![]),
+        Frame::Integer(1),
+        Frame::Integer(1),
+        Frame::Integer(1),
+    ]);
+    assert_eq!(
+        send(&mut client, &["COMMAND", "INFO", "SUBSTR", "nosuchcommand"]).await,
+        Frame::Array(vec![getrange, Frame::Null])
+    );
+
+    // Flags come from the command table
+    let flags = |name: &str| {
+        Frame::Array(
+            name.split_whitespace()
+                .map(|flag| Frame::Simple(flag.to_string()))
+                .collect(),
+        )
+    };
+    match send(&mut client, &["COMMAND", "INFO", "set", "subscribe"]).await {
+        Frame::Array(entries) => {
+            let flags_of = |entry: &Frame| match entry {
+                Frame::Array(fields) => fields[2].clone(),
+                other => panic!("unexpected entry: {:?}", other),
+            };
+            assert_eq!(flags_of(&entries[0]), flags("write"));
+            assert_eq!(flags_of(&entries[1]), flags("pubsub no-multi"));
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_expiretime_matches_expireat() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    let at = crate::clock::unix_time_ms() / 1000 + 3600;
+    send(&mut client, &["SET", "k", "v"]).await;
+    assert_eq!(
+        send(&mut client, &["EXPIREAT", "k", &at.to_string()]).await,
+        Frame::Integer(1)
+    );
+
+    match send(&mut client, &["EXPIRETIME", "k"]).await {
+        Frame::Integer(secs) => assert!((secs - at as i64).abs() <= 1, "got {}", secs),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    match send(&mut client, &["PEXPIRETIME", "k"]).await {
+        Frame::Integer(ms) => assert!((ms - at as i64 * 1000).abs() <= 1000, "got {}", ms),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+
+    send(&mut client, &["SET", "persistent", "v"]).await;
+    assert_eq!(
+        send(&mut client, &["EXPIRETIME", "persistent"]).await,
+        Frame::Integer(-1)
+    );
+    assert_eq!(
+        send(&mut client, &["PEXPIRETIME", "missing"]).await,
+        Frame::Integer(-2)
+    );
+}
+
+#[tokio::test]
+async fn test_expire_is_logged_as_pexpireat() {
+    let config = aof_test_config("expire");
+    let server = start_server_with(config.clone()).await;
+    let mut client = connect(&server).await;
+    let at = crate::clock::unix_time_ms() / 1000 + 3600;
+
+    for key in ["e", "p", "a"] {
+        send(&mut client, &["SET", key, "v"]).await;
+    }
+    for args in [
+        &["EXPIRE", "e", "100"][..],
+        &["PEXPIRE", "p", "100000"],
+        &["EXPIREAT", "a", &at.to_string()],
+    ] {
+        assert_eq!(send(&mut client, args).await, Frame::Integer(1));
+    }
+    // Nothing changed, nothing logged
+    assert_eq!(
+        send(&mut client, &["EXPIRE", "missing", "100"]).await,
+        Frame::Integer(0)
+    );
+
+    // Logged with the absolute deadline, not the relative TTL
+    let logged = Aof::load(&config.aof_path).unwrap();
+    let expirations: Vec<_> = logged
+        .iter()
+        .filter_map(|frame| match frame {
+            Frame::Array(args) if args[0] != Frame::Bulk(Bytes::from("SET")) => Some(args),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(expirations.len(), 3, "{:?}", logged);
+    for (args, key) in expirations.iter().zip(["e", "p", "a"]) {
+        assert_eq!(args[0], Frame::Bulk(Bytes::from("PEXPIREAT")));
+        assert_eq!(args[1], Frame::Bulk(Bytes::from(key)));
+    }
+    assert_eq!(
+        expirations[2][2],
+        Frame::Bulk(Bytes::from((at * 1000).to_string()))
+    );
+
+    // Replaying restores the deadline instead of restarting the countdown
+    let restarted = start_server_with(config.clone()).await;
+    let mut client = connect(&restarted).await;
+    match send(&mut client, &["PTTL", "e"]).await {
+        Frame::Integer(ms) => assert!(ms > 90_000 && ms <= 100_000, "{}", ms),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_incrby_rejects_loose_integers() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    let not_an_integer = Frame::Error("ERR value is not an integer or out of range".to_string());
+
+    assert_eq!(
+        send(&mut client, &["INCRBY", "n", " 10"]).await,
+        not_an_integer
+    );
+    assert_eq!(
+        send(&mut client, &["INCRBY", "n", "+10"]).await,
+        not_an_integer
+    );
+    assert_eq!(
+        send(&mut client, &["INCRBY", "n", "-10"]).await,
+        Frame::Integer(-10)
+    );
+    assert_eq!(send(&mut client, &["INCR", "n"]).await, Frame::Integer(-9));
+
+    send(&mut client, &["HSET", "h", "f", "v"]).await;
+    assert_eq!(
+        send(&mut client, &["APPEND", "h", "x"]).await,
+        Frame::Error(crate::db::WRONGTYPE_ERR.to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_save_point_writes_snapshot() {
+    let path =
+        std::env::temp_dir().join(format!("rustredis-save-point-{}.rdb", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let server = start_server_with(ServerConfig {
+        rdb_path: path.to_string_lossy().into_owned(),
+        save_points: vec![crate::snapshot::SavePoint {
+            seconds: 0,
+            changes: 3,
+        }],
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(&server).await;
+
+    send(&mut client, &["SET", "a", "1"]).await;
+    send(&mut client, &["SET", "b", "2"]).await;
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    assert!(!path.exists(), "saved before reaching the change threshold");
+
+    send(&mut client, &["RPUSH", "c", "x", "y"]).await;
+    for _ in 0..50 {
+        if path.exists() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+
+    let restored = Db::new();
+    assert_eq!(
+        crate::snapshot::load(std::slice::from_ref(&restored), &path).unwrap(),
+        3
+    );
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(restored.read_string(b"b"), Some(Bytes::from("2")));
+    assert_eq!(restored.llen(b"c"), Some(2));
+}
+
+#[tokio::test]
+async fn test_save_resets_changes_since_last_save() {
+    let path = std::env::temp_dir().join(format!("rustredis-save-{}.rdb", std::process::id()));
+    let server = start_server_with(ServerConfig {
+        rdb_path: path.to_string_lossy().into_owned(),
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(&server).await;
+
+    async fn changes(client: &mut Connection) -> String {
+        let info = match send(client, &["INFO", "persistence"]).await {
+            Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+            other => panic!("unexpected reply: {:?}", other),
+        };
+        assert!(info.contains("aof_enabled:0\r\n"), "{}", info);
+        assert!(info.contains("loading:0\r\n"), "{}", info);
+        info.lines()
+            .find_map(|line| line.strip_prefix("rdb_changes_since_last_save:"))
+            .unwrap()
+            .to_string()
+    }
+    let lastsave = |reply| match reply {
+        Frame::Integer(time) => time,
+        other => panic!("unexpected reply: {:?}", other),
+    };
+
+    assert_eq!(changes(&mut client).await, "0");
+    send(&mut client, &["SET", "k", "v"]).await;
+    assert_eq!(changes(&mut client).await, "1");
+
+    let before = lastsave(send(&mut client, &["LASTSAVE"]).await);
+    assert_eq!(
+        send(&mut client, &["SAVE"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert_eq!(changes(&mut client).await, "0");
+    assert!(lastsave(send(&mut client, &["LASTSAVE"]).await) >= before);
+
+    // SAVE is not queued in a transaction
+    send(&mut client, &["MULTI"]).await;
+    assert_eq!(
+        send(&mut client, &["SAVE"]).await,
+        Frame::error("ERR SAVE inside MULTI is not allowed")
+    );
+    send(&mut client, &["DISCARD"]).await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_info_reports_pubsub_channels() {
+    let server = start_server().await;
+    let mut subscriber = connect(&server).await;
+    let mut client = connect(&server).await;
+
+    send(&mut subscriber, &["SUBSCRIBE", "news"]).await;
+
+    let info = match send(&mut client, &["INFO"]).await {
+        Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    assert!(info.contains("pubsub_channels:1\r\n"), "{}", info);
+    assert!(info.contains("pubsub_patterns:0\r\n"), "{}", info);
+
+    assert_eq!(
+        send(&mut client, &["PUBSUB", "NUMSUB", "news", "other"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("news")),
+            Frame::Integer(1),
+            Frame::Bulk(Bytes::from("other")),
+            Frame::Integer(0),
+        ])
+    );
+    assert_eq!(
+        send(&mut client, &["PUBSUB", "NUMPAT"]).await,
+        Frame::Integer(0)
+    );
+}
+
+#[tokio::test]
+async fn test_info_reports_replication_offset() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    async fn info(client: &mut Connection) -> HashMap<String, String> {
+        let info = match send(client, &["INFO"]).await {
+            Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+            other => panic!("unexpected reply: {:?}", other),
+        };
+        info.lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    let before = info(&mut client).await;
+    assert_eq!(before["role"], "master");
+    assert_eq!(before["connected_slaves"], "0");
+    assert_eq!(before["master_replid"].len(), 40);
+    assert_eq!(before["master_repl_offset"], "0");
+
+    send(&mut client, &["SET", "k", "v"]).await;
+    let after_set = info(&mut client).await;
+    let offset =
+        |info: &HashMap<String, String>| info["master_repl_offset"].parse::<u64>().unwrap();
+    assert!(offset(&after_set) > 0);
+    assert_eq!(after_set["master_replid"], before["master_replid"]);
+
+    // Reads and writes that change nothing produce no stream
+    send(&mut client, &["GET", "k"]).await;
+    send(&mut client, &["DEL", "missing"]).await;
+    assert_eq!(offset(&info(&mut client).await), offset(&after_set));
+
+    // *3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nw\r\n
+    send(&mut client, &["SET", "k", "w"]).await;
+    assert_eq!(offset(&info(&mut client).await), offset(&after_set) + 27);
+}
+
+#[tokio::test]
+async fn test_role_and_failover() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    let role = |offset| {
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("master")),
+            Frame::Integer(offset),
+            Frame::Array(Vec::new()),
+        ])
+    };
+    assert_eq!(send(&mut client, &["ROLE"]).await, role(0));
+    // *3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n after *2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n
+    send(&mut client, &["SET", "k", "v"]).await;
+    assert_eq!(send(&mut client, &["ROLE"]).await, role(23 + 27));
+
+    assert_eq!(
+        send(&mut client, &["FAILOVER", "ABORT"]).await,
+        Frame::error("ERR No failover in progress.")
+    );
+    assert_eq!(
+        send(&mut client, &["FAILOVER"]).await,
+        Frame::error("ERR FAILOVER requires connected replicas.")
+    );
+}
+
+#[tokio::test]
+async fn test_cluster_stubs() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    let info = match send(&mut client, &["CLUSTER", "INFO"]).await {
+        Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    assert!(info.contains("cluster_enabled:0\r\n"), "{}", info);
+
+    let id = send(&mut client, &["CLUSTER", "MYID"]).await;
+    match &id {
+        Frame::Bulk(id) => {
+            assert_eq!(id.len(), 40);
+            assert!(id.iter().all(u8::is_ascii_hexdigit));
+        }
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    assert_eq!(send(&mut client, &["CLUSTER", "MYID"]).await, id);
+
+    assert_eq!(
+        send(&mut client, &["CLUSTER", "SLOTS"]).await,
+        Frame::Array(vec![])
+    );
+    assert_eq!(
+        send(&mut client, &["CLUSTER", "NODES"]).await,
+        Frame::Bulk(Bytes::new())
+    );
+}
+
+#[tokio::test]
+async fn test_inline_commands_and_their_size_limit() {
+    use tokio::io::AsyncWriteExt;
+
+    let server = start_server_with(ServerConfig {
+        proto_inline_max_size: 64,
+        ..test_config()
+    })
+    .await;
+
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    stream.write_all(b"SET k v\r\nGET k\n").await.unwrap();
+    let mut client = Connection::new(stream);
+    assert_eq!(
+        client.read_frame().await.unwrap(),
+        Some(Frame::Simple("OK".to_string()))
+    );
+    assert_eq!(
+        client.read_frame().await.unwrap(),
+        Some(Frame::Bulk(Bytes::from("v")))
+    );
+
+    // No newline within the limit: refused, then disconnected
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    let request = format!("GET {}", "x".repeat(100));
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut client = Connection::new(stream);
+    assert_eq!(
+        client.read_frame().await.unwrap(),
+        Some(Frame::error("ERR Protocol error: too big inline request"))
+    );
+    assert_eq!(client.read_frame().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_output_buffer_hard_limit_drops_stuck_client() {
+    use tokio::io::AsyncWriteExt;
+
+    let server = start_server_with(ServerConfig {
+        client_output_buffer_limit: crate::connection::OutputBufferLimit {
+            hard: 1024 * 1024,
+            soft: 0,
+            soft_seconds: 0,
+        },
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(&server).await;
+    let big = "x".repeat(16 * 1024);
+    send(&mut client, &["SET", "big", &big]).await;
+    drop(client);
+
+    // Pipeline far more replies than the socket buffers can hold and never
+    // read any of them
+    let mut stuck = TcpStream::connect(server.addr).await.unwrap();
+    let request = "*2\r\n$3\r\nGET\r\n$3\r\nbig\r\n".repeat(4096);
+    stuck.write_all(request.as_bytes()).await.unwrap();
+
+    let deadline = Instant::now() + std::time::Duration::from_secs(5);
+    while server.metrics.active_connections() > 0 {
+        assert!(Instant::now() < deadline, "stuck client was not dropped");
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    drop(stuck);
+}
+
+#[tokio::test]
+async fn test_half_closed_client_gets_whole_reply() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    let big = "x".repeat(8 * 1024 * 1024);
+    send(&mut client, &["SET", "big", &big]).await;
+
+    // Far more than the socket buffers hold, so most of the reply is still
+    // in the output buffer when the server sees EOF
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    stream
+        .write_all(b"*2\r\n$3\r\nGET\r\n$3\r\nbig\r\n")
+        .await
+        .unwrap();
+    stream.shutdown().await.unwrap();
+
+    let mut received = Vec::new();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(10),
+        stream.read_to_end(&mut received),
+    )
+    .await
+    .expect("reply never finished")
+    .unwrap();
+    let header = format!("${}\r\n", big.len());
+    assert_eq!(received.len(), header.len() + big.len() + 2);
+    assert!(received.starts_with(header.as_bytes()));
+}
+
+#[tokio::test]
+async fn test_scan_iterates_all_keys() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    for i in 0..25 {
+        let key = format!("key:{}", i);
+        send(&mut client, &["SET", &key, "v"]).await;
+    }
+
+    let mut cursor = "0".to_string();
+    let mut keys = Vec::new();
+    loop {
+        let reply = send(&mut client, &["SCAN", &cursor, "COUNT", "7"]).await;
+        let (next, batch) = match reply {
+            Frame::Array(mut parts) if parts.len() == 2 => (parts.remove(0), parts.remove(0)),
+            other => panic!("unexpected reply: {:?}", other),
+        };
+        match batch {
+            Frame::Array(batch) => keys.extend(batch),
+            other => panic!("unexpected batch: {:?}", other),
+        }
+        cursor = match next {
+            Frame::Bulk(next) => String::from_utf8(next.to_vec()).unwrap(),
+            other => panic!("unexpected cursor: {:?}", other),
+        };
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(keys.len(), 25);
+
+    assert_eq!(
+        send(&mut client, &["SCAN", "abc"]).await,
+        Frame::Error("ERR invalid cursor".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_idempotent_retry_applies_once() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    let incr = ["IDEMPOTENT", "retry-1", "INCR", "counter"];
+    assert_eq!(send(&mut client, &incr).await, Frame::Integer(1));
+    // The retry gets the recorded reply, also on another connection
+    let mut other = connect(&server).await;
+    assert_eq!(send(&mut other, &incr).await, Frame::Integer(1));
+    assert_eq!(
+        send(&mut client, &["GET", "counter"]).await,
+        Frame::Bulk(Bytes::from("1"))
+    );
+
+    // A new token runs the command again
+    assert_eq!(
+        send(&mut client, &["IDEMPOTENT", "retry-2", "INCR", "counter"]).await,
+        Frame::Integer(2)
+    );
+
+    // Commands that would block are refused
+    assert_eq!(
+        send(&mut client, &["IDEMPOTENT", "t", "BLPOP", "list", "0"]).await,
+        Frame::error("ERR 'blpop' cannot be used with IDEMPOTENT")
+    );
+    assert_eq!(
+        send(&mut client, &["IDEMPOTENT", "t", "DEBUG", "SLEEP", "1"]).await,
+        Frame::error("ERR 'debug' cannot be used with IDEMPOTENT")
+    );
+
+    // So are the commands the table tags as changing connection state
+    for command in [&["SELECT", "1"][..], &["SUBSCRIBE", "news"], &["QUIT"]] {
+        let mut args = vec!["IDEMPOTENT", "t"];
+        args.extend_from_slice(command);
+        assert_eq!(
+            send(&mut client, &args).await,
+            Frame::error(format!(
+                "ERR '{}' cannot be used with IDEMPOTENT",
+                command[0].to_lowercase()
+            ))
+        );
+    }
+
+    send(&mut client, &["MULTI"]).await;
+    assert!(matches!(
+        send(&mut client, &incr).await,
+        Frame::Error(e) if e.contains("inside MULTI")
+    ));
+}
+
+#[tokio::test]
+async fn test_empty_array_and_empty_bulk() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    // "*0\r\n" is a well-formed frame but not a command
+    assert_eq!(
+        send(&mut client, &[]).await,
+        Frame::Error("empty command".to_string())
+    );
+
+    assert_eq!(
+        send(&mut client, &["SET", "k", ""]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["GET", "k"]).await,
+        Frame::Bulk(Bytes::new())
+    );
+}
+
+#[tokio::test]
+async fn test_expired_keyevent_on_access() {
+    let server = start_server_with(ServerConfig {
+        notify_keyspace_events: NotifyFlags::parse("Ex").unwrap(),
+        ..test_config()
+    })
+    .await;
+    let mut subscriber = connect(&server).await;
+    let mut client = connect(&server).await;
+
+    send(&mut subscriber, &["SUBSCRIBE", "__keyevent@0__:expired"]).await;
+    send(&mut client, &["SET", "session", "v"]).await;
+    send(&mut client, &["PEXPIRE", "session", "20"]).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(send(&mut client, &["GET", "session"]).await, Frame::Null);
+
+    let event = tokio::time::timeout(Duration::from_secs(5), subscriber.read_frame())
+        .await
+        .expect("no expired event")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        event,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("message")),
+            Frame::Bulk(Bytes::from("__keyevent@0__:expired")),
+            Frame::Bulk(Bytes::from("session")),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn test_monitor_hides_internal_expiry_events() {
+    for show_internal in [false, true] {
+        let server = start_server_with(ServerConfig {
+            notify_keyspace_events: NotifyFlags::parse("Ex").unwrap(),
+            monitor_internal_events: show_internal,
+            ..test_config()
+        })
+        .await;
+        let mut monitor = connect(&server).await;
+        let mut client = connect(&server).await;
+
+        assert_eq!(
+            send(&mut monitor, &["MONITOR"]).await,
+            Frame::Simple("OK".to_string())
+        );
+        send(&mut client, &["SET", "session", "v"]).await;
+        send(&mut client, &["PEXPIRE", "session", "20"]).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(send(&mut client, &["GET", "session"]).await, Frame::Null);
+        send(&mut client, &["PING"]).await;
+
+        // Everything up to the PING that ends the test
+        let mut lines = Vec::new();
+        loop {
+            let line = tokio::time::timeout(Duration::from_secs(5), monitor.read_frame())
+                .await
+                .expect("MONITOR feed stalled")
+                .unwrap()
+                .unwrap();
+            let Frame::Simple(line) = line else {
+                panic!("unexpected MONITOR frame {:?}", line);
+            };
+            if line.ends_with("\"PING\"") {
+                break;
+            }
+            lines.push(line);
+        }
+
+        let commands: Vec<_> = lines
+            .iter()
+            .map(|line| line.split_once("] ").unwrap().1)
+            .collect();
+        let mut expected = vec![
+            "\"SET\" \"session\" \"v\"",
+            "\"PEXPIRE\" \"session\" \"20\"",
+            "\"GET\" \"session\"",
+        ];
+        if show_internal {
+            expected.push("\"PUBLISH\" \"__keyevent@0__:expired\" \"session\"");
+            assert!(lines[3].contains(" [0 internal] "), "{}", lines[3]);
+        }
+        assert_eq!(commands, expected);
+    }
+}
+
+/// Test configuration with the AOF enabled at a fresh temporary path
+fn aof_test_config(name: &str) -> ServerConfig {
+    let path = std::env::temp_dir().join(format!("rustredis-{}-{}.aof", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    ServerConfig {
+        disable_aof: false,
+        aof_path: path.to_string_lossy().into_owned(),
+        ..test_config()
+    }
+}
+
+#[tokio::test]
+async fn test_config_rewrite_persists_config_set() {
+    let path = std::env::temp_dir().join(format!("rustredis-{}.conf", std::process::id()));
+    let path = path.to_string_lossy().into_owned();
+    std::fs::write(
+        &path,
+        "# Test configuration\ndisable-aof yes\nmetrics-strategy disabled\nmaxclients 100\n",
+    )
+    .unwrap();
+    let mut config = ServerConfig::default();
+    config.load_file(&path).unwrap();
+    assert_eq!(config.maxclients, 100);
+
+    let server = start_server_with(config).await;
+    let mut client = connect(&server).await;
+    let ok = Frame::Simple("OK".to_string());
+
+    assert_eq!(
+        send(&mut client, &["CONFIG", "GET", "maxclients"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("maxclients")),
+            Frame::Bulk(Bytes::from("100")),
+        ])
+    );
+    assert_eq!(
+        send(
+            &mut client,
+            &[
+                "CONFIG",
+                "SET",
+                "maxclients",
+                "50",
+                "latency-monitor-threshold",
+                "5"
+            ]
+        )
+        .await,
+        ok
+    );
+    assert!(matches!(
+        send(&mut client, &["CONFIG", "SET", "aof-path", "other.aof"]).await,
+        Frame::Error(e) if e.contains("immutable")
+    ));
+    assert_eq!(send(&mut client, &["CONFIG", "REWRITE"]).await, ok);
+
+    // CONFIG is not queued in a transaction
+    assert_eq!(send(&mut client, &["MULTI"]).await, ok);
+    assert_eq!(
+        send(&mut client, &["CONFIG", "SET", "maxclients", "10"]).await,
+        Frame::error("ERR CONFIG inside MULTI is not allowed")
+    );
+    assert_eq!(send(&mut client, &["DISCARD"]).await, ok);
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert!(
+        contents.starts_with("# Test configuration\n"),
+        "{}",
+        contents
+    );
+    let mut reloaded = ServerConfig::default();
+    reloaded.load_file(&path).unwrap();
+    assert_eq!(reloaded.maxclients, 50);
+    assert_eq!(reloaded.latency_monitor_threshold_ms, 5);
+    assert!(reloaded.disable_aof);
+    let _ = std::fs::remove_file(&path);
+
+    // Without a config file there is nothing to rewrite
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    assert_eq!(
+        send(&mut client, &["CONFIG", "REWRITE"]).await,
+        Frame::error("ERR The server is running without a config file")
+    );
+}
+
+#[tokio::test]
+async fn test_default_ttl_applies_to_new_keys() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    let ok = Frame::Simple("OK".to_string());
+
+    assert_eq!(send(&mut client, &["SET", "before", "v"]).await, ok);
+    assert_eq!(
+        send(
+            &mut client,
+            &["CONFIG", "SET", "default-ttl-seconds", "100"]
+        )
+        .await,
+        ok
+    );
+    assert_eq!(send(&mut client, &["SET", "plain", "v"]).await, ok);
+    assert_eq!(
+        send(&mut client, &["SET", "explicit", "v", "EX", "5"]).await,
+        ok
+    );
+    assert_eq!(
+        send(&mut client, &["HSET", "hash", "f", "v"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        send(&mut client, &["LPUSH", "list", "a"]).await,
+        Frame::Integer(1)
+    );
+
+    assert_eq!(
+        send(&mut client, &["TTL", "plain"]).await,
+        Frame::Integer(100)
+    );
+    assert_eq!(
+        send(&mut client, &["TTL", "explicit"]).await,
+        Frame::Integer(5)
+    );
+    assert_eq!(
+        send(&mut client, &["TTL", "hash"]).await,
+        Frame::Integer(100)
+    );
+    assert_eq!(
+        send(&mut client, &["TTL", "list"]).await,
+        Frame::Integer(100)
+    );
+    assert_eq!(
+        send(&mut client, &["TTL", "before"]).await,
+        Frame::Integer(-1)
+    );
+}
+
+#[test]
+fn test_loglevel_warning_suppresses_info() {
+    let path = std::env::temp_dir().join(format!("rustredis-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig {
+        loglevel: LogLevel::Warning,
+        logfile: Some(path.to_string_lossy().into_owned()),
+        ..test_config()
+    };
+
+    tracing::subscriber::with_default(log_subscriber(&config).unwrap(), || {
+        info!("routine event");
+        warn!("unusual event");
+    });
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert!(log.contains("unusual event"), "{}", log);
+    assert!(!log.contains("routine event"), "{}", log);
+}
+
+#[tokio::test]
+async fn test_pipelined_writes_replay_in_order() {
+    let config = aof_test_config("pipeline");
+    let server = start_server_with(config.clone()).await;
+    let mut client = connect(&server).await;
+
+    // Send all three before reading any reply
+    for args in [["SET", "k", "1"].as_slice(), &["INCR", "k"], &["INCR", "k"]] {
+        let frame = Frame::Array(
+            args.iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        client.write_frame(&frame).await.unwrap();
+    }
+    for _ in 0..3 {
+        client.read_frame().await.unwrap().unwrap();
+    }
+
+    // A server started from the same AOF sees the replayed value
+    let restarted = start_server_with(config.clone()).await;
+    let mut client = connect(&restarted).await;
+    assert_eq!(
+        send(&mut client, &["GET", "k"]).await,
+        Frame::Bulk(Bytes::from("3"))
+    );
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_pipelined_writes_share_aof_writes() {
+    use tokio::io::AsyncWriteExt;
+
+    let config = aof_test_config("batch");
+    let server = start_server_with(config.clone()).await;
+
+    // 100 INCRs in a single TCP write, so the server reads them together
+    let mut pipeline = BytesMut::new();
+    for _ in 0..100 {
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("INCR")),
+            Frame::Bulk(Bytes::from("counter")),
+        ])
+        .encode(&mut pipeline);
+    }
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+    stream.write_all(&pipeline).await.unwrap();
+    let mut client = Connection::new(stream);
+    for i in 1..=100 {
+        assert_eq!(client.read_frame().await.unwrap(), Some(Frame::Integer(i)));
+    }
+    let writes = server.metrics.total_aof_writes();
+    assert!((1..=10).contains(&writes), "{} AOF writes", writes);
+
+    // Every write made it into the log
+    let restarted = start_server_with(config.clone()).await;
+    let mut client = connect(&restarted).await;
+    assert_eq!(
+        send(&mut client, &["GET", "counter"]).await,
+        Frame::Bulk(Bytes::from("100"))
+    );
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_evicted_keys_are_logged_and_announced() {
+    let config = ServerConfig {
+        notify_keyspace_events: NotifyFlags::parse("Ee").unwrap(),
+        ..aof_test_config("evict")
+    };
+    let server = start_server_with(config.clone()).await;
+    let mut subscriber = connect(&server).await;
+    let mut client = connect(&server).await;
+
+    send(&mut subscriber, &["SUBSCRIBE", "__keyevent@0__:evicted"]).await;
+    send(&mut client, &["SET", "k", "v"]).await;
+    send(
+        &mut client,
+        &["CONFIG", "SET", "maxmemory-policy", "allkeys-random"],
+    )
+    .await;
+    send(&mut client, &["CONFIG", "SET", "maxmemory", "1"]).await;
+
+    let event = tokio::time::timeout(Duration::from_secs(5), subscriber.read_frame())
+        .await
+        .expect("no evicted event")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        event,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("message")),
+            Frame::Bulk(Bytes::from("__keyevent@0__:evicted")),
+            Frame::Bulk(Bytes::from("k")),
+        ])
+    );
+
+    // The AOF has the key deleted, so it does not come back
+    let restarted = start_server_with(config.clone()).await;
+    let mut client = connect(&restarted).await;
+    assert_eq!(send(&mut client, &["GET", "k"]).await, Frame::Null);
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_aof_order_matches_execution_order() {
+    let config = aof_test_config("order");
+    let server = start_server_with(config.clone()).await;
+
+    // APPEND is order-sensitive: the final value spells out the order in
+    // which the writes were applied
+    let mut clients = Vec::new();
+    for i in 0..8 {
+        let mut client = connect(&server).await;
+        clients.push(tokio::spawn(async move {
+            for j in 0..50 {
+                send(&mut client, &["APPEND", "log", &format!("{}.{},", i, j)]).await;
+            }
+        }));
+    }
+    for client in clients {
+        client.await.unwrap();
+    }
+    let mut client = connect(&server).await;
+    let live = send(&mut client, &["GET", "log"]).await;
+
+    let replayed = Db::new();
+    for frame in Aof::load(&config.aof_path).unwrap() {
+        Command::from_frame(frame)
+            .unwrap()
+            .replay(&replayed)
+            .unwrap();
+    }
+    std::fs::remove_file(&config.aof_path).unwrap();
+    assert_eq!(Frame::Bulk(replayed.read_string(b"log").unwrap()), live);
+}
+
+#[tokio::test]
+async fn test_waitaof_waits_for_fsync() {
+    let config = aof_test_config("waitaof");
+    let server = start_server_with(config.clone()).await;
+    let mut client = connect(&server).await;
+    // Let the background sync's first, immediate fsync pass
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    send(&mut client, &["SET", "k", "v"]).await;
+    // Returns once the next background sync has covered the SET
+    let reply = tokio::time::timeout(
+        Duration::from_secs(5),
+        send(&mut client, &["WAITAOF", "1", "0", "0"]),
+    )
+    .await
+    .expect("WAITAOF did not return");
+    assert_eq!(
+        reply,
+        Frame::Array(vec![Frame::Integer(1), Frame::Integer(0)])
+    );
+    std::fs::remove_file(&config.aof_path).unwrap();
+
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    assert_eq!(
+        send(&mut client, &["WAITAOF", "1", "0", "0"]).await,
+        Frame::error("ERR WAITAOF cannot be used when numlocal is set but appendonly is disabled.")
+    );
+    assert_eq!(
+        send(&mut client, &["WAITAOF", "0", "0", "0"]).await,
+        Frame::Array(vec![Frame::Integer(0), Frame::Integer(0)])
+    );
+}
+
+#[tokio::test]
+async fn test_per_client_rate_limit_rejects_excess_commands() {
+    let server = start_server_with(ServerConfig {
+        per_client_rate_limit: RateLimit {
+            max_ops_per_sec: 10,
+            mode: RateLimitMode::Error,
+        },
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(&server).await;
+
+    // The first second's worth of commands goes through as a burst
+    for _ in 0..10 {
+        assert_eq!(
+            send(&mut client, &["PING"]).await,
+            Frame::Simple("PONG".to_string())
+        );
+    }
+    assert!(matches!(
+        send(&mut client, &["PING"]).await,
+        Frame::Error(e) if e.contains("max ops per second")
+    ));
+
+    // The limit is per connection
+    let mut other = connect(&server).await;
+    assert_eq!(
+        send(&mut other, &["PING"]).await,
+        Frame::Simple("PONG".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_blpop_waits_across_flushdb() {
+    let server = start_server().await;
+    let mut blocked = connect(&server).await;
+    let mut client = connect(&server).await;
+
+    assert_eq!(
+        send(&mut client, &["BLPOP", "missing", "0.05"]).await,
+        Frame::Null
+    );
+
+    send(&mut client, &["RPUSH", "queue", "old"]).await;
+    send(&mut client, &["LPOP", "queue"]).await;
+    let blpop = Frame::Array(vec![
+        Frame::Bulk(Bytes::from("BLPOP")),
+        Frame::Bulk(Bytes::from("queue")),
+        Frame::Bulk(Bytes::from("0")),
+    ]);
+    blocked.write_frame(&blpop).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // FLUSHDB neither wakes the client nor forgets it
+    assert_eq!(
+        send(&mut client, &["FLUSHDB"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), blocked.read_frame())
+            .await
+            .is_err()
+    );
+
+    send(&mut client, &["RPUSH", "queue", "new"]).await;
+    let reply = tokio::time::timeout(Duration::from_secs(5), blocked.read_frame())
+        .await
+        .expect("blocked client was not woken")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        reply,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("queue")),
+            Frame::Bulk(Bytes::from("new")),
+        ])
+    );
+    assert_eq!(
+        send(&mut client, &["LLEN", "queue"]).await,
+        Frame::Integer(0)
+    );
+}
+
+#[tokio::test]
+async fn test_blpop_huge_timeout() {
+    let server = start_server().await;
+    let mut blocked = connect(&server).await;
+    let mut client = connect(&server).await;
+
+    assert_eq!(
+        send(&mut client, &["BLPOP", "queue", "1e20"]).await,
+        Frame::Error("ERR timeout is out of range".to_string())
+    );
+
+    // Past what a deadline can hold, so it waits like a timeout of 0
+    let blpop = Frame::Array(vec![
+        Frame::Bulk(Bytes::from("BLPOP")),
+        Frame::Bulk(Bytes::from("queue")),
+        Frame::Bulk(Bytes::from("1e19")),
+    ]);
+    blocked.write_frame(&blpop).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    send(&mut client, &["RPUSH", "queue", "item"]).await;
+    let reply = tokio::time::timeout(Duration::from_secs(5), blocked.read_frame())
+        .await
+        .expect("blocked client was not woken")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        reply,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("queue")),
+            Frame::Bulk(Bytes::from("item")),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn test_active_expire_sweeps_every_database() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    async fn total_keys(client: &mut Connection) -> i64 {
+        let mut total = 0;
+        for index in 0..DEFAULT_DATABASES {
+            send(client, &["SELECT", &index.to_string()]).await;
+            match send(client, &["DBSIZE"]).await {
+                Frame::Integer(keys) => total += keys,
+                other => panic!("unexpected reply: {:?}", other),
+            }
+        }
+        total
+    }
+
+    async fn fill(client: &mut Connection, keys_per_db: usize) {
+        for index in 0..DEFAULT_DATABASES {
+            send(client, &["SELECT", &index.to_string()]).await;
+            for key in 0..keys_per_db {
+                send(client, &["PSETEX", &key.to_string(), "20", "v"]).await;
+            }
+        }
+    }
+
+    // Nothing is reclaimed while active expiration is off
+    send(&mut client, &["CONFIG", "SET", "active-expire", "no"]).await;
+    fill(&mut client, 300).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(
+        total_keys(&mut client).await,
+        300 * DEFAULT_DATABASES as i64
+    );
+
+    // More keys per database than one sweep step checks
+    send(&mut client, &["CONFIG", "SET", "active-expire", "yes"]).await;
+    let deadline = Instant::now() + Duration::from_secs(2);
+    while total_keys(&mut client).await > 0 {
+        assert!(Instant::now() < deadline, "expired keys were not reclaimed");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test]
+async fn test_info_reports_blocked_clients() {
+    let server = start_server().await;
+    let mut blocked = connect(&server).await;
+    let mut client = connect(&server).await;
+
+    async fn blocked_clients(client: &mut Connection) -> String {
+        let info = match send(client, &["INFO"]).await {
+            Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+            other => panic!("unexpected reply: {:?}", other),
+        };
+        info.lines()
+            .find_map(|line| line.strip_prefix("blocked_clients:"))
+            .unwrap()
+            .to_string()
+    }
+
+    assert_eq!(blocked_clients(&mut client).await, "0");
+    let blpop = Frame::Array(vec![
+        Frame::Bulk(Bytes::from("BLPOP")),
+        Frame::Bulk(Bytes::from("queue")),
+        Frame::Bulk(Bytes::from("0")),
+    ]);
+    blocked.write_frame(&blpop).await.unwrap();
+    for _ in 0..50 {
+        if blocked_clients(&mut client).await == "1" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(blocked_clients(&mut client).await, "1");
+
+    send(&mut client, &["RPUSH", "queue", "v"]).await;
+    tokio::time::timeout(Duration::from_secs(5), blocked.read_frame())
+        .await
+        .expect("blocked client was not woken")
+        .unwrap();
+    assert_eq!(blocked_clients(&mut client).await, "0");
+}
+
+#[tokio::test]
+#[cfg(target_os = "linux")]
+async fn test_failing_aof_refuses_writes() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let db = Db::new();
+
+    // Every write to /dev/full fails with ENOSPC
+    let aof = Arc::new(Aof::new("/dev/full", AofSyncPolicy::No).unwrap());
+    let handler_db = db.clone();
+    tokio::spawn(async move {
+        let (socket, peer_addr) = listener.accept().await.unwrap();
+        let _ = handle_connection(
+            socket,
+            peer_addr,
+            true,
+            vec![handler_db],
+            Some(aof),
+            PubSub::new(),
+            Metrics::new(),
+            CommandMetricsCollector::new(MetricsStrategy::Disabled),
+            IdempotencyCache::new(16, Duration::from_secs(60)),
+            OutputBufferLimit::default(),
+            RateLimit::default(),
+            Arc::new(RwLock::new(test_config())),
+            Acl::new(),
+        )
+        .await;
+    });
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    // The write whose append fails was already applied, like in Redis
+    assert_eq!(
+        send(&mut client, &["SET", "applied", "v"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert!(db.exists(b"applied"));
+
+    // Writes after the failure are refused and not applied
+    for _ in 0..2 {
+        match send(&mut client, &["SET", "k", "v"]).await {
+            Frame::Error(e) => assert!(
+                e.starts_with("MISCONF Errors writing to the AOF file"),
+                "{}",
+                e
+            ),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+    }
+    assert!(!db.exists(b"k"));
+
+    // Reads still work
+    assert_eq!(send(&mut client, &["GET", "k"]).await, Frame::Null);
+}
+
+#[tokio::test]
+async fn test_background_aof_load_serves_reads_and_refuses_writes() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = aof_test_config("loading");
+    let db = Db::new();
+    db.write_string(Bytes::from("loaded"), Bytes::from("v"), None);
+
+    // The replay has got as far as `loaded`
+    let aof = Arc::new(Aof::new(&config.aof_path, AofSyncPolicy::No).unwrap());
+    aof.set_loading(true);
+    let (handler_db, handler_aof) = (db.clone(), Arc::clone(&aof));
+    tokio::spawn(async move {
+        let (socket, peer_addr) = listener.accept().await.unwrap();
+        let _ = handle_connection(
+            socket,
+            peer_addr,
+            true,
+            vec![handler_db],
+            Some(handler_aof),
+            PubSub::new(),
+            Metrics::new(),
+            CommandMetricsCollector::new(MetricsStrategy::Disabled),
+            IdempotencyCache::new(16, Duration::from_secs(60)),
+            OutputBufferLimit::default(),
+            RateLimit::default(),
+            Arc::new(RwLock::new(test_config())),
+            Acl::new(),
+        )
+        .await;
+    });
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    assert_eq!(
+        send(&mut client, &["GET", "loaded"]).await,
+        Frame::Bulk(Bytes::from("v"))
+    );
+    assert_eq!(
+        send(&mut client, &["SET", "k", "v"]).await,
+        Frame::error(LOADING_ERR)
+    );
+    assert!(!db.exists(b"k"));
+
+    aof.set_loading(false);
+    assert_eq!(
+        send(&mut client, &["SET", "k", "v"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    let _ = std::fs::remove_file(&config.aof_path);
+}
+
+#[tokio::test]
+async fn test_aof_replays_on_top_of_snapshot() {
+    let rdb_path = std::env::temp_dir().join(format!("rustredis-base-{}.rdb", std::process::id()));
+    let config = ServerConfig {
+        rdb_path: rdb_path.to_string_lossy().into_owned(),
+        ..aof_test_config("base")
+    };
+
+    let db = Db::new();
+    db.write_string(Bytes::from("a"), Bytes::from("v"), None);
+    crate::snapshot::save(std::slice::from_ref(&db), &rdb_path).unwrap();
+    let aof = Aof::new(&config.aof_path, AofSyncPolicy::Always).unwrap();
+    for command in [&["FLUSHDB"][..], &["SET", "b", "v"]] {
+        let parts = command.iter().map(|part| Frame::Bulk(Bytes::from(*part)));
+        aof.append(&Frame::Array(parts.collect())).unwrap();
+    }
+    drop(aof);
+
+    let server = start_server_with(config.clone()).await;
+    let mut client = connect(&server).await;
+    assert_eq!(send(&mut client, &["EXISTS", "a"]).await, Frame::Integer(0));
+    assert_eq!(
+        send(&mut client, &["GET", "b"]).await,
+        Frame::Bulk(Bytes::from("v"))
+    );
+    let _ = std::fs::remove_file(&config.aof_path);
+    let _ = std::fs::remove_file(&rdb_path);
+}
+
+#[tokio::test]
+async fn test_aof_rewrite_keeps_writes_made_during_it() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = aof_test_config("rewrite");
+    let dbs = vec![Db::new(), Db::new()];
+    let aof = Arc::new(Aof::new(&config.aof_path, AofSyncPolicy::No).unwrap());
+    let (handler_dbs, handler_aof) = (dbs.clone(), Arc::clone(&aof));
+    tokio::spawn(async move {
+        let (socket, peer_addr) = listener.accept().await.unwrap();
+        let _ = handle_connection(
+            socket,
+            peer_addr,
+            true,
+            handler_dbs,
+            Some(handler_aof),
+            PubSub::new(),
+            Metrics::new(),
+            CommandMetricsCollector::new(MetricsStrategy::Disabled),
+            IdempotencyCache::new(16, Duration::from_secs(60)),
+            OutputBufferLimit::default(),
+            RateLimit::default(),
+            Arc::new(RwLock::new(test_config())),
+            Acl::new(),
+        )
+        .await;
+    });
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    send(&mut client, &["SET", "a", "old"]).await;
+    send(&mut client, &["SET", "a", "1"]).await;
+    send(&mut client, &["RPUSH", "l", "x"]).await;
+    send(&mut client, &["ZADD", "z", "1.5", "m"]).await;
+
+    // Drive the rewrite by hand so writes land between its steps
+    let rewrite = {
+        let _order = aof.lock_order().await;
+        aof.start_rewrite(&dbs).unwrap()
+    };
+    send(&mut client, &["RPUSH", "l", "y"]).await;
+    rewrite.write_base().unwrap();
+    send(&mut client, &["SELECT", "1"]).await;
+    send(&mut client, &["SADD", "s", "m"]).await;
+    assert_eq!(
+        send(&mut client, &["BGREWRITEAOF"]).await,
+        Frame::error("ERR Background append only file rewriting already in progress")
+    );
+    {
+        let _order = aof.lock_order().await;
+        aof.finish_rewrite(rewrite).unwrap();
+    }
+    send(&mut client, &["SET", "after", "v"]).await;
+
+    let contents = std::fs::read(&config.aof_path).unwrap();
+    assert!(!contents.windows(3).any(|window| window == b"old"));
+
+    let restored = vec![Db::new(), Db::new()];
+    replay_aof(&aof, &restored, &config.aof_path).unwrap();
+    assert_eq!(restored[0].read_string(b"a"), Some(Bytes::from("1")));
+    assert_eq!(
+        restored[0].lrange(b"l", 0, -1),
+        Some(vec![Bytes::from("x"), Bytes::from("y")])
+    );
+    assert_eq!(
+        restored[0]
+            .zscore(b"z", b"m")
+            .unwrap()
+            .map(|score| score.value()),
+        Some(1.5)
+    );
+    assert!(restored[1].sismember(b"s", b"m").unwrap());
+    assert_eq!(restored[1].read_string(b"after"), Some(Bytes::from("v")));
+
+    // The same through the command
+    assert_eq!(
+        send(&mut client, &["BGREWRITEAOF"]).await,
+        Frame::Simple("Background append only file rewriting started".to_string())
+    );
+    while aof.is_rewriting() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let restored = vec![Db::new(), Db::new()];
+    replay_aof(&aof, &restored, &config.aof_path).unwrap();
+    assert_eq!(restored[1].read_string(b"after"), Some(Bytes::from("v")));
+    assert_eq!(restored[0].dbsize(), 3);
+    let _ = std::fs::remove_file(&config.aof_path);
+}
+
+#[tokio::test]
+async fn test_set_nx_xx() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    assert_eq!(
+        send(&mut client, &["SET", "k", "1", "XX"]).await,
+        Frame::Null
+    );
+    assert_eq!(
+        send(&mut client, &["SET", "k", "1", "NX"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["SET", "k", "2", "NX"]).await,
+        Frame::Null
+    );
+    assert_eq!(
+        send(&mut client, &["SET", "k", "3", "XX"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["GET", "k"]).await,
+        Frame::Bulk(Bytes::from("3"))
+    );
+    assert_eq!(
+        send(&mut client, &["SET", "k", "4", "NX", "XX"]).await,
+        Frame::Error("ERR NX and XX options at the same time are not compatible".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_setex_and_psetex() {
+    let config = aof_test_config("setex");
+    let server = start_server_with(config.clone()).await;
+    let mut client = connect(&server).await;
+
+    assert_eq!(
+        send(&mut client, &["SETEX", "s", "100", "v"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["PSETEX", "p", "100000", "v"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    for key in ["s", "p"] {
+        assert_eq!(
+            send(&mut client, &["GET", key]).await,
+            Frame::Bulk(Bytes::from("v"))
+        );
+        match send(&mut client, &["PTTL", key]).await {
+            Frame::Integer(ms) => assert!(ms > 90_000 && ms <= 100_000, "{}", ms),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+    }
+
+    for (cmd, time) in [("SETEX", "0"), ("PSETEX", "-5")] {
+        assert_eq!(
+            send(&mut client, &[cmd, "bad", time, "v"]).await,
+            Frame::Error(format!(
+                "ERR invalid expire time in '{}' command",
+                cmd.to_lowercase()
+            ))
+        );
+    }
+    assert_eq!(
+        send(&mut client, &["EXISTS", "bad"]).await,
+        Frame::Integer(0)
+    );
+
+    // Logged with the absolute deadline, not the relative TTL
+    let logged = Aof::load(&config.aof_path).unwrap();
+    let names: Vec<_> = logged
+        .iter()
+        .map(|frame| match frame {
+            Frame::Array(args) => args[0].clone(),
+            other => panic!("unexpected frame: {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        names,
+        ["SET", "PEXPIREAT", "SET", "PEXPIREAT"].map(|name| Frame::Bulk(Bytes::from(name)))
+    );
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_set_and_getex_absolute_expiry() {
+    let config = aof_test_config("set-exat");
+    let server = start_server_with(config.clone()).await;
+    let mut client = connect(&server).await;
+    let now_secs = crate::clock::unix_time_ms() / 1000;
+    let ok = Frame::Simple("OK".to_string());
+
+    let future = (now_secs + 100).to_string();
+    assert_eq!(
+        send(&mut client, &["SET", "k", "v", "EXAT", &future]).await,
+        ok
+    );
+    match send(&mut client, &["TTL", "k"]).await {
+        Frame::Integer(secs) => assert!(secs > 90 && secs <= 100, "{}", secs),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+
+    // A deadline in the past sets the key and expires it right away
+    let past = (now_secs - 100).to_string();
+    assert_eq!(
+        send(&mut client, &["SET", "old", "v", "EXAT", &past]).await,
+        ok
+    );
+    assert_eq!(
+        send(&mut client, &["EXISTS", "old"]).await,
+        Frame::Integer(0)
+    );
+    assert_eq!(send(&mut client, &["DBSIZE"]).await, Frame::Integer(1));
+
+    let pxat = ((now_secs + 200) * 1000).to_string();
+    assert_eq!(
+        send(&mut client, &["GETEX", "k", "PXAT", &pxat]).await,
+        Frame::Bulk(Bytes::from("v"))
+    );
+    match send(&mut client, &["TTL", "k"]).await {
+        Frame::Integer(secs) => assert!(secs > 190 && secs <= 200, "{}", secs),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    assert_eq!(
+        send(&mut client, &["GETEX", "k", "PERSIST"]).await,
+        Frame::Bulk(Bytes::from("v"))
+    );
+    assert_eq!(send(&mut client, &["TTL", "k"]).await, Frame::Integer(-1));
+
+    for args in [
+        &["SET", "k", "v", "PX", "0"][..],
+        &["SET", "k", "v", "EXAT", "-1"],
+        &["GETEX", "k", "EX", "0"],
+    ] {
+        assert_eq!(
+            send(&mut client, args).await,
+            Frame::Error(format!(
+                "ERR invalid expire time in '{}' command",
+                args[0].to_lowercase()
+            ))
+        );
+    }
+    assert_eq!(
+        send(&mut client, &["SET", "k", "v", "EX", "10", "PX", "10"]).await,
+        Frame::error("ERR syntax error")
+    );
+
+    // Logged with absolute deadlines
+    let logged = Aof::load(&config.aof_path).unwrap();
+    let names: Vec<_> = logged
+        .iter()
+        .map(|frame| match frame {
+            Frame::Array(args) => args[0].clone(),
+            other => panic!("unexpected frame: {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        names,
+        ["SET", "PEXPIREAT", "SET", "PEXPIREAT", "PEXPIREAT", "GETEX"]
+            .map(|name| Frame::Bulk(Bytes::from(name)))
+    );
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_maxclients_rejects_extra_connections() {
+    let server = start_server_with(ServerConfig {
+        maxclients: 1,
+        ..test_config()
+    })
+    .await;
+    let mut first = connect(&server).await;
+    assert_eq!(
+        send(&mut first, &["PING"]).await,
+        Frame::Simple("PONG".to_string())
+    );
+
+    let mut second = connect(&server).await;
+    assert_eq!(
+        second.read_frame().await.unwrap(),
+        Some(Frame::Error(
+            "ERR max number of clients reached".to_string()
+        ))
+    );
+    assert_eq!(second.read_frame().await.unwrap(), None);
+
+    // The slot is freed once the first client disconnects
+    drop(first);
+    for _ in 0..100 {
+        if server.metrics.active_connections() == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let mut third = connect(&server).await;
+    assert_eq!(
+        send(&mut third, &["PING"]).await,
+        Frame::Simple("PONG".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_zset_type_and_encoding() {
+    let server = start_server_with(ServerConfig {
+        zset_max_listpack_entries: 2,
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(&server).await;
+    let encoding = |name: &str| Frame::Bulk(Bytes::from(name.to_string()));
+
+    assert_eq!(
+        send(&mut client, &["ZADD", "small", "2", "b", "1", "a"]).await,
+        Frame::Integer(2)
+    );
+    assert_eq!(
+        send(&mut client, &["TYPE", "small"]).await,
+        Frame::Simple("zset".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["OBJECT", "ENCODING", "small"]).await,
+        encoding("listpack")
+    );
+
+    assert_eq!(
+        send(
+            &mut client,
+            &["ZADD", "large", "3", "c", "1", "a", "2", "b"]
+        )
+        .await,
+        Frame::Integer(3)
+    );
+    assert_eq!(
+        send(&mut client, &["TYPE", "large"]).await,
+        Frame::Simple("zset".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["OBJECT", "ENCODING", "large"]).await,
+        encoding("skiplist")
+    );
+
+    // Both encodings keep members in score order
+    for key in ["small", "large"] {
+        assert_eq!(
+            send(&mut client, &["ZRANGE", key, "0", "1", "WITHSCORES"]).await,
+            Frame::Array(vec![
+                Frame::Bulk(Bytes::from("a")),
+                Frame::Bulk(Bytes::from("1")),
+                Frame::Bulk(Bytes::from("b")),
+                Frame::Bulk(Bytes::from("2")),
+            ])
+        );
+    }
+
+    // Growing past the threshold converts the small one too
+    assert_eq!(
+        send(&mut client, &["ZADD", "small", "0.5", "c"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        send(&mut client, &["OBJECT", "ENCODING", "small"]).await,
+        encoding("skiplist")
+    );
+    assert_eq!(
+        send(&mut client, &["ZSCORE", "small", "c"]).await,
+        Frame::Bulk(Bytes::from("0.5"))
+    );
+    assert_eq!(
+        send(&mut client, &["ZREM", "small", "c", "x"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        send(&mut client, &["ZCARD", "small"]).await,
+        Frame::Integer(2)
+    );
+}
+
+#[tokio::test]
+async fn test_zrangebylex_and_zlexcount() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    send(
+        &mut client,
+        &["ZADD", "z", "0", "apple", "0", "apricot", "0", "banana"],
+    )
+    .await;
+
+    assert_eq!(
+        send(&mut client, &["ZRANGEBYLEX", "z", "[ap", "(aq"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("apple")),
+            Frame::Bulk(Bytes::from("apricot")),
+        ])
+    );
+    assert_eq!(
+        send(
+            &mut client,
+            &["ZRANGEBYLEX", "z", "-", "+", "limit", "1", "1"]
+        )
+        .await,
+        Frame::Array(vec![Frame::Bulk(Bytes::from("apricot"))])
+    );
+    assert_eq!(
+        send(&mut client, &["ZLEXCOUNT", "z", "(apple", "+"]).await,
+        Frame::Integer(2)
+    );
+
+    let invalid = Frame::error("ERR min or max not valid string range item");
+    assert_eq!(
+        send(&mut client, &["ZLEXCOUNT", "z", "a", "+"]).await,
+        invalid
+    );
+    assert_eq!(
+        send(&mut client, &["ZRANGEBYLEX", "z", "-", "b"]).await,
+        invalid
+    );
+    assert_eq!(
+        send(
+            &mut client,
+            &["ZRANGEBYLEX", "z", "-", "+", "OFFSET", "0", "1"]
+        )
+        .await,
+        Frame::error("ERR syntax error")
+    );
+    for partial in [&["LIMIT"][..], &["LIMIT", "0"]] {
+        let mut args = vec!["ZRANGEBYLEX", "z", "-", "+"];
+        args.extend_from_slice(partial);
+        assert_eq!(
+            send(&mut client, &args).await,
+            Frame::error("ERR syntax error")
+        );
+    }
+    send(&mut client, &["SET", "s", "v"]).await;
+    assert_eq!(
+        send(&mut client, &["ZLEXCOUNT", "s", "-", "+"]).await,
+        Frame::error(crate::db::WRONGTYPE_ERR)
+    );
+}
+
+#[tokio::test]
+async fn test_binary_safe_keys() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    let key: &[u8] = b"bin\xff\x00key";
+
+    assert_eq!(
+        send_bytes(&mut client, &[b"SET", key, b"v"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert_eq!(
+        send_bytes(&mut client, &[b"GET", key]).await,
+        Frame::Bulk(Bytes::from("v"))
+    );
+    assert_eq!(
+        send(&mut client, &["KEYS", "bin*"]).await,
+        Frame::Array(vec![Frame::Bulk(Bytes::copy_from_slice(key))])
+    );
+    // Differs from `key` only in a byte that lossy UTF-8 decoding would erase
+    assert_eq!(
+        send_bytes(&mut client, &[b"GET", b"bin\xfe\x00key"]).await,
+        Frame::Null
+    );
+    // Patterns are binary-safe too
+    let matched = Frame::Array(vec![Frame::Bulk(Bytes::copy_from_slice(key))]);
+    assert_eq!(
+        send_bytes(&mut client, &[b"KEYS", b"bin\xff*"]).await,
+        matched
+    );
+    assert_eq!(
+        send_bytes(&mut client, &[b"KEYS", b"bin\xfe*"]).await,
+        Frame::Array(vec![])
+    );
+    assert_eq!(
+        send_bytes(&mut client, &[b"COUNTKEYS", b"bin\xff*"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        send_bytes(&mut client, &[b"SCAN", b"0", b"MATCH", b"bin\xff*"]).await,
+        Frame::Array(vec![Frame::Bulk(Bytes::from("0")), matched])
+    );
+    assert_eq!(
+        send_bytes(&mut client, &[b"DEL", key]).await,
+        Frame::Integer(1)
+    );
+}
+
+#[tokio::test]
+async fn test_binary_safe_set_members_and_hash_fields() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    let member: &[u8] = b"m\xff\x00";
+    let field: &[u8] = b"f\xfe\x00";
+
+    assert_eq!(
+        send_bytes(&mut client, &[b"SADD", b"set", member]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        send_bytes(&mut client, &[b"SISMEMBER", b"set", member]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        send(&mut client, &["SMEMBERS", "set"]).await,
+        Frame::Array(vec![Frame::Bulk(Bytes::copy_from_slice(member))])
+    );
+
+    assert_eq!(
+        send_bytes(&mut client, &[b"HSET", b"hash", field, b"v"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        send_bytes(&mut client, &[b"HGET", b"hash", field]).await,
+        Frame::Bulk(Bytes::from("v"))
+    );
+    assert_eq!(
+        send(&mut client, &["HGETALL", "hash"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::copy_from_slice(field)),
+            Frame::Bulk(Bytes::from("v")),
+        ])
+    );
+
+    assert_eq!(
+        send_bytes(&mut client, &[b"SREM", b"set", member]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        send_bytes(&mut client, &[b"HDEL", b"hash", field]).await,
+        Frame::Integer(1)
+    );
+}
+
+#[tokio::test]
+async fn test_retained_channel_replays_backlog_to_new_subscribers() {
+    let server = start_server().await;
+    let mut publisher = connect(&server).await;
+    let mut subscriber = connect(&server).await;
+
+    let message = |payload: &str| {
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("message")),
+            Frame::Bulk(Bytes::from("news")),
+            Frame::Bulk(Bytes::from(payload.to_string())),
+        ])
+    };
+
+    assert_eq!(
+        send(&mut publisher, &["PUBSUB", "RETAIN", "news", "2"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    for payload in ["a", "b", "c"] {
+        assert_eq!(
+            send(&mut publisher, &["PUBLISH", "news", payload]).await,
+            Frame::Integer(0)
+        );
+    }
+
+    assert_eq!(
+        send(&mut subscriber, &["SUBSCRIBE", "news"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("subscribe")),
+            Frame::Bulk(Bytes::from("news")),
+            Frame::Integer(1),
+        ])
+    );
+    assert_eq!(
+        subscriber.read_frame().await.unwrap().unwrap(),
+        message("b")
+    );
+    assert_eq!(
+        subscriber.read_frame().await.unwrap().unwrap(),
+        message("c")
+    );
+
+    // Live messages follow the backlog
+    assert_eq!(
+        send(&mut publisher, &["PUBLISH", "news", "d"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        subscriber.read_frame().await.unwrap().unwrap(),
+        message("d")
+    );
+}
+
+#[tokio::test]
+async fn test_spublish_reaches_only_shard_subscribers() {
+    let server = start_server().await;
+    let mut shard = connect(&server).await;
+    let mut regular = connect(&server).await;
+    let mut publisher = connect(&server).await;
+
+    assert_eq!(
+        send(&mut shard, &["SSUBSCRIBE", "news"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("ssubscribe")),
+            Frame::Bulk(Bytes::from("news")),
+            Frame::Integer(1),
+        ])
+    );
+    send(&mut regular, &["SUBSCRIBE", "news"]).await;
+
+    assert_eq!(
+        send(&mut publisher, &["SPUBLISH", "news", "sharded"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        shard.read_frame().await.unwrap().unwrap(),
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("smessage")),
+            Frame::Bulk(Bytes::from("news")),
+            Frame::Bulk(Bytes::from("sharded")),
+        ])
+    );
+
+    // The regular subscriber's next push is the PUBLISH, not the SPUBLISH
+    assert_eq!(
+        send(&mut publisher, &["PUBLISH", "news", "plain"]).await,
+        Frame::Integer(1)
+    );
+    assert_eq!(
+        regular.read_frame().await.unwrap().unwrap(),
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("message")),
+            Frame::Bulk(Bytes::from("news")),
+            Frame::Bulk(Bytes::from("plain")),
+        ])
+    );
+
+    assert_eq!(
+        send(&mut shard, &["SUNSUBSCRIBE"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("sunsubscribe")),
+            Frame::Bulk(Bytes::from("news")),
+            Frame::Integer(0),
+        ])
+    );
+    assert_eq!(
+        send(&mut shard, &["PING"]).await,
+        Frame::Simple("PONG".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_acl_user_limited_to_read_category() {
+    let server = start_server().await;
+    let mut admin = connect(&server).await;
+    let mut client = connect(&server).await;
+
+    let ok = Frame::Simple("OK".to_string());
+    assert_eq!(
+        send(
+            &mut admin,
+            &["ACL", "SETUSER", "reader", "on", ">pw", "+@read"]
+        )
+        .await,
+        ok
+    );
+    assert_eq!(send(&mut admin, &["SET", "k", "v"]).await, ok);
+    match send(&mut admin, &["ACL", "CAT", "read"]).await {
+        Frame::Array(names) => assert!(names.contains(&Frame::Bulk(Bytes::from("get")))),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    assert_eq!(
+        send(&mut admin, &["ACL", "CAT", "nosuch"]).await,
+        Frame::error("ERR Unknown category 'nosuch'")
+    );
+
+    assert_eq!(
+        send(&mut client, &["AUTH", "reader", "wrong"]).await,
+        Frame::error(WRONGPASS_ERR)
+    );
+    assert_eq!(send(&mut client, &["AUTH", "reader", "pw"]).await, ok);
+    assert_eq!(
+        send(&mut client, &["GET", "k"]).await,
+        Frame::Bulk(Bytes::from("v"))
+    );
+    assert_eq!(
+        send(&mut client, &["SET", "k", "w"]).await,
+        Frame::error("NOPERM User reader has no permissions to run the 'set' command")
+    );
+    assert_eq!(
+        send(&mut admin, &["GET", "k"]).await,
+        Frame::Bulk(Bytes::from("v"))
+    );
+
+    // RESET logs back in as the default user
+    send(&mut client, &["RESET"]).await;
+    assert_eq!(
+        send(&mut client, &["ACL", "WHOAMI"]).await,
+        Frame::Bulk(Bytes::from("default"))
+    );
+}
+
+#[tokio::test]
+async fn test_client_reply_off_and_skip() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = start_server().await;
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+
+    // Nothing comes back until replies are turned on again
+    stream
+        .write_all(b"CLIENT REPLY OFF\r\nSET a 1\r\nSET b 2\r\nBADCOMMAND\r\nCLIENT REPLY ON\r\n")
+        .await
+        .unwrap();
+    let mut reply = [0u8; 5];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"+OK\r\n");
+
+    // SKIP drops just the next reply
+    stream
+        .write_all(b"CLIENT REPLY SKIP\r\nGET a\r\nGET b\r\nPING\r\n")
+        .await
+        .unwrap();
+    let mut reply = [0u8; 14];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"$1\r\n2\r\n+PONG\r\n");
+}
+
+#[tokio::test]
+async fn test_client_info_reflects_selected_db_and_name() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    assert_eq!(
+        send(&mut client, &["SELECT", "2"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["CLIENT", "SETNAME", "worker-1"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["CLIENT", "GETNAME"]).await,
+        Frame::Bulk(Bytes::from("worker-1"))
+    );
+
+    let info = match send(&mut client, &["CLIENT", "INFO"]).await {
+        Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    let fields: Vec<&str> = info.trim_end().split(' ').collect();
+    assert!(fields.contains(&"db=2"), "{}", info);
+    assert!(fields.contains(&"name=worker-1"), "{}", info);
+    assert!(fields.contains(&"multi=-1"), "{}", info);
+    assert!(fields.contains(&"cmd=client"), "{}", info);
+    // SELECT, SETNAME, GETNAME and INFO itself
+    assert!(fields.contains(&"tot-cmds=4"), "{}", info);
+
+    // Keys set in db 2 are not visible from db 0
+    send(&mut client, &["SET", "k", "v"]).await;
+    send(&mut client, &["SELECT", "0"]).await;
+    assert_eq!(send(&mut client, &["GET", "k"]).await, Frame::Null);
+    assert_eq!(
+        send(&mut client, &["SELECT", "16"]).await,
+        Frame::error("ERR DB index is out of range")
+    );
+}
+
+#[tokio::test]
+async fn test_noop_writes_are_not_logged() {
+    let config = aof_test_config("noop");
+    let server = start_server_with(config.clone()).await;
+    let mut client = connect(&server).await;
+    let aof_len = || std::fs::metadata(&config.aof_path).unwrap().len();
+
+    send(&mut client, &["SET", "k", "v"]).await;
+    let before = aof_len();
+    assert_eq!(
+        send(&mut client, &["DEL", "missing"]).await,
+        Frame::Integer(0)
+    );
+    assert_eq!(
+        send(&mut client, &["EXPIRE", "missing", "10"]).await,
+        Frame::Integer(0)
+    );
+    assert_eq!(aof_len(), before);
+
+    assert_eq!(send(&mut client, &["DEL", "k"]).await, Frame::Integer(1));
+    assert!(aof_len() > before);
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_lcs_len_and_idx() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+    send(&mut client, &["SET", "key1", "ohmytext"]).await;
+    send(&mut client, &["SET", "key2", "mynewtext"]).await;
+
+    assert_eq!(
+        send(&mut client, &["LCS", "key1", "key2"]).await,
+        Frame::Bulk(Bytes::from("mytext"))
+    );
+    assert_eq!(
+        send(&mut client, &["LCS", "key1", "key2", "LEN"]).await,
+        Frame::Integer(6)
+    );
+
+    let range = |start, end| Frame::Array(vec![Frame::Integer(start), Frame::Integer(end)]);
+    assert_eq!(
+        send(
+            &mut client,
+            &[
+                "LCS",
+                "key1",
+                "key2",
+                "IDX",
+                "MINMATCHLEN",
+                "4",
+                "WITHMATCHLEN"
+            ]
+        )
+        .await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("matches")),
+            Frame::Array(vec![Frame::Array(vec![
+                range(4, 7),
+                range(5, 8),
+                Frame::Integer(4),
+            ])]),
+            Frame::Bulk(Bytes::from("len")),
+            Frame::Integer(6),
+        ])
+    );
+    assert_eq!(
+        send(&mut client, &["LCS", "key1", "key2", "IDX"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("matches")),
+            Frame::Array(vec![
+                Frame::Array(vec![range(4, 7), range(5, 8)]),
+                Frame::Array(vec![range(2, 3), range(0, 1)]),
+            ]),
+            Frame::Bulk(Bytes::from("len")),
+            Frame::Integer(6),
+        ])
+    );
+
+    // Missing keys are empty strings; inputs over the size cap are refused
+    assert_eq!(
+        send(&mut client, &["LCS", "key1", "missing", "LEN"]).await,
+        Frame::Integer(0)
+    );
+    let big = "x".repeat(5000);
+    send(&mut client, &["SET", "big1", &big]).await;
+    send(&mut client, &["SET", "big2", &big]).await;
+    assert!(matches!(
+        send(&mut client, &["LCS", "big1", "big2"]).await,
+        Frame::Error(_)
+    ));
+}
+
+#[tokio::test]
+async fn test_protected_mode_denies_non_loopback_peers() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // The sockets are local; the handler is told they come from these
+    let peers: [SocketAddr; 3] = [
+        "10.1.2.3:40000".parse().unwrap(),
+        "127.0.0.1:40001".parse().unwrap(),
+        "10.1.2.3:40002".parse().unwrap(),
+    ];
+    tokio::spawn(async move {
+        for peer_addr in peers {
+            let acl = Acl::new();
+            if peer_addr.port() == 40002 {
+                acl.set_user("default", &[">secret".to_string()]).unwrap();
+            }
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(handle_connection(
+                socket,
+                peer_addr,
+                true,
+                vec![Db::new()],
+                None,
+                PubSub::new(),
+                Metrics::new(),
+                CommandMetricsCollector::new(MetricsStrategy::Disabled),
+                IdempotencyCache::new(16, Duration::from_secs(60)),
+                OutputBufferLimit::default(),
+                RateLimit::default(),
+                Arc::new(RwLock::new(test_config())),
+                acl,
+            ));
+        }
+    });
+
+    // Denied right away, before sending anything
+    let mut remote = Connection::new(TcpStream::connect(addr).await.unwrap());
+    match remote.read_frame().await.unwrap() {
+        Some(Frame::Error(e)) => assert!(e.starts_with("DENIED"), "{}", e),
+        other => panic!("unexpected reply: {:?}", other),
+    }
+    assert!(remote.read_frame().await.unwrap().is_none());
+
+    let mut local = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(
+        send(&mut local, &["PING"]).await,
+        Frame::Simple("PONG".to_string())
+    );
+
+    // With a password set, remote clients are left to authenticate
+    let mut remote = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(
+        send(&mut remote, &["AUTH", "secret"]).await,
+        Frame::Simple("OK".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_pipelined_gets_share_one_lock() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(ServerConfig {
+        active_expire: false,
+        ..test_config()
+    });
+    let db = server.db().clone();
+    tokio::spawn(server.run(listener, std::future::pending()));
+    db.write_string(Bytes::from("k"), Bytes::from("v"), None);
+
+    // Sent in one write so the whole run is buffered when the first GET is read
+    let command = |args: &[&str]| {
+        Frame::Array(
+            args.iter()
+                .map(|arg| Frame::Bulk(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        )
+    };
+    let mut pipeline = BytesMut::new();
+    for key in ["k", "missing", "k"].repeat(20) {
+        command(&["GET", key]).encode(&mut pipeline);
+    }
+    command(&["PING"]).encode(&mut pipeline);
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    // Once a reply arrives the server is done with its startup work
+    stream.write_all(b"PING\r\n").await.unwrap();
+    let mut pong = [0; 7];
+    stream.read_exact(&mut pong).await.unwrap();
+    assert_eq!(&pong, b"+PONG\r\n");
+
+    let locks = db.lock_count();
+    stream.write_all(&pipeline).await.unwrap();
+    let mut client = Connection::new(stream);
+    for key in ["k", "missing", "k"].repeat(20) {
+        let expected = match key {
+            "k" => Frame::Bulk(Bytes::from("v")),
+            _ => Frame::Null,
+        };
+        assert_eq!(client.read_frame().await.unwrap().unwrap(), expected);
+    }
+    // The command after the run is still served
+    assert_eq!(
+        client.read_frame().await.unwrap().unwrap(),
+        Frame::Simple("PONG".to_string())
+    );
+    assert_eq!(db.lock_count() - locks, 1);
+}
+
+#[tokio::test]
+async fn test_change_observer_sees_writes() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+
+    let server = Server::new(test_config());
+    let seen = Arc::clone(&events);
+    let db = server.db().clone();
+    server.set_change_observer(Some(Arc::new(move |event: &ChangeEvent| {
+        // Called outside the database lock, so the database can be read
+        let value = event.key.as_ref().and_then(|key| db.read_string(key));
+        seen.lock().unwrap().push((event.clone(), value));
+    })));
+    tokio::spawn(server.run(listener, std::future::pending()));
+
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    send(&mut client, &["SET", "k", "v"]).await;
+    send(&mut client, &["GET", "k"]).await;
+    send(&mut client, &["DEL", "k"]).await;
+    // No-ops are not reported
+    send(&mut client, &["DEL", "k"]).await;
+    send(&mut client, &["SELECT", "3"]).await;
+    send(&mut client, &["MULTI"]).await;
+    send(&mut client, &["RPUSH", "list", "a"]).await;
+    send(&mut client, &["EXEC"]).await;
+
+    let event = |command, key: &'static str, db| ChangeEvent {
+        command,
+        key: Some(Bytes::from(key)),
+        db,
+    };
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            (event("SET", "k", 0), Some(Bytes::from("v"))),
+            (event("DEL", "k", 0), None),
+            (event("RPUSH", "list", 3), None),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_incrbyfloat() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    assert_eq!(
+        send(&mut client, &["INCRBYFLOAT", "f", "1.5"]).await,
+        Frame::Bulk(Bytes::from("1.5"))
+    );
+    assert_eq!(
+        send(&mut client, &["INCRBYFLOAT", "f", "1.5e0"]).await,
+        Frame::Bulk(Bytes::from("3"))
+    );
+    assert_eq!(
+        send(&mut client, &["INCRBYFLOAT", "f", "nan"]).await,
+        Frame::Error("ERR value is not a valid float".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["INCRBYFLOAT", "f", "inf"]).await,
+        Frame::Error("ERR increment would produce NaN or Infinity".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["GET", "f"]).await,
+        Frame::Bulk(Bytes::from("3"))
+    );
+}
+
+#[tokio::test]
+async fn test_restarted_server_rebinds_its_port() {
+    let addr: SocketAddr = ([127, 0, 0, 1], 0).into();
+    let server = Server::new(test_config());
+    let listener = server.bind(addr).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    let handle = tokio::spawn(server.run(listener, async {
+        let _ = shutdown_rx.await;
+    }));
+
+    // Leave a closed connection behind in TIME_WAIT
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(
+        send(&mut client, &["PING"]).await,
+        Frame::Simple("PONG".to_string())
+    );
+    shutdown_tx.send(()).unwrap();
+    handle.await.unwrap().unwrap();
+    drop(client);
+
+    let server = Server::new(test_config());
+    let listener = server.bind(addr).unwrap();
+    tokio::spawn(server.run(listener, std::future::pending()));
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(
+        send(&mut client, &["PING"]).await,
+        Frame::Simple("PONG".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_spop_is_propagated_as_srem() {
+    let config = aof_test_config("spop");
+    let server = start_server_with(config.clone()).await;
+    let mut replica = server.metrics.replication().attach_replica();
+    let mut client = connect(&server).await;
+
+    send(&mut client, &["SADD", "s", "a", "b", "c", "d"]).await;
+    let popped = match send(&mut client, &["SPOP", "s"]).await {
+        Frame::Bulk(member) => vec![member],
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    let popped_two = match send(&mut client, &["SPOP", "s", "2"]).await {
+        Frame::Array(members) => members,
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    assert_eq!(popped_two.len(), 2);
+    // Nothing popped, nothing propagated
+    send(&mut client, &["SPOP", "missing"]).await;
+
+    let mut received = BytesMut::new();
+    while let Ok(chunk) = replica.try_recv() {
+        received.extend_from_slice(&chunk);
+    }
+    let mut streamed = Vec::new();
+    while let Some(frame) = Frame::parse(&mut received).unwrap() {
+        streamed.push(frame);
+    }
+    let command = |args: Vec<Frame>| Frame::Array(args);
+    let bulk = |arg: &'static str| Frame::Bulk(Bytes::from(arg));
+    let mut first_srem = vec![bulk("SREM"), bulk("s")];
+    first_srem.extend(popped.into_iter().map(Frame::Bulk));
+    let mut second_srem = vec![bulk("SREM"), bulk("s")];
+    second_srem.extend(popped_two);
+    assert_eq!(
+        streamed,
+        vec![
+            command(vec![bulk("SELECT"), bulk("0")]),
+            command(vec![
+                bulk("SADD"),
+                bulk("s"),
+                bulk("a"),
+                bulk("b"),
+                bulk("c"),
+                bulk("d")
+            ]),
+            command(first_srem),
+            command(second_srem),
+        ]
+    );
+
+    // The AOF records the same commands; it starts out in database 0
+    let logged = Aof::load(&config.aof_path).unwrap();
+    assert_eq!(logged, streamed[1..]);
+
+    // A replica applying it ends up with the master's data
+    let copy = Db::new();
+    for frame in &streamed[1..] {
+        Command::from_frame(frame.clone())
+            .unwrap()
+            .replay(&copy)
+            .unwrap();
+    }
+    let remaining = match send(&mut client, &["SMEMBERS", "s"]).await {
+        Frame::Array(members) => members,
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    assert_eq!(
+        copy.smembers(b"s")
+            .unwrap()
+            .into_iter()
+            .map(Frame::Bulk)
+            .collect::<Vec<_>>(),
+        remaining
+    );
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_wrong_number_of_arguments() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    for args in [
+        &["GET"][..],
+        &["GET", "a", "b"],
+        &["DEL"],
+        &["MSET", "k"],
+        &["MSET", "k", "v", "k2"],
+        &["ZADD", "k", "1", "m", "2"],
+        &["INCRBYFLOAT", "k"],
+        &["idempotent", "token"],
+    ] {
+        assert_eq!(
+            send(&mut client, args).await,
+            Frame::error(format!(
+                "ERR wrong number of arguments for '{}' command",
+                args[0].to_lowercase()
+            )),
+            "{:?}",
+            args
+        );
+    }
+    assert_eq!(
+        send(&mut client, &["MSET", "k", "v", "k2", "v2"]).await,
+        Frame::Simple("OK".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_too_many_arguments_are_refused() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    let mut args: Vec<&[u8]> = vec![b"MSET"];
+    args.resize(table::MAX_VARIADIC_ARGS + 2, b"x");
+    assert_eq!(
+        send_bytes(&mut client, &args).await,
+        Frame::error("ERR Too many arguments")
+    );
+    assert_eq!(
+        send(&mut client, &["PING", "a", "b"]).await,
+        Frame::error("ERR wrong number of arguments for 'ping' command")
+    );
+    assert_eq!(send(&mut client, &["DBSIZE"]).await, Frame::Integer(0));
+}
+
+#[tokio::test]
+async fn test_pubsub_message_size_limit() {
+    let server = start_server_with(ServerConfig {
+        proto_max_pubsub_message_size: 8,
+        ..test_config()
+    })
+    .await;
+    let mut subscriber = connect(&server).await;
+    let mut publisher = connect(&server).await;
+    send(&mut subscriber, &["SUBSCRIBE", "news"]).await;
+
+    for command in ["PUBLISH", "SPUBLISH"] {
+        assert_eq!(
+            send(&mut publisher, &[command, "news", "123456789"]).await,
+            Frame::error(pubsub::MESSAGE_TOO_BIG_ERR)
+        );
+    }
+    assert_eq!(
+        send(&mut publisher, &["PUBLISH", "news", "12345678"]).await,
+        Frame::Integer(1)
+    );
+    // The refused message never reached the subscriber
+    assert_eq!(
+        subscriber.read_frame().await.unwrap().unwrap(),
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("message")),
+            Frame::Bulk(Bytes::from("news")),
+            Frame::Bulk(Bytes::from("12345678")),
+        ])
+    );
+
+    send(
+        &mut publisher,
+        &["CONFIG", "SET", "proto-max-pubsub-message-size", "0"],
+    )
+    .await;
+    assert_eq!(
+        send(&mut publisher, &["PUBLISH", "news", "123456789"]).await,
+        Frame::Integer(1)
+    );
+}
+
+#[tokio::test]
+async fn test_debug_error_and_panic() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    assert_eq!(
+        send(&mut client, &["DEBUG", "ERROR", "CUSTOM oops"]).await,
+        Frame::error("CUSTOM oops")
+    );
+    assert_eq!(
+        send(&mut client, &["DEBUG", "ERROR", "two\r\nlines"]).await,
+        Frame::error("two  lines")
+    );
+    assert_eq!(
+        send(&mut client, &["DEBUG", "SLEEP", "1e20"]).await,
+        Frame::error("ERR value is out of range")
+    );
+    assert_eq!(
+        send(&mut client, &["DEBUG", "SLEEP", "inf"]).await,
+        Frame::error("ERR value is not a valid float")
+    );
+
+    // Only this connection goes away
+    if cfg!(debug_assertions) {
+        let frame = Frame::Array(vec![
+            Frame::Bulk(Bytes::from("DEBUG")),
+            Frame::Bulk(Bytes::from("PANIC")),
+        ]);
+        client.write_frame(&frame).await.unwrap();
+        client.flush().await.unwrap();
+        // Closed or reset, but no reply
+        assert!(!matches!(client.read_frame().await, Ok(Some(_))));
+        let mut other = connect(&server).await;
+        assert_eq!(
+            send(&mut other, &["PING"]).await,
+            Frame::Simple("PONG".to_string())
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_reject_empty_keys() {
+    let server = start_server_with(ServerConfig {
+        reject_empty_keys: true,
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(&server).await;
+    let refused = Frame::error("ERR empty keys are not allowed (reject-empty-keys is on)");
+
+    assert_eq!(send(&mut client, &["SET", "", "x"]).await, refused);
+    assert_eq!(
+        send(&mut client, &["MSET", "a", "1", "", "2"]).await,
+        refused
+    );
+    assert_eq!(
+        send(&mut client, &["IDEMPOTENT", "t1", "SET", "", "x"]).await,
+        refused
+    );
+    // Reads and writes to other keys are unaffected
+    assert_eq!(send(&mut client, &["GET", ""]).await, Frame::Null);
+    assert_eq!(send(&mut client, &["EXISTS", "a"]).await, Frame::Integer(0));
+
+    send(&mut client, &["CONFIG", "SET", "reject-empty-keys", "no"]).await;
+    assert_eq!(
+        send(&mut client, &["SET", "", "x"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["GET", ""]).await,
+        Frame::Bulk(Bytes::from("x"))
+    );
+}
+
+#[tokio::test]
+async fn test_pubsub_message_sequence() {
+    let server = start_server_with(ServerConfig {
+        pubsub_message_sequence: true,
+        ..test_config()
+    })
+    .await;
+    let mut subscriber = connect(&server).await;
+    let mut publisher = connect(&server).await;
+    send(&mut subscriber, &["SUBSCRIBE", "news"]).await;
+
+    for message in ["first", "second"] {
+        send(&mut publisher, &["PUBLISH", "news", message]).await;
+    }
+    for (message, sequence) in [("first", 1), ("second", 2)] {
+        assert_eq!(
+            subscriber.read_frame().await.unwrap().unwrap(),
+            Frame::Array(vec![
+                Frame::Bulk(Bytes::from("message")),
+                Frame::Bulk(Bytes::from("news")),
+                Frame::Bulk(Bytes::from(message)),
+                Frame::Integer(sequence),
+            ])
+        );
+    }
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_sigterm_shuts_the_server_down() {
+    let config = aof_test_config("sigterm");
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::new(config.clone());
+    let handle = tokio::spawn(server.run(listener, shutdown_signal().unwrap()));
+
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    send(&mut client, &["SET", "k", "v"]).await;
+
+    let status = std::process::Command::new("kill")
+        .args(["-TERM", &std::process::id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    tokio::time::timeout(Duration::from_secs(5), handle)
+        .await
+        .expect("server did not shut down")
+        .unwrap()
+        .unwrap();
+
+    // The listener is gone and the write was synced
+    assert!(TcpStream::connect(addr).await.is_err());
+    assert_eq!(Aof::load(&config.aof_path).unwrap().len(), 1);
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_maxmemory_eviction() {
+    let server = start_server_with(ServerConfig {
+        maxmemory: 1,
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(&server).await;
+    let oom = Frame::error("OOM command not allowed when used memory > 'maxmemory'.");
+
+    // With noeviction, writes are refused once the limit is noticed
+    send(&mut client, &["SET", "a", "1"]).await;
+    let mut refused = false;
+    for _ in 0..50 {
+        if send(&mut client, &["SET", "b", "2"]).await == oom {
+            refused = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(refused);
+    // Deleting is still allowed
+    assert_ne!(send(&mut client, &["DEL", "b"]).await, oom);
+
+    send(
+        &mut client,
+        &["CONFIG", "SET", "maxmemory-policy", "allkeys-lru"],
+    )
+    .await;
+    let mut dbsize = Frame::Null;
+    for _ in 0..50 {
+        dbsize = send(&mut client, &["DBSIZE"]).await;
+        if dbsize == Frame::Integer(0) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(dbsize, Frame::Integer(0));
+    assert!(server.metrics.evicted_keys() > 0);
+    assert_eq!(
+        send(&mut client, &["CONFIG", "SET", "maxmemory-policy", "lfu"]).await,
+        Frame::error("ERR CONFIG SET failed (possibly related to argument 'maxmemory-policy') - unknown maxmemory policy 'lfu'")
+    );
+}
+
+#[tokio::test]
+async fn test_commands_refused_in_subscribe_mode() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    send(&mut client, &["SUBSCRIBE", "news"]).await;
+    assert_eq!(
+        send(&mut client, &["GET", "k"]).await,
+        Frame::error("ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context")
+    );
+    assert_eq!(
+        send(&mut client, &["PING"]).await,
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("pong")),
+            Frame::Bulk(Bytes::new()),
+        ])
+    );
+
+    // RESET leaves subscribed mode
+    assert_eq!(
+        send(&mut client, &["RESET"]).await,
+        Frame::Simple("RESET".to_string())
+    );
+    assert_eq!(send(&mut client, &["GET", "k"]).await, Frame::Null);
+
+    // Refused inside MULTI without being queued
+    send(&mut client, &["MULTI"]).await;
+    assert_eq!(
+        send(&mut client, &["WATCH", "k"]).await,
+        Frame::error("ERR WATCH inside MULTI is not allowed")
+    );
+    send(&mut client, &["SELECT", "1"]).await;
+    assert_eq!(
+        send(&mut client, &["RESET"]).await,
+        Frame::Simple("RESET".to_string())
+    );
+    assert_eq!(
+        send(&mut client, &["EXEC"]).await,
+        Frame::error("ERR EXEC without MULTI")
+    );
+
+    assert_eq!(
+        send(&mut client, &["QUIT"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    assert!(client.read_frame().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_idle_subscriber_gets_keepalive() {
+    let server = start_server_with(ServerConfig {
+        pubsub_keepalive_secs: 1,
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(&server).await;
+
+    send(&mut client, &["SUBSCRIBE", "news"]).await;
+    let start = Instant::now();
+    let keepalive = tokio::time::timeout(Duration::from_secs(3), client.read_frame())
+        .await
+        .expect("no keepalive within the interval")
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    assert_eq!(
+        keepalive,
+        Some(Frame::Array(vec![
+            Frame::Bulk(Bytes::from("pong")),
+            Frame::Bulk(Bytes::new()),
+        ]))
+    );
+}
+
+#[tokio::test]
+async fn test_xadd_ids_survive_aof_replay() {
+    let config = aof_test_config("stream");
+    let server = start_server_with(config.clone()).await;
+    let mut client = connect(&server).await;
+
+    let mut ids = Vec::new();
+    for n in ["1", "2", "3"] {
+        match send(&mut client, &["XADD", "s", "*", "n", n]).await {
+            Frame::Bulk(id) => ids.push(id),
+            other => panic!("unexpected XADD reply {:?}", other),
+        }
+    }
+    assert!(ids.windows(2).all(|pair| {
+        let parse = |id: &Bytes| StreamId::parse(id, 0).unwrap();
+        parse(&pair[0]) < parse(&pair[1])
+    }));
+    assert_eq!(
+        send(&mut client, &["XADD", "s", "0-1", "n", "0"]).await,
+        Frame::error(
+            "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+        )
+    );
+    assert_eq!(send(&mut client, &["XLEN", "s"]).await, Frame::Integer(3));
+    let range = send(&mut client, &["XRANGE", "s", "-", "+", "COUNT", "2"]).await;
+    let entry = |id: &Bytes, n: &str| {
+        Frame::Array(vec![
+            Frame::Bulk(id.clone()),
+            Frame::Array(vec![
+                Frame::Bulk(Bytes::from("n")),
+                Frame::Bulk(Bytes::from(n.to_string())),
+            ]),
+        ])
+    };
+    assert_eq!(
+        range,
+        Frame::Array(vec![entry(&ids[0], "1"), entry(&ids[1], "2")])
+    );
+
+    // The generated IDs were logged, not `*`
+    let restarted = start_server_with(config.clone()).await;
+    let mut client = connect(&restarted).await;
+    assert_eq!(
+        send(&mut client, &["XRANGE", "s", "-", "+"]).await,
+        Frame::Array(vec![
+            entry(&ids[0], "1"),
+            entry(&ids[1], "2"),
+            entry(&ids[2], "3")
+        ])
+    );
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_xread_block_wakes_on_xadd() {
+    let server = start_server().await;
+    let mut reader = connect(&server).await;
+    let mut writer = connect(&server).await;
+
+    send(&mut writer, &["XADD", "s", "1-1", "n", "old"]).await;
+    // Without BLOCK, `$` never has anything new
+    assert_eq!(
+        send(&mut reader, &["XREAD", "STREAMS", "s", "$"]).await,
+        Frame::Null
+    );
+    assert_eq!(
+        send(&mut reader, &["XREAD", "BLOCK", "50", "STREAMS", "s", "$"]).await,
+        Frame::Null
+    );
+
+    let read = tokio::spawn(async move {
+        send(
+            &mut reader,
+            &["XREAD", "BLOCK", "0", "STREAMS", "other", "s", "$", "$"],
+        )
+        .await
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!read.is_finished());
+    assert_eq!(
+        send(&mut writer, &["XADD", "s", "2-1", "n", "new"]).await,
+        Frame::Bulk(Bytes::from("2-1"))
+    );
+
+    let entry = Frame::Array(vec![
+        Frame::Bulk(Bytes::from("2-1")),
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from("n")),
+            Frame::Bulk(Bytes::from("new")),
+        ]),
+    ]);
+    assert_eq!(
+        read.await.unwrap(),
+        Frame::Array(vec![Frame::Array(vec![
+            Frame::Bulk(Bytes::from("s")),
+            Frame::Array(vec![entry.clone()]),
+        ])])
+    );
+
+    // An explicit ID reads everything after it
+    let mut client = connect(&server).await;
+    assert_eq!(
+        send(&mut client, &["XREAD", "COUNT", "5", "STREAMS", "s", "1-1"]).await,
+        Frame::Array(vec![Frame::Array(vec![
+            Frame::Bulk(Bytes::from("s")),
+            Frame::Array(vec![entry]),
+        ])])
+    );
+    assert!(matches!(
+        send(&mut client, &["XREAD", "STREAMS", "s", "t", "$"]).await,
+        Frame::Error(e) if e.starts_with("ERR Unbalanced")
+    ));
+}
+
+#[tokio::test]
+async fn test_deterministic_iteration_sorts_replies() {
+    let members: Vec<String> = (0..50).map(|n| format!("member:{}", n)).collect();
+    let mut sorted = members.clone();
+    sorted.sort();
+    let sorted: Vec<Frame> = sorted
+        .into_iter()
+        .map(|member| Frame::Bulk(Bytes::from(member)))
+        .collect();
+
+    // Fresh servers hash with fresh seeds, yet all reply in the same order
+    for _ in 0..3 {
+        let server = start_server_with(ServerConfig {
+            deterministic_iteration: true,
+            ..test_config()
+        })
+        .await;
+        let mut client = connect(&server).await;
+        let mut sadd = vec!["SADD", "set"];
+        sadd.extend(members.iter().map(String::as_str));
+        send(&mut client, &sadd).await;
+        assert_eq!(
+            send(&mut client, &["SMEMBERS", "set"]).await,
+            Frame::Array(sorted.clone())
+        );
+
+        for key in ["b", "c", "a"] {
+            send(&mut client, &["HSET", "hash", key, "v"]).await;
+            send(&mut client, &["SET", key, "v"]).await;
+        }
+        assert_eq!(
+            send(&mut client, &["HGETALL", "hash"]).await,
+            Frame::Array(
+                ["a", "v", "b", "v", "c", "v"]
+                    .map(|s| Frame::Bulk(Bytes::from(s)))
+                    .to_vec()
+            )
+        );
+        assert_eq!(
+            send(&mut client, &["KEYS", "?"]).await,
+            Frame::Array(
+                ["a", "b", "c"]
+                    .map(|s| Frame::Bulk(Bytes::from(s)))
+                    .to_vec()
+            )
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_info_commandstats_counts_calls() {
+    let server = start_server().await;
+    let mut client = connect(&server).await;
+
+    send(&mut client, &["SET", "k", "v"]).await;
+    for _ in 0..3 {
+        send(&mut client, &["GET", "k"]).await;
+    }
+    send(&mut client, &["INCR", "k"]).await;
+    send(&mut client, &["MULTI"]).await;
+    send(&mut client, &["WATCH", "k"]).await;
+    send(&mut client, &["DISCARD"]).await;
+
+    let info = match send(&mut client, &["INFO", "commandstats"]).await {
+        Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    assert!(info.starts_with("# Commandstats\r\n"), "{}", info);
+    let stat = |name: &str| {
+        info.lines()
+            .find_map(|line| line.strip_prefix(&format!("cmdstat_{}:", name)))
+            .unwrap_or_else(|| panic!("no cmdstat_{} in {}", name, info))
+            .to_string()
+    };
+    let (get, incr, watch) = (stat("get"), stat("incr"), stat("watch"));
+    assert!(get.starts_with("calls=3,usec="), "{}", info);
+    assert!(
+        get.ends_with(",rejected_calls=0,failed_calls=0"),
+        "{}",
+        info
+    );
+    assert!(incr.starts_with("calls=1,"), "{}", info);
+    assert!(incr.ends_with(",failed_calls=1"), "{}", info);
+    assert!(watch.starts_with("calls=0,usec=0,"), "{}", info);
+    assert!(
+        watch.ends_with(",rejected_calls=1,failed_calls=0"),
+        "{}",
+        info
+    );
+    // Only the section asked for
+    assert!(!info.contains("connected_clients"), "{}", info);
+
+    let info = match send(&mut client, &["INFO"]).await {
+        Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+        other => panic!("unexpected reply: {:?}", other),
+    };
+    assert!(info.contains("connected_clients:1\r\n"), "{}", info);
+    assert!(!info.contains("cmdstat_"), "{}", info);
+}
+
+#[tokio::test]
+async fn test_databases_config_bounds_select_and_loading() {
+    let server = start_server_with(ServerConfig {
+        databases: 4,
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(&server).await;
+    assert_eq!(
+        send(&mut client, &["SELECT", "3"]).await,
+        Frame::Simple("OK".to_string())
+    );
+    for index in ["4", "5"] {
+        assert_eq!(
+            send(&mut client, &["SELECT", index]).await,
+            Frame::error("ERR DB index is out of range")
+        );
+    }
+
+    // An AOF written with more databases is refused, not replayed into
+    // the wrong one
+    let config = aof_test_config("databases");
+    let server = start_server_with(config.clone()).await;
+    let mut client = connect(&server).await;
+    send(&mut client, &["SELECT", "5"]).await;
+    send(&mut client, &["SET", "k", "v"]).await;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let fewer = Server::new(ServerConfig {
+        databases: 4,
+        ..config.clone()
+    });
+    let e = fewer
+        .run(listener, std::future::pending())
+        .await
+        .unwrap_err();
+    assert!(
+        e.to_string().contains("selects DB 5 but only 4 databases"),
+        "{}",
+        e
+    );
+    std::fs::remove_file(&config.aof_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_keys_max_results_advises_scan() {
+    let server = start_server_with(ServerConfig {
+        keys_max_results: 10,
+        ..test_config()
+    })
+    .await;
+    let mut client = connect(&server).await;
+    for n in 0..100 {
+        send(&mut client, &["SET", &format!("key:{}", n), "v"]).await;
+    }
+
+    assert_eq!(
+        send(&mut client, &["KEYS", "*"]).await,
+        Frame::error("ERR KEYS result set too large, use SCAN")
+    );
+    // Under the cap is fine
+    match send(&mut client, &["KEYS", "key:1?"]).await {
+        Frame::Array(keys) => assert_eq!(keys.len(), 10),
+        other => panic!("unexpected KEYS reply {:?}", other),
+    }
+
+    // 0 lifts the cap
+    send(&mut client, &["CONFIG", "SET", "keys-max-results", "0"]).await;
+    match send(&mut client, &["KEYS", "*"]).await {
+        Frame::Array(keys) => assert_eq!(keys.len(), 100),
+        other => panic!("unexpected KEYS reply {:?}", other),
+    }
+}
@@ -0,0 +1,474 @@
+use crate::clock;
+use crate::db::{Db, Score, StreamId, Value};
+use crate::metrics::{Metrics, SharedMetrics};
+use crate::persistence::Aof;
+use bytes::Bytes;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{info, warn};
+
+/// Magic bytes and format version at the start of every snapshot file
+const MAGIC: &[u8] = b"RUSTREDIS-RDB-1\n";
+
+/// Marker written after the last entry
+const EOF_MARKER: u8 = 0xFF;
+
+/// Marker followed by a database index; the entries after it belong to
+/// that database. Entries before the first marker belong to database 0.
+const SELECT_DB: u8 = 0xFE;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_HASH: u8 = 3;
+#[cfg(feature = "json")]
+const TYPE_JSON: u8 = 4;
+const TYPE_ZSET: u8 = 5;
+const TYPE_STREAM: u8 = 6;
+
+/// How often the scheduler checks the save points (Redis' serverCron rate)
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Delay before retrying after a failed background save
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// A `save <seconds> <changes>` point
+///
+/// A snapshot is taken once at least `changes` writes happened and at least
+/// `seconds` elapsed since the last successful snapshot.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Parse save points in the redis.conf form, e.g. `"900 1 300 10"`
+///
+/// An empty string means no save points.
+pub fn parse_save_points(spec: &str) -> Result<Vec<SavePoint>, String> {
+    let numbers = spec
+        .split_whitespace()
+        .map(|n| {
+            n.parse::<u64>()
+                .map_err(|_| format!("invalid save point value '{}'", n))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() % 2 != 0 {
+        return Err("save points must be '<seconds> <changes>' pairs".to_string());
+    }
+
+    Ok(numbers
+        .chunks(2)
+        .map(|pair| SavePoint {
+            seconds: pair[0],
+            changes: pair[1],
+        })
+        .collect())
+}
+
+/// Write a point-in-time snapshot of the databases `dbs` to `path`
+///
+/// The data is written to a temporary file next to `path` and renamed
+/// over it, so a crash mid-save never leaves a truncated snapshot behind.
+/// Returns the number of keys saved.
+pub fn save(dbs: &[Db], path: impl AsRef<Path>) -> io::Result<usize> {
+    let path = path.as_ref();
+
+    let tmp_path = temp_path(path);
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    out.write_all(MAGIC)?;
+    let mut saved = 0;
+    for (index, db) in dbs.iter().enumerate() {
+        let snapshot = db.snapshot_clone();
+        if index > 0 && !snapshot.is_empty() {
+            out.write_all(&[SELECT_DB])?;
+            write_len(&mut out, index)?;
+        }
+        for (key, value, expires_at) in snapshot.iter() {
+            write_entry(&mut out, key, value, expires_at)?;
+        }
+        saved += snapshot.len();
+    }
+    out.write_all(&[EOF_MARKER])?;
+
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+
+    Ok(saved)
+}
+
+/// Load a snapshot written by `save` into the databases `dbs`
+///
+/// Keys whose expiration has already passed are skipped. Returns the
+/// number of keys loaded. A snapshot of more databases than `dbs` fails
+/// with `InvalidInput`.
+pub fn load(dbs: &[Db], path: impl AsRef<Path>) -> io::Result<usize> {
+    let data = fs::read(path)?;
+    let mut reader = Reader { data: &data };
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+
+    let mut db = dbs
+        .first()
+        .ok_or_else(|| invalid("no database to load into"))?;
+    let mut loaded = 0;
+    loop {
+        let kind = reader.u8()?;
+        if kind == EOF_MARKER {
+            break;
+        }
+        if kind == SELECT_DB {
+            let index = reader.u32()? as usize;
+            db = dbs.get(index).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "it uses DB {} but only {} databases are configured",
+                        index,
+                        dbs.len()
+                    ),
+                )
+            })?;
+            continue;
+        }
+        let expires_at = match reader.u64()? {
+            0 => None,
+            unix_ms => Some(clock::unix_ms_to_instant(unix_ms)),
+        };
+        let key = reader.bytes()?;
+
+        match kind {
+            TYPE_STRING => db.write_string(key.clone(), reader.bytes()?, None),
+            TYPE_LIST => {
+                let items = (0..reader.u32()?)
+                    .map(|_| reader.bytes())
+                    .collect::<io::Result<Vec<_>>>()?;
+                db.rpush(key.clone(), items);
+            }
+            TYPE_SET => {
+                let members = (0..reader.u32()?)
+                    .map(|_| reader.bytes())
+                    .collect::<io::Result<Vec<_>>>()?;
+                db.sadd(key.clone(), members);
+            }
+            TYPE_HASH => {
+                for _ in 0..reader.u32()? {
+                    let field = reader.bytes()?;
+                    db.hset(key.clone(), field, reader.bytes()?);
+                }
+            }
+            TYPE_ZSET => {
+                let mut members = Vec::new();
+                for _ in 0..reader.u32()? {
+                    let member = reader.bytes()?;
+                    let score = Score::new(f64::from_bits(reader.u64()?))
+                        .ok_or_else(|| invalid("NaN score in snapshot"))?;
+                    members.push((score, member));
+                }
+                db.zadd(key.clone(), members).map_err(invalid)?;
+            }
+            TYPE_STREAM => {
+                for _ in 0..reader.u32()? {
+                    let id = StreamId {
+                        ms: reader.u64()?,
+                        seq: reader.u64()?,
+                    };
+                    let fields = (0..reader.u32()?)
+                        .map(|_| Ok((reader.bytes()?, reader.bytes()?)))
+                        .collect::<io::Result<Vec<_>>>()?;
+                    db.xadd(key.clone(), Some(id), fields).map_err(invalid)?;
+                }
+            }
+            #[cfg(feature = "json")]
+            TYPE_JSON => {
+                let document = serde_json::from_slice(&reader.bytes()?)
+                    .map_err(|e| invalid(&e.to_string()))?;
+                db.json_set(key.clone(), &crate::db::json::JsonPath::root(), document)
+                    .map_err(invalid)?;
+            }
+            other => return Err(invalid(&format!("unknown value type {}", other))),
+        }
+
+        match expires_at {
+            Some(at) if at <= Instant::now() => {
+                db.delete(&key);
+            }
+            Some(at) => {
+                db.expire_at(&key, at);
+                loaded += 1;
+            }
+            None => loaded += 1,
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// Take a snapshot now (SAVE and the save points)
+///
+/// With an AOF, writes wait while the snapshot is taken and the AOF is
+/// emptied afterwards: at startup the AOF is replayed on top of the
+/// snapshot, so it must only hold the writes that came after it.
+///
+/// On success the write counter is reduced by the writes the snapshot
+/// covers and the last save time is updated. Returns the number of keys
+/// saved.
+pub async fn save_now(
+    dbs: &[Db],
+    path: &Path,
+    aof: Option<&Aof>,
+    metrics: &Metrics,
+) -> io::Result<usize> {
+    let _order = match aof {
+        Some(aof) => Some(aof.lock_order().await),
+        None => None,
+    };
+    let dirty = metrics.dirty();
+    let (save_dbs, save_path) = (dbs.to_vec(), path.to_path_buf());
+    let keys = tokio::task::spawn_blocking(move || save(&save_dbs, &save_path))
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))?;
+    if let Some(aof) = aof {
+        if let Err(e) = aof.truncate() {
+            warn!("Could not empty the AOF after saving: {}", e);
+        }
+    }
+    metrics.record_save(dirty);
+    Ok(keys)
+}
+
+/// Start the task that takes a snapshot whenever a save point is reached
+///
+/// Writes that land while a save is running count towards the next one,
+/// see `save_now`.
+pub fn start_save_scheduler(
+    dbs: Vec<Db>,
+    path: PathBuf,
+    points: Vec<SavePoint>,
+    aof: Option<Arc<Aof>>,
+    metrics: SharedMetrics,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(CHECK_INTERVAL);
+        let mut last_failure: Option<Instant> = None;
+
+        loop {
+            interval.tick().await;
+
+            if last_failure.is_some_and(|at| at.elapsed() < RETRY_DELAY) {
+                continue;
+            }
+            let dirty = metrics.dirty();
+            let since_save = clock::unix_time_ms().saturating_sub(metrics.last_save_ms()) / 1000;
+            let point = points
+                .iter()
+                .find(|p| dirty >= p.changes && since_save >= p.seconds);
+            let point = match point {
+                Some(point) => *point,
+                None => continue,
+            };
+
+            info!(
+                "{} changes in {} seconds. Saving...",
+                point.changes, point.seconds
+            );
+            match save_now(&dbs, &path, aof.as_deref(), &metrics).await {
+                Ok(keys) => {
+                    info!("Background saving terminated with success ({} keys)", keys);
+                    last_failure = None;
+                }
+                Err(e) => {
+                    warn!("Background saving error: {}", e);
+                    last_failure = Some(Instant::now());
+                }
+            }
+        }
+    })
+}
+
+/// File next to `path` that is written first and then renamed over it
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".tmp-{}", std::process::id()));
+    path.with_file_name(name)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn write_entry(
+    out: &mut impl Write,
+    key: &[u8],
+    value: &Value,
+    expires_at: Option<Instant>,
+) -> io::Result<()> {
+    let kind = match value {
+        Value::String(_) => TYPE_STRING,
+        // Stored plainly so that the file does not depend on the feature
+        #[cfg(feature = "compression")]
+        Value::Compressed(_) => TYPE_STRING,
+        Value::List(_) => TYPE_LIST,
+        Value::Set(_) => TYPE_SET,
+        Value::Hash(_) => TYPE_HASH,
+        Value::ZSet(_) => TYPE_ZSET,
+        Value::Stream(_) => TYPE_STREAM,
+        #[cfg(feature = "json")]
+        Value::Json(_) => TYPE_JSON,
+    };
+    out.write_all(&[kind])?;
+    let expires_ms = expires_at.map_or(0, clock::instant_to_unix_ms);
+    out.write_all(&expires_ms.to_le_bytes())?;
+    write_blob(out, key)?;
+
+    match value {
+        Value::String(bytes) => write_blob(out, bytes),
+        #[cfg(feature = "compression")]
+        Value::Compressed(compressed) => write_blob(out, &compressed.decompress()),
+        Value::List(list) => {
+            write_len(out, list.len())?;
+            list.iter().try_for_each(|item| write_blob(out, item))
+        }
+        Value::Set(set) => {
+            write_len(out, set.len())?;
+            set.iter().try_for_each(|member| write_blob(out, &member))
+        }
+        Value::Hash(hash) => {
+            write_len(out, hash.len())?;
+            hash.iter().try_for_each(|(field, value)| {
+                write_blob(out, field)?;
+                write_blob(out, value)
+            })
+        }
+        Value::ZSet(zset) => {
+            write_len(out, zset.len())?;
+            zset.iter().try_for_each(|(member, score)| {
+                write_blob(out, member)?;
+                out.write_all(&score.value().to_le_bytes())
+            })
+        }
+        Value::Stream(stream) => {
+            write_len(out, stream.len())?;
+            stream.iter().try_for_each(|(id, fields)| {
+                out.write_all(&id.ms.to_le_bytes())?;
+                out.write_all(&id.seq.to_le_bytes())?;
+                write_len(out, fields.len())?;
+                fields.iter().try_for_each(|(field, value)| {
+                    write_blob(out, field)?;
+                    write_blob(out, value)
+                })
+            })
+        }
+        #[cfg(feature = "json")]
+        Value::Json(document) => write_blob(out, &serde_json::to_vec(document)?),
+    }
+}
+
+fn write_len(out: &mut impl Write, len: usize) -> io::Result<()> {
+    let len = u32::try_from(len).map_err(|_| invalid("value too large for snapshot"))?;
+    out.write_all(&len.to_le_bytes())
+}
+
+fn write_blob(out: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    write_len(out, data.len())?;
+    out.write_all(data)
+}
+
+/// Cursor over the bytes of a snapshot file
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < n {
+            return Err(invalid("unexpected end of snapshot"));
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<Bytes> {
+        let len = self.u32()? as usize;
+        Ok(Bytes::copy_from_slice(self.take(len)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_save_points() {
+        assert_eq!(
+            parse_save_points("900 1 300 10"),
+            Ok(vec![
+                SavePoint {
+                    seconds: 900,
+                    changes: 1
+                },
+                SavePoint {
+                    seconds: 300,
+                    changes: 10
+                },
+            ])
+        );
+        assert_eq!(parse_save_points(""), Ok(vec![]));
+        assert!(parse_save_points("900").is_err());
+        assert!(parse_save_points("900 x").is_err());
+    }
+
+    #[test]
+    fn test_save_and_load_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("rustredis-snapshot-{}.rdb", std::process::id()));
+        let db = Db::new();
+        db.write_string(Bytes::from("s"), Bytes::from("v"), None);
+        db.rpush(Bytes::from("l"), vec![Bytes::from("a"), Bytes::from("b")]);
+        db.sadd(Bytes::from("set"), vec![Bytes::from("m")]);
+        db.hset(Bytes::from("h"), Bytes::from("f"), Bytes::from("1"));
+        let score = Score::new(-1.5).unwrap();
+        db.zadd(Bytes::from("z"), vec![(score, Bytes::from("m"))])
+            .unwrap();
+        let future = clock::unix_time_ms() + 60_000;
+        db.write_string(Bytes::from("ttl"), Bytes::from("x"), None);
+        db.expire_at(b"ttl", clock::unix_ms_to_instant(future));
+
+        assert_eq!(save(std::slice::from_ref(&db), &path).unwrap(), 6);
+
+        let restored = Db::new();
+        assert_eq!(load(std::slice::from_ref(&restored), &path).unwrap(), 6);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored.read_string(b"s"), Some(Bytes::from("v")));
+        assert_eq!(
+            restored.lrange(b"l", 0, -1),
+            Some(vec![Bytes::from("a"), Bytes::from("b")])
+        );
+        assert!(restored.sismember(b"set", b"m").unwrap());
+        assert_eq!(restored.hget(b"h", b"f").unwrap(), Some(Bytes::from("1")));
+        assert_eq!(restored.zscore(b"z", b"m").unwrap(), Some(score));
+        assert_eq!(restored.pexpiretime(b"ttl"), future as i64);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::cmd::Command;
+use crate::db::{Db, WatchVersion};
+use crate::frame::Frame;
+use bytes::Bytes;
+
+/// Per-connection MULTI/EXEC/WATCH state
+///
+/// WATCH records each key's `(generation, version)` pair. EXEC compares
+/// those snapshots against the current ones, so any mutation, deletion, or
+/// delete-and-recreate of a watched key aborts the transaction without
+/// relying on a global write counter.
+#[derive(Default)]
+pub struct Transaction {
+    /// Commands queued since MULTI, `None` outside a transaction
+    queued: Option<Vec<(Frame, Command)>>,
+
+    /// Watched keys, the index of their database, and their state at WATCH
+    /// time
+    watched: Vec<(usize, Bytes, WatchVersion)>,
+
+    /// Whether a command was refused while queueing, so EXEC must abort
+    failed: bool,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether MULTI has been issued and not yet finished
+    pub fn is_active(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    /// Number of queued commands, `None` outside a transaction
+    pub fn queued_len(&self) -> Option<usize> {
+        self.queued.as_ref().map(Vec::len)
+    }
+
+    /// Start queueing commands (MULTI)
+    pub fn begin(&mut self) -> Result<(), &'static str> {
+        if self.is_active() {
+            return Err("ERR MULTI calls can not be nested");
+        }
+        self.queued = Some(Vec::new());
+        self.failed = false;
+        Ok(())
+    }
+
+    /// Record that a command was refused instead of queued
+    ///
+    /// Like Redis, EXEC then discards the whole transaction rather than
+    /// run it without that command. Does nothing outside MULTI.
+    pub fn flag_error(&mut self) {
+        if self.is_active() {
+            self.failed = true;
+        }
+    }
+
+    /// Queue a command to run at EXEC
+    pub fn queue(&mut self, frame: Frame, command: Command) {
+        if let Some(queued) = self.queued.as_mut() {
+            queued.push((frame, command));
+        }
+    }
+
+    /// Abandon the transaction (DISCARD), returning false outside MULTI
+    pub fn discard(&mut self) -> bool {
+        let was_active = self.queued.take().is_some();
+        self.watched.clear();
+        self.failed = false;
+        was_active
+    }
+
+    /// Snapshot keys of `db`, the database at `db_index`, so EXEC can detect
+    /// concurrent modification (WATCH)
+    pub fn watch(&mut self, db_index: usize, db: &Db, keys: &[Bytes]) {
+        for key in keys {
+            if self
+                .watched
+                .iter()
+                .any(|(index, watched, _)| *index == db_index && watched == key)
+            {
+                continue;
+            }
+            self.watched
+                .push((db_index, key.clone(), db.watch_version(key)));
+        }
+    }
+
+    /// Forget all watched keys (UNWATCH)
+    pub fn unwatch(&mut self) {
+        self.watched.clear();
+    }
+
+    /// Whether a key watched in a database other than `db_index` changed
+    /// since it was watched
+    ///
+    /// Checked before EXEC takes the lock of its own database: reading
+    /// another database's versions while holding it could deadlock with an
+    /// EXEC running there that watches keys here.
+    pub fn is_stale_elsewhere(&self, dbs: &[Db], db_index: usize) -> bool {
+        self.watched.iter().any(|(index, key, version)| {
+            *index != db_index && dbs[*index].watch_version(key) != *version
+        })
+    }
+
+    /// Finish the transaction for EXEC
+    ///
+    /// Returns the queued commands, or `None` if a watched key was modified
+    /// and the transaction must abort. Fails with EXECABORT if a command was
+    /// refused while queueing (see `flag_error`). Watches are cleared either
+    /// way. Keys watched in `db`, the database at `db_index`, are checked
+    /// here; `stale_elsewhere` is the result of `is_stale_elsewhere`.
+    pub fn take_for_exec(
+        &mut self,
+        db_index: usize,
+        db: &Db,
+        stale_elsewhere: bool,
+    ) -> Result<Option<Vec<(Frame, Command)>>, &'static str> {
+        let queued = self.queued.take().ok_or("ERR EXEC without MULTI")?;
+        let stale = stale_elsewhere
+            || self.watched.iter().any(|(index, key, version)| {
+                *index == db_index && db.watch_version(key) != *version
+            });
+        self.watched.clear();
+
+        if std::mem::take(&mut self.failed) {
+            return Err("EXECABORT Transaction discarded because of previous errors.");
+        }
+
+        if stale {
+            Ok(None)
+        } else {
+            Ok(Some(queued))
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Lock `mutex` even if a thread panicked while holding it
+///
+/// A panicking command handler poisons every lock it holds. Recovering the
+/// guard keeps one bad command from failing every later one on the same
+/// lock; the state behind it is whatever the handler left.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Read-lock `lock` even if a writer panicked, see `lock`
+pub fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Write-lock `lock` even if a writer panicked, see `lock`
+pub fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Parse an integer exactly as Redis does (`string2ll`)
+///
+/// Only an optional leading `-` followed by decimal digits is accepted:
+/// no whitespace, no `+` sign, no leading zeros and nothing outside the
+/// `i64` range. `"0"` is the only value that may start with a zero.
+#[allow(clippy::result_unit_err)]
+pub fn parse_redis_int(bytes: &[u8]) -> Result<i64, ()> {
+    if bytes == b"0" {
+        return Ok(0);
+    }
+
+    let (negative, digits) = match bytes.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, bytes),
+    };
+    match digits.first() {
+        Some(b'1'..=b'9') => {}
+        _ => return Err(()),
+    }
+
+    let mut value: u64 = 0;
+    for &b in digits {
+        if !b.is_ascii_digit() {
+            return Err(());
+        }
+        value = value
+            .checked_mul(10)
+            .and_then(|v| v.checked_add((b - b'0') as u64))
+            .ok_or(())?;
+    }
+
+    if negative {
+        if value > i64::MAX as u64 + 1 {
+            return Err(());
+        }
+        Ok((value as i64).wrapping_neg())
+    } else {
+        i64::try_from(value).map_err(|_| ())
+    }
+}
+
+/// Parse a float the way INCRBYFLOAT reads its operands
+///
+/// Decimal and exponent forms and `inf` are accepted; NaN, surrounding
+/// whitespace and anything else are not.
+pub fn parse_redis_float(bytes: &[u8]) -> Option<f64> {
+    let value = std::str::from_utf8(bytes).ok()?.parse::<f64>().ok()?;
+    (!value.is_nan()).then_some(value)
+}
+
+/// Resolve the inclusive `start`/`stop` indexes of LRANGE, GETRANGE,
+/// ZRANGE and the like against a sequence of `len` items
+///
+/// Negative indexes count from the end, -1 being the last item. Returns
+/// the half-open range `start..end` of the positions selected, clamped to
+/// `0..len`. It is empty (`start == end`) when nothing is selected: `stop`
+/// before `start`, `start` past the end or `stop` before the beginning.
+pub fn normalize_range(start: isize, stop: isize, len: usize) -> (usize, usize) {
+    let len = len as isize;
+    let resolve = |index: isize| {
+        if index < 0 {
+            len.saturating_add(index)
+        } else {
+            index
+        }
+    };
+    let (start, stop) = (resolve(start).max(0), resolve(stop).min(len - 1));
+    if start > stop {
+        (0, 0)
+    } else {
+        (start as usize, stop as usize + 1)
+    }
+}
+
+/// Match `string` against a Redis glob pattern (`stringmatchlen`)
+///
+/// Supports `*`, `?`, `[abc]`, `[^abc]`, ranges like `[a-z]` and `\` to
+/// escape the next character. Works on raw bytes, so matching allocates
+/// nothing. A `*` only ever backtracks to the most recent `*`, which keeps
+/// the matching time linear in `pattern.len() * string.len()`.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Pattern position after the last `*` and the string position it resumes at
+    let mut star: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        if p < pattern.len() {
+            if pattern[p] == b'*' {
+                p += 1;
+                star = Some((p, s));
+                continue;
+            }
+            if let Some(next) = match_one(pattern, p, string[s]) {
+                p = next;
+                s += 1;
+                continue;
+            }
+        }
+
+        // Mismatch: let the last `*` swallow one more byte
+        match star {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                star = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match the single-byte token at `pattern[p]` against `c`
+///
+/// Returns the position after the token if it matches.
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        b'[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+
+            let mut matched = false;
+            // An unterminated class ends with the pattern
+            while i < pattern.len() {
+                match pattern[i] {
+                    b']' => {
+                        i += 1;
+                        break;
+                    }
+                    b'\\' if i + 1 < pattern.len() => {
+                        matched |= pattern[i + 1] == c;
+                        i += 2;
+                    }
+                    start if i + 2 < pattern.len() && pattern[i + 1] == b'-' => {
+                        let end = pattern[i + 2];
+                        let (low, high) = if start <= end {
+                            (start, end)
+                        } else {
+                            (end, start)
+                        };
+                        matched |= (low..=high).contains(&c);
+                        i += 3;
+                    }
+                    literal => {
+                        matched |= literal == c;
+                        i += 1;
+                    }
+                }
+            }
+
+            (matched != negate).then_some(i)
+        }
+        literal => (literal == c).then_some(p + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_redis_int() {
+        assert_eq!(parse_redis_int(b"0"), Ok(0));
+        assert_eq!(parse_redis_int(b"10"), Ok(10));
+        assert_eq!(parse_redis_int(b"-10"), Ok(-10));
+        assert_eq!(parse_redis_int(b"9223372036854775807"), Ok(i64::MAX));
+        assert_eq!(parse_redis_int(b"-9223372036854775808"), Ok(i64::MIN));
+
+        for bad in [
+            &b""[..],
+            b" 10",
+            b"10 ",
+            b"+10",
+            b"-",
+            b"-0",
+            b"010",
+            b"1.5",
+            b"1e3",
+            b"9223372036854775808",
+            b"-9223372036854775809",
+        ] {
+            assert_eq!(parse_redis_int(bad), Err(()), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_normalize_range_matches_brute_force() {
+        // Check every position against the resolved indexes directly
+        fn reference(start: isize, stop: isize, len: usize) -> Vec<usize> {
+            let resolve = |index: isize| {
+                let index = index as i128;
+                if index < 0 {
+                    len as i128 + index
+                } else {
+                    index
+                }
+            };
+            (0..len)
+                .filter(|&i| resolve(start) <= i as i128 && i as i128 <= resolve(stop))
+                .collect()
+        }
+
+        let mut indexes: Vec<isize> = (-12..=12).collect();
+        indexes.extend([isize::MIN, isize::MIN + 1, isize::MAX - 1, isize::MAX]);
+        for len in 0..=9 {
+            for &start in &indexes {
+                for &stop in &indexes {
+                    let (from, to) = normalize_range(start, stop, len);
+                    assert!(from <= to && to <= len, "{} {} over {}", start, stop, len);
+                    assert_eq!(
+                        (from..to).collect::<Vec<_>>(),
+                        reference(start, stop, len),
+                        "{} {} over {}",
+                        start,
+                        stop,
+                        len
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "hllo", true),
+            ("h*llo", "heeeello", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[b-a]llo", "hallo", true),
+            ("h[a-b]llo", "hcllo", false),
+            ("user:*:name", "user:42:name", true),
+            ("user:*:name", "user:42:email", false),
+            ("a*b*c", "aXXbYYbZZc", true),
+            ("a*b*c", "aXXbYY", false),
+            ("\\*", "*", true),
+            ("\\*", "x", false),
+            ("[\\]]", "]", true),
+            ("key.[0-9]", "key.7", true),
+            ("key.[0-9]", "keyx7", false),
+        ];
+        for &(pattern, string, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), string.as_bytes()),
+                expected,
+                "{:?} ~ {:?}",
+                pattern,
+                string
+            );
+        }
+    }
+}
@@ -0,0 +1,48 @@
+//! Allocation counting needs its own `#[global_allocator]`, which would
+//! otherwise apply to every unit test of the crate.
+
+use bytes::{Bytes, BytesMut};
+use rust_redis::frame::Frame;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// The system allocator, counting the allocations of each thread so
+/// tests running in parallel don't see each other's
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+#[test]
+fn test_encode_integers_without_allocating() {
+    let values = [0, 1, -1, 9, 10, 256, -257, 1 << 40, i64::MAX, i64::MIN];
+    let mut out = BytesMut::with_capacity(1024);
+    let before = ALLOCATIONS.with(Cell::get);
+    for n in values {
+        Frame::Integer(n).encode(&mut out);
+    }
+    assert_eq!(ALLOCATIONS.with(Cell::get), before);
+
+    let expected: String = values.iter().map(|n| format!(":{}\r\n", n)).collect();
+    assert_eq!(&out[..], expected.as_bytes());
+
+    // Length prefixes take the same path
+    let mut out = BytesMut::new();
+    Frame::Array(vec![Frame::Bulk(Bytes::from(vec![b'x'; 12]))]).encode(&mut out);
+    assert_eq!(&out[..], b"*1\r\n$12\r\nxxxxxxxxxxxx\r\n");
+}